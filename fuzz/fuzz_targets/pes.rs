@@ -0,0 +1,29 @@
+//! PES headers and the reassembly of the video access units, with the
+//! parsing of their SPS.
+
+#![no_main]
+
+use it9910_stream_example::h264;
+use it9910_stream_example::pes;
+use it9910_stream_example::snapshot::KeyframeExtractor;
+use it9910_stream_example::ts::{Packet, PACKET_SIZE, SYNC_BYTE};
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|data: &[u8]| {
+    if let Some(header) = pes::parse_header(data) {
+        if let Some(es) = data.get(header.header_len..) {
+            let _ = h264::starts_keyframe(es);
+            let _ = h264::find_sps(es);
+        }
+    }
+    // The same bytes as the packets of the video PID.
+    let mut extractor = KeyframeExtractor::new();
+    for chunk in data.chunks(PACKET_SIZE - 1) {
+        let mut pkt = [0xff; PACKET_SIZE];
+        pkt[0] = SYNC_BYTE;
+        pkt[1..1 + chunk.len()].copy_from_slice(chunk);
+        if let Some(frame) = extractor.packet(&Packet::new(&pkt).expect("sync byte set")) {
+            let _ = h264::find_sps(&frame);
+        }
+    }
+});
@@ -0,0 +1,20 @@
+//! PAT and PMT sections, from a single packet.
+
+#![no_main]
+
+use it9910_stream_example::psi;
+use it9910_stream_example::ts::{Packet, PACKET_SIZE, SYNC_BYTE};
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|data: &[u8]| {
+    let mut pkt = [0xff; PACKET_SIZE];
+    let len = data.len().min(PACKET_SIZE - 1);
+    pkt[0] = SYNC_BYTE;
+    pkt[1..1 + len].copy_from_slice(&data[..len]);
+    let pkt = Packet::new(&pkt).expect("sync byte set");
+    let _ = psi::crc_ok(&pkt);
+    let _ = psi::parse_pat(&pkt);
+    let _ = psi::parse_pmt(&pkt);
+    let _ = pkt.pcr();
+    let _ = pkt.discontinuity();
+});
@@ -0,0 +1,21 @@
+//! Responses of the command endpoint, as the dispatcher parses them.
+
+#![no_main]
+
+use it9910_stream_example::response::{self, HEADER_LEN};
+use it9910_stream_example::Response;
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|data: &[u8]| {
+    let _ = response::is_header(data);
+    if let Ok(declared) = Response::declared_len(data) {
+        assert!(declared >= HEADER_LEN);
+    }
+    if let Ok(resp) = Response::parse(data) {
+        assert_eq!(resp.payload.len(), data.len() - HEADER_LEN);
+        for offset in 0..resp.payload.len() + 4 {
+            let _ = resp.word(offset);
+        }
+        let _ = resp.rejection(0);
+    }
+});
@@ -0,0 +1,33 @@
+//! Recovery of the packet boundaries, fed in transfers of varying sizes.
+
+#![no_main]
+
+use it9910_stream_example::ts::{Aligner, PACKET_SIZE, SYNC_BYTE};
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|data: &[u8]| {
+    let (sizes, stream) = match data.split_first() {
+        Some((&sizes, stream)) => (sizes, stream),
+        None => return,
+    };
+    let mut aligner = Aligner::new();
+    let mut next = 0;
+    let mut packets = 0;
+    let mut pos = 0;
+    let mut step = usize::from(sizes) + 1;
+    while pos < stream.len() {
+        let end = (pos + step).min(stream.len());
+        aligner.push(&stream[pos..end], |offset, pkt| {
+            assert!(offset >= next, "packets overlap or go backwards");
+            assert_eq!(pkt.data()[0], SYNC_BYTE);
+            next = offset + PACKET_SIZE as u64;
+            packets += 1;
+        });
+        pos = end;
+        step = step * 7 % 4093 + 1;
+    }
+    // Every byte is either in a packet, skipped or kept for the next push.
+    let consumed = packets * PACKET_SIZE as u64 + aligner.skipped();
+    assert_eq!(consumed, aligner.offset());
+    assert!(aligner.offset() <= stream.len() as u64);
+});
@@ -0,0 +1,36 @@
+#!/usr/bin/env python3
+"""Capture two seconds of stream for each brightness setting.
+
+Build and install the module first, see python/README. Pass --emulate to
+run against the software emulation of the device.
+"""
+
+import sys
+
+from it9910_stream_example import It9910
+
+CAPTURE_SECONDS = 2
+
+
+def main():
+    emulate = "--emulate" in sys.argv[1:]
+    dev = It9910.open(emulate=emulate)
+    print("Firmware version: %#010x" % (dev.firmware_version or 0))
+    for brightness in range(0, 101, 25):
+        dev.configure(brightness=brightness)
+        dev.start()
+        name = "brightness-%03d.ts" % brightness
+        with open(name, "wb") as out:
+            # The emulated stream comes at 4 Mbit/s; count bytes rather
+            # than time so that the sweep does not depend on the clock.
+            size = 0
+            while size < CAPTURE_SECONDS * 500_000:
+                data = dev.read()
+                out.write(data)
+                size += len(data)
+        dev.stop()
+        print("%s: %d bytes, settings %s" % (name, size, dev.settings()))
+
+
+if __name__ == "__main__":
+    main()
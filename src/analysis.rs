@@ -0,0 +1,669 @@
+//! Live analysis of the transport stream.
+
+use std::collections::{BTreeMap, HashMap};
+
+use serde::Serialize;
+
+use crate::h264::{self, Sps};
+use crate::pes::{self, TIMESTAMP_HZ};
+use crate::psi::{self, ElementaryStream, Pmt, PAT_PID, STREAM_TYPE_H264};
+use crate::ts::{Packet, NULL_PID};
+
+/// A problem found in the stream.
+#[derive(Clone, Debug, Serialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum Event {
+    /// The decoding timestamp of a PID jumped backwards, or forward by much
+    /// more than its usual cadence.
+    TimestampDiscontinuity {
+        pid: u16,
+        offset: u64,
+        /// Jump in 90 kHz ticks, negative when going backwards.
+        delta: i64,
+        /// Usual step between two PES packets, when known.
+        expected: Option<i64>,
+    },
+    /// Packets of a PID were lost or reordered.
+    ContinuityError {
+        pid: u16,
+        offset: u64,
+        expected: u8,
+        found: u8,
+    },
+    /// No data was received from the device for a while.
+    Gap { offset: u64, duration_ms: u64 },
+    /// The outputs were split before the packet at `offset`.
+    Split {
+        offset: u64,
+        /// Whether the split happened on a keyframe, rather than being
+        /// forced when none came within the window.
+        aligned: bool,
+        reason: String,
+    },
+    /// The format of the input signal changed.
+    InputChange {
+        offset: u64,
+        /// UTC time the change was seen.
+        time: String,
+        signal: String,
+    },
+    /// The firmware status reported an error state.
+    FirmwareError {
+        offset: u64,
+        time: String,
+        error: String,
+    },
+    /// A notification was received on the event endpoint of the device.
+    Notification {
+        offset: u64,
+        time: String,
+        length: usize,
+    },
+    /// The data before the first PAT, at `offset`, was not written out.
+    LeadingDataDiscarded { offset: u64 },
+    /// The video before the first keyframe, at `offset`, was not written
+    /// out, nor the other packets but the last PAT and PMT and possibly the
+    /// audio.
+    PrerollDiscarded { offset: u64, bytes: u64 },
+    /// An output file written atomically was given its final name, or a
+    /// partial file left by a crash its recovered name.
+    OutputRenamed {
+        from: String,
+        to: String,
+        time: String,
+    },
+    /// A partial file left by a crash is written on.
+    PartialOutputResumed { path: String, time: String },
+    /// An existing file is written on from `file_offset`, after trimming
+    /// its last partial packet.
+    OutputAppended {
+        path: String,
+        file_offset: u64,
+        trimmed: u64,
+    },
+    /// The first packet of a PID with the transport scrambling control bits
+    /// set, which players cannot decode.
+    Scrambled {
+        pid: u16,
+        offset: u64,
+        scrambling: u8,
+    },
+    /// The first packet of a PID with the transport error indicator set,
+    /// which the sender knew to be corrupt.
+    TransportError { pid: u16, offset: u64 },
+}
+
+impl std::fmt::Display for Event {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Event::TimestampDiscontinuity {
+                pid,
+                offset,
+                delta,
+                expected,
+            } => {
+                write!(
+                    f,
+                    "Timestamp discontinuity on PID {:#06x} at offset {}: {:+.3} s",
+                    pid,
+                    offset,
+                    *delta as f64 / TIMESTAMP_HZ as f64
+                )?;
+                if let Some(expected) = expected {
+                    write!(
+                        f,
+                        " (expected {:.3} s)",
+                        *expected as f64 / TIMESTAMP_HZ as f64
+                    )?;
+                }
+                Ok(())
+            }
+            Event::ContinuityError {
+                pid,
+                offset,
+                expected,
+                found,
+            } => write!(
+                f,
+                "Continuity error on PID {:#06x} at offset {}: expected CC {}, found {}",
+                pid, offset, expected, found
+            ),
+            Event::Gap {
+                offset,
+                duration_ms,
+            } => write!(
+                f,
+                "Data resumed at offset {} after {:.1} s",
+                offset,
+                *duration_ms as f64 / 1000.0
+            ),
+            Event::Split {
+                offset,
+                aligned,
+                reason,
+            } => write!(
+                f,
+                "Split ({}) at offset {}, {}",
+                reason,
+                offset,
+                if *aligned {
+                    "aligned on a keyframe"
+                } else {
+                    "forced without keyframe"
+                }
+            ),
+            Event::InputChange {
+                offset,
+                time,
+                signal,
+            } => write!(
+                f,
+                "Input signal changed at offset {} ({}): {}",
+                offset, time, signal
+            ),
+            Event::FirmwareError {
+                offset,
+                time,
+                error,
+            } => write!(
+                f,
+                "Firmware error at offset {} ({}): {}",
+                offset, time, error
+            ),
+            Event::Notification {
+                offset,
+                time,
+                length,
+            } => write!(
+                f,
+                "Device notification at offset {} ({}): {} bytes",
+                offset, time, length
+            ),
+            Event::LeadingDataDiscarded { offset } => write!(
+                f,
+                "Output starts at the first PAT, {} bytes before it discarded",
+                offset
+            ),
+            Event::PrerollDiscarded { offset, bytes } => write!(
+                f,
+                "Output starts at the keyframe at offset {}, {} bytes of pre-roll discarded",
+                offset, bytes
+            ),
+            Event::OutputRenamed { from, to, time } => {
+                write!(f, "Renamed {} to {} ({})", from, to, time)
+            }
+            Event::PartialOutputResumed { path, time } => {
+                write!(f, "Resuming the partial output {} ({})", path, time)
+            }
+            Event::OutputAppended {
+                path,
+                file_offset,
+                trimmed,
+            } => write!(
+                f,
+                "Appending to {} from offset {}, {} bytes of a partial packet trimmed",
+                path, file_offset, trimmed
+            ),
+            Event::Scrambled {
+                pid,
+                offset,
+                scrambling,
+            } => write!(
+                f,
+                "Scrambled packets on PID {:#06x} from offset {} (scrambling control {:#04b})",
+                pid, offset, scrambling
+            ),
+            Event::TransportError { pid, offset } => write!(
+                f,
+                "Packets with the transport error indicator on PID {:#06x} from offset {}",
+                pid, offset
+            ),
+        }
+    }
+}
+
+/// Continuity of the decoding timestamps (DTS, or PTS when there is no DTS)
+/// of one PID.
+#[derive(Default)]
+struct TimestampTrack {
+    last: Option<u64>,
+    /// Recent positive steps, used to learn the cadence.
+    steps: Vec<i64>,
+}
+
+impl TimestampTrack {
+    const HISTORY: usize = 16;
+    /// Jumps are tolerated up to this factor of the usual cadence...
+    const CADENCE_FACTOR: i64 = 3;
+    /// ...and never flagged below this, to absorb the jitter (100 ms).
+    const MIN_THRESHOLD: i64 = 9_000;
+    /// Before the cadence is known, only jumps above this are flagged (1 s).
+    const UNKNOWN_THRESHOLD: i64 = 90_000;
+
+    fn expected(&self) -> Option<i64> {
+        if self.steps.len() < 4 {
+            return None;
+        }
+        let mut sorted = self.steps.clone();
+        sorted.sort_unstable();
+        Some(sorted[sorted.len() / 2])
+    }
+
+    /// Returns the jump and expected step if `ts` is discontinuous.
+    fn update(&mut self, ts: u64) -> Option<(i64, Option<i64>)> {
+        let last = self.last.replace(ts)?;
+        let delta = pes::timestamp_delta(last, ts);
+        let expected = self.expected();
+        let threshold = match expected {
+            Some(e) => (e * Self::CADENCE_FACTOR).max(Self::MIN_THRESHOLD),
+            None => Self::UNKNOWN_THRESHOLD,
+        };
+        if delta < 0 || delta > threshold {
+            return Some((delta, expected));
+        }
+        if delta > 0 {
+            if self.steps.len() == Self::HISTORY {
+                self.steps.remove(0);
+            }
+            self.steps.push(delta);
+        }
+        None
+    }
+}
+
+/// What the analyzer learnt about a packet.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct PacketInfo {
+    /// The packet starts a video keyframe.
+    pub keyframe: bool,
+}
+
+/// Follows the PSI of the stream and checks the elementary streams.
+#[derive(Default)]
+pub struct StreamAnalyzer {
+    pmt_pid: Option<u16>,
+    pmt: Option<Pmt>,
+    /// Last continuity counter of each PID.
+    cc: HashMap<u16, u8>,
+    timestamps: HashMap<u16, TimestampTrack>,
+    video_format: Option<Sps>,
+    events: Vec<Event>,
+    discontinuities: u64,
+    continuity_errors: u64,
+    /// Number of scrambled packets of each PID.
+    scrambled: BTreeMap<u16, u64>,
+    /// Number of packets of each PID with the transport error indicator.
+    transport_errors: BTreeMap<u16, u64>,
+}
+
+impl StreamAnalyzer {
+    pub fn new() -> StreamAnalyzer {
+        StreamAnalyzer::default()
+    }
+
+    pub fn pmt(&self) -> Option<&Pmt> {
+        self.pmt.as_ref()
+    }
+
+    /// Format of the video, from the last SPS seen.
+    pub fn video_format(&self) -> Option<Sps> {
+        self.video_format
+    }
+
+    fn stream(&self, pid: u16) -> Option<&ElementaryStream> {
+        self.pmt.as_ref()?.streams.iter().find(|s| s.pid == pid)
+    }
+
+    /// Feed a packet found at byte `offset`.
+    pub fn packet(&mut self, offset: u64, pkt: &Packet) -> PacketInfo {
+        let mut info = PacketInfo::default();
+        let pid = pkt.pid();
+        self.check_continuity(offset, pkt);
+        if pkt.tei() {
+            // The payload cannot be trusted, but the header usually can.
+            let count = self.transport_errors.entry(pid).or_default();
+            if *count == 0 {
+                self.events.push(Event::TransportError { pid, offset });
+            }
+            *count += 1;
+            return info;
+        }
+        if pkt.scrambling() != 0 {
+            // The header is clear, but not the payload.
+            let count = self.scrambled.entry(pid).or_default();
+            if *count == 0 {
+                self.events.push(Event::Scrambled {
+                    pid,
+                    offset,
+                    scrambling: pkt.scrambling(),
+                });
+            }
+            *count += 1;
+            return info;
+        }
+        if pid == PAT_PID {
+            if let Some(programs) = psi::parse_pat(pkt) {
+                self.pmt_pid = programs.iter().find(|p| p.0 != 0).map(|p| p.1);
+            }
+            return info;
+        }
+        if Some(pid) == self.pmt_pid {
+            if let Some(pmt) = psi::parse_pmt(pkt) {
+                self.pmt = Some(pmt);
+            }
+            return info;
+        }
+        let es = match self.stream(pid) {
+            Some(es) if es.is_video() || es.is_audio() => *es,
+            _ => return info,
+        };
+        if !pkt.pusi() {
+            return info;
+        }
+        let payload = match pkt.payload() {
+            Some(payload) => payload,
+            None => return info,
+        };
+        let header = match pes::parse_header(payload) {
+            Some(header) => header,
+            None => return info,
+        };
+        if es.stream_type == STREAM_TYPE_H264 {
+            let data = payload.get(header.header_len..).unwrap_or_default();
+            info.keyframe = h264::starts_keyframe(data);
+            if info.keyframe {
+                if let Some(sps) = h264::find_sps(data) {
+                    self.video_format = Some(sps);
+                }
+            }
+        }
+        if let Some(ts) = header.dts.or(header.pts) {
+            let track = self.timestamps.entry(es.pid).or_default();
+            if let Some((delta, expected)) = track.update(ts) {
+                self.discontinuities += 1;
+                self.events.push(Event::TimestampDiscontinuity {
+                    pid,
+                    offset,
+                    delta,
+                    expected,
+                });
+            }
+        }
+        info
+    }
+
+    fn check_continuity(&mut self, offset: u64, pkt: &Packet) {
+        let pid = pkt.pid();
+        if pid == NULL_PID {
+            return;
+        }
+        // The counter only increments with a payload, but a packet without
+        // one can still announce a discontinuity, as at an append seam.
+        if !pkt.has_payload() {
+            if pkt.discontinuity() {
+                self.cc.remove(&pid);
+            }
+            return;
+        }
+        let cc = pkt.cc();
+        let last = match self.cc.insert(pid, cc) {
+            Some(last) if !pkt.discontinuity() => last,
+            _ => return,
+        };
+        let expected = (last + 1) & 0x0f;
+        // A packet may be sent twice.
+        if cc != expected && cc != last {
+            self.continuity_errors += 1;
+            self.events.push(Event::ContinuityError {
+                pid,
+                offset,
+                expected,
+                found: cc,
+            });
+        }
+    }
+
+    /// Events found since the last call.
+    pub fn take_events(&mut self) -> Vec<Event> {
+        std::mem::take(&mut self.events)
+    }
+
+    pub fn timestamp_discontinuities(&self) -> u64 {
+        self.discontinuities
+    }
+
+    pub fn continuity_errors(&self) -> u64 {
+        self.continuity_errors
+    }
+
+    /// Number of scrambled packets of each PID which had any.
+    pub fn scrambled_packets(&self) -> &BTreeMap<u16, u64> {
+        &self.scrambled
+    }
+
+    /// Number of packets with the transport error indicator of each PID
+    /// which had any.
+    pub fn transport_errors(&self) -> &BTreeMap<u16, u64> {
+        &self.transport_errors
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::emulator::{TsGenerator, PMT_PID, VIDEO_PID};
+    use crate::ts::{PACKET_SIZE, SYNC_BYTE};
+
+    /// Packet of `pid` with a payload, with the given scrambling control.
+    fn packet(pid: u16, cc: u8, scrambling: u8) -> [u8; PACKET_SIZE] {
+        let mut data = [0xff; PACKET_SIZE];
+        data[0] = SYNC_BYTE;
+        data[1] = (pid >> 8) as u8;
+        data[2] = pid as u8;
+        data[3] = scrambling << 6 | 0x10 | cc;
+        data
+    }
+
+    /// Packet of `pid` with a payload and the transport error indicator.
+    fn errored(pid: u16, cc: u8) -> [u8; PACKET_SIZE] {
+        let mut data = packet(pid, cc, 0);
+        data[1] |= 0x80;
+        data
+    }
+
+    fn feed(analyzer: &mut StreamAnalyzer, offset: u64, data: &[u8]) -> PacketInfo {
+        analyzer.packet(offset, &Packet::new(data).unwrap())
+    }
+
+    #[test]
+    fn scrambled_packets_are_counted_per_pid() {
+        let mut analyzer = StreamAnalyzer::new();
+        let mut offset = 0;
+        for cc in 0..3 {
+            for (pid, scrambling) in [(0x200, 0b10), (0x201, 0b11), (0x202, 0)] {
+                feed(&mut analyzer, offset, &packet(pid, cc, scrambling));
+                offset += PACKET_SIZE as u64;
+            }
+        }
+        let counts: Vec<_> = analyzer.scrambled_packets().iter().collect();
+        assert_eq!(counts, [(&0x200, &3), (&0x201, &3)]);
+        assert_eq!(analyzer.continuity_errors(), 0);
+    }
+
+    #[test]
+    fn scrambling_is_reported_once_per_pid() {
+        let mut analyzer = StreamAnalyzer::new();
+        feed(&mut analyzer, 0, &packet(0x200, 0, 0));
+        feed(&mut analyzer, 188, &packet(0x200, 1, 0b10));
+        feed(&mut analyzer, 376, &packet(0x200, 2, 0b11));
+        feed(&mut analyzer, 564, &packet(0x201, 0, 0b01));
+        let events: Vec<_> = analyzer
+            .take_events()
+            .into_iter()
+            .map(|e| match e {
+                Event::Scrambled {
+                    pid,
+                    offset,
+                    scrambling,
+                } => (pid, offset, scrambling),
+                e => panic!("unexpected event {:?}", e),
+            })
+            .collect();
+        assert_eq!(events, [(0x200, 188, 0b10), (0x201, 564, 0b01)]);
+    }
+
+    #[test]
+    fn scrambled_pat_is_not_parsed() {
+        let pat = TsGenerator::new(1_000_000).next_packet();
+        assert_eq!(Packet::new(&pat).unwrap().pid(), PAT_PID);
+        let mut scrambled = pat;
+        scrambled[3] |= 0b10 << 6;
+
+        let mut analyzer = StreamAnalyzer::new();
+        feed(&mut analyzer, 0, &scrambled);
+        assert_eq!(analyzer.pmt_pid, None);
+        assert_eq!(analyzer.scrambled_packets().get(&PAT_PID), Some(&1));
+        feed(&mut analyzer, 188, &pat);
+        assert_eq!(analyzer.pmt_pid, Some(PMT_PID));
+    }
+
+    #[test]
+    fn transport_errors_are_counted_per_pid() {
+        let mut analyzer = StreamAnalyzer::new();
+        feed(&mut analyzer, 0, &packet(0x200, 0, 0));
+        feed(&mut analyzer, 188, &errored(0x200, 1));
+        feed(&mut analyzer, 376, &errored(0x200, 2));
+        feed(&mut analyzer, 564, &errored(0x201, 0));
+        feed(&mut analyzer, 752, &packet(0x201, 1, 0));
+        let counts: Vec<_> = analyzer.transport_errors().iter().collect();
+        assert_eq!(counts, [(&0x200, &2), (&0x201, &1)]);
+        let events: Vec<_> = analyzer
+            .take_events()
+            .into_iter()
+            .map(|e| match e {
+                Event::TransportError { pid, offset } => (pid, offset),
+                e => panic!("unexpected event {:?}", e),
+            })
+            .collect();
+        assert_eq!(events, [(0x200, 188), (0x201, 564)]);
+    }
+
+    #[test]
+    fn errored_packets_still_carry_the_counter() {
+        let mut analyzer = StreamAnalyzer::new();
+        feed(&mut analyzer, 0, &packet(0x200, 0, 0));
+        feed(&mut analyzer, 188, &errored(0x200, 1));
+        feed(&mut analyzer, 376, &packet(0x200, 2, 0));
+        assert_eq!(analyzer.continuity_errors(), 0);
+        feed(&mut analyzer, 564, &errored(0x200, 5));
+        assert_eq!(analyzer.continuity_errors(), 1);
+    }
+
+    #[test]
+    fn errored_pat_is_not_parsed() {
+        let pat = TsGenerator::new(1_000_000).next_packet();
+        let mut errored = pat;
+        errored[1] |= 0x80;
+
+        let mut analyzer = StreamAnalyzer::new();
+        feed(&mut analyzer, 0, &errored);
+        assert_eq!(analyzer.pmt_pid, None);
+        assert!(analyzer.scrambled_packets().is_empty());
+        feed(&mut analyzer, 188, &pat);
+        assert_eq!(analyzer.pmt_pid, Some(PMT_PID));
+    }
+
+    /// Packet of the emulated video PID starting a PES packet with `pts`.
+    fn pes(cc: u8, pts: u64) -> [u8; PACKET_SIZE] {
+        let mut data = packet(VIDEO_PID, cc, 0);
+        data[1] |= 0x40;
+        data[4..18].copy_from_slice(&[
+            0x00,
+            0x00,
+            0x01,
+            0xe0,
+            0x00,
+            0x00,
+            0x80,
+            0x80,
+            5,
+            0x21 | (pts >> 29) as u8 & 0x0e,
+            (pts >> 22) as u8,
+            (pts >> 14) as u8 | 1,
+            (pts >> 7) as u8,
+            (pts << 1) as u8 | 1,
+        ]);
+        data
+    }
+
+    /// Feed the PAT and PMT of the emulated stream, then a PES packet with
+    /// each of `pts`. Returns the discontinuities as (offset, delta,
+    /// expected).
+    fn discontinuities(pts: &[u64]) -> Vec<(u64, i64, Option<i64>)> {
+        let mut generator = TsGenerator::new(1_000_000);
+        let mut analyzer = StreamAnalyzer::new();
+        feed(&mut analyzer, 0, &generator.next_packet());
+        feed(&mut analyzer, 188, &generator.next_packet());
+        assert!(analyzer.pmt().is_some());
+        for (i, &pts) in pts.iter().enumerate() {
+            let offset = (2 + i as u64) * PACKET_SIZE as u64;
+            feed(&mut analyzer, offset, &pes(i as u8 & 0x0f, pts));
+        }
+        let events = analyzer.take_events();
+        assert_eq!(analyzer.timestamp_discontinuities(), events.len() as u64);
+        events
+            .into_iter()
+            .map(|e| match e {
+                Event::TimestampDiscontinuity {
+                    pid,
+                    offset,
+                    delta,
+                    expected,
+                } => {
+                    assert_eq!(pid, VIDEO_PID);
+                    (offset, delta, expected)
+                }
+                e => panic!("unexpected event {:?}", e),
+            })
+            .collect()
+    }
+
+    /// Timestamps `step` apart from `start`, on 33 bits.
+    fn cadence(start: u64, step: u64, count: u64) -> Vec<u64> {
+        (0..count)
+            .map(|i| (start + i * step) % pes::TIMESTAMP_MODULUS)
+            .collect()
+    }
+
+    #[test]
+    fn timestamp_wrap_is_not_a_discontinuity() {
+        let pts = cadence(pes::TIMESTAMP_MODULUS - 5 * 3600, 3600, 12);
+        assert!(pts[5] < pts[4]);
+        assert_eq!(discontinuities(&pts), []);
+    }
+
+    #[test]
+    fn backwards_timestamp_is_a_discontinuity() {
+        let mut pts = cadence(90_000, 3600, 8);
+        pts.push(pts[7] - 7200);
+        pts.extend(cadence(pts[8] + 3600, 3600, 4));
+        assert_eq!(discontinuities(&pts), [(10 * 188, -7200, Some(3600))]);
+    }
+
+    #[test]
+    fn jumps_within_three_steps_are_tolerated() {
+        let mut pts = cadence(0, 3600, 8);
+        // Two frames dropped, then three.
+        pts.push(pts[7] + 3 * 3600);
+        pts.extend(cadence(pts[8] + 3600, 3600, 4));
+        pts.push(pts[12] + 4 * 3600);
+        assert_eq!(discontinuities(&pts), [(15 * 188, 4 * 3600, Some(3600))]);
+    }
+
+    #[test]
+    fn jumps_before_the_cadence_is_known() {
+        // Up to 1 s is tolerated before four steps were seen.
+        let pts = [0, 3600, 3600 + 90_000, 3600 + 90_001 + 90_000];
+        assert_eq!(discontinuities(&pts), [(5 * 188, 90_001, None)]);
+    }
+}
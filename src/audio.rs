@@ -0,0 +1,593 @@
+//! Audio level metering during capture.
+//!
+//! Decoding AAC is out of reach here, but MPEG audio Layer II, used by some
+//! firmware revisions, carries a scale factor per subband and block which
+//! bounds the amplitude of its samples. Peak and RMS levels are estimated
+//! from them without decoding the samples. For the other codecs, only the
+//! presence and bitrate of the audio are reported.
+
+use std::fmt;
+use std::time::{Duration, Instant};
+
+use crate::pes;
+use crate::psi::{ElementaryStream, STREAM_TYPE_MPEG1_AUDIO, STREAM_TYPE_MPEG2_AUDIO};
+use crate::ts::Packet;
+
+/// Level below which the audio is taken as silent.
+pub const SILENCE_DB: f64 = -60.0;
+/// Peak level taken as clipping.
+pub const CLIPPING_DB: f64 = -0.1;
+
+/// Audio levels over one second, in dB relative to full scale.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Level {
+    pub peak_db: f64,
+    pub rms_db: f64,
+}
+
+impl Level {
+    /// Width of the meter shown in the status line.
+    const METER_WIDTH: usize = 12;
+
+    fn meter(&self) -> String {
+        let fill = ((self.peak_db - SILENCE_DB) / -SILENCE_DB * Self::METER_WIDTH as f64)
+            .round()
+            .clamp(0.0, Self::METER_WIDTH as f64) as usize;
+        format!(
+            "[{}{}]",
+            "#".repeat(fill),
+            " ".repeat(Self::METER_WIDTH - fill)
+        )
+    }
+}
+
+fn format_db(db: f64) -> String {
+    if db.is_finite() {
+        format!("{:.1} dB", db)
+    } else {
+        "-inf dB".to_string()
+    }
+}
+
+impl fmt::Display for Level {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} peak {}, {} RMS",
+            self.meter(),
+            format_db(self.peak_db),
+            format_db(self.rms_db)
+        )
+    }
+}
+
+/// What is known of the audio over the last second.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum AudioStatus {
+    /// No audio packets were received.
+    Absent,
+    /// Audio in a codec the levels cannot be measured for.
+    Present {
+        bitrate: u64,
+    },
+    Measured {
+        level: Level,
+        bitrate: u64,
+    },
+}
+
+impl fmt::Display for AudioStatus {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AudioStatus::Absent => write!(f, "no audio packets"),
+            AudioStatus::Present { bitrate } => {
+                write!(f, "packets present, {} kbit/s", bitrate / 1000)
+            }
+            AudioStatus::Measured { level, .. } => write!(f, "{}", level),
+        }
+    }
+}
+
+/// A condition which lasted long enough to be reported.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum AudioAlert {
+    Silence(Duration),
+    Clipping(Duration),
+    NoPackets(Duration),
+}
+
+impl fmt::Display for AudioAlert {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AudioAlert::Silence(d) => write!(f, "audio silent for {} s", d.as_secs()),
+            AudioAlert::Clipping(d) => write!(f, "audio clipping for {} s", d.as_secs()),
+            AudioAlert::NoPackets(d) => write!(f, "no audio packets for {} s", d.as_secs()),
+        }
+    }
+}
+
+/// Tracks how long a condition has been true, raising an alert once when
+/// it lasted `threshold`.
+struct Sustained {
+    threshold: Duration,
+    since: Option<Instant>,
+    raised: bool,
+}
+
+impl Sustained {
+    fn new(threshold: Duration) -> Sustained {
+        Sustained {
+            threshold,
+            since: None,
+            raised: false,
+        }
+    }
+
+    fn update(&mut self, active: bool, now: Instant) -> Option<Duration> {
+        if !active {
+            self.since = None;
+            self.raised = false;
+            return None;
+        }
+        let since = *self.since.get_or_insert(now);
+        let lasted = now.duration_since(since);
+        if !self.raised && lasted >= self.threshold {
+            self.raised = true;
+            return Some(lasted);
+        }
+        None
+    }
+}
+
+/// Measures the audio PID, one second at a time.
+pub struct AudioMeter {
+    /// Elementary stream data not yet parsed into frames.
+    es: Vec<u8>,
+    window_start: Option<Instant>,
+    bytes: u64,
+    frames: u64,
+    peak: f64,
+    energy: f64,
+    status: Option<AudioStatus>,
+    silence: Sustained,
+    clipping: Sustained,
+    absence: Sustained,
+    alerts: Vec<AudioAlert>,
+}
+
+impl Default for AudioMeter {
+    fn default() -> AudioMeter {
+        AudioMeter::new()
+    }
+}
+
+impl AudioMeter {
+    const WINDOW: Duration = Duration::from_secs(1);
+    const SILENCE_ALERT: Duration = Duration::from_secs(10);
+    const CLIPPING_ALERT: Duration = Duration::from_secs(3);
+    const ABSENCE_ALERT: Duration = Duration::from_secs(10);
+
+    pub fn new() -> AudioMeter {
+        AudioMeter {
+            es: Vec::new(),
+            window_start: None,
+            bytes: 0,
+            frames: 0,
+            peak: 0.0,
+            energy: 0.0,
+            status: None,
+            silence: Sustained::new(Self::SILENCE_ALERT),
+            clipping: Sustained::new(Self::CLIPPING_ALERT),
+            absence: Sustained::new(Self::ABSENCE_ALERT),
+            alerts: Vec::new(),
+        }
+    }
+
+    /// Feed a packet of the audio stream `es`, received at `now`.
+    pub fn packet(&mut self, es: &ElementaryStream, pkt: &Packet, now: Instant) {
+        self.tick(now);
+        let payload = match pkt.payload() {
+            Some(payload) => payload,
+            None => return,
+        };
+        let data = if pkt.pusi() {
+            match pes::parse_header(payload) {
+                Some(header) => payload.get(header.header_len..).unwrap_or_default(),
+                None => return,
+            }
+        } else {
+            payload
+        };
+        self.bytes += data.len() as u64;
+        if matches!(
+            es.stream_type,
+            STREAM_TYPE_MPEG1_AUDIO | STREAM_TYPE_MPEG2_AUDIO
+        ) {
+            self.es.extend_from_slice(data);
+            self.parse_frames();
+        }
+    }
+
+    /// Close the current window if it lasted a second. Also to be called
+    /// when no packets come, for the absence to be noticed.
+    pub fn tick(&mut self, now: Instant) {
+        let start = *self.window_start.get_or_insert(now);
+        let elapsed = now.duration_since(start);
+        if elapsed < Self::WINDOW {
+            return;
+        }
+        let bitrate = self.bytes * 8 * 1000 / elapsed.as_millis().max(1) as u64;
+        let status = if self.bytes == 0 {
+            AudioStatus::Absent
+        } else if self.frames > 0 {
+            AudioStatus::Measured {
+                level: Level {
+                    peak_db: 20.0 * self.peak.log10(),
+                    rms_db: 10.0 * (self.energy / self.frames as f64).log10(),
+                },
+                bitrate,
+            }
+        } else {
+            AudioStatus::Present { bitrate }
+        };
+        self.status = Some(status);
+        let level = match status {
+            AudioStatus::Measured { level, .. } => Some(level),
+            _ => None,
+        };
+        let alerts = [
+            self.silence
+                .update(level.is_some_and(|l| l.peak_db < SILENCE_DB), now)
+                .map(AudioAlert::Silence),
+            self.clipping
+                .update(level.is_some_and(|l| l.peak_db >= CLIPPING_DB), now)
+                .map(AudioAlert::Clipping),
+            self.absence
+                .update(status == AudioStatus::Absent, now)
+                .map(AudioAlert::NoPackets),
+        ];
+        self.alerts.extend(alerts.iter().flatten());
+        self.window_start = Some(now);
+        self.bytes = 0;
+        self.frames = 0;
+        self.peak = 0.0;
+        self.energy = 0.0;
+    }
+
+    /// Status over the last complete second, `None` until a second passed.
+    pub fn status(&self) -> Option<AudioStatus> {
+        self.status
+    }
+
+    /// Alerts raised since the last call.
+    pub fn take_alerts(&mut self) -> Vec<AudioAlert> {
+        std::mem::take(&mut self.alerts)
+    }
+
+    fn parse_frames(&mut self) {
+        let mut pos = 0;
+        while let Some(rest) = self.es.get(pos..) {
+            if rest.len() < 4 {
+                break;
+            }
+            let header = match FrameHeader::parse(rest) {
+                Some(header) => header,
+                None => {
+                    pos += 1;
+                    continue;
+                }
+            };
+            if rest.len() < header.len {
+                break;
+            }
+            if let Some((peak, energy)) = header.levels(&rest[..header.len]) {
+                self.frames += 1;
+                self.peak = self.peak.max(peak);
+                self.energy += energy;
+            }
+            pos += header.len;
+        }
+        self.es.drain(..pos);
+    }
+}
+
+/// Header of an MPEG audio Layer II frame.
+struct FrameHeader {
+    lsf: bool,
+    crc: bool,
+    bitrate: u32,
+    sample_rate: u32,
+    channels: usize,
+    /// Joint stereo bound, as given by the mode extension.
+    bound: Option<usize>,
+    len: usize,
+}
+
+const BITRATES: [u32; 15] = [
+    0, 32, 48, 56, 64, 80, 96, 112, 128, 160, 192, 224, 256, 320, 384,
+];
+const BITRATES_LSF: [u32; 15] = [0, 8, 16, 24, 32, 40, 48, 56, 64, 80, 96, 112, 128, 144, 160];
+const SAMPLE_RATES: [u32; 3] = [44100, 48000, 32000];
+
+impl FrameHeader {
+    fn parse(data: &[u8]) -> Option<FrameHeader> {
+        // Sync word, MPEG-1 or MPEG-2, Layer II.
+        if data[0] != 0xff || data[1] & 0xe0 != 0xe0 || data[1] & 0x06 != 0x04 {
+            return None;
+        }
+        let lsf = match (data[1] >> 3) & 0x03 {
+            3 => false,
+            2 => true,
+            _ => return None,
+        };
+        let bitrate_index = usize::from(data[2] >> 4);
+        let rate_index = usize::from((data[2] >> 2) & 0x03);
+        if bitrate_index == 0 || bitrate_index == 15 || rate_index == 3 {
+            return None;
+        }
+        let bitrate = if lsf {
+            BITRATES_LSF[bitrate_index]
+        } else {
+            BITRATES[bitrate_index]
+        };
+        let sample_rate = SAMPLE_RATES[rate_index] >> lsf as u32;
+        let padding = usize::from((data[2] >> 1) & 0x01);
+        let mode = data[3] >> 6;
+        Some(FrameHeader {
+            lsf,
+            crc: data[1] & 0x01 == 0,
+            bitrate,
+            sample_rate,
+            channels: if mode == 3 { 1 } else { 2 },
+            bound: if mode == 1 {
+                Some(4 + 4 * usize::from((data[3] >> 4) & 0x03))
+            } else {
+                None
+            },
+            len: (144_000 * bitrate / sample_rate) as usize + padding,
+        })
+    }
+
+    /// Number of bits of the allocation of each subband, for the
+    /// allocation table used by the frame.
+    fn allocation_bits(&self) -> Vec<u32> {
+        let per_channel = self.bitrate / self.channels as u32;
+        let table: &[(usize, u32)] = if self.lsf {
+            &[(4, 4), (7, 3), (19, 2)]
+        } else if (self.sample_rate == 48000 && per_channel >= 80)
+            || (56..=80).contains(&per_channel)
+        {
+            &[(11, 4), (12, 3), (4, 2)]
+        } else if self.sample_rate != 48000 && per_channel >= 96 {
+            &[(11, 4), (12, 3), (7, 2)]
+        } else if self.sample_rate != 32000 && per_channel <= 48 {
+            &[(2, 4), (6, 3)]
+        } else {
+            &[(2, 4), (10, 3)]
+        };
+        table
+            .iter()
+            .flat_map(|&(count, bits)| std::iter::repeat_n(bits, count))
+            .collect()
+    }
+
+    /// Peak and mean square level of the frame, estimated from the scale
+    /// factors of its subbands.
+    fn levels(&self, frame: &[u8]) -> Option<(f64, f64)> {
+        let nbal = self.allocation_bits();
+        let sblimit = nbal.len();
+        let bound = self.bound.unwrap_or(sblimit).min(sblimit);
+        let mut bits = BitReader::new(frame, if self.crc { 48 } else { 32 });
+        let mut allocated = vec![[false; 2]; sblimit];
+        for (sb, &n) in nbal.iter().enumerate() {
+            if sb < bound {
+                for a in allocated[sb].iter_mut().take(self.channels) {
+                    *a = bits.read(n)? != 0;
+                }
+            } else {
+                let a = bits.read(n)? != 0;
+                allocated[sb] = [a, a];
+            }
+        }
+        let mut scfsi = vec![[0u32; 2]; sblimit];
+        for sb in 0..sblimit {
+            for ch in 0..self.channels {
+                if allocated[sb][ch] {
+                    scfsi[sb][ch] = bits.read(2)?;
+                }
+            }
+        }
+        let mut peak = 0.0f64;
+        let mut energy = 0.0;
+        for sb in 0..sblimit {
+            for ch in 0..self.channels {
+                if !allocated[sb][ch] {
+                    continue;
+                }
+                let count = match scfsi[sb][ch] {
+                    0 => 3,
+                    2 => 1,
+                    _ => 2,
+                };
+                let mut max = 0.0f64;
+                for _ in 0..count {
+                    max = max.max(scale_factor(bits.read(6)?));
+                }
+                peak = peak.max(max);
+                // A full scale sine in the subband has an RMS of max / √2.
+                energy += max * max / 2.0 / self.channels as f64;
+            }
+        }
+        Some((peak, energy))
+    }
+}
+
+/// Value of a Layer II scale factor index.
+fn scale_factor(index: u32) -> f64 {
+    2.0 * 2f64.powf(-(index as f64) / 3.0)
+}
+
+struct BitReader<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> BitReader<'a> {
+    fn new(data: &'a [u8], pos: usize) -> BitReader<'a> {
+        BitReader { data, pos }
+    }
+
+    fn read(&mut self, count: u32) -> Option<u32> {
+        let mut value = 0;
+        for _ in 0..count {
+            let byte = self.data.get(self.pos / 8)?;
+            value = value << 1 | u32::from(byte >> (7 - self.pos % 8) & 1);
+            self.pos += 1;
+        }
+        Some(value)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::psi::STREAM_TYPE_AAC_ADTS;
+    use crate::ts::PACKET_SIZE;
+
+    /// Header of a mono MPEG-1 Layer II frame at 192 kbit/s and 48 kHz,
+    /// without CRC: 576 bytes, 27 subbands.
+    const HEADER: [u8; 4] = [0xff, 0xfd, 0xa4, 0xc0];
+
+    struct BitWriter {
+        data: Vec<u8>,
+        pos: usize,
+    }
+
+    impl BitWriter {
+        fn write(&mut self, count: u32, value: u32) {
+            for i in (0..count).rev() {
+                if self.pos / 8 == self.data.len() {
+                    self.data.push(0);
+                }
+                self.data[self.pos / 8] |= ((value >> i & 1) as u8) << (7 - self.pos % 8);
+                self.pos += 1;
+            }
+        }
+    }
+
+    /// A frame whose first subband alone is allocated, with the single
+    /// scale factor `index`.
+    fn frame(index: u32) -> Vec<u8> {
+        let mut bits = BitWriter {
+            data: HEADER.to_vec(),
+            pos: 32,
+        };
+        bits.write(4, 1);
+        for n in [4; 10].iter().chain(&[3; 12]).chain(&[2; 4]) {
+            bits.write(*n, 0);
+        }
+        // One scale factor for the three blocks.
+        bits.write(2, 2);
+        bits.write(6, index);
+        bits.data.resize(576, 0);
+        bits.data
+    }
+
+    #[test]
+    fn header_rejects_reserved_indexes() {
+        let header = FrameHeader::parse(&HEADER).unwrap();
+        assert_eq!(
+            (
+                header.bitrate,
+                header.sample_rate,
+                header.channels,
+                header.len
+            ),
+            (192, 48000, 1, 576)
+        );
+        assert_eq!(header.allocation_bits().len(), 27);
+        for byte in [0x04, 0xf4, 0xac] {
+            assert!(FrameHeader::parse(&[0xff, 0xfd, byte, 0xc0]).is_none());
+        }
+        // Layer III.
+        assert!(FrameHeader::parse(&[0xff, 0xfb, 0xa4, 0xc0]).is_none());
+    }
+
+    #[test]
+    fn levels_of_the_scale_factors() {
+        let header = FrameHeader::parse(&HEADER).unwrap();
+        // Index 3 is full scale, each further 3 halving it.
+        assert_eq!(header.levels(&frame(3)), Some((1.0, 0.5)));
+        assert_eq!(header.levels(&frame(9)), Some((0.25, 0.03125)));
+        // Cut before the scale factor.
+        assert_eq!(header.levels(&frame(3)[..15]), None);
+    }
+
+    #[test]
+    fn sustained_fires_once_after_its_threshold() {
+        let mut sustained = Sustained::new(Duration::from_secs(3));
+        let start = Instant::now();
+        let at = |secs| start + Duration::from_secs(secs);
+        assert_eq!(sustained.update(true, at(0)), None);
+        assert_eq!(sustained.update(true, at(2)), None);
+        assert_eq!(sustained.update(true, at(3)), Some(Duration::from_secs(3)));
+        assert_eq!(sustained.update(true, at(4)), None);
+        assert_eq!(sustained.update(false, at(5)), None);
+        assert_eq!(sustained.update(true, at(6)), None);
+        assert_eq!(sustained.update(true, at(10)), Some(Duration::from_secs(4)));
+    }
+
+    /// Packets of a PES carrying `es` on PID 0x201, the last one padded.
+    fn packets(es: &[u8]) -> Vec<Vec<u8>> {
+        let mut data = vec![0x00, 0x00, 0x01, 0xc0, 0x00, 0x00, 0x80, 0x00, 0x00];
+        data.extend_from_slice(es);
+        data.chunks(PACKET_SIZE - 4)
+            .enumerate()
+            .map(|(i, chunk)| {
+                let pusi = if i == 0 { 0x40 } else { 0x00 };
+                let mut pkt = vec![0x47, pusi | 0x02, 0x01, 0x10 | (i as u8 & 0x0f)];
+                pkt.extend_from_slice(chunk);
+                pkt.resize(PACKET_SIZE, 0xff);
+                pkt
+            })
+            .collect()
+    }
+
+    fn measure(stream_type: u8, es: &[u8]) -> Option<AudioStatus> {
+        let stream = ElementaryStream {
+            stream_type,
+            pid: 0x201,
+        };
+        let mut meter = AudioMeter::new();
+        let start = Instant::now();
+        for pkt in packets(es) {
+            meter.packet(&stream, &Packet::new(&pkt).unwrap(), start);
+        }
+        meter.tick(start + Duration::from_secs(1));
+        meter.status()
+    }
+
+    #[test]
+    fn mp2_is_measured() {
+        let es = [frame(3), frame(9)].concat();
+        match measure(STREAM_TYPE_MPEG1_AUDIO, &es) {
+            Some(AudioStatus::Measured { level, .. }) => {
+                assert_eq!(level.peak_db, 0.0);
+                assert!((level.rms_db - 10.0 * (0.53125f64 / 2.0).log10()).abs() < 1e-9);
+            }
+            other => panic!("unexpected status {:?}", other),
+        }
+    }
+
+    #[test]
+    fn other_codecs_fall_back_to_presence() {
+        let es = [frame(3), frame(9)].concat();
+        let packets = packets(&es).len() as u64;
+        // The payload of every packet but the PES header, over a second.
+        let bitrate = (packets * (PACKET_SIZE as u64 - 4) - 9) * 8;
+        assert_eq!(
+            measure(STREAM_TYPE_AAC_ADTS, &es),
+            Some(AudioStatus::Present { bitrate })
+        );
+    }
+}
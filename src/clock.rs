@@ -0,0 +1,373 @@
+//! Correlation of the device and stream clocks with the host wall clock.
+//!
+//! Three time domains are involved: the millisecond counter returned by the
+//! time query (u32, wrapping), the PCR carried in the stream (27 MHz,
+//! wrapping at 2^33 * 300), and the host wall clock. Each device domain is
+//! mapped to the host clock with a linear model (offset + drift) fitted over
+//! the most recent samples.
+
+use std::collections::VecDeque;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use serde::Serialize;
+
+use crate::ts::{PCR_HZ, PCR_MODULUS};
+
+/// Extends a wrapping counter to 64 bits.
+///
+/// Steps of more than half the modulus are taken as the counter going
+/// backwards rather than wrapping.
+pub struct Unwrapper {
+    modulus: u64,
+    last: Option<u64>,
+}
+
+impl Unwrapper {
+    pub fn new(modulus: u64) -> Unwrapper {
+        Unwrapper {
+            modulus,
+            last: None,
+        }
+    }
+
+    /// An unwrapper carrying on from `last`, a value it already returned.
+    pub fn resume(modulus: u64, last: u64) -> Unwrapper {
+        Unwrapper {
+            modulus,
+            last: Some(last),
+        }
+    }
+
+    pub fn unwrap(&mut self, raw: u64) -> u64 {
+        let raw = raw % self.modulus;
+        let value = match self.last {
+            None => raw,
+            Some(last) => {
+                let last_raw = last % self.modulus;
+                let forward = (raw + self.modulus - last_raw) % self.modulus;
+                if forward <= self.modulus / 2 {
+                    last + forward
+                } else {
+                    last.saturating_sub(self.modulus - forward)
+                }
+            }
+        };
+        self.last = Some(value);
+        value
+    }
+}
+
+/// Least squares fit of `y = offset + slope * x` over a sliding window.
+pub struct LinearFit {
+    samples: VecDeque<(f64, f64)>,
+    window: usize,
+}
+
+impl LinearFit {
+    pub fn new(window: usize) -> LinearFit {
+        LinearFit {
+            samples: VecDeque::with_capacity(window),
+            window,
+        }
+    }
+
+    pub fn add(&mut self, x: f64, y: f64) {
+        if self.samples.len() == self.window {
+            self.samples.pop_front();
+        }
+        self.samples.push_back((x, y));
+    }
+
+    pub fn len(&self) -> usize {
+        self.samples.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.samples.is_empty()
+    }
+
+    /// Slope of the fit, 1.0 when there are not enough samples yet.
+    pub fn slope(&self) -> f64 {
+        let n = self.samples.len() as f64;
+        if self.samples.len() < 2 {
+            return 1.0;
+        }
+        // Center on the first sample to keep precision with large values.
+        let (x0, y0) = self.samples[0];
+        let (mut sx, mut sy, mut sxx, mut sxy) = (0.0, 0.0, 0.0, 0.0);
+        for &(x, y) in &self.samples {
+            let (x, y) = (x - x0, y - y0);
+            sx += x;
+            sy += y;
+            sxx += x * x;
+            sxy += x * y;
+        }
+        let den = n * sxx - sx * sx;
+        if den.abs() < f64::EPSILON {
+            return 1.0;
+        }
+        (n * sxy - sx * sy) / den
+    }
+
+    pub fn eval(&self, x: f64) -> Option<f64> {
+        let slope = self.slope();
+        let n = self.samples.len() as f64;
+        let (x0, y0) = *self.samples.front()?;
+        let mx = self.samples.iter().map(|s| s.0 - x0).sum::<f64>() / n;
+        let my = self.samples.iter().map(|s| s.1 - y0).sum::<f64>() / n;
+        Some(y0 + my + slope * (x - x0 - mx))
+    }
+}
+
+/// One line of the time mapping table.
+#[derive(Clone, Debug, Serialize)]
+pub struct MappingEntry {
+    /// Byte offset of the packet carrying the PCR.
+    pub offset: u64,
+    /// Unwrapped PCR, in 27 MHz ticks.
+    pub pcr: u64,
+    /// Host wall clock, as milliseconds since the Unix epoch.
+    pub wall_clock_ms: u64,
+    /// Device timestamp (time query domain), when the model knows it.
+    pub device_ms: Option<u64>,
+}
+
+pub struct ClockModel {
+    device: Unwrapper,
+    device_fit: LinearFit,
+    pcr: Unwrapper,
+    pcr_fit: LinearFit,
+    table: Vec<MappingEntry>,
+    last_entry_pcr: Option<u64>,
+}
+
+impl Default for ClockModel {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl ClockModel {
+    /// Minimum PCR distance between two entries of the mapping table.
+    const TABLE_INTERVAL: u64 = PCR_HZ;
+
+    pub fn new() -> ClockModel {
+        ClockModel {
+            device: Unwrapper::new(1 << 32),
+            device_fit: LinearFit::new(32),
+            pcr: Unwrapper::new(PCR_MODULUS),
+            pcr_fit: LinearFit::new(256),
+            table: Vec::new(),
+            last_entry_pcr: None,
+        }
+    }
+
+    /// Record a device timestamp, received at host time `at`.
+    pub fn add_device_sample(&mut self, device_ms: u32, at: SystemTime) {
+        let x = self.device.unwrap(u64::from(device_ms));
+        self.device_fit.add(x as f64, unix_ms(at));
+    }
+
+    /// Record a PCR found at byte `offset`, received at host time `at`.
+    pub fn add_pcr_sample(&mut self, pcr: u64, offset: u64, at: SystemTime) {
+        let pcr = self.pcr.unwrap(pcr);
+        self.pcr_fit
+            .add(pcr as f64 / PCR_HZ as f64 * 1000.0, unix_ms(at));
+        let due = match self.last_entry_pcr {
+            None => true,
+            Some(last) => pcr >= last + Self::TABLE_INTERVAL || pcr < last,
+        };
+        if due {
+            if let Some(wall) = self.pcr_to_wall_ms(pcr) {
+                self.table.push(MappingEntry {
+                    offset,
+                    pcr,
+                    wall_clock_ms: wall as u64,
+                    device_ms: self.wall_to_device_ms(wall),
+                });
+                self.last_entry_pcr = Some(pcr);
+            }
+        }
+    }
+
+    /// Drift of the device clock against the host, in parts per million.
+    pub fn device_drift_ppm(&self) -> Option<f64> {
+        if self.device_fit.len() < 2 {
+            return None;
+        }
+        Some((self.device_fit.slope() - 1.0) * 1e6)
+    }
+
+    /// Wall clock matching an unwrapped PCR.
+    pub fn pcr_to_wall_ms(&self, pcr: u64) -> Option<f64> {
+        self.pcr_fit.eval(pcr as f64 / PCR_HZ as f64 * 1000.0)
+    }
+
+    /// Wall clock matching a byte offset, from the closest preceding entry
+    /// of the table.
+    pub fn offset_to_wall(&self, offset: u64) -> Option<SystemTime> {
+        let idx = self.table.partition_point(|e| e.offset <= offset);
+        let entry = if idx == 0 {
+            self.table.first()?
+        } else {
+            &self.table[idx - 1]
+        };
+        Some(UNIX_EPOCH + Duration::from_millis(entry.wall_clock_ms))
+    }
+
+    fn wall_to_device_ms(&self, wall: f64) -> Option<u64> {
+        if self.device_fit.is_empty() {
+            return None;
+        }
+        // Invert the device fit.
+        let at_zero = self.device_fit.eval(0.0)?;
+        let ms = (wall - at_zero) / self.device_fit.slope();
+        if ms < 0.0 {
+            return None;
+        }
+        Some(ms as u64)
+    }
+
+    pub fn table(&self) -> &[MappingEntry] {
+        &self.table
+    }
+}
+
+fn unix_ms(t: SystemTime) -> f64 {
+    t.duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs_f64() * 1000.0)
+        .unwrap_or(0.0)
+}
+
+/// Format a wall clock time as compact UTC ISO 8601 (`20240131T235959Z`),
+/// suitable for file names.
+pub fn format_utc_compact(t: SystemTime) -> String {
+    let full = format_utc(t);
+    let mut out: String = full[..19]
+        .chars()
+        .filter(|c| *c != '-' && *c != ':')
+        .collect();
+    out.push('Z');
+    out
+}
+
+/// Format a wall clock time as UTC ISO 8601, to the millisecond.
+pub fn format_utc(t: SystemTime) -> String {
+    let ms = t
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_millis() as u64)
+        .unwrap_or(0);
+    let secs = ms / 1000;
+    let days = (secs / 86400) as i64;
+    let sod = secs % 86400;
+    // Civil date from days since the epoch (Howard Hinnant's algorithm).
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z.rem_euclid(146_097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+    format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}.{:03}Z",
+        year,
+        month,
+        day,
+        sod / 3600,
+        sod / 60 % 60,
+        sod % 60,
+        ms % 1000
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::pes::TIMESTAMP_MODULUS;
+    use crate::ts::{Packet, PACKET_SIZE, SYNC_BYTE};
+
+    /// A packet of PID 0x100 carrying a PCR made of `base` and `ext`.
+    fn pcr_packet(base: u64, ext: u64) -> [u8; PACKET_SIZE] {
+        let mut pkt = [0xff; PACKET_SIZE];
+        pkt[..4].copy_from_slice(&[SYNC_BYTE, 0x01, 0x00, 0x20]);
+        pkt[4] = 7;
+        pkt[5] = 0x10;
+        pkt[6] = (base >> 25) as u8;
+        pkt[7] = (base >> 17) as u8;
+        pkt[8] = (base >> 9) as u8;
+        pkt[9] = (base >> 1) as u8;
+        pkt[10] = ((base & 1) << 7) as u8 | 0x7e | (ext >> 8) as u8;
+        pkt[11] = ext as u8;
+        pkt
+    }
+
+    fn pcr(base: u64, ext: u64) -> u64 {
+        Packet::new(&pcr_packet(base, ext)).unwrap().pcr().unwrap()
+    }
+
+    #[test]
+    fn pts_wraps_at_33_bits() {
+        let mut pts = Unwrapper::new(TIMESTAMP_MODULUS);
+        assert_eq!(pts.unwrap(TIMESTAMP_MODULUS - 1), TIMESTAMP_MODULUS - 1);
+        assert_eq!(pts.unwrap(0), TIMESTAMP_MODULUS);
+        assert_eq!(pts.unwrap(3600), TIMESTAMP_MODULUS + 3600);
+        // A step back is not a wrap.
+        assert_eq!(pts.unwrap(0), TIMESTAMP_MODULUS);
+    }
+
+    #[test]
+    fn pcr_extension_carries_into_base() {
+        assert_eq!(pcr(1000, 299) + 1, pcr(1001, 0));
+        let mut unwrapper = Unwrapper::new(PCR_MODULUS);
+        let before = unwrapper.unwrap(pcr(1000, 299));
+        assert_eq!(unwrapper.unwrap(pcr(1001, 0)), before + 1);
+    }
+
+    #[test]
+    fn pcr_wraps_at_base_and_extension_rollover() {
+        let last = pcr((1 << 33) - 1, 299);
+        assert_eq!(last, PCR_MODULUS - 1);
+        assert_eq!(pcr(0, 0), 0);
+        let mut unwrapper = Unwrapper::new(PCR_MODULUS);
+        assert_eq!(unwrapper.unwrap(last), PCR_MODULUS - 1);
+        assert_eq!(unwrapper.unwrap(pcr(0, 0)), PCR_MODULUS);
+        assert_eq!(unwrapper.unwrap(pcr(0, 150)), PCR_MODULUS + 150);
+    }
+
+    #[test]
+    fn fit_follows_the_pcr_across_the_wrap() {
+        let mut model = ClockModel::new();
+        let start = UNIX_EPOCH + Duration::from_secs(1_700_000_000);
+        // Ten seconds of PCR, one sample every 100 ms, wrapping halfway.
+        let first = PCR_MODULUS - 5 * PCR_HZ;
+        for i in 0..100u64 {
+            let raw = (first + i * PCR_HZ / 10) % PCR_MODULUS;
+            let at = start + Duration::from_millis(i * 100);
+            model.add_pcr_sample(raw, i * 1000, at);
+        }
+        let table = model.table();
+        assert_eq!(table.len(), 10);
+        assert!(table.windows(2).all(|w| w[1].pcr == w[0].pcr + PCR_HZ));
+        assert!(table
+            .windows(2)
+            .all(|w| w[1].wall_clock_ms - w[0].wall_clock_ms == 1000));
+        let wrapped = PCR_MODULUS + PCR_HZ;
+        let expected = unix_ms(start) + 6000.0;
+        assert!((model.pcr_to_wall_ms(wrapped).unwrap() - expected).abs() < 1.0);
+    }
+
+    #[test]
+    fn linear_fit_slope_and_offset() {
+        let mut fit = LinearFit::new(4);
+        assert_eq!(fit.eval(0.0), None);
+        for x in 0..6 {
+            let x = f64::from(x);
+            fit.add(x, 10.0 + 2.0 * x);
+        }
+        assert_eq!(fit.len(), 4);
+        assert!((fit.slope() - 2.0).abs() < 1e-9);
+        assert!((fit.eval(10.0).unwrap() - 30.0).abs() < 1e-9);
+    }
+}
@@ -0,0 +1,343 @@
+use std::convert::TryFrom;
+use std::fmt;
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+
+use crate::grabber::{GrabberConfig, GrabberEntry};
+use crate::protocol::{self, INDEX, LENGTH, MAGIC, MAGIC1, MAGIC2, OPCODE, OPERATION, SEQ, VALUE};
+
+/// Value of the operation field of a command.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Operation {
+    Get,
+    Set,
+    /// Seen in the Windows traces after a series of SET commands, apparently
+    /// to have the device apply them.
+    Commit,
+    /// Any other value, for experiments.
+    Raw(u32),
+}
+
+impl Operation {
+    pub fn value(self) -> u32 {
+        match self {
+            Operation::Get => 1,
+            Operation::Set => 2,
+            Operation::Commit => 3,
+            Operation::Raw(value) => value,
+        }
+    }
+}
+
+impl From<u32> for Operation {
+    fn from(value: u32) -> Operation {
+        match value {
+            1 => Operation::Get,
+            2 => Operation::Set,
+            3 => Operation::Commit,
+            _ => Operation::Raw(value),
+        }
+    }
+}
+
+impl fmt::Display for Operation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Operation::Get => write!(f, "get"),
+            Operation::Set => write!(f, "set"),
+            Operation::Commit => write!(f, "commit"),
+            Operation::Raw(value) => write!(f, "{:#x}", value),
+        }
+    }
+}
+
+/// Parses `get`, `set`, `commit` or a number, decimal or prefixed with `0x`.
+impl FromStr for Operation {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Operation, String> {
+        match s {
+            "get" => Ok(Operation::Get),
+            "set" => Ok(Operation::Set),
+            "commit" => Ok(Operation::Commit),
+            _ => {
+                let value = match s.strip_prefix("0x") {
+                    Some(hex) => u32::from_str_radix(hex, 16),
+                    None => s.parse(),
+                };
+                value
+                    .map(Operation::from)
+                    .map_err(|_| format!("unknown operation `{}`", s))
+            }
+        }
+    }
+}
+
+/// Builds the commands sent to a device, numbering them in sequence.
+///
+/// The sequence counter belongs to one device: factories are only created
+/// by `Device`, and clones share the counter of the device they come from.
+pub struct CommandFactory {
+    seq: Arc<Mutex<u16>>,
+}
+
+impl CommandFactory {
+    pub(crate) fn new() -> CommandFactory {
+        CommandFactory {
+            seq: Arc::new(Mutex::new(0u16)),
+        }
+    }
+
+    pub fn make_command(&mut self, opcode: u16, operation: Operation, data: &[u8]) -> Vec<u8> {
+        let len = u16::try_from(protocol::HEADER_LEN + data.len()).unwrap();
+        let seq = {
+            let mut guard = self.seq.lock().unwrap();
+            let previous = *guard;
+            *guard = previous.overflowing_add(1).0;
+            previous
+        };
+        let mut cmd = vec![0u8; usize::from(len)];
+        cmd[LENGTH.range()].copy_from_slice(&len.to_le_bytes());
+        cmd[OPCODE.range()].copy_from_slice(&opcode.to_le_bytes());
+        cmd[MAGIC1.range()].copy_from_slice(&MAGIC);
+        cmd[OPERATION.range()].copy_from_slice(&operation.value().to_le_bytes());
+        cmd[SEQ.range()].copy_from_slice(&seq.to_le_bytes());
+        cmd[MAGIC2.range()].copy_from_slice(&MAGIC);
+        cmd[protocol::HEADER_LEN..].copy_from_slice(data);
+        cmd
+    }
+
+    /// Payload of the parameters addressed by an index word.
+    fn indexed(index: u32, value: u32) -> [u8; 8] {
+        let mut data = [0u8; 8];
+        data[INDEX.range()].copy_from_slice(&index.to_le_bytes());
+        data[VALUE.range()].copy_from_slice(&value.to_le_bytes());
+        data
+    }
+
+    pub fn make_reboot(&mut self) -> Vec<u8> {
+        self.make_command(protocol::REBOOT, Operation::Set, &[])
+    }
+
+    pub fn make_set_state(&mut self, word1: u32) -> Vec<u8> {
+        let mut data = [0u8; 4];
+        data[0..=3].copy_from_slice(&word1.to_le_bytes());
+        self.make_command(protocol::STATE, Operation::Set, &data)
+    }
+
+    pub fn make_get_state(&mut self) -> Vec<u8> {
+        self.make_command(protocol::STATE, Operation::Get, &[0u8; 4])
+    }
+
+    pub fn make_get_source(&mut self) -> Vec<u8> {
+        const GET_SOURCE_DATA: [u8; 8] = [0u8; 8];
+        self.make_command(protocol::SOURCE, Operation::Get, &GET_SOURCE_DATA)
+    }
+
+    pub fn make_set_source(&mut self, audio_src: u32, video_src: u32) -> Vec<u8> {
+        let mut data = [0u8; 8];
+        data[protocol::AUDIO_SOURCE.range()].copy_from_slice(&audio_src.to_le_bytes());
+        data[protocol::VIDEO_SOURCE.range()].copy_from_slice(&video_src.to_le_bytes());
+        self.make_command(protocol::SOURCE, Operation::Set, &data)
+    }
+
+    /// Read a parameter which is addressed by an index word (channel or
+    /// stream number) and returned as a pair of little-endian words.
+    pub fn make_get_indexed(&mut self, opcode: u16, index: u32) -> Vec<u8> {
+        self.make_command(opcode, Operation::Get, &Self::indexed(index, 0))
+    }
+
+    pub fn make_get_brightness(&mut self) -> Vec<u8> {
+        self.make_get_indexed(protocol::BRIGHTNESS, 0)
+    }
+
+    pub fn make_get_contrast(&mut self) -> Vec<u8> {
+        self.make_get_indexed(protocol::CONTRAST, 0)
+    }
+
+    pub fn make_get_hue(&mut self) -> Vec<u8> {
+        self.make_get_indexed(protocol::HUE, 0)
+    }
+
+    pub fn make_get_saturation(&mut self) -> Vec<u8> {
+        self.make_get_indexed(protocol::SATURATION, 0)
+    }
+
+    pub fn make_get_video_compression_keyframe_rate(&mut self, stream_idx: u32) -> Vec<u8> {
+        self.make_get_indexed(protocol::KEYFRAME_RATE, stream_idx)
+    }
+
+    pub fn make_get_video_compression_quality(&mut self, stream_idx: u32) -> Vec<u8> {
+        self.make_get_indexed(protocol::QUALITY, stream_idx)
+    }
+
+    pub fn make_set_brightness(&mut self, brightness: u32) -> Vec<u8> {
+        let data = Self::indexed(0, brightness);
+        self.make_command(protocol::BRIGHTNESS, Operation::Set, &data)
+    }
+
+    pub fn make_set_contrast(&mut self, contrast: u32) -> Vec<u8> {
+        let data = Self::indexed(0, contrast);
+        self.make_command(protocol::CONTRAST, Operation::Set, &data)
+    }
+
+    pub fn make_set_hue(&mut self, hue: u32) -> Vec<u8> {
+        let data = Self::indexed(0, hue);
+        self.make_command(protocol::HUE, Operation::Set, &data)
+    }
+
+    pub fn make_set_saturation(&mut self, saturation: u32) -> Vec<u8> {
+        let data = Self::indexed(0, saturation);
+        self.make_command(protocol::SATURATION, Operation::Set, &data)
+    }
+
+    pub fn make_set_video_compression_keyframe_rate(&mut self, stream_idx: u32, rate: u32) -> Vec<u8> {
+        let data = Self::indexed(stream_idx, rate);
+        self.make_command(protocol::KEYFRAME_RATE, Operation::Set, &data)
+    }
+
+    pub fn make_set_video_compression_quality(&mut self, stream_idx: u32, quality: u32) -> Vec<u8> {
+        let data = Self::indexed(stream_idx, quality);
+        self.make_command(protocol::QUALITY, Operation::Set, &data)
+    }
+
+    pub fn make_get_firmware_status(&mut self) -> Vec<u8> {
+        self.make_command(protocol::FIRMWARE_STATUS, Operation::Get, &[])
+    }
+
+    pub fn make_get_profile(&mut self) -> Vec<u8> {
+        self.make_command(protocol::PROFILE, Operation::Get, &[])
+    }
+
+    pub fn make_get_pc_grabber_small(&mut self) -> Vec<u8> {
+        let dummy = [
+            0x01u8, 0x40, 0x38, 0x38, 0x3c, 0xc6, 0xb0, 0x93, 0xba, 0xc1, 0xb0, 0x93,
+        ];
+        self.make_command(protocol::PC_GRABBER, Operation::Get, &dummy)
+    }
+
+    pub fn make_set_pc_grabber_small(&mut self, enable: bool) -> Vec<u8> {
+        let data: [u8; 0x0c] = [
+            0x01, 0x40, 0x38, 0x38, 0x51, 0xd3, 0xcf, 0x77, if enable { 0x01 } else { 0x00 }, 0x00, 0x00, 0x00,
+        ];
+        self.make_command(protocol::PC_GRABBER, Operation::Set, &data)
+    }
+
+    /// Payload of a PC grabber configuration entry.
+    fn pc_grabber_entry(index: u32, config: &GrabberConfig) -> [u8; 0x3c] {
+        let mut data: [u8; 0x3c] = [
+            0x08, 0x20, 0x38, 0x38, 0x00, 0x00, 0x00, 0x00, 0x05, 0x00, 0x00, 0x00, 0x00, 0x00,
+            0x00, 0x00, 0x0f, 0x00, 0x00, 0x00, 0x80, 0x07, 0x00, 0x00, 0x38, 0x04, 0x00, 0x00,
+            0x10, 0x27, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x1e, 0x00,
+            0x00, 0x00, 0x1e, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+            0x00, 0x00, 0x00, 0x00,
+        ];
+        let index_offset = GrabberConfig::INDEX_OFFSET;
+        data[index_offset..index_offset + 4].copy_from_slice(&index.to_le_bytes());
+        let width = GrabberConfig::WIDTH_OFFSET;
+        data[width..width + 4].copy_from_slice(&config.width.to_le_bytes());
+        let height = GrabberConfig::HEIGHT_OFFSET;
+        data[height..height + 4].copy_from_slice(&config.height.to_le_bytes());
+        data
+    }
+
+    pub fn make_set_pc_grabber(&mut self, entry: &GrabberEntry, config: &GrabberConfig) -> Vec<u8> {
+        let mut data = Self::pc_grabber_entry(entry.index, config);
+        entry.apply(&mut data);
+        self.make_command(protocol::PC_GRABBER, Operation::Set, &data)
+    }
+
+    /// Read back a configuration entry. The response mirrors the payload of
+    /// `make_set_pc_grabber`.
+    pub fn make_get_pc_grabber(&mut self, index: u32) -> Vec<u8> {
+        let data = Self::pc_grabber_entry(index, &GrabberConfig::default());
+        self.make_command(protocol::PC_GRABBER, Operation::Get, &data)
+    }
+
+    /// Have the device apply the configuration entries sent with
+    /// `make_set_pc_grabber`. The payload of the traces is not understood;
+    /// the header of the entries is sent.
+    pub fn make_commit_pc_grabber(&mut self) -> Vec<u8> {
+        let data = Self::pc_grabber_entry(0, &GrabberConfig::default());
+        self.make_command(protocol::PC_GRABBER, Operation::Commit, &data[..0x0c])
+    }
+
+    pub fn make_set_pc_grabber_large(&mut self) -> Vec<u8> {
+        let data: [u8; 0x200] = [
+            0x00, 0x02, 0x00, 0x00, 0x01, 0xe0, 0x10, 0x99, 0x01, 0x00, 0x00, 0x00, 0x36, 0x00,
+            0x10, 0x99, 0x02, 0x00, 0x38, 0x38, 0x3c, 0xc6, 0xb0, 0x93, 0xba, 0xc1, 0xb0, 0x93,
+            0x00, 0x00, 0x00, 0x00, 0x28, 0x8b, 0x5d, 0x8a, 0x5d, 0x6b, 0xb0, 0x93, 0x74, 0xd0,
+            0xcc, 0x84, 0xb8, 0x63, 0xdf, 0x84, 0xb8, 0x65, 0xdf, 0x84, 0x48, 0xce, 0xd8, 0x84,
+            0x07, 0x00, 0x00, 0x00, 0x3c, 0xc6, 0xb0, 0x93, 0xae, 0xba, 0xb0, 0x93, 0x24, 0x8b,
+            0x5d, 0x8a, 0x98, 0xc6, 0xb0, 0x93, 0xc0, 0xa8, 0x98, 0x84, 0x01, 0x00, 0x00, 0xc0,
+            0x78, 0x8b, 0x5d, 0x8a, 0x21, 0x61, 0x22, 0x8d, 0x74, 0xd0, 0xcc, 0x84, 0xb8, 0x65,
+            0xdf, 0x84, 0xb8, 0x63, 0xdf, 0x84, 0xac, 0xaa, 0x7f, 0x07, 0xd0, 0x12, 0x22, 0x8d,
+            0x28, 0x00, 0x00, 0x00, 0x05, 0xce, 0xd8, 0x84, 0x00, 0x00, 0x00, 0x00, 0x20, 0x00,
+            0x00, 0x00, 0x00, 0x02, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00, 0x00, 0x02, 0x00, 0x00,
+            0x3c, 0x8b, 0x5d, 0x8a, 0x00, 0x00, 0x00, 0x00, 0xc0, 0x8c, 0x5d, 0x8a, 0xea, 0x0a,
+            0x22, 0x8d, 0xd4, 0x3b, 0x00, 0x00, 0xfe, 0xff, 0xff, 0xff, 0xac, 0x8b, 0x5d, 0x8a,
+            0x85, 0x5a, 0x22, 0x8d, 0x48, 0xce, 0xd8, 0x84, 0x05, 0x00, 0x00, 0x00, 0xb0, 0x38,
+            0xcb, 0x95, 0xb8, 0x63, 0xdf, 0x84, 0x28, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+            0x00, 0x00, 0x00, 0x00, 0xe8, 0xe2, 0xd8, 0x84, 0x48, 0xce, 0xd8, 0x84, 0x48, 0xce,
+            0xd8, 0x84, 0x25, 0x02, 0x00, 0xc0, 0xd4, 0x8b, 0x5d, 0x8a, 0x43, 0x6c, 0x22, 0x8d,
+            0x48, 0xce, 0xd8, 0x84, 0x60, 0x38, 0xcb, 0x95, 0x30, 0x52, 0xd8, 0x84, 0x38, 0x52,
+            0xd8, 0x84, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0xe8, 0xe2, 0xd8, 0x84,
+            0x08, 0xd0, 0xcc, 0x84, 0xe4, 0x8b, 0x5d, 0x8a, 0x8f, 0x54, 0x22, 0x8d, 0x70, 0x5c,
+            0x3e, 0x84, 0x48, 0xce, 0xd8, 0x84, 0xfc, 0x8b, 0x5d, 0x8a, 0xba, 0x50, 0x21, 0x8d,
+            0x70, 0x5c, 0x3e, 0x84, 0x48, 0xce, 0xd8, 0x84, 0x70, 0x5c, 0x3e, 0x84, 0x00, 0x00,
+            0x00, 0x00, 0x14, 0x8c, 0x5d, 0x8a, 0x47, 0x20, 0x83, 0x82, 0x70, 0x5c, 0x3e, 0x84,
+            0x48, 0xce, 0xd8, 0x84, 0x48, 0xce, 0xd8, 0x84, 0x70, 0x5c, 0x3e, 0x84, 0x34, 0x8c,
+            0x5d, 0x8a, 0xd5, 0x89, 0xa0, 0x82, 0xe8, 0xe2, 0xd8, 0x84, 0x48, 0xce, 0xd8, 0x84,
+            0x48, 0xcf, 0xd8, 0x84, 0xb4, 0x01, 0x00, 0x00, 0x8c, 0x8c, 0x5d, 0x04, 0x44, 0x8c,
+            0x5d, 0x8a, 0xd0, 0x8c, 0x5d, 0x8a, 0xc8, 0xad, 0xa0, 0x82, 0x70, 0x5c, 0x3e, 0x84,
+            0xe8, 0xe2, 0xd8, 0x84, 0x00, 0x00, 0x00, 0x00, 0x01, 0xf1, 0xa4, 0x82, 0x00, 0x7a,
+            0x6b, 0x20, 0x02, 0x00, 0x00, 0x00, 0xf4, 0x7d, 0x6b, 0x20, 0x44, 0x04, 0x00, 0x00,
+            0xc8, 0xfb, 0x25, 0x09, 0x73, 0x1d, 0xa1, 0x82, 0x00, 0x00, 0x00, 0x00, 0x9f, 0x01,
+            0x12, 0x00, 0x00, 0x00, 0x00, 0x00, 0x10, 0x00, 0x00, 0x00, 0xe8, 0xe2, 0xd8, 0x84,
+            0x00, 0x00, 0x00, 0x00, 0xd5, 0x74, 0xa5, 0x08, 0xc0, 0x0a, 0xd8, 0x84, 0x84, 0x75,
+            0xa5, 0x82, 0x01, 0x8e, 0x8b, 0x82, 0xc8, 0xf5, 0x42, 0x84, 0x10, 0x00, 0x00, 0x00,
+            0xa4, 0x8c, 0x5d, 0x8a, 0x30, 0xfc, 0x25, 0x09, 0x00, 0x7a, 0x6b, 0x20, 0x03, 0x00,
+            0x00, 0x00, 0x01, 0xf1, 0xa4, 0x82, 0xc8, 0xf5, 0x42, 0x84, 0xe8, 0xe2, 0xd8, 0x84,
+            0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x54, 0x8c, 0x5d, 0x8a, 0x18, 0x8d,
+            0x5d, 0x8a, 0xff, 0xff, 0xff, 0xff, 0x0b, 0x8e, 0x8b, 0x82, 0x7c, 0xf2, 0xb3, 0x28,
+            0xfe, 0xff, 0xff, 0xff, 0x04, 0x8d, 0x5d, 0x8a,
+        ];
+        self.make_command(protocol::PC_GRABBER, Operation::Set, &data)
+    }
+
+    pub fn make_time_query(&mut self, ts: u32) -> Vec<u8> {
+        let mut data = [0u8; 4];
+        data[0..=3].copy_from_slice(&ts.to_le_bytes());
+        self.make_command(protocol::TIME, Operation::Get, &data)
+    }
+
+    pub fn make_get_hw_grabber(&mut self) -> Vec<u8> {
+        self.make_command(protocol::HW_GRABBER, Operation::Get, &[])
+    }
+}
+
+impl Clone for CommandFactory {
+    fn clone(&self) -> Self {
+        Self {
+            seq: self.seq.clone(),
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn operation_parses_names_and_numbers() {
+        assert_eq!("get".parse(), Ok(Operation::Get));
+        assert_eq!("set".parse(), Ok(Operation::Set));
+        assert_eq!("3".parse(), Ok(Operation::Commit));
+        assert_eq!("0x10".parse(), Ok(Operation::Raw(0x10)));
+        assert_eq!("16".parse(), Ok(Operation::Raw(0x10)));
+        for garbage in ["", "GET", "0x", "0xfg", "-1", "apply"] {
+            assert!(garbage.parse::<Operation>().is_err(), "{}", garbage);
+        }
+    }
+}
@@ -0,0 +1,80 @@
+//! Configuration file, in TOML.
+//!
+//! All the sections are optional. Command line options take precedence over
+//! the values of the file.
+
+use std::collections::BTreeMap;
+use std::path::Path;
+
+use serde::{Deserialize, Serialize};
+
+use crate::error::Error;
+use crate::grabber::GrabberEntry;
+use crate::profile::{SourceProfile, StreamProfile};
+use crate::sources::VIDEO_SOURCES;
+use crate::validate::Thresholds;
+
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    /// Criteria for a recording to be usable.
+    pub validate: Thresholds,
+    /// Overrides of the settings of each video source, by source name.
+    pub sources: BTreeMap<String, SourceProfile>,
+    /// PC grabber configuration entries, replacing those of the firmware
+    /// revision.
+    pub grabber_entries: Option<Vec<GrabberEntry>>,
+    /// Encoder settings of stream 0, over those of the video source.
+    #[serde(skip_serializing_if = "StreamProfile::is_empty")]
+    pub stream0: StreamProfile,
+    /// Encoder settings of stream 1, with dual-stream firmware.
+    #[serde(skip_serializing_if = "StreamProfile::is_empty")]
+    pub stream1: StreamProfile,
+}
+
+impl Config {
+    pub fn load(path: &Path) -> Result<Config, Error> {
+        let text = std::fs::read_to_string(path)?;
+        let config: Config = toml::from_str(&text)
+            .map_err(|e| Error::Config(format!("{}: {}", path.display(), e)))?;
+        for (name, profile) in &config.sources {
+            if !VIDEO_SOURCES.iter().any(|s| s.name == name) {
+                return Err(Error::Config(format!(
+                    "{}: unknown source `{}`",
+                    path.display(),
+                    name
+                )));
+            }
+            profile.check().map_err(|e| {
+                Error::Config(format!("{}: sources.{}: {}", path.display(), name, e))
+            })?;
+        }
+        for entry in config.grabber_entries.iter().flatten() {
+            entry.check().map_err(|e| {
+                Error::Config(format!("{}: grabber_entries: {}", path.display(), e))
+            })?;
+        }
+        Ok(config)
+    }
+
+    /// Settings of a video source: the built-in ones, with the overrides
+    /// of the file.
+    pub fn source_profile(&self, source: &str) -> SourceProfile {
+        let builtin = SourceProfile::builtin(source);
+        match self.sources.get(source) {
+            Some(profile) => builtin.merge(profile),
+            None => builtin,
+        }
+    }
+
+    /// The configuration with the built-in source settings filled in.
+    pub fn effective(&self) -> Config {
+        let mut config = self.clone();
+        for source in VIDEO_SOURCES {
+            config
+                .sources
+                .insert(source.name.to_string(), self.source_profile(source.name));
+        }
+        config
+    }
+}
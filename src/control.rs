@@ -0,0 +1,133 @@
+//! Control file, changing the encoder settings during capture.
+//!
+//! The file takes the `[stream0]` and `[stream1]` sections of the
+//! configuration file, and a `source` key switching the video input, the
+//! settings of the new source applying under those of the file and of the
+//! command line as at the start. It is checked every second, and read again whenever
+//! its modification time changes; the settings it holds are queued for the
+//! capture loop, which applies those differing from the current ones. A file
+//! which cannot be read or parsed is reported and ignored until it changes
+//! again.
+
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime};
+
+use serde::Deserialize;
+
+use crate::encoder::EncoderParam;
+use crate::profile::StreamProfile;
+use crate::worker::PeriodicWorker;
+
+const POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct Controls {
+    source: Option<String>,
+    stream0: StreamProfile,
+    stream1: StreamProfile,
+}
+
+/// An encoder setting asked for in the control file.
+#[derive(Clone, Copy, Debug)]
+pub struct EncoderChange {
+    pub stream: u32,
+    pub param: EncoderParam,
+    pub value: u32,
+}
+
+/// What the control file asks for, as last read.
+#[derive(Debug, Default)]
+pub struct Requests {
+    /// Name of the video source to switch to.
+    pub source: Option<String>,
+    pub changes: Vec<EncoderChange>,
+}
+
+fn load(path: &Path) -> Result<Requests, String> {
+    let text = std::fs::read_to_string(path).map_err(|e| e.to_string())?;
+    let controls: Controls = toml::from_str(&text).map_err(|e| e.to_string())?;
+    let mut changes = Vec::new();
+    for (stream, profile) in [(0, &controls.stream0), (1, &controls.stream1)] {
+        for (param, value) in profile.encoder() {
+            changes.push(EncoderChange {
+                stream,
+                param,
+                value,
+            });
+        }
+    }
+    Ok(Requests {
+        source: controls.source,
+        changes,
+    })
+}
+
+/// Watches the control file and queues its settings, for the capture loop
+/// to pick up.
+pub struct ControlFile {
+    worker: PeriodicWorker,
+    requests: Arc<Mutex<Requests>>,
+}
+
+impl ControlFile {
+    pub fn start(path: PathBuf) -> ControlFile {
+        let requests = Arc::new(Mutex::new(Requests::default()));
+        let worker = {
+            let requests = requests.clone();
+            let mut modified: Option<SystemTime> = None;
+            PeriodicWorker::spawn("control", POLL_INTERVAL, move || {
+                // A missing file is not an error, it may be written later.
+                let mtime = match std::fs::metadata(&path).and_then(|m| m.modified()) {
+                    Ok(mtime) => mtime,
+                    Err(_) => return,
+                };
+                if modified == Some(mtime) {
+                    return;
+                }
+                modified = Some(mtime);
+                match load(&path) {
+                    Ok(loaded) => *requests.lock().unwrap() = loaded,
+                    Err(e) => eprintln!("Ignoring the control file {}: {}", path.display(), e),
+                }
+            })
+        };
+        ControlFile { worker, requests }
+    }
+
+    /// Settings read since the last call.
+    pub fn take(&self) -> Requests {
+        std::mem::take(&mut *self.requests.lock().unwrap())
+    }
+
+    pub fn stop(&mut self) {
+        self.worker.stop();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn source_and_settings_are_queued() {
+        let dir = std::env::temp_dir().join(format!("it9910-control-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("control.toml");
+        std::fs::write(&path, "source = \"composite\"\n[stream1]\nquality = 40\n").unwrap();
+        let requests = load(&path).unwrap();
+        assert_eq!(requests.source.as_deref(), Some("composite"));
+        assert_eq!(requests.changes.len(), 1);
+        assert_eq!(requests.changes[0].stream, 1);
+        assert_eq!(requests.changes[0].param, EncoderParam::Quality);
+        assert_eq!(requests.changes[0].value, 40);
+
+        std::fs::write(&path, "[stream0]\nquality = 40\n").unwrap();
+        assert_eq!(load(&path).unwrap().source, None);
+        std::fs::write(&path, "input = \"composite\"\n").unwrap();
+        assert!(load(&path).is_err());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,275 @@
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use crate::command::CommandFactory;
+use crate::dispatch::{Dispatcher, ProtocolStats};
+use crate::error::Error;
+use crate::response::Response;
+use crate::transport::{Transport, UsbTransport};
+
+pub const VENDOR_ID: u16 = 0x048d;
+pub const PRODUCT_ID: u16 = 0x9910;
+
+const USB_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// An opened IT9910 device, along with the command factory whose sequence
+/// numbers are used on it.
+///
+/// Each opened device has its own sequence counter. Clones share the handle
+/// and the counter, so that several threads can issue commands to the same
+/// device.
+#[derive(Clone)]
+pub struct Device {
+    transport: Arc<dyn Transport>,
+    /// Held while a command is written. Responses are read by the
+    /// dispatcher, and the stream endpoint without any lock, so that commands
+    /// can be issued while a stream read is pending.
+    command_lock: Arc<Mutex<()>>,
+    dispatcher: Arc<Dispatcher>,
+    factory: CommandFactory,
+}
+
+impl Device {
+    /// A device reached through `transport`, such as an emulator.
+    pub fn with_transport(transport: Arc<dyn Transport>) -> Device {
+        Device {
+            dispatcher: Arc::new(Dispatcher::new(transport.clone())),
+            transport,
+            command_lock: Arc::new(Mutex::new(())),
+            factory: CommandFactory::new(),
+        }
+    }
+
+    /// Open the first IT9910 device found on the system.
+    pub fn open() -> Result<Device, Error> {
+        let hnd = rusb::open_device_with_vid_pid(VENDOR_ID, PRODUCT_ID).ok_or(Error::NoDevice)?;
+        Ok(Device::with_transport(Arc::new(UsbTransport::new(hnd))))
+    }
+
+    /// Open all the IT9910 devices found on the system, each with its own
+    /// sequence counter.
+    pub fn open_all() -> Result<Vec<Device>, Error> {
+        let mut devices = Vec::new();
+        for dev in rusb::devices()?.iter() {
+            let desc = dev.device_descriptor()?;
+            if desc.vendor_id() == VENDOR_ID && desc.product_id() == PRODUCT_ID {
+                let transport = UsbTransport::new(dev.open()?);
+                devices.push(Device::with_transport(Arc::new(transport)));
+            }
+        }
+        Ok(devices)
+    }
+
+    pub fn reset(&self) -> Result<(), Error> {
+        self.transport.reset()?;
+        Ok(())
+    }
+
+    /// Claim the interface and bring the endpoints to a known state.
+    pub fn claim(&self) -> Result<(), Error> {
+        self.transport.claim()?;
+        Ok(())
+    }
+
+    /// Claim the interface without clearing the endpoints, for queries
+    /// which must leave the device as it is.
+    pub fn claim_interface(&self) -> Result<(), Error> {
+        self.transport.claim_interface()?;
+        Ok(())
+    }
+
+    /// Command factory sharing its sequence counter with this device.
+    pub fn factory(&self) -> CommandFactory {
+        self.factory.clone()
+    }
+
+    /// Send a command and wait for its response.
+    ///
+    /// The response is the one carrying the sequence number of the command,
+    /// so that several threads can wait for their responses at the same time.
+    /// A rejection by the device is returned as `Error::DeviceRejected`.
+    pub fn transact(&self, cmd: &[u8]) -> Result<Response, Error> {
+        self.transact_timeout(cmd, USB_TIMEOUT)
+    }
+
+    /// `transact`, waiting at most `timeout` for the response.
+    pub fn transact_timeout(&self, cmd: &[u8], timeout: Duration) -> Result<Response, Error> {
+        let seq = u16::from_le_bytes([cmd[0x0c], cmd[0x0d]]);
+        let rx = self.dispatcher.expect(seq);
+        let sent = {
+            let _guard = self.command_lock.lock().unwrap();
+            self.transport.write_command(cmd, timeout)
+        };
+        if let Err(e) = sent {
+            self.dispatcher.cancel(seq);
+            return Err(e.into());
+        }
+        let resp = self.dispatcher.wait(seq, rx, timeout)?;
+        let operation = u32::from_le_bytes([cmd[0x08], cmd[0x09], cmd[0x0a], cmd[0x0b]]);
+        match resp.rejection(operation) {
+            Some(status) => Err(Error::DeviceRejected {
+                opcode: resp.opcode,
+                status,
+                payload: resp.payload,
+            }),
+            None => Ok(resp),
+        }
+    }
+
+    /// Counters of the anomalies seen on the response endpoint.
+    pub fn protocol_stats(&self) -> &ProtocolStats {
+        self.dispatcher.stats()
+    }
+
+    /// Read a chunk of the MPEG TS stream.
+    ///
+    /// This does not wait for commands in progress, nor blocks them.
+    pub fn read_stream(&self, buf: &mut [u8], timeout: Duration) -> rusb::Result<usize> {
+        self.transport.read_stream(buf, timeout)
+    }
+
+    /// Whether the device is still on the bus under the same address.
+    pub fn is_attached(&self) -> bool {
+        self.transport.is_attached()
+    }
+
+    /// Close the device and open it again, for instance once it is back on
+    /// the bus after a reboot. Clones use the new handle as well.
+    pub fn reopen(&self) -> Result<(), Error> {
+        let _guard = self.command_lock.lock().unwrap();
+        self.transport.reopen()?;
+        Ok(())
+    }
+
+    /// Whether the device has an endpoint for asynchronous notifications.
+    pub fn has_events(&self) -> bool {
+        self.transport.has_events()
+    }
+
+    /// Wait for a notification on the event endpoint, like `read_stream`
+    /// without taking the command lock.
+    pub fn read_event(&self, buf: &mut [u8], timeout: Duration) -> rusb::Result<usize> {
+        self.transport.read_event(buf, timeout)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::emulator::{Emulator, EmulatorConfig};
+    use std::collections::VecDeque;
+    use std::sync::Condvar;
+    use std::time::Instant;
+
+    /// Echoes commands back at once, while a stream read never returns data
+    /// before its timeout, like a device which is not streaming yet.
+    #[derive(Default)]
+    struct SlowStream {
+        responses: Mutex<VecDeque<Vec<u8>>>,
+        ready: Condvar,
+    }
+
+    impl Transport for SlowStream {
+        fn reset(&self) -> rusb::Result<()> {
+            Ok(())
+        }
+
+        fn claim(&self) -> rusb::Result<()> {
+            Ok(())
+        }
+
+        fn write_command(&self, data: &[u8], _timeout: Duration) -> rusb::Result<usize> {
+            self.responses.lock().unwrap().push_back(data.to_vec());
+            self.ready.notify_all();
+            Ok(data.len())
+        }
+
+        fn read_response(&self, buf: &mut [u8], timeout: Duration) -> rusb::Result<usize> {
+            let responses = self.responses.lock().unwrap();
+            let (mut responses, _) = self
+                .ready
+                .wait_timeout_while(responses, timeout, |r| r.is_empty())
+                .unwrap();
+            let resp = responses.pop_front().ok_or(rusb::Error::Timeout)?;
+            buf[..resp.len()].copy_from_slice(&resp);
+            Ok(resp.len())
+        }
+
+        fn read_stream(&self, _buf: &mut [u8], timeout: Duration) -> rusb::Result<usize> {
+            std::thread::sleep(timeout);
+            Err(rusb::Error::Timeout)
+        }
+    }
+
+    fn emulated() -> Device {
+        Device::with_transport(Arc::new(Emulator::new(EmulatorConfig::default())))
+    }
+
+    fn query_state(device: &Device) -> u16 {
+        let cmd = device.factory().make_get_state();
+        let seq = u16::from_le_bytes([cmd[0x0c], cmd[0x0d]]);
+        let resp = device.transact(&cmd).unwrap();
+        assert_eq!(resp.seq, seq);
+        seq
+    }
+
+    #[test]
+    fn devices_keep_separate_sequence_counters() {
+        let first = emulated();
+        let second = emulated();
+        let a: Vec<u16> = (0..3).map(|_| query_state(&first)).collect();
+        let b = query_state(&second);
+        // The second device starts its own count, untouched by the first.
+        assert_eq!(b, a[0]);
+        assert_eq!(a[1], a[0].wrapping_add(1));
+        assert_eq!(a[2], a[1].wrapping_add(1));
+        // Clones share the counter of the device they come from.
+        let c = query_state(&first.clone());
+        assert_eq!(c, a[2].wrapping_add(1));
+        assert_eq!(query_state(&second), b.wrapping_add(1));
+    }
+
+    #[test]
+    fn commands_are_not_blocked_behind_stream_reads() {
+        let device = Device::with_transport(Arc::new(SlowStream::default()));
+        let reader = {
+            let device = device.clone();
+            std::thread::spawn(move || {
+                let mut buf = [0u8; 188];
+                device.read_stream(&mut buf, Duration::from_secs(2))
+            })
+        };
+        // Let the stream read start before sending commands.
+        std::thread::sleep(Duration::from_millis(100));
+        let start = Instant::now();
+        for _ in 0..10 {
+            let cmd = device.factory().make_get_state();
+            device.transact(&cmd).unwrap();
+        }
+        let elapsed = start.elapsed();
+        assert!(!reader.is_finished(), "stream read returned early");
+        assert!(elapsed < Duration::from_millis(500), "{:?}", elapsed);
+        assert!(reader.join().unwrap().is_err());
+    }
+
+    #[test]
+    fn rejection_is_an_error() {
+        let emulator = Arc::new(Emulator::new(EmulatorConfig::default()));
+        let device = Device::with_transport(emulator.clone());
+        emulator.reject(crate::protocol::BRIGHTNESS, 0x8000_0001);
+        match device.transact(&device.factory().make_set_brightness(0)) {
+            Err(Error::DeviceRejected {
+                opcode,
+                status,
+                payload,
+            }) => {
+                assert_eq!(opcode, crate::protocol::BRIGHTNESS);
+                assert_eq!(status, 0x8000_0001);
+                assert!(payload.is_empty());
+            }
+            other => panic!("unexpected result {:?}", other),
+        }
+        // Other commands are still answered.
+        query_state(&device);
+    }
+}
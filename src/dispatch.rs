@@ -0,0 +1,492 @@
+//! Routing of the command responses to the commands waiting for them.
+//!
+//! A single reader keeps a read pending on the response endpoint and hands
+//! each response to the command carrying the same sequence number, whatever
+//! the order they arrive in. Responses no command is waiting for, such as
+//! the late answer to a command which timed out, are logged and dropped
+//! instead of being taken for the answer to the next command.
+//!
+//! The length field of the header tells where a response ends. A transfer
+//! may hold less, the rest following in the next transfer, or more: a second
+//! response, or leftovers of a previous transfer which are dropped.
+
+use std::fmt;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, SyncSender};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use log::{debug, warn};
+
+use crate::error::Error;
+use crate::protocol::{MAGIC, MAGIC1};
+use crate::response::{self, ParseError, Response, HEADER_LEN};
+use crate::transport::Transport;
+use crate::worker::PeriodicWorker;
+
+type Reply = Result<Response, Error>;
+
+#[derive(Default)]
+pub struct ProtocolStats {
+    /// Responses nobody was waiting for.
+    pub dead_letters: AtomicU64,
+    /// Responses shorter than their length field, whether the rest came in
+    /// a later transfer or not.
+    pub truncated: AtomicU64,
+    /// Truncated responses completed by a later transfer.
+    pub reassembled: AtomicU64,
+    /// Responses received in the same transfer as a previous one.
+    pub coalesced: AtomicU64,
+    /// Transfers with bytes past the end of their responses which were not
+    /// a response.
+    pub trailing_garbage: AtomicU64,
+}
+
+impl ProtocolStats {
+    fn count(counter: &AtomicU64) {
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+impl fmt::Display for ProtocolStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let get = |counter: &AtomicU64| counter.load(Ordering::Relaxed);
+        write!(
+            f,
+            "{} unclaimed, {} truncated ({} reassembled), {} coalesced, {} with trailing garbage",
+            get(&self.dead_letters),
+            get(&self.truncated),
+            get(&self.reassembled),
+            get(&self.coalesced),
+            get(&self.trailing_garbage)
+        )
+    }
+}
+
+/// What the reader made of the bytes received.
+enum Frame {
+    Response(Response),
+    /// A response cut short, with the sequence number of its header.
+    Truncated(u16, ParseError),
+    /// Bytes which cannot be tied to a command.
+    Invalid(ParseError),
+}
+
+/// Splits the transfers of the response endpoint into responses.
+#[derive(Default)]
+struct Framer {
+    /// Start of a response waiting for the rest of its bytes.
+    partial: Vec<u8>,
+}
+
+impl Framer {
+    fn feed(&mut self, data: &[u8], stats: &ProtocolStats) -> Vec<Frame> {
+        let mut frames = Vec::new();
+        if !self.partial.is_empty() && response::is_header(data) {
+            // A new response: the previous one will not be completed.
+            frames.extend(self.flush());
+        }
+        let continued = !self.partial.is_empty();
+        self.partial.extend_from_slice(data);
+        let mut pos = 0;
+        while pos < self.partial.len() {
+            let rest = &self.partial[pos..];
+            if rest.len() < HEADER_LEN && (pos == 0 || may_be_header(rest)) {
+                // Wait for the rest of the header.
+                if !continued || pos > 0 {
+                    ProtocolStats::count(&stats.truncated);
+                }
+                self.partial.drain(..pos);
+                return frames;
+            }
+            let declared = match Response::declared_len(rest) {
+                Ok(declared) => declared,
+                Err(e) if pos == 0 => {
+                    frames.push(Frame::Invalid(e));
+                    break;
+                }
+                Err(_) => {
+                    ProtocolStats::count(&stats.trailing_garbage);
+                    debug!(
+                        "Dropping {} bytes following a response: {:02x?}",
+                        rest.len(),
+                        &rest[..rest.len().min(HEADER_LEN)]
+                    );
+                    break;
+                }
+            };
+            if rest.len() < declared {
+                if !continued || pos > 0 {
+                    ProtocolStats::count(&stats.truncated);
+                }
+                self.partial.drain(..pos);
+                return frames;
+            }
+            if pos == 0 && continued {
+                ProtocolStats::count(&stats.reassembled);
+            }
+            if pos > 0 {
+                ProtocolStats::count(&stats.coalesced);
+            }
+            match Response::parse(&rest[..declared]) {
+                Ok(resp) => frames.push(Frame::Response(resp)),
+                Err(e) => frames.push(Frame::Invalid(e)),
+            }
+            pos += declared;
+        }
+        self.partial.clear();
+        frames
+    }
+
+    /// Give up on the response being reassembled, if any.
+    fn flush(&mut self) -> Option<Frame> {
+        if self.partial.is_empty() {
+            return None;
+        }
+        let data = std::mem::take(&mut self.partial);
+        Some(match Response::declared_len(&data) {
+            Ok(declared) => Frame::Truncated(
+                u16::from_le_bytes([data[0x0c], data[0x0d]]),
+                ParseError::Length {
+                    declared,
+                    received: data.len(),
+                },
+            ),
+            Err(e) => Frame::Invalid(e),
+        })
+    }
+}
+
+/// Whether `data`, shorter than a header, starts like one, its first magic
+/// bytes included.
+fn may_be_header(data: &[u8]) -> bool {
+    data.get(MAGIC1.range()) == Some(&MAGIC[..])
+}
+
+/// Commands waiting for their response, oldest first.
+struct Pending {
+    waiting: Vec<(u16, SyncSender<Reply>)>,
+    stats: Arc<ProtocolStats>,
+}
+
+impl Pending {
+    fn handle(&mut self, frame: Frame) {
+        match frame {
+            Frame::Response(resp) => self.deliver(resp),
+            Frame::Truncated(seq, e) => {
+                if !self.fail(seq, e.clone().into()) {
+                    ProtocolStats::count(&self.stats.dead_letters);
+                    warn!("Dropping unclaimed response, sequence {}: {}", seq, e);
+                }
+            }
+            Frame::Invalid(e) => {
+                if !self.fail_oldest(e.clone().into()) {
+                    ProtocolStats::count(&self.stats.dead_letters);
+                    warn!("Dropping invalid response: {}", e);
+                }
+            }
+        }
+    }
+
+    fn deliver(&mut self, resp: Response) {
+        match self.waiting.iter().position(|(seq, _)| *seq == resp.seq) {
+            Some(i) => {
+                let (_, tx) = self.waiting.remove(i);
+                let _ = tx.send(Ok(resp));
+            }
+            None => {
+                ProtocolStats::count(&self.stats.dead_letters);
+                warn!(
+                    "Dropping unclaimed response: opcode {:#06x}, sequence {}, {} bytes of payload",
+                    resp.opcode,
+                    resp.seq,
+                    resp.payload.len()
+                );
+            }
+        }
+    }
+
+    /// Fail the command with sequence number `seq`. Returns whether it was
+    /// waiting.
+    fn fail(&mut self, seq: u16, err: Error) -> bool {
+        match self.waiting.iter().position(|(s, _)| *s == seq) {
+            Some(i) => {
+                let (_, tx) = self.waiting.remove(i);
+                let _ = tx.send(Err(err));
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Hand a failure that cannot be tied to a sequence number to the oldest
+    /// command, which the device answers first. Returns whether a command was
+    /// waiting.
+    fn fail_oldest(&mut self, err: Error) -> bool {
+        if self.waiting.is_empty() {
+            return false;
+        }
+        let (_, tx) = self.waiting.remove(0);
+        let _ = tx.send(Err(err));
+        true
+    }
+}
+
+/// Owner of the response endpoint of a device.
+///
+/// The reader thread is started along with the first command.
+pub struct Dispatcher {
+    transport: Arc<dyn Transport>,
+    pending: Arc<Mutex<Pending>>,
+    stats: Arc<ProtocolStats>,
+    reader: Mutex<Option<PeriodicWorker>>,
+}
+
+impl Dispatcher {
+    /// Timeout of each read, bounding the time taken to stop the reader.
+    /// It is also how long the rest of a truncated response is waited for.
+    const READ_TIMEOUT: Duration = Duration::from_millis(200);
+
+    pub fn new(transport: Arc<dyn Transport>) -> Dispatcher {
+        let stats = Arc::new(ProtocolStats::default());
+        Dispatcher {
+            transport,
+            pending: Arc::new(Mutex::new(Pending {
+                waiting: Vec::new(),
+                stats: stats.clone(),
+            })),
+            stats,
+            reader: Mutex::new(None),
+        }
+    }
+
+    /// Register a command about to be sent, before sending it so that its
+    /// response cannot arrive unclaimed.
+    pub fn expect(&self, seq: u16) -> Receiver<Reply> {
+        self.start_reader();
+        let (tx, rx) = mpsc::sync_channel(1);
+        self.pending.lock().unwrap().waiting.push((seq, tx));
+        rx
+    }
+
+    /// Wait for the response registered with `expect`.
+    ///
+    /// On timeout the command is forgotten, its response being dropped if it
+    /// comes later.
+    pub fn wait(&self, seq: u16, rx: Receiver<Reply>, timeout: Duration) -> Reply {
+        match rx.recv_timeout(timeout) {
+            Ok(reply) => reply,
+            Err(RecvTimeoutError::Timeout) | Err(RecvTimeoutError::Disconnected) => {
+                self.cancel(seq);
+                // The response may have been delivered in the meantime.
+                rx.try_recv()
+                    .unwrap_or(Err(Error::Usb(rusb::Error::Timeout)))
+            }
+        }
+    }
+
+    /// Forget a registered command, for instance when it could not be sent.
+    pub fn cancel(&self, seq: u16) {
+        self.pending
+            .lock()
+            .unwrap()
+            .waiting
+            .retain(|(s, _)| *s != seq);
+    }
+
+    pub fn stats(&self) -> &ProtocolStats {
+        &self.stats
+    }
+
+    fn start_reader(&self) {
+        let mut reader = self.reader.lock().unwrap();
+        if reader.is_some() {
+            return;
+        }
+        let transport = self.transport.clone();
+        let pending = self.pending.clone();
+        let stats = self.stats.clone();
+        let mut framer = Framer::default();
+        // The reads pace the worker.
+        *reader = Some(PeriodicWorker::spawn(
+            "responses",
+            Duration::ZERO,
+            move || {
+                let mut buf = [0u8; 0x200];
+                match transport.read_response(&mut buf, Self::READ_TIMEOUT) {
+                    Ok(len) => {
+                        let frames = framer.feed(&buf[..len], &stats);
+                        let mut pending = pending.lock().unwrap();
+                        for frame in frames {
+                            pending.handle(frame);
+                        }
+                    }
+                    Err(rusb::Error::Timeout) => {
+                        if let Some(frame) = framer.flush() {
+                            pending.lock().unwrap().handle(frame);
+                        }
+                    }
+                    Err(e) => {
+                        if !pending.lock().unwrap().fail_oldest(e.into()) {
+                            // Nobody to report to, such as while the device
+                            // is gone: do not spin on the failure.
+                            std::thread::sleep(Self::READ_TIMEOUT);
+                        }
+                    }
+                }
+            },
+        ));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::command::{CommandFactory, Operation};
+
+    /// Responses of `payload_len` bytes of payload, numbered in sequence.
+    fn responses(count: usize, payload_len: usize) -> Vec<Vec<u8>> {
+        let mut factory = CommandFactory::new();
+        (0..count)
+            .map(|i| factory.make_command(0x0101, Operation::Get, &vec![i as u8; payload_len]))
+            .collect()
+    }
+
+    /// Sequence numbers of the responses among `frames`, `None` for the
+    /// other frames.
+    fn seqs(frames: &[Frame]) -> Vec<Option<u16>> {
+        frames
+            .iter()
+            .map(|frame| match frame {
+                Frame::Response(resp) => Some(resp.seq),
+                _ => None,
+            })
+            .collect()
+    }
+
+    fn seq(data: &[u8]) -> u16 {
+        u16::from_le_bytes([data[0x0c], data[0x0d]])
+    }
+
+    fn get(counter: &AtomicU64) -> u64 {
+        counter.load(Ordering::Relaxed)
+    }
+
+    #[test]
+    fn exact_transfer() {
+        let stats = ProtocolStats::default();
+        let mut framer = Framer::default();
+        for resp in responses(3, 8) {
+            let frames = framer.feed(&resp, &stats);
+            assert_eq!(seqs(&frames), vec![Some(seq(&resp))]);
+            match &frames[0] {
+                Frame::Response(parsed) => assert_eq!(parsed.payload, resp[HEADER_LEN..]),
+                _ => unreachable!(),
+            }
+        }
+        assert!(framer.flush().is_none());
+        assert_eq!(stats.to_string(), ProtocolStats::default().to_string());
+    }
+
+    #[test]
+    fn short_transfer_never_completed() {
+        let stats = ProtocolStats::default();
+        let mut framer = Framer::default();
+        let resp = &responses(1, 8)[0];
+        assert!(framer.feed(&resp[..20], &stats).is_empty());
+        assert_eq!(get(&stats.truncated), 1);
+        match framer.flush() {
+            Some(Frame::Truncated(s, ParseError::Length { declared, received })) => {
+                assert_eq!((s, declared, received), (seq(resp), 24, 20));
+            }
+            _ => panic!("not a truncated frame"),
+        }
+        assert!(framer.flush().is_none());
+    }
+
+    #[test]
+    fn split_across_reads() {
+        let all = responses(3, 40);
+        let stats = ProtocolStats::default();
+        let mut framer = Framer::default();
+        // Cut in the header, in the payload, and in three pieces.
+        let cuts: [&[usize]; 3] = [&[6], &[30], &[HEADER_LEN, 20]];
+        for (resp, cuts) in all.iter().zip(cuts.iter()) {
+            let mut start = 0;
+            for &cut in cuts.iter() {
+                assert!(framer.feed(&resp[start..cut], &stats).is_empty());
+                start = cut;
+            }
+            let frames = framer.feed(&resp[start..], &stats);
+            assert_eq!(seqs(&frames), vec![Some(seq(resp))]);
+        }
+        assert_eq!(get(&stats.truncated), 3);
+        assert_eq!(get(&stats.reassembled), 3);
+        assert_eq!(get(&stats.coalesced), 0);
+    }
+
+    #[test]
+    fn long_transfer_with_two_responses() {
+        let all = responses(2, 4);
+        let stats = ProtocolStats::default();
+        let mut framer = Framer::default();
+        let frames = framer.feed(&all.concat(), &stats);
+        assert_eq!(seqs(&frames), vec![Some(seq(&all[0])), Some(seq(&all[1]))]);
+        assert_eq!(get(&stats.coalesced), 1);
+    }
+
+    #[test]
+    fn long_transfer_with_the_start_of_the_next_response() {
+        let all = responses(2, 12);
+        let stats = ProtocolStats::default();
+        let mut framer = Framer::default();
+        let mut data = all[0].clone();
+        data.extend_from_slice(&all[1][..10]);
+        let frames = framer.feed(&data, &stats);
+        assert_eq!(seqs(&frames), vec![Some(seq(&all[0]))]);
+        let frames = framer.feed(&all[1][10..], &stats);
+        assert_eq!(seqs(&frames), vec![Some(seq(&all[1]))]);
+        assert_eq!(get(&stats.truncated), 1);
+        assert_eq!(get(&stats.reassembled), 1);
+    }
+
+    #[test]
+    fn long_transfer_with_trailing_garbage() {
+        let resp = &responses(1, 4)[0];
+        let stats = ProtocolStats::default();
+        let mut framer = Framer::default();
+        // Shorter and longer than a header.
+        for len in [4, 20] {
+            let mut data = resp.clone();
+            data.extend_from_slice(&vec![0xaa; len]);
+            let frames = framer.feed(&data, &stats);
+            assert_eq!(seqs(&frames), vec![Some(seq(resp))]);
+            assert!(framer.flush().is_none());
+        }
+        assert_eq!(get(&stats.trailing_garbage), 2);
+    }
+
+    #[test]
+    fn new_response_abandons_the_truncated_one() {
+        let all = responses(2, 8);
+        let stats = ProtocolStats::default();
+        let mut framer = Framer::default();
+        assert!(framer.feed(&all[0][..20], &stats).is_empty());
+        let frames = framer.feed(&all[1], &stats);
+        assert_eq!(seqs(&frames), vec![None, Some(seq(&all[1]))]);
+        assert!(matches!(frames[0], Frame::Truncated(s, _) if s == seq(&all[0])));
+    }
+
+    #[test]
+    fn length_field_shorter_than_the_header() {
+        let mut resp = responses(1, 0).remove(0);
+        resp[0] = 4;
+        let stats = ProtocolStats::default();
+        let mut framer = Framer::default();
+        let frames = framer.feed(&resp, &stats);
+        assert!(matches!(
+            frames[..],
+            [Frame::Invalid(ParseError::Length { declared: 4, .. })]
+        ));
+    }
+}
@@ -0,0 +1,401 @@
+//! EDID presented on the HDMI input.
+//!
+//! Only the basics are decoded: identification, monitor name and the video
+//! modes advertised in the base block and in a CTA-861 extension.
+
+use std::fmt;
+
+pub const BLOCK_SIZE: usize = 128;
+const HEADER: [u8; 8] = [0x00, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0x00];
+const CTA_EXTENSION_TAG: u8 = 0x02;
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum EdidError {
+    /// The length is not a whole number of blocks.
+    Length(usize),
+    BadHeader,
+    /// The checksum of the given block is wrong.
+    Checksum(usize),
+}
+
+impl fmt::Display for EdidError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            EdidError::Length(len) => write!(f, "EDID length {} is not a multiple of 128", len),
+            EdidError::BadHeader => write!(f, "bad EDID header"),
+            EdidError::Checksum(block) => write!(f, "bad checksum in EDID block {}", block),
+        }
+    }
+}
+
+/// A video mode advertised by the EDID.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Mode {
+    pub width: u32,
+    pub height: u32,
+    pub refresh: u32,
+    pub interlaced: bool,
+}
+
+impl fmt::Display for Mode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{}x{}{}{}",
+            self.width,
+            self.height,
+            if self.interlaced { "i" } else { "p" },
+            self.refresh
+        )
+    }
+}
+
+pub struct Edid {
+    data: Vec<u8>,
+}
+
+impl Edid {
+    /// Check the structure and the checksums of an EDID.
+    pub fn parse(data: &[u8]) -> Result<Edid, EdidError> {
+        if data.is_empty() || !data.len().is_multiple_of(BLOCK_SIZE) {
+            return Err(EdidError::Length(data.len()));
+        }
+        if data[..8] != HEADER {
+            return Err(EdidError::BadHeader);
+        }
+        for (i, block) in data.chunks(BLOCK_SIZE).enumerate() {
+            if block.iter().fold(0u8, |sum, b| sum.wrapping_add(*b)) != 0 {
+                return Err(EdidError::Checksum(i));
+            }
+        }
+        Ok(Edid {
+            data: data.to_vec(),
+        })
+    }
+
+    pub fn data(&self) -> &[u8] {
+        &self.data
+    }
+
+    /// Size announced by the base block, extensions included.
+    pub fn announced_len(base: &[u8]) -> Option<usize> {
+        Some((usize::from(*base.get(126)?) + 1) * BLOCK_SIZE)
+    }
+
+    /// Three-letter PNP identifier of the manufacturer.
+    pub fn manufacturer(&self) -> String {
+        let id = u16::from_be_bytes([self.data[8], self.data[9]]);
+        [10, 5, 0]
+            .iter()
+            .map(|shift| (b'A' - 1 + ((id >> shift) & 0x1f) as u8) as char)
+            .collect()
+    }
+
+    pub fn product(&self) -> u16 {
+        u16::from_le_bytes([self.data[10], self.data[11]])
+    }
+
+    /// The 18-byte descriptors of the base block.
+    fn descriptors(&self) -> impl Iterator<Item = &[u8]> {
+        self.data[54..126].chunks(18)
+    }
+
+    pub fn monitor_name(&self) -> Option<String> {
+        self.descriptors()
+            .find(|d| d[..3] == [0, 0, 0] && d[3] == 0xfc)
+            .map(|d| {
+                String::from_utf8_lossy(&d[5..])
+                    .trim_end_matches(['\n', ' '])
+                    .to_string()
+            })
+    }
+
+    /// Modes advertised by the EDID, preferred first.
+    pub fn modes(&self) -> Vec<Mode> {
+        let mut modes = Vec::new();
+        for d in self.descriptors() {
+            if let Some(mode) = detailed_timing(d) {
+                modes.push(mode);
+            }
+        }
+        if let Some(block) = self.data.get(BLOCK_SIZE..2 * BLOCK_SIZE) {
+            if block[0] == CTA_EXTENSION_TAG {
+                modes.extend(cta_modes(block));
+            }
+        }
+        for st in self.data[38..54].chunks(2) {
+            if let Some(mode) = standard_timing(st) {
+                modes.push(mode);
+            }
+        }
+        for (i, mode) in ESTABLISHED_TIMINGS.iter().enumerate() {
+            if self.data[35 + i / 8] & (0x80 >> (i % 8)) != 0 {
+                if let Some(mode) = mode {
+                    modes.push(*mode);
+                }
+            }
+        }
+        let mut unique = Vec::new();
+        for mode in modes {
+            if !unique.contains(&mode) {
+                unique.push(mode);
+            }
+        }
+        unique
+    }
+}
+
+impl fmt::Display for Edid {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(
+            f,
+            "Manufacturer: {}, product {:#06x}",
+            self.manufacturer(),
+            self.product()
+        )?;
+        writeln!(
+            f,
+            "Monitor name: {}",
+            self.monitor_name().as_deref().unwrap_or("none")
+        )?;
+        writeln!(f, "Blocks: {}", self.data.len() / BLOCK_SIZE)?;
+        let modes: Vec<_> = self.modes().iter().map(|m| m.to_string()).collect();
+        writeln!(f, "Modes: {}", modes.join(", "))
+    }
+}
+
+fn detailed_timing(d: &[u8]) -> Option<Mode> {
+    let clock = u32::from(u16::from_le_bytes([d[0], d[1]])) * 10_000;
+    if clock == 0 {
+        return None;
+    }
+    let h_active = u32::from(d[2]) | u32::from(d[4] >> 4) << 8;
+    let h_blank = u32::from(d[3]) | u32::from(d[4] & 0x0f) << 8;
+    let v_active = u32::from(d[5]) | u32::from(d[7] >> 4) << 8;
+    let v_blank = u32::from(d[6]) | u32::from(d[7] & 0x0f) << 8;
+    let interlaced = d[17] & 0x80 != 0;
+    let total = (h_active + h_blank) * (v_active + v_blank);
+    if total == 0 {
+        return None;
+    }
+    let field_rate = (clock as f64 / total as f64).round() as u32;
+    Some(Mode {
+        width: h_active,
+        height: if interlaced { v_active * 2 } else { v_active },
+        refresh: field_rate,
+        interlaced,
+    })
+}
+
+fn standard_timing(st: &[u8]) -> Option<Mode> {
+    if st[0] == 0x01 && st[1] == 0x01 || st[0] == 0 {
+        return None;
+    }
+    let width = (u32::from(st[0]) + 31) * 8;
+    let height = match st[1] >> 6 {
+        0 => width * 10 / 16,
+        1 => width * 3 / 4,
+        2 => width * 4 / 5,
+        _ => width * 9 / 16,
+    };
+    Some(Mode {
+        width,
+        height,
+        refresh: u32::from(st[1] & 0x3f) + 60,
+        interlaced: false,
+    })
+}
+
+const fn mode(width: u32, height: u32, refresh: u32, interlaced: bool) -> Mode {
+    Mode {
+        width,
+        height,
+        refresh,
+        interlaced,
+    }
+}
+
+/// Established timings, in the bit order of bytes 35 to 37.
+const ESTABLISHED_TIMINGS: [Option<Mode>; 17] = [
+    Some(mode(720, 400, 70, false)),
+    Some(mode(720, 400, 88, false)),
+    Some(mode(640, 480, 60, false)),
+    Some(mode(640, 480, 67, false)),
+    Some(mode(640, 480, 72, false)),
+    Some(mode(640, 480, 75, false)),
+    Some(mode(800, 600, 56, false)),
+    Some(mode(800, 600, 60, false)),
+    Some(mode(800, 600, 72, false)),
+    Some(mode(800, 600, 75, false)),
+    Some(mode(832, 624, 75, false)),
+    Some(mode(1024, 768, 87, true)),
+    Some(mode(1024, 768, 60, false)),
+    Some(mode(1024, 768, 70, false)),
+    Some(mode(1024, 768, 75, false)),
+    Some(mode(1280, 1024, 75, false)),
+    Some(mode(1152, 870, 75, false)),
+];
+
+/// Video identification codes of the common CTA-861 modes.
+const VICS: &[(u8, Mode)] = &[
+    (1, mode(640, 480, 60, false)),
+    (2, mode(720, 480, 60, false)),
+    (3, mode(720, 480, 60, false)),
+    (4, mode(1280, 720, 60, false)),
+    (5, mode(1920, 1080, 60, true)),
+    (16, mode(1920, 1080, 60, false)),
+    (17, mode(720, 576, 50, false)),
+    (18, mode(720, 576, 50, false)),
+    (19, mode(1280, 720, 50, false)),
+    (20, mode(1920, 1080, 50, true)),
+    (31, mode(1920, 1080, 50, false)),
+    (32, mode(1920, 1080, 24, false)),
+    (33, mode(1920, 1080, 25, false)),
+    (34, mode(1920, 1080, 30, false)),
+];
+
+/// Modes of the short video descriptors and detailed timings of a CTA-861
+/// extension block.
+fn cta_modes(block: &[u8]) -> Vec<Mode> {
+    let mut modes = Vec::new();
+    let dtd_start = usize::from(block[2]);
+    let mut pos = 4;
+    while pos < dtd_start.min(BLOCK_SIZE - 1) {
+        let tag = block[pos] >> 5;
+        let len = usize::from(block[pos] & 0x1f);
+        // Video data block.
+        if tag == 2 {
+            for &svd in block.get(pos + 1..pos + 1 + len).unwrap_or_default() {
+                let vic = if (129..=192).contains(&svd) {
+                    svd & 0x7f
+                } else {
+                    svd
+                };
+                if let Some((_, mode)) = VICS.iter().find(|v| v.0 == vic) {
+                    modes.push(*mode);
+                }
+            }
+        }
+        pos += 1 + len;
+    }
+    if dtd_start >= 4 {
+        let mut pos = dtd_start;
+        while pos + 18 < BLOCK_SIZE {
+            if let Some(mode) = detailed_timing(&block[pos..pos + 18]) {
+                modes.push(mode);
+            }
+            pos += 18;
+        }
+    }
+    modes
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Fix the checksum byte of each block.
+    fn seal(data: &mut [u8]) {
+        for block in data.chunks_mut(BLOCK_SIZE) {
+            let sum = block[..BLOCK_SIZE - 1]
+                .iter()
+                .fold(0u8, |sum, b| sum.wrapping_add(*b));
+            block[BLOCK_SIZE - 1] = sum.wrapping_neg();
+        }
+    }
+
+    /// An EDID of a "DEL" monitor named "TEST", preferring 1080p60, with
+    /// a CTA-861 extension whose video data block lists `vics`.
+    fn sample(vics: &[u8]) -> Vec<u8> {
+        let mut data = vec![0u8; 2 * BLOCK_SIZE];
+        data[..8].copy_from_slice(&HEADER);
+        data[8..10].copy_from_slice(&0x10acu16.to_be_bytes());
+        data[10..12].copy_from_slice(&0xa0b1u16.to_le_bytes());
+        // 640x480p60 among the established timings.
+        data[35] = 0x20;
+        for st in data[38..54].chunks_mut(2) {
+            st.copy_from_slice(&[0x01, 0x01]);
+        }
+        // 1920x1080p60 at 148.5 MHz, with 280 and 45 blanking.
+        data[54..72].copy_from_slice(&[
+            0x02, 0x3a, 0x80, 0x18, 0x71, 0x38, 0x2d, 0x40, 0x58, 0x2c, 0x45, 0x00, 0, 0, 0, 0, 0,
+            0x1e,
+        ]);
+        data[72..77].copy_from_slice(&[0, 0, 0, 0xfc, 0]);
+        data[77..90].copy_from_slice(b"TEST\n        ");
+        data[126] = 1;
+        let cta = &mut data[BLOCK_SIZE..];
+        cta[..4].copy_from_slice(&[CTA_EXTENSION_TAG, 3, 5 + vics.len() as u8, 0]);
+        cta[4] = 2 << 5 | vics.len() as u8;
+        cta[5..5 + vics.len()].copy_from_slice(vics);
+        seal(&mut data);
+        data
+    }
+
+    fn p(width: u32, height: u32, refresh: u32) -> Mode {
+        mode(width, height, refresh, false)
+    }
+
+    #[test]
+    fn decodes_identification() {
+        let edid = Edid::parse(&sample(&[])).unwrap();
+        assert_eq!(edid.manufacturer(), "DEL");
+        assert_eq!(edid.product(), 0xa0b1);
+        assert_eq!(edid.monitor_name().as_deref(), Some("TEST"));
+        assert_eq!(Edid::announced_len(edid.data()), Some(2 * BLOCK_SIZE));
+    }
+
+    #[test]
+    fn decodes_manufacturer_letters() {
+        let mut data = sample(&[]);
+        // "AAA" and "ZZZ", the ends of the 5-bit letter codes.
+        for (id, name) in [(0x0421u16, "AAA"), (0x6b5a, "ZZZ")] {
+            data[8..10].copy_from_slice(&id.to_be_bytes());
+            seal(&mut data);
+            assert_eq!(Edid::parse(&data).unwrap().manufacturer(), name);
+        }
+    }
+
+    #[test]
+    fn lists_the_modes_preferred_first() {
+        let edid = Edid::parse(&sample(&[16, 4, 31])).unwrap();
+        assert_eq!(
+            edid.modes(),
+            vec![
+                p(1920, 1080, 60),
+                p(1280, 720, 60),
+                p(1920, 1080, 50),
+                p(640, 480, 60)
+            ]
+        );
+    }
+
+    #[test]
+    fn cta_video_data_block() {
+        // 0x90 is VIC 16 flagged as native, 200 and 99 are not known.
+        let edid = Edid::parse(&sample(&[0x90, 200, 5, 99, 17])).unwrap();
+        let modes = edid.modes();
+        assert_eq!(modes[1..3], [mode(1920, 1080, 60, true), p(720, 576, 50)]);
+        assert_eq!(modes.len(), 4);
+    }
+
+    #[test]
+    fn checks_the_checksums() {
+        let data = sample(&[16]);
+        let mut bad = data.clone();
+        bad[20] ^= 1;
+        assert_eq!(Edid::parse(&bad).err(), Some(EdidError::Checksum(0)));
+        let mut bad = data.clone();
+        bad[BLOCK_SIZE + 5] ^= 1;
+        assert_eq!(Edid::parse(&bad).err(), Some(EdidError::Checksum(1)));
+        let mut bad = data.clone();
+        bad[0] = 0xff;
+        seal(&mut bad);
+        assert_eq!(Edid::parse(&bad).err(), Some(EdidError::BadHeader));
+        assert_eq!(
+            Edid::parse(&data[..200]).err(),
+            Some(EdidError::Length(200))
+        );
+        assert_eq!(Edid::parse(&[]).err(), Some(EdidError::Length(0)));
+    }
+}
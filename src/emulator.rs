@@ -0,0 +1,642 @@
+//! Software emulation of an IT9910 device.
+//!
+//! The emulator answers the commands used by this program with canned
+//! payloads shaped like those of real devices, refuses to start the capture
+//! before the PC grabber is ready, and then produces a synthetic transport
+//! stream (PAT, PMT, an H.264 video PID carrying the PCR, and an audio PID)
+//! at the configured bitrate.
+
+use std::collections::{HashMap, VecDeque};
+use std::ops::Range;
+use std::sync::{Condvar, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::command::Operation;
+use crate::psi::{self, STREAM_TYPE_AAC_ADTS, STREAM_TYPE_H264};
+use crate::response::HEADER_LEN;
+use crate::transport::Transport;
+use crate::ts::{PACKET_SIZE, PCR_HZ, SYNC_BYTE};
+
+pub const PMT_PID: u16 = 0x0100;
+pub const VIDEO_PID: u16 = 0x0200;
+pub const AUDIO_PID: u16 = 0x0201;
+
+/// Frame rate of the emulated video.
+const FRAME_RATE: u64 = 25;
+/// Keyframe interval, in frames.
+const GOP_LENGTH: u64 = 25;
+/// Delay of the PTS over the PCR, in 90 kHz ticks.
+const PTS_DELAY: u64 = 9_000;
+
+const PROFILE: [u8; 0x10] = [
+    0x01, 0x00, 0x00, 0x00, 0x80, 0x07, 0x00, 0x00, 0x38, 0x04, 0x00, 0x00, 0x19, 0x00, 0x00, 0x00,
+];
+const FIRMWARE_VERSION: u32 = 0x0001_0203;
+/// An HDMI-only device: HDMI video and audio.
+const HW_GRABBER: [u8; 8] = [0x01, 0x00, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00];
+
+#[derive(Clone, Debug)]
+pub struct EmulatorConfig {
+    /// Bitrate of the stream, in bits per second.
+    pub bitrate: u64,
+    /// Number of PC grabber state polls answered as not ready after the
+    /// grabber was enabled.
+    pub grabber_delay: u32,
+}
+
+impl Default for EmulatorConfig {
+    fn default() -> Self {
+        EmulatorConfig {
+            bitrate: 4_000_000,
+            grabber_delay: 2,
+        }
+    }
+}
+
+/// Command side of the emulated device.
+#[derive(Default)]
+struct Control {
+    responses: VecDeque<rusb::Result<Vec<u8>>>,
+    grabber_enabled: bool,
+    grabber_polls: u32,
+    source: (u32, u32),
+    /// Values of the indexed parameters, by (opcode, index).
+    values: HashMap<(u16, u32), u32>,
+    /// PC grabber configuration entries, by index.
+    grabber_entries: HashMap<u32, Vec<u8>>,
+    streaming: Option<Instant>,
+    /// Error flags of the firmware status (word 2), cleared when the
+    /// encoder starts.
+    status_errors: u32,
+}
+
+impl Control {
+    fn grabber_ready(&self, config: &EmulatorConfig) -> bool {
+        self.grabber_enabled && self.grabber_polls > config.grabber_delay
+    }
+
+    fn value(&self, key: (u16, u32)) -> u32 {
+        self.values.get(&key).copied().unwrap_or(match key.0 {
+            0x0102 | 0x0104 => 100,
+            0x0202 => GOP_LENGTH as u32,
+            0x0203 => 80,
+            _ => 0,
+        })
+    }
+}
+
+pub struct Emulator {
+    config: EmulatorConfig,
+    created: Instant,
+    control: Mutex<Control>,
+    response_ready: Condvar,
+    stream: Mutex<TsGenerator>,
+    /// Number of commands received, by opcode, resets included.
+    received: Mutex<HashMap<u16, u32>>,
+    /// Commands answered with a NAK, by opcode, see `nak`.
+    naks: Mutex<HashMap<u16, Range<u32>>>,
+    /// Rejection status of the refused commands, by opcode.
+    rejections: Mutex<HashMap<u16, u32>>,
+}
+
+impl Emulator {
+    pub fn new(config: EmulatorConfig) -> Emulator {
+        let stream = TsGenerator::new(config.bitrate);
+        Emulator {
+            config,
+            created: Instant::now(),
+            control: Mutex::new(Control::default()),
+            response_ready: Condvar::new(),
+            stream: Mutex::new(stream),
+            received: Mutex::new(HashMap::new()),
+            naks: Mutex::new(HashMap::new()),
+            rejections: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Flag errors in the firmware status, as the encoder would on an
+    /// overload (bit 0) or a buffer overrun (bit 1), until it is started
+    /// again.
+    pub fn raise_status_errors(&self, flags: u32) {
+        self.control.lock().unwrap().status_errors |= flags;
+    }
+
+    /// Number of commands with `opcode` received since the emulator was
+    /// created.
+    pub fn received(&self, opcode: u16) -> u32 {
+        self.received
+            .lock()
+            .unwrap()
+            .get(&opcode)
+            .copied()
+            .unwrap_or(0)
+    }
+
+    /// Answer the commands with `opcode` whose number, counting from 0 for
+    /// the first one received, is in `commands`, with a NAK: the first word
+    /// of their payload is complemented instead of echoed.
+    pub fn nak(&self, opcode: u16, commands: Range<u32>) {
+        self.naks.lock().unwrap().insert(opcode, commands);
+    }
+
+    /// Refuse the commands with `opcode` from now on, echoing them with
+    /// `status` in the operation field as the firmware does.
+    pub fn reject(&self, opcode: u16, status: u32) {
+        self.rejections.lock().unwrap().insert(opcode, status);
+    }
+
+    /// Answer of the device to a command, `Err` when it stalls the
+    /// endpoint.
+    fn handle(&self, ctl: &mut Control, cmd: &[u8]) -> rusb::Result<Vec<u8>> {
+        if cmd.len() < HEADER_LEN || cmd[0x06..=0x07] != [0x10, 0x99] {
+            return Err(rusb::Error::Pipe);
+        }
+        let opcode = u16::from_le_bytes([cmd[0x04], cmd[0x05]]);
+        let operation = Operation::from(u32::from_le_bytes([
+            cmd[0x08], cmd[0x09], cmd[0x0a], cmd[0x0b],
+        ]));
+        let data = &cmd[HEADER_LEN..];
+        let word = |i: usize| {
+            data.get(i * 4..i * 4 + 4)
+                .map(|w| u32::from_le_bytes([w[0], w[1], w[2], w[3]]))
+                .unwrap_or(0)
+        };
+        let get = operation == Operation::Get;
+        let payload = match opcode {
+            // Settings are applied as they are set, there is nothing to
+            // commit.
+            _ if operation == Operation::Commit => Vec::new(),
+            0x0001 => {
+                *ctl = Control::default();
+                Vec::new()
+            }
+            0x0002 if get => words(&[if ctl.streaming.is_some() { 2 } else { 0 }]),
+            0x0002 => {
+                if word(0) == 2 {
+                    if !ctl.grabber_ready(&self.config) {
+                        return Err(rusb::Error::Pipe);
+                    }
+                    ctl.streaming = Some(Instant::now());
+                    ctl.status_errors = 0;
+                    self.stream.lock().unwrap().restart();
+                } else {
+                    ctl.streaming = None;
+                }
+                data.to_vec()
+            }
+            0x0003 => {
+                if !get {
+                    ctl.source = (word(0), word(1));
+                }
+                words(&[ctl.source.0, ctl.source.1])
+            }
+            0x0008 => {
+                let uptime = self.created.elapsed().as_secs() as u32;
+                words(&[FIRMWARE_VERSION, uptime, ctl.status_errors, 0, 1, 0, 0, 0])
+            }
+            0x000a => PROFILE.to_vec(),
+            0x0101..=0x0104 | 0x0202 | 0x0203 => {
+                let key = (opcode, word(0));
+                if !get {
+                    ctl.values.insert(key, word(1));
+                }
+                words(&[key.1, ctl.value(key)])
+            }
+            0xe001 => self.pc_grabber(ctl, get, data)?,
+            0xf001 => words(&[self.created.elapsed().as_millis() as u32]),
+            0xf002 => HW_GRABBER.to_vec(),
+            // Other commands are acknowledged by echoing their first word.
+            _ => data.get(..4).unwrap_or_default().to_vec(),
+        };
+        let mut resp = cmd[..HEADER_LEN].to_vec();
+        let len = (HEADER_LEN + payload.len()) as u16;
+        resp[0x00..=0x01].copy_from_slice(&len.to_le_bytes());
+        resp.extend_from_slice(&payload);
+        Ok(resp)
+    }
+
+    fn pc_grabber(&self, ctl: &mut Control, get: bool, data: &[u8]) -> rusb::Result<Vec<u8>> {
+        match data.first() {
+            // Small state command.
+            Some(0x01) => {
+                if get {
+                    if ctl.grabber_enabled {
+                        ctl.grabber_polls += 1;
+                    }
+                } else {
+                    ctl.grabber_enabled = data.get(8) == Some(&0x01);
+                    ctl.grabber_polls = 0;
+                }
+                let mut state = vec![0u8; 0x0c];
+                state[8] = ctl.grabber_ready(&self.config) as u8;
+                Ok(state)
+            }
+            // Configuration entries.
+            Some(0x08) if !ctl.grabber_ready(&self.config) => Err(rusb::Error::Pipe),
+            Some(0x08) => {
+                let index = data
+                    .get(0x0c..0x10)
+                    .map(|w| u32::from_le_bytes([w[0], w[1], w[2], w[3]]))
+                    .unwrap_or(0);
+                if get {
+                    ctl.grabber_entries
+                        .get(&index)
+                        .cloned()
+                        .ok_or(rusb::Error::Pipe)
+                } else {
+                    ctl.grabber_entries.insert(index, data.to_vec());
+                    Ok(Vec::new())
+                }
+            }
+            _ => Ok(Vec::new()),
+        }
+    }
+}
+
+impl Transport for Emulator {
+    fn reset(&self) -> rusb::Result<()> {
+        *self.control.lock().unwrap() = Control::default();
+        Ok(())
+    }
+
+    fn claim(&self) -> rusb::Result<()> {
+        Ok(())
+    }
+
+    fn write_command(&self, data: &[u8], _timeout: Duration) -> rusb::Result<usize> {
+        let mut nak = false;
+        let mut rejection = None;
+        if let Some(opcode) = data.get(0x04..0x06) {
+            let opcode = u16::from_le_bytes([opcode[0], opcode[1]]);
+            rejection = self.rejections.lock().unwrap().get(&opcode).copied();
+            let mut received = self.received.lock().unwrap();
+            let count = received.entry(opcode).or_insert(0);
+            nak = self
+                .naks
+                .lock()
+                .unwrap()
+                .get(&opcode)
+                .is_some_and(|naks| naks.contains(count));
+            *count += 1;
+        }
+        let mut ctl = self.control.lock().unwrap();
+        let mut resp = match rejection {
+            Some(status) if data.len() >= HEADER_LEN => {
+                let mut resp = data[..HEADER_LEN].to_vec();
+                resp[0x00..=0x01].copy_from_slice(&(HEADER_LEN as u16).to_le_bytes());
+                resp[0x08..0x0c].copy_from_slice(&status.to_le_bytes());
+                Ok(resp)
+            }
+            _ => self.handle(&mut ctl, data),
+        };
+        if let (true, Ok(resp)) = (nak, resp.as_mut()) {
+            for b in resp.iter_mut().skip(HEADER_LEN).take(4) {
+                *b = !*b;
+            }
+        }
+        ctl.responses.push_back(resp);
+        self.response_ready.notify_all();
+        Ok(data.len())
+    }
+
+    fn read_response(&self, buf: &mut [u8], timeout: Duration) -> rusb::Result<usize> {
+        let ctl = self.control.lock().unwrap();
+        let (mut ctl, _) = self
+            .response_ready
+            .wait_timeout_while(ctl, timeout, |c| c.responses.is_empty())
+            .unwrap();
+        let resp = ctl.responses.pop_front().ok_or(rusb::Error::Timeout)??;
+        let len = resp.len().min(buf.len());
+        buf[..len].copy_from_slice(&resp[..len]);
+        Ok(len)
+    }
+
+    fn read_stream(&self, buf: &mut [u8], timeout: Duration) -> rusb::Result<usize> {
+        let streaming = self.control.lock().unwrap().streaming;
+        let since = match streaming {
+            Some(since) => since,
+            None => {
+                thread::sleep(timeout);
+                return Err(rusb::Error::Timeout);
+            }
+        };
+        let next_due = self.stream.lock().unwrap().next_due();
+        let wait = next_due.saturating_sub(since.elapsed());
+        if wait > timeout {
+            thread::sleep(timeout);
+            return Err(rusb::Error::Timeout);
+        }
+        thread::sleep(wait);
+        let mut stream = self.stream.lock().unwrap();
+        let due = stream.due_packets(since.elapsed());
+        let count = (buf.len() / PACKET_SIZE).min(due as usize);
+        for pkt in buf.chunks_exact_mut(PACKET_SIZE).take(count) {
+            pkt.copy_from_slice(&stream.next_packet());
+        }
+        Ok(count * PACKET_SIZE)
+    }
+}
+
+fn words(values: &[u32]) -> Vec<u8> {
+    values.iter().flat_map(|w| w.to_le_bytes()).collect()
+}
+
+/// Generator of the emulated transport stream.
+///
+/// The packets are laid out frame by frame: the PSI every fifth frame, one
+/// audio packet, and the video filling the rest of the frame interval.
+pub struct TsGenerator {
+    bitrate: u64,
+    packets_per_frame: u64,
+    /// Number of packets generated.
+    count: u64,
+    frame: u64,
+    queue: VecDeque<[u8; PACKET_SIZE]>,
+    cc: [u8; 4],
+}
+
+impl TsGenerator {
+    pub fn new(bitrate: u64) -> TsGenerator {
+        let bitrate = bitrate.max(PACKET_SIZE as u64 * 8 * FRAME_RATE * 4);
+        TsGenerator {
+            bitrate,
+            packets_per_frame: bitrate / 8 / PACKET_SIZE as u64 / FRAME_RATE,
+            count: 0,
+            frame: 0,
+            queue: VecDeque::new(),
+            cc: [0; 4],
+        }
+    }
+
+    fn restart(&mut self) {
+        *self = TsGenerator::new(self.bitrate);
+    }
+
+    /// Time at which the next packet is due, from the start of the stream.
+    fn next_due(&self) -> Duration {
+        Duration::from_nanos(
+            ((u128::from(self.count) + 1) * PACKET_SIZE as u128 * 8 * 1_000_000_000
+                / u128::from(self.bitrate)) as u64,
+        )
+    }
+
+    /// Number of packets due at `elapsed` and not generated yet.
+    fn due_packets(&self, elapsed: Duration) -> u64 {
+        let total = elapsed.as_nanos() * u128::from(self.bitrate)
+            / (PACKET_SIZE as u128 * 8 * 1_000_000_000);
+        (total as u64).saturating_sub(self.count)
+    }
+
+    /// PCR of the packet with the given index.
+    fn pcr(&self, index: u64) -> u64 {
+        (u128::from(index) * PACKET_SIZE as u128 * 8 * u128::from(PCR_HZ)
+            / u128::from(self.bitrate)) as u64
+    }
+
+    pub fn next_packet(&mut self) -> [u8; PACKET_SIZE] {
+        if self.queue.is_empty() {
+            self.queue_frame();
+        }
+        self.count += 1;
+        self.queue.pop_front().unwrap()
+    }
+
+    fn queue_frame(&mut self) {
+        let first = self.count;
+        if self.frame.is_multiple_of(5) {
+            let pat = [
+                0x00,
+                0xb0,
+                0x0d,
+                0x00,
+                0x01,
+                0xc1,
+                0x00,
+                0x00,
+                0x00,
+                0x01,
+                0xe0 | (PMT_PID >> 8) as u8,
+                PMT_PID as u8,
+            ];
+            self.queue_section(0, &pat);
+            let mut pmt = vec![
+                0x02,
+                0xb0,
+                0x17,
+                0x00,
+                0x01,
+                0xc1,
+                0x00,
+                0x00,
+                0xe0 | (VIDEO_PID >> 8) as u8,
+                VIDEO_PID as u8,
+                0xf0,
+                0x00,
+            ];
+            for &(stream_type, pid) in &[
+                (STREAM_TYPE_H264, VIDEO_PID),
+                (STREAM_TYPE_AAC_ADTS, AUDIO_PID),
+            ] {
+                pmt.extend_from_slice(&[
+                    stream_type,
+                    0xe0 | (pid >> 8) as u8,
+                    pid as u8,
+                    0xf0,
+                    0x00,
+                ]);
+            }
+            self.queue_section(PMT_PID, &pmt);
+        }
+        let pcr = self.pcr(first);
+        let pts = pcr / 300 + PTS_DELAY;
+        let mut audio = pes_header(0xc0, pts);
+        audio.resize(PACKET_SIZE - 4, 0x00);
+        self.queue_payload(AUDIO_PID, true, None, &audio);
+
+        let mut es = pes_header(0xe0, pts);
+        es.extend_from_slice(&[0x00, 0x00, 0x00, 0x01, 0x09, 0xf0]);
+        if self.frame.is_multiple_of(GOP_LENGTH) {
+            for nal in &[sps(), pps(), vec![0x65, 0x88, 0x84]] {
+                es.extend_from_slice(&[0x00, 0x00, 0x00, 0x01]);
+                es.extend_from_slice(nal);
+            }
+        } else {
+            es.extend_from_slice(&[0x00, 0x00, 0x00, 0x01, 0x41, 0x9a]);
+        }
+        let used = self.queue.len() as u64;
+        let packets = self.packets_per_frame.saturating_sub(used).max(1);
+        // The first video packet carries the PCR in an 8-byte adaptation
+        // field, the rest of the access unit is filler.
+        let capacity = (packets * (PACKET_SIZE as u64 - 4) - 8) as usize;
+        es.resize(capacity.max(es.len()), 0xff);
+        let pcr = self.pcr(first + used);
+        let (head, tail) = es.split_at((PACKET_SIZE - 12).min(es.len()));
+        self.queue_payload(VIDEO_PID, true, Some(pcr), head);
+        for chunk in tail.chunks(PACKET_SIZE - 4) {
+            self.queue_payload(VIDEO_PID, false, None, chunk);
+        }
+        self.frame += 1;
+    }
+
+    fn queue_section(&mut self, pid: u16, section: &[u8]) {
+        let mut payload = vec![0x00];
+        payload.extend_from_slice(section);
+        payload.extend_from_slice(&psi::crc32(section).to_be_bytes());
+        payload.resize(PACKET_SIZE - 4, 0xff);
+        self.queue_payload(pid, true, None, &payload);
+    }
+
+    /// Queue a packet, padding `payload` with an adaptation field if short.
+    fn queue_payload(&mut self, pid: u16, pusi: bool, pcr: Option<u64>, payload: &[u8]) {
+        let cc = match pid {
+            0 => &mut self.cc[0],
+            PMT_PID => &mut self.cc[1],
+            VIDEO_PID => &mut self.cc[2],
+            _ => &mut self.cc[3],
+        };
+        let mut pkt = [0xffu8; PACKET_SIZE];
+        pkt[0] = SYNC_BYTE;
+        pkt[1] = (pusi as u8) << 6 | (pid >> 8) as u8;
+        pkt[2] = pid as u8;
+        pkt[3] = 0x10 | *cc;
+        *cc = (*cc + 1) & 0x0f;
+        let af_len = PACKET_SIZE - 4 - payload.len();
+        let mut pos = 4;
+        if af_len > 0 || pcr.is_some() {
+            pkt[3] |= 0x20;
+            pkt[4] = (af_len - 1) as u8;
+            if af_len > 1 {
+                pkt[5] = 0x00;
+            }
+            if let Some(pcr) = pcr {
+                let base = pcr / 300;
+                let ext = pcr % 300;
+                pkt[5] = 0x10;
+                pkt[6] = (base >> 25) as u8;
+                pkt[7] = (base >> 17) as u8;
+                pkt[8] = (base >> 9) as u8;
+                pkt[9] = (base >> 1) as u8;
+                pkt[10] = (base << 7) as u8 | 0x7e | (ext >> 8) as u8;
+                pkt[11] = ext as u8;
+            }
+            pos += af_len;
+        }
+        pkt[pos..].copy_from_slice(payload);
+        self.queue.push_back(pkt);
+    }
+}
+
+/// PES header with a PTS, without length.
+fn pes_header(stream_id: u8, pts: u64) -> Vec<u8> {
+    let pts = pts % (1 << 33);
+    vec![
+        0x00,
+        0x00,
+        0x01,
+        stream_id,
+        0x00,
+        0x00,
+        0x80,
+        0x80,
+        0x05,
+        0x21 | ((pts >> 29) & 0x0e) as u8,
+        (pts >> 22) as u8,
+        0x01 | ((pts >> 14) & 0xfe) as u8,
+        (pts >> 7) as u8,
+        0x01 | ((pts << 1) & 0xfe) as u8,
+    ]
+}
+
+/// Writes the bits of a NAL unit payload.
+#[derive(Default)]
+struct BitWriter {
+    bytes: Vec<u8>,
+    bits: u32,
+}
+
+impl BitWriter {
+    fn bit(&mut self, b: bool) {
+        if self.bits.is_multiple_of(8) {
+            self.bytes.push(0);
+        }
+        if b {
+            *self.bytes.last_mut().unwrap() |= 0x80 >> (self.bits % 8);
+        }
+        self.bits += 1;
+    }
+
+    fn bits(&mut self, value: u32, n: u32) {
+        for i in (0..n).rev() {
+            self.bit(value >> i & 1 != 0);
+        }
+    }
+
+    /// Unsigned Exp-Golomb code.
+    fn ue(&mut self, value: u32) {
+        let v = value + 1;
+        let n = 32 - v.leading_zeros();
+        self.bits(0, n - 1);
+        self.bits(v, n);
+    }
+
+    /// RBSP trailing bits, and emulation prevention.
+    fn finish(mut self, header: u8) -> Vec<u8> {
+        self.bit(true);
+        let mut nal = vec![header];
+        let mut zeros = 0;
+        for b in self.bytes {
+            if zeros >= 2 && b <= 3 {
+                nal.push(0x03);
+                zeros = 0;
+            }
+            zeros = if b == 0 { zeros + 1 } else { 0 };
+            nal.push(b);
+        }
+        nal
+    }
+}
+
+/// Main profile SPS for 1920x1080 progressive.
+fn sps() -> Vec<u8> {
+    let mut w = BitWriter::default();
+    w.bits(77, 8); // profile_idc
+    w.bits(0x40, 8); // constraint flags
+    w.bits(40, 8); // level_idc
+    w.ue(0); // seq_parameter_set_id
+    w.ue(0); // log2_max_frame_num_minus4
+    w.ue(2); // pic_order_cnt_type
+    w.ue(1); // max_num_ref_frames
+    w.bit(false); // gaps_in_frame_num_value_allowed_flag
+    w.ue(1920 / 16 - 1);
+    w.ue(1088 / 16 - 1);
+    w.bit(true); // frame_mbs_only_flag
+    w.bit(true); // direct_8x8_inference_flag
+    w.bit(true); // frame_cropping_flag
+    w.ue(0);
+    w.ue(0);
+    w.ue(0);
+    w.ue(4); // 8 lines at the bottom
+    w.bit(false); // vui_parameters_present_flag
+    w.finish(0x67)
+}
+
+fn pps() -> Vec<u8> {
+    let mut w = BitWriter::default();
+    w.ue(0); // pic_parameter_set_id
+    w.ue(0); // seq_parameter_set_id
+    w.bit(false); // entropy_coding_mode_flag
+    w.bit(false); // bottom_field_pic_order_in_frame_present_flag
+    w.ue(0); // num_slice_groups_minus1
+    w.ue(0); // num_ref_idx_l0_default_active_minus1
+    w.ue(0); // num_ref_idx_l1_default_active_minus1
+    w.bit(false); // weighted_pred_flag
+    w.bits(0, 2); // weighted_bipred_idc
+    w.ue(0); // pic_init_qp_minus26 (se)
+    w.ue(0); // pic_init_qs_minus26 (se)
+    w.ue(0); // chroma_qp_index_offset (se)
+    w.bit(true); // deblocking_filter_control_present_flag
+    w.bit(false); // constrained_intra_pred_flag
+    w.bit(false); // redundant_pic_cnt_present_flag
+    w.finish(0x68)
+}
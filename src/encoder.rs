@@ -0,0 +1,95 @@
+//! Parameters of the H.264 encoder.
+//!
+//! Some values make the encoder emit a corrupt stream without reporting any
+//! error, so the parameters are checked against the limits of the firmware
+//! before being sent, and read back afterwards.
+
+use std::fmt;
+use std::ops::RangeInclusive;
+
+use crate::command::CommandFactory;
+
+/// Accepted values of the encoder parameters, for a firmware revision.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct EncoderLimits {
+    /// Keyframe interval, in frames.
+    pub keyframe_rate: RangeInclusive<u32>,
+    pub quality: RangeInclusive<u32>,
+}
+
+impl EncoderLimits {
+    /// Limits for unknown revisions, which have been working on all of
+    /// those tried so far.
+    pub const DEFAULT: EncoderLimits = EncoderLimits {
+        keyframe_rate: 1..=300,
+        quality: 1..=100,
+    };
+}
+
+/// How a firmware revision takes a change of an encoder parameter while
+/// the encoder runs.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum LiveChange {
+    /// The change takes effect right away.
+    Live,
+    /// The encoder has to be restarted for the change to take effect.
+    Restart,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum EncoderParam {
+    KeyframeRate,
+    Quality,
+}
+
+impl EncoderParam {
+    pub fn name(&self) -> &'static str {
+        match self {
+            EncoderParam::KeyframeRate => "keyframe rate",
+            EncoderParam::Quality => "quality",
+        }
+    }
+
+    pub fn range<'a>(&self, limits: &'a EncoderLimits) -> &'a RangeInclusive<u32> {
+        match self {
+            EncoderParam::KeyframeRate => &limits.keyframe_rate,
+            EncoderParam::Quality => &limits.quality,
+        }
+    }
+
+    pub fn check(&self, value: u32, limits: &EncoderLimits) -> Result<(), String> {
+        let range = self.range(limits);
+        if !range.contains(&value) {
+            return Err(format!(
+                "{} {} out of range, this firmware accepts {} to {}",
+                self.name(),
+                value,
+                range.start(),
+                range.end()
+            ));
+        }
+        Ok(())
+    }
+
+    pub fn make_set(&self, factory: &mut CommandFactory, stream: u32, value: u32) -> Vec<u8> {
+        match self {
+            EncoderParam::KeyframeRate => {
+                factory.make_set_video_compression_keyframe_rate(stream, value)
+            }
+            EncoderParam::Quality => factory.make_set_video_compression_quality(stream, value),
+        }
+    }
+
+    pub fn make_get(&self, factory: &mut CommandFactory, stream: u32) -> Vec<u8> {
+        match self {
+            EncoderParam::KeyframeRate => factory.make_get_video_compression_keyframe_rate(stream),
+            EncoderParam::Quality => factory.make_get_video_compression_quality(stream),
+        }
+    }
+}
+
+impl fmt::Display for EncoderParam {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.name())
+    }
+}
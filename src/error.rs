@@ -0,0 +1,100 @@
+use std::fmt;
+use std::path::PathBuf;
+
+use crate::edid::EdidError;
+use crate::response::ParseError;
+
+#[derive(Debug)]
+pub enum Error {
+    Io(std::io::Error),
+    Usb(rusb::Error),
+    Response(ParseError),
+    NoDevice,
+    SequenceMismatch {
+        expected: u16,
+        received: u16,
+    },
+    SettingsFile(String),
+    Config(String),
+    /// The device did not acknowledge a chunk of a blob transfer.
+    ChunkRejected {
+        offset: u32,
+        response: Vec<u8>,
+    },
+    /// The device answered a command with a rejection status.
+    DeviceRejected {
+        opcode: u16,
+        status: u32,
+        payload: Vec<u8>,
+    },
+    /// No keyframe was received within the given number of seconds.
+    NoKeyframe(u64),
+    Edid(EdidError),
+    /// An output file would replace an existing one.
+    OutputExists(PathBuf),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Io(e) => write!(f, "I/O error: {}", e),
+            Error::Usb(e) => write!(f, "USB error: {}", e),
+            Error::Response(e) => write!(f, "Invalid response: {}", e),
+            Error::NoDevice => write!(f, "No device found."),
+            Error::SequenceMismatch { expected, received } => write!(
+                f,
+                "Response sequence number {} does not match command {}",
+                received, expected
+            ),
+            Error::SettingsFile(msg) => write!(f, "Invalid settings file: {}", msg),
+            Error::ChunkRejected { offset, response } => write!(
+                f,
+                "Chunk at offset {:#x} rejected by the device: {:02x?}",
+                offset, response
+            ),
+            Error::DeviceRejected {
+                opcode,
+                status,
+                payload,
+            } => write!(
+                f,
+                "Command {:#06x} rejected by the device with status {:#x}: {:02x?}",
+                opcode, status, payload
+            ),
+            Error::Config(msg) => write!(f, "Invalid configuration: {}", msg),
+            Error::NoKeyframe(secs) => write!(f, "No keyframe received within {} s", secs),
+            Error::Edid(e) => write!(f, "Invalid EDID: {}", e),
+            Error::OutputExists(path) => write!(
+                f,
+                "{} already exists, use --force to overwrite it",
+                path.display()
+            ),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+impl std::convert::From<std::io::Error> for Error {
+    fn from(ioerr: std::io::Error) -> Self {
+        Error::Io(ioerr)
+    }
+}
+
+impl std::convert::From<rusb::Error> for Error {
+    fn from(err: rusb::Error) -> Self {
+        Error::Usb(err)
+    }
+}
+
+impl std::convert::From<ParseError> for Error {
+    fn from(err: ParseError) -> Self {
+        Error::Response(err)
+    }
+}
+
+impl std::convert::From<EdidError> for Error {
+    fn from(err: EdidError) -> Self {
+        Error::Edid(err)
+    }
+}
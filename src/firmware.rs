@@ -0,0 +1,203 @@
+//! Firmware revisions and their quirks.
+//!
+//! The first word of the firmware status response identifies the firmware
+//! build. Revisions known to need a different handling are listed in
+//! `KNOWN_FIRMWARE`, and the behaviour of the program is adjusted through
+//! their quirk flags and encoder limits.
+
+use std::fmt;
+
+use crate::device::Device;
+use crate::encoder::{EncoderLimits, EncoderParam, LiveChange};
+use crate::error::Error;
+use crate::grabber::{GrabberEntry, DEFAULT_ENTRIES};
+use crate::status::{FirmwareStatus, StatusError};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct FirmwareVersion(pub u32);
+
+impl FirmwareVersion {
+    /// Query the version of the firmware running on `device`.
+    pub fn query(device: &Device) -> Result<Option<FirmwareVersion>, Error> {
+        let resp = device.transact(&device.factory().make_get_firmware_status())?;
+        Ok(FirmwareStatus::parse(&resp)
+            .and_then(|status| status.words.first().copied())
+            .map(FirmwareVersion))
+    }
+
+    pub fn known(&self) -> Option<&'static KnownFirmware> {
+        KNOWN_FIRMWARE.iter().find(|fw| fw.version == self.0)
+    }
+}
+
+impl fmt::Display for FirmwareVersion {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let b = self.0.to_be_bytes();
+        write!(f, "{}.{}.{}.{} ({:#010x})", b[0], b[1], b[2], b[3], self.0)
+    }
+}
+
+/// Behaviour adjustments for a firmware revision.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Quirks {
+    /// The stream stops unless the host time is sent periodically, so the
+    /// heartbeat is enabled by default.
+    pub needs_heartbeat: bool,
+    /// The large 0xe001 blob is sent after starting the capture.
+    pub legacy_blob: bool,
+    /// Restarting the encoder needs the whole PC grabber initialization,
+    /// not only the start command.
+    pub full_restart: bool,
+    /// The PC grabber configuration entries only take effect once
+    /// committed.
+    pub commit_grabber: bool,
+}
+
+impl Quirks {
+    /// Behaviour for unknown revisions, which is that of the Windows driver.
+    pub const DEFAULT: Quirks = Quirks {
+        needs_heartbeat: false,
+        legacy_blob: true,
+        full_restart: true,
+        commit_grabber: false,
+    };
+
+    pub const NAMES: &'static [&'static str] =
+        &["heartbeat", "legacy-blob", "full-restart", "commit-grabber"];
+
+    fn flag(&mut self, name: &str) -> Option<&mut bool> {
+        match name {
+            "heartbeat" => Some(&mut self.needs_heartbeat),
+            "legacy-blob" => Some(&mut self.legacy_blob),
+            "full-restart" => Some(&mut self.full_restart),
+            "commit-grabber" => Some(&mut self.commit_grabber),
+            _ => None,
+        }
+    }
+
+    /// Apply overrides given as a comma separated list of quirk names,
+    /// each enabling the quirk, or disabling it when prefixed with `-`.
+    pub fn apply_overrides(&mut self, list: &str) -> Result<(), String> {
+        for item in list.split(',').map(str::trim).filter(|s| !s.is_empty()) {
+            let (name, value) = match item.strip_prefix('-') {
+                Some(name) => (name, false),
+                None => (item.strip_prefix('+').unwrap_or(item), true),
+            };
+            *self.flag(name).ok_or_else(|| {
+                format!(
+                    "unknown quirk `{}` (known: {})",
+                    name,
+                    Self::NAMES.join(", ")
+                )
+            })? = value;
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Display for Quirks {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut flags = *self;
+        let enabled: Vec<_> = Self::NAMES
+            .iter()
+            .filter(|name| flags.flag(name).is_some_and(|v| *v))
+            .copied()
+            .collect();
+        if enabled.is_empty() {
+            write!(f, "none")
+        } else {
+            write!(f, "{}", enabled.join(", "))
+        }
+    }
+}
+
+pub struct KnownFirmware {
+    pub version: u32,
+    pub name: &'static str,
+    pub quirks: Quirks,
+    pub limits: EncoderLimits,
+    /// PC grabber configuration entries sent at start.
+    pub grabber_entries: &'static [GrabberEntry],
+    /// How the encoder parameters can be changed during capture, for those
+    /// tried on this revision.
+    pub live_changes: &'static [(EncoderParam, LiveChange)],
+    /// Error states flagged in the firmware status.
+    pub status_errors: &'static [StatusError],
+}
+
+pub const KNOWN_FIRMWARE: &[KnownFirmware] = &[KnownFirmware {
+    version: 0x0001_0203,
+    name: "software emulator",
+    quirks: Quirks {
+        needs_heartbeat: false,
+        legacy_blob: false,
+        full_restart: false,
+        commit_grabber: false,
+    },
+    limits: EncoderLimits::DEFAULT,
+    grabber_entries: DEFAULT_ENTRIES,
+    live_changes: &[
+        (EncoderParam::Quality, LiveChange::Live),
+        (EncoderParam::KeyframeRate, LiveChange::Live),
+    ],
+    status_errors: &[
+        StatusError {
+            word: 2,
+            mask: 0x1,
+            name: "encoder overload",
+        },
+        StatusError {
+            word: 2,
+            mask: 0x2,
+            name: "buffer overrun",
+        },
+    ],
+}];
+
+/// Quirks of a firmware revision, `Quirks::DEFAULT` when unknown.
+pub fn quirks_for(version: Option<FirmwareVersion>) -> Quirks {
+    version
+        .and_then(|v| v.known())
+        .map(|fw| fw.quirks)
+        .unwrap_or(Quirks::DEFAULT)
+}
+
+/// Encoder limits of a firmware revision, `EncoderLimits::DEFAULT` when
+/// unknown.
+pub fn limits_for(version: Option<FirmwareVersion>) -> EncoderLimits {
+    version
+        .and_then(|v| v.known())
+        .map(|fw| fw.limits.clone())
+        .unwrap_or(EncoderLimits::DEFAULT)
+}
+
+/// PC grabber configuration entries of a firmware revision, those of the
+/// Windows driver when unknown.
+pub fn grabber_entries_for(version: Option<FirmwareVersion>) -> &'static [GrabberEntry] {
+    version
+        .and_then(|v| v.known())
+        .map(|fw| fw.grabber_entries)
+        .unwrap_or(DEFAULT_ENTRIES)
+}
+
+/// Error states flagged in the firmware status of a revision, none when
+/// unknown.
+pub fn status_errors_for(version: Option<FirmwareVersion>) -> &'static [StatusError] {
+    version
+        .and_then(|v| v.known())
+        .map(|fw| fw.status_errors)
+        .unwrap_or(&[])
+}
+
+/// How a firmware revision takes a change of `param` during capture,
+/// `None` when not known yet.
+pub fn live_change_for(
+    version: Option<FirmwareVersion>,
+    param: EncoderParam,
+) -> Option<LiveChange> {
+    let fw = version.and_then(|v| v.known())?;
+    fw.live_changes
+        .iter()
+        .find(|(p, _)| *p == param)
+        .map(|(_, live)| *live)
+}
@@ -0,0 +1,783 @@
+//! Repackaging of the transport stream into FLV tags, for RTMP.
+//!
+//! The H.264 access units and the AAC frames are taken out of their PES
+//! packets, and stamped in milliseconds from their DTS, relative to the
+//! first keyframe. Each stream starts with its sequence header, built from
+//! the SPS and PPS or from the ADTS header, which is sent again whenever
+//! the muxer restarts, as after a reconnection.
+//!
+//! After a restart or a split, where the encoder may have started over,
+//! timestamps going backwards are taken as a new stream: the tags go on
+//! from the next keyframe, with the sequence headers, their timestamps
+//! following those sent before.
+
+use std::fmt;
+
+use crate::h264::{self, NAL_AUD, NAL_IDR, NAL_PPS, NAL_SPS};
+use crate::pes::{self, TIMESTAMP_HZ, TIMESTAMP_MODULUS};
+use crate::psi::{self, PAT_PID, STREAM_TYPE_AAC_ADTS, STREAM_TYPE_H264};
+use crate::ts::{Aligner, Packet};
+
+pub const TAG_AUDIO: u8 = 8;
+pub const TAG_VIDEO: u8 = 9;
+
+/// FLV codec IDs.
+const CODEC_AVC: u8 = 7;
+const SOUND_FORMAT_AAC: u8 = 10;
+
+/// Samples in an AAC frame.
+const AAC_FRAME_SAMPLES: u64 = 1024;
+const AAC_SAMPLE_RATES: [u64; 13] = [
+    96000, 88200, 64000, 48000, 44100, 32000, 24000, 22050, 16000, 12000, 11025, 8000, 7350,
+];
+
+/// Body of an FLV audio or video tag, with its timestamp in milliseconds.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Tag {
+    pub kind: u8,
+    pub timestamp: u32,
+    pub data: Vec<u8>,
+}
+
+/// A stream which cannot be carried in FLV.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum FlvError {
+    UnsupportedVideo(u8),
+    UnsupportedAudio(u8),
+    /// A keyframe came without an SPS and a PPS having been seen.
+    NoParameterSets,
+    /// The decoding timestamps of the video did not increase, as happens
+    /// with B-frames when the PES packets only carry a PTS.
+    VideoTimestampsBackwards {
+        previous: u64,
+        dts: u64,
+    },
+    /// A picture is presented before being decoded.
+    PtsBeforeDts {
+        pts: u64,
+        dts: u64,
+    },
+}
+
+impl fmt::Display for FlvError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            FlvError::UnsupportedVideo(t) => write!(
+                f,
+                "video stream type {:#04x} cannot be sent over RTMP, only H.264 can",
+                t
+            ),
+            FlvError::UnsupportedAudio(t) => write!(
+                f,
+                "audio stream type {:#04x} cannot be sent over RTMP, only AAC (ADTS) can",
+                t
+            ),
+            FlvError::NoParameterSets => {
+                write!(f, "keyframe without SPS and PPS, no AVC sequence header")
+            }
+            FlvError::VideoTimestampsBackwards { previous, dts } => write!(
+                f,
+                "video decoding timestamps go backwards ({} then {}), \
+                 B-frames without DTS are not supported",
+                previous, dts
+            ),
+            FlvError::PtsBeforeDts { pts, dts } => {
+                write!(f, "video PTS {} before its DTS {}", pts, dts)
+            }
+        }
+    }
+}
+
+impl std::error::Error for FlvError {}
+
+/// A PES packet being reassembled.
+#[derive(Default)]
+struct Track {
+    pid: u16,
+    data: Vec<u8>,
+    pts: Option<u64>,
+    dts: Option<u64>,
+}
+
+impl Track {
+    fn new(pid: u16) -> Track {
+        Track {
+            pid,
+            ..Default::default()
+        }
+    }
+
+    /// Feed a packet of the PID, returning the previous PES packet when
+    /// this one starts a new one.
+    fn packet(&mut self, pkt: &Packet) -> Option<(Vec<u8>, Option<u64>, Option<u64>)> {
+        let payload = pkt.payload()?;
+        if !pkt.pusi() {
+            if self.pts.is_some() {
+                self.data.extend_from_slice(payload);
+            }
+            return None;
+        }
+        let done = self
+            .pts
+            .take()
+            .map(|pts| (std::mem::take(&mut self.data), Some(pts), self.dts.take()));
+        self.data.clear();
+        if let Some(header) = pes::parse_header(payload) {
+            if let Some(es) = payload.get(header.header_len..) {
+                self.data.extend_from_slice(es);
+                self.pts = header.pts;
+                self.dts = header.dts;
+            }
+        }
+        done
+    }
+}
+
+/// Fields of an ADTS header making the AAC AudioSpecificConfig.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+struct AacConfig {
+    object_type: u8,
+    rate_index: u8,
+    channels: u8,
+}
+
+impl AacConfig {
+    fn audio_specific_config(&self) -> [u8; 2] {
+        let asc = u16::from(self.object_type) << 11
+            | u16::from(self.rate_index) << 7
+            | u16::from(self.channels) << 3;
+        asc.to_be_bytes()
+    }
+}
+
+/// Parse the ADTS header at the start of `data`, returning the
+/// configuration, the header length and the frame length.
+fn parse_adts(data: &[u8]) -> Option<(AacConfig, usize, usize)> {
+    if data.len() < 7 || data[0] != 0xff || data[1] & 0xf6 != 0xf0 {
+        return None;
+    }
+    let header_len = if data[1] & 0x01 != 0 { 7 } else { 9 };
+    let config = AacConfig {
+        object_type: (data[2] >> 6) + 1,
+        rate_index: (data[2] >> 2) & 0x0f,
+        channels: (data[2] & 0x01) << 2 | data[3] >> 6,
+    };
+    let frame_len =
+        usize::from(data[3] & 0x03) << 11 | usize::from(data[4]) << 3 | usize::from(data[5]) >> 5;
+    if usize::from(config.rate_index) >= AAC_SAMPLE_RATES.len() || frame_len < header_len {
+        return None;
+    }
+    Some((config, header_len, frame_len))
+}
+
+/// NAL units of Annex B data, header byte included, without their start
+/// codes.
+fn nal_units(data: &[u8]) -> Vec<&[u8]> {
+    let starts: Vec<usize> = h264::nal_units(data).map(|(pos, _)| pos).collect();
+    starts
+        .iter()
+        .enumerate()
+        .map(|(i, &start)| {
+            let end = starts.get(i + 1).map_or(data.len(), |&next| next - 3);
+            let mut nal = &data[start..end];
+            // Trailing zeros belong to the next start code.
+            while let [rest @ .., 0] = nal {
+                nal = rest;
+            }
+            nal
+        })
+        .filter(|nal| !nal.is_empty())
+        .collect()
+}
+
+/// Turns the transport stream into FLV tags.
+#[derive(Default)]
+pub struct FlvMuxer {
+    aligner: Aligner,
+    pmt_pid: Option<u16>,
+    video: Option<Track>,
+    audio: Option<Track>,
+    sps: Option<Vec<u8>>,
+    pps: Option<Vec<u8>>,
+    /// The sequence headers sent since the last restart.
+    video_header: Option<(Vec<u8>, Vec<u8>)>,
+    audio_header: Option<AacConfig>,
+    /// No video is sent until a keyframe.
+    started: bool,
+    /// DTS of the first keyframe, time 0 of the tags.
+    base: Option<u64>,
+    last_dts: Option<u64>,
+    /// Timestamp of the last video tag.
+    last_timestamp: u32,
+    /// Set by a restart or a split until the next keyframe: the timestamps
+    /// may start over.
+    discontinuity: bool,
+}
+
+impl FlvMuxer {
+    pub fn new() -> FlvMuxer {
+        FlvMuxer::default()
+    }
+
+    /// Start again from the next keyframe, with the sequence headers.
+    pub fn restart(&mut self) {
+        self.video_header = None;
+        self.audio_header = None;
+        self.started = false;
+        self.discontinuity = true;
+    }
+
+    /// Note a split of the stream, after which the timestamps may start
+    /// over.
+    pub fn split(&mut self) {
+        self.discontinuity = true;
+    }
+
+    /// Go on from the stream whose timestamps started over at `dts`: from
+    /// its next keyframe, with the sequence headers, one millisecond after
+    /// the last video tag.
+    fn rebase(&mut self, dts: u64) {
+        let elapsed = (u64::from(self.last_timestamp) + 1) * TIMESTAMP_HZ / 1000;
+        self.base =
+            Some((dts + TIMESTAMP_MODULUS - elapsed % TIMESTAMP_MODULUS) % TIMESTAMP_MODULUS);
+        self.last_dts = None;
+        self.video_header = None;
+        self.audio_header = None;
+        self.started = false;
+    }
+
+    /// Feed stream data, returning the tags completed.
+    pub fn push(&mut self, data: &[u8]) -> Result<Vec<Tag>, FlvError> {
+        let mut packets = Vec::new();
+        self.aligner
+            .push(data, |_, pkt| packets.push(pkt.data().to_vec()));
+        let mut tags = Vec::new();
+        for data in packets {
+            let pkt = Packet::new(&data).expect("aligned packet");
+            self.packet(&pkt, &mut tags)?;
+        }
+        Ok(tags)
+    }
+
+    fn packet(&mut self, pkt: &Packet, tags: &mut Vec<Tag>) -> Result<(), FlvError> {
+        let pid = pkt.pid();
+        if pid == PAT_PID {
+            if let Some(programs) = psi::parse_pat(pkt) {
+                self.pmt_pid = programs.iter().find(|(p, _)| *p != 0).map(|(_, pid)| *pid);
+            }
+            return Ok(());
+        }
+        if Some(pid) == self.pmt_pid {
+            if let Some(pmt) = psi::parse_pmt(pkt) {
+                self.set_streams(&pmt)?;
+            }
+            return Ok(());
+        }
+        if let Some(track) = self.video.as_mut().filter(|t| t.pid == pid) {
+            if let Some((es, pts, dts)) = track.packet(pkt) {
+                self.video_frame(&es, pts, dts, tags)?;
+            }
+        } else if let Some(track) = self.audio.as_mut().filter(|t| t.pid == pid) {
+            if let Some((es, Some(pts), _)) = track.packet(pkt) {
+                self.audio_frames(&es, pts, tags);
+            }
+        }
+        Ok(())
+    }
+
+    fn set_streams(&mut self, pmt: &psi::Pmt) -> Result<(), FlvError> {
+        if let Some(es) = pmt.streams.iter().find(|es| es.is_video()) {
+            if es.stream_type != STREAM_TYPE_H264 {
+                return Err(FlvError::UnsupportedVideo(es.stream_type));
+            }
+            if self.video.as_ref().map(|t| t.pid) != Some(es.pid) {
+                self.video = Some(Track::new(es.pid));
+            }
+        }
+        if let Some(es) = pmt.streams.iter().find(|es| es.is_audio()) {
+            if es.stream_type != STREAM_TYPE_AAC_ADTS {
+                return Err(FlvError::UnsupportedAudio(es.stream_type));
+            }
+            if self.audio.as_ref().map(|t| t.pid) != Some(es.pid) {
+                self.audio = Some(Track::new(es.pid));
+            }
+        }
+        Ok(())
+    }
+
+    /// Milliseconds from the first keyframe to `ts`.
+    fn timestamp(&self, ts: u64) -> Option<u32> {
+        let delta = pes::timestamp_delta(self.base?, ts);
+        if delta < 0 {
+            return None;
+        }
+        Some((delta as u64 * 1000 / TIMESTAMP_HZ) as u32)
+    }
+
+    fn video_frame(
+        &mut self,
+        es: &[u8],
+        pts: Option<u64>,
+        dts: Option<u64>,
+        tags: &mut Vec<Tag>,
+    ) -> Result<(), FlvError> {
+        let pts = match pts {
+            Some(pts) => pts,
+            None => return Ok(()),
+        };
+        let dts = dts.unwrap_or(pts);
+        let mut keyframe = false;
+        let mut body = Vec::with_capacity(es.len() + 5);
+        for nal in nal_units(es) {
+            match nal[0] & 0x1f {
+                NAL_SPS if nal.len() >= 4 => self.sps = Some(nal.to_vec()),
+                NAL_PPS => self.pps = Some(nal.to_vec()),
+                NAL_SPS | NAL_AUD => (),
+                t => {
+                    keyframe |= t == NAL_IDR;
+                    body.extend_from_slice(&(nal.len() as u32).to_be_bytes());
+                    body.extend_from_slice(nal);
+                }
+            }
+        }
+        let backwards = self
+            .last_dts
+            .is_some_and(|previous| pes::timestamp_delta(previous, dts) <= 0);
+        if backwards && self.discontinuity {
+            self.rebase(dts);
+        }
+        if body.is_empty() || !(self.started || keyframe) {
+            return Ok(());
+        }
+        if let Some(previous) = self.last_dts {
+            if pes::timestamp_delta(previous, dts) <= 0 {
+                return Err(FlvError::VideoTimestampsBackwards { previous, dts });
+            }
+        }
+        let cts = pes::timestamp_delta(dts, pts);
+        if cts < 0 {
+            return Err(FlvError::PtsBeforeDts { pts, dts });
+        }
+        self.last_dts = Some(dts);
+        self.base.get_or_insert(dts);
+        let timestamp = self.timestamp(dts).unwrap_or(0);
+        if keyframe {
+            let sets = match (&self.sps, &self.pps) {
+                (Some(sps), Some(pps)) => (sps.clone(), pps.clone()),
+                _ => return Err(FlvError::NoParameterSets),
+            };
+            if self.video_header.as_ref() != Some(&sets) {
+                tags.push(Tag {
+                    kind: TAG_VIDEO,
+                    timestamp,
+                    data: avc_sequence_header(&sets.0, &sets.1),
+                });
+                self.video_header = Some(sets);
+            }
+            self.started = true;
+            self.discontinuity = false;
+        }
+        let cts = (cts as u64 * 1000 / TIMESTAMP_HZ) as u32;
+        let mut data = vec![
+            if keyframe { 0x10 } else { 0x20 } | CODEC_AVC,
+            1,
+            (cts >> 16) as u8,
+            (cts >> 8) as u8,
+            cts as u8,
+        ];
+        data.extend_from_slice(&body);
+        tags.push(Tag {
+            kind: TAG_VIDEO,
+            timestamp,
+            data,
+        });
+        self.last_timestamp = timestamp;
+        Ok(())
+    }
+
+    /// Split a PES packet in its ADTS frames. Audio before the first
+    /// keyframe is dropped, and so is data without ADTS header.
+    fn audio_frames(&mut self, es: &[u8], pts: u64, tags: &mut Vec<Tag>) {
+        if !self.started {
+            return;
+        }
+        let mut pos = 0;
+        let mut index = 0;
+        while let Some((config, header_len, frame_len)) = es.get(pos..).and_then(parse_adts) {
+            let frame = match es.get(pos + header_len..pos + frame_len) {
+                Some(frame) => frame,
+                None => break,
+            };
+            let rate = AAC_SAMPLE_RATES[usize::from(config.rate_index)];
+            let ts = pts + index * AAC_FRAME_SAMPLES * TIMESTAMP_HZ / rate;
+            if let Some(timestamp) = self.timestamp(ts) {
+                let flags = SOUND_FORMAT_AAC << 4 | 0x0f;
+                if self.audio_header != Some(config) {
+                    let mut data = vec![flags, 0];
+                    data.extend_from_slice(&config.audio_specific_config());
+                    tags.push(Tag {
+                        kind: TAG_AUDIO,
+                        timestamp,
+                        data,
+                    });
+                    self.audio_header = Some(config);
+                }
+                let mut data = vec![flags, 1];
+                data.extend_from_slice(frame);
+                tags.push(Tag {
+                    kind: TAG_AUDIO,
+                    timestamp,
+                    data,
+                });
+            }
+            pos += frame_len;
+            index += 1;
+        }
+    }
+}
+
+/// AVC sequence header: the AVCDecoderConfigurationRecord of the SPS and
+/// PPS.
+fn avc_sequence_header(sps: &[u8], pps: &[u8]) -> Vec<u8> {
+    let mut data = vec![0x10 | CODEC_AVC, 0, 0, 0, 0];
+    data.extend_from_slice(&[1, sps[1], sps[2], sps[3], 0xff, 0xe1]);
+    data.extend_from_slice(&(sps.len() as u16).to_be_bytes());
+    data.extend_from_slice(sps);
+    data.push(1);
+    data.extend_from_slice(&(pps.len() as u16).to_be_bytes());
+    data.extend_from_slice(pps);
+    data
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ts::{PACKET_SIZE, SYNC_BYTE};
+    use std::collections::HashMap;
+
+    const PMT_PID: u16 = 0x100;
+    const VIDEO_PID: u16 = 0x200;
+    const AUDIO_PID: u16 = 0x201;
+
+    const SPS: [u8; 5] = [0x67, 0x64, 0x00, 0x1f, 0xac];
+    const PPS: [u8; 4] = [0x68, 0xee, 0x3c, 0x80];
+    const IDR: [u8; 3] = [0x65, 0x88, 0x84];
+    const INTER: [u8; 2] = [0x41, 0x9a];
+
+    /// A transport stream with a PAT, a PMT of H.264 and AAC, and PES
+    /// packets.
+    #[derive(Default)]
+    struct Stream {
+        data: Vec<u8>,
+        cc: HashMap<u16, u8>,
+    }
+
+    impl Stream {
+        fn new() -> Stream {
+            let mut stream = Stream::default();
+            stream.tables();
+            stream
+        }
+
+        /// Packets of `payload`, the last one padded with an adaptation
+        /// field.
+        fn packets(&mut self, pid: u16, payload: &[u8]) {
+            for (i, chunk) in payload.chunks(PACKET_SIZE - 4).enumerate() {
+                let cc = self.cc.entry(pid).or_insert(0);
+                let mut pkt = vec![SYNC_BYTE, (pid >> 8) as u8, pid as u8, 0x10 | *cc];
+                *cc = (*cc + 1) & 0x0f;
+                if i == 0 {
+                    pkt[1] |= 0x40;
+                }
+                let af_len = PACKET_SIZE - 4 - chunk.len();
+                if af_len > 0 {
+                    pkt[3] |= 0x20;
+                    pkt.push((af_len - 1) as u8);
+                    if af_len > 1 {
+                        pkt.push(0x00);
+                        pkt.resize(4 + af_len, 0xff);
+                    }
+                }
+                pkt.extend_from_slice(chunk);
+                self.data.extend_from_slice(&pkt);
+            }
+        }
+
+        fn section(&mut self, pid: u16, section: &[u8]) {
+            let mut payload = vec![0x00];
+            payload.extend_from_slice(section);
+            payload.extend_from_slice(&psi::crc32(section).to_be_bytes());
+            self.packets(pid, &payload);
+        }
+
+        fn tables(&mut self) {
+            self.section(
+                PAT_PID,
+                &[
+                    0x00, 0xb0, 0x0d, 0x00, 0x01, 0xc1, 0x00, 0x00, 0x00, 0x01, 0xe1, 0x00,
+                ],
+            );
+            self.section(
+                PMT_PID,
+                &[
+                    0x02,
+                    0xb0,
+                    0x17,
+                    0x00,
+                    0x01,
+                    0xc1,
+                    0x00,
+                    0x00,
+                    0xe2,
+                    0x00,
+                    0xf0,
+                    0x00, //
+                    STREAM_TYPE_H264,
+                    0xe2,
+                    0x00,
+                    0xf0,
+                    0x00, //
+                    STREAM_TYPE_AAC_ADTS,
+                    0xe2,
+                    0x01,
+                    0xf0,
+                    0x00,
+                ],
+            );
+        }
+
+        fn pes(&mut self, pid: u16, pts: u64, dts: Option<u64>, es: &[u8]) {
+            let stream_id = if pid == VIDEO_PID { 0xe0 } else { 0xc0 };
+            let mut payload = vec![0x00, 0x00, 0x01, stream_id, 0x00, 0x00, 0x80];
+            match dts {
+                Some(dts) => {
+                    payload.extend_from_slice(&[0xc0, 10]);
+                    payload.extend_from_slice(&timestamp(0x30, pts));
+                    payload.extend_from_slice(&timestamp(0x10, dts));
+                }
+                None => {
+                    payload.extend_from_slice(&[0x80, 5]);
+                    payload.extend_from_slice(&timestamp(0x20, pts));
+                }
+            }
+            payload.extend_from_slice(es);
+            self.packets(pid, &payload);
+        }
+
+        /// An access unit made of `nals`, decoded at `dts` and presented
+        /// one frame later.
+        fn video(&mut self, dts: u64, nals: &[&[u8]]) {
+            let mut es = vec![0x00, 0x00, 0x00, 0x01, 0x09, 0xf0];
+            for nal in nals {
+                es.extend_from_slice(&[0x00, 0x00, 0x00, 0x01]);
+                es.extend_from_slice(nal);
+            }
+            self.pes(VIDEO_PID, dts + 3600, Some(dts), &es);
+        }
+    }
+
+    fn timestamp(prefix: u8, ts: u64) -> [u8; 5] {
+        [
+            prefix | ((ts >> 29) & 0x0e) as u8 | 0x01,
+            (ts >> 22) as u8,
+            ((ts >> 14) & 0xfe) as u8 | 0x01,
+            (ts >> 7) as u8,
+            ((ts << 1) & 0xfe) as u8 | 0x01,
+        ]
+    }
+
+    /// An ADTS frame of AAC LC at 48 kHz in stereo, carrying `payload`.
+    fn adts(payload: &[u8]) -> Vec<u8> {
+        let len = 7 + payload.len();
+        let mut frame = vec![
+            0xff,
+            0xf1,
+            0x4c,
+            0x80 | (len >> 11) as u8,
+            (len >> 3) as u8,
+            (len << 5) as u8 | 0x1f,
+            0xfc,
+        ];
+        frame.extend_from_slice(payload);
+        frame
+    }
+
+    fn video_tag(timestamp: u32, keyframe: bool, nal: &[u8]) -> Tag {
+        // Presented one frame, 40 ms, after being decoded.
+        let mut data = vec![if keyframe { 0x17 } else { 0x27 }, 1, 0, 0, 40];
+        data.extend_from_slice(&(nal.len() as u32).to_be_bytes());
+        data.extend_from_slice(nal);
+        Tag {
+            kind: TAG_VIDEO,
+            timestamp,
+            data,
+        }
+    }
+
+    fn video_header(timestamp: u32) -> Tag {
+        Tag {
+            kind: TAG_VIDEO,
+            timestamp,
+            data: avc_sequence_header(&SPS, &PPS),
+        }
+    }
+
+    fn audio_tag(timestamp: u32, data: &[u8]) -> Tag {
+        Tag {
+            kind: TAG_AUDIO,
+            timestamp,
+            data: [&[0xaf][..], data].concat(),
+        }
+    }
+
+    #[test]
+    fn adts_header() {
+        let frame = adts(&[0x21; 10]);
+        let config = AacConfig {
+            object_type: 2,
+            rate_index: 3,
+            channels: 2,
+        };
+        assert_eq!(parse_adts(&frame), Some((config, 7, 17)));
+        assert_eq!(config.audio_specific_config(), [0x11, 0x90]);
+        // With a CRC.
+        let mut crc = frame.clone();
+        crc[1] = 0xf0;
+        assert_eq!(parse_adts(&crc), Some((config, 9, 17)));
+
+        assert_eq!(parse_adts(&frame[..6]), None);
+        let mut bad = frame.clone();
+        bad[1] = 0xe1;
+        assert_eq!(parse_adts(&bad), None);
+        // Reserved sample rate index.
+        let mut bad = frame.clone();
+        bad[2] = 0x40 | 13 << 2;
+        assert_eq!(parse_adts(&bad), None);
+        // Shorter than its header.
+        assert_eq!(parse_adts(&adts(&[])[..]).map(|f| f.2), Some(7));
+        let mut bad = frame;
+        bad[4] = 0;
+        bad[5] = 6 << 5 | 0x1f;
+        assert_eq!(parse_adts(&bad), None);
+    }
+
+    #[test]
+    fn nal_units_without_start_codes() {
+        let data = [
+            0x00, 0x00, 0x00, 0x01, 0x67, 0xaa, 0x00, 0x00, 0x01, 0x68, 0xbb, 0x00, 0x00, 0x00,
+            0x01, 0x65, 0xcc, 0x00,
+        ];
+        assert_eq!(
+            nal_units(&data),
+            [&[0x67, 0xaa][..], &[0x68, 0xbb], &[0x65, 0xcc]]
+        );
+        assert!(nal_units(&[0x00, 0x00, 0x01]).is_empty());
+        assert!(nal_units(&[0xff; 8]).is_empty());
+    }
+
+    #[test]
+    fn avc_decoder_configuration_record() {
+        let mut expected = vec![0x17, 0, 0, 0, 0, 1, 0x64, 0x00, 0x1f, 0xff, 0xe1, 0, 5];
+        expected.extend_from_slice(&SPS);
+        expected.extend_from_slice(&[1, 0, 4]);
+        expected.extend_from_slice(&PPS);
+        assert_eq!(avc_sequence_header(&SPS, &PPS), expected);
+    }
+
+    /// A keyframe, an audio PES of two frames and a picture, each sent
+    /// when the next PES packet of its PID starts.
+    fn first_run(stream: &mut Stream) {
+        stream.video(90_000, &[&SPS, &PPS, &IDR]);
+        stream.video(93_600, &[&INTER]);
+        stream.pes(
+            AUDIO_PID,
+            91_800,
+            None,
+            &[adts(&[1; 4]), adts(&[2; 4])].concat(),
+        );
+        stream.video(97_200, &[&INTER]);
+        stream.pes(AUDIO_PID, 97_800, None, &adts(&[3; 4]));
+    }
+
+    #[test]
+    fn push_makes_the_tags() {
+        let mut stream = Stream::new();
+        // Audio from before the first keyframe is dropped.
+        stream.pes(AUDIO_PID, 80_000, None, &adts(&[0; 4]));
+        first_run(&mut stream);
+        let mut muxer = FlvMuxer::new();
+        // Fed in pieces not aligned on packets.
+        let (head, tail) = stream.data.split_at(1000);
+        let mut tags = muxer.push(head).unwrap();
+        tags.extend(muxer.push(tail).unwrap());
+        assert_eq!(
+            tags,
+            [
+                video_header(0),
+                video_tag(0, true, &IDR),
+                video_tag(40, false, &INTER),
+                audio_tag(20, &[0, 0x11, 0x90]),
+                audio_tag(20, &[1, 1, 1, 1, 1]),
+                // 1024 samples later.
+                audio_tag(41, &[1, 2, 2, 2, 2]),
+            ]
+        );
+    }
+
+    #[test]
+    fn push_rebases_after_a_split() {
+        let mut stream = Stream::new();
+        first_run(&mut stream);
+        let mut muxer = FlvMuxer::new();
+        muxer.push(&stream.data).unwrap();
+
+        // The encoder starts over, with earlier timestamps.
+        let mut restarted = Stream::new();
+        restarted.video(9_000, &[&SPS, &PPS, &IDR]);
+        restarted.video(12_600, &[&INTER]);
+        restarted.video(16_200, &[&INTER]);
+        assert_eq!(
+            FlvMuxer::new().push(&[&stream.data[..], &restarted.data].concat()),
+            Err(FlvError::VideoTimestampsBackwards {
+                previous: 97_200,
+                dts: 9_000
+            })
+        );
+
+        muxer.split();
+        assert_eq!(
+            muxer.push(&restarted.data).unwrap(),
+            [
+                // The last picture of the first run.
+                video_tag(80, false, &INTER),
+                video_header(81),
+                video_tag(81, true, &IDR),
+                video_tag(121, false, &INTER),
+            ]
+        );
+    }
+
+    #[test]
+    fn unsupported_streams_are_refused() {
+        let mut stream = Stream::default();
+        stream.section(
+            PAT_PID,
+            &[
+                0x00, 0xb0, 0x0d, 0x00, 0x01, 0xc1, 0x00, 0x00, 0x00, 0x01, 0xe1, 0x00,
+            ],
+        );
+        stream.section(
+            PMT_PID,
+            &[
+                0x02, 0xb0, 0x12, 0x00, 0x01, 0xc1, 0x00, 0x00, 0xe2, 0x00, 0xf0, 0x00, //
+                0x02, 0xe2, 0x00, 0xf0, 0x00,
+            ],
+        );
+        // A third packet, for the stream to be aligned.
+        stream.section(PAT_PID, &[0x00, 0xb0, 0x09, 0x00, 0x01, 0xc1, 0x00, 0x00]);
+        assert_eq!(
+            FlvMuxer::new().push(&stream.data),
+            Err(FlvError::UnsupportedVideo(0x02))
+        );
+    }
+}
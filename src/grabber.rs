@@ -0,0 +1,200 @@
+//! Configuration of the PC grabber, the capture front-end of the encoder.
+
+use std::borrow::Cow;
+use std::fmt;
+
+use serde::{Deserialize, Serialize};
+
+use crate::device::Device;
+use crate::error::Error;
+use crate::status::InputSignal;
+
+/// Capture format programmed by the PC grabber configuration entries.
+///
+/// Only the fields identified in the entries are represented, the rest of
+/// the payload is sent as captured from the Windows driver.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct GrabberConfig {
+    pub width: u32,
+    pub height: u32,
+}
+
+impl GrabberConfig {
+    /// Offsets of the fields in the payload of a configuration entry.
+    pub(crate) const INDEX_OFFSET: usize = 0x0c;
+    pub(crate) const WIDTH_OFFSET: usize = 0x14;
+    pub(crate) const HEIGHT_OFFSET: usize = 0x18;
+    /// First byte of the configuration entries.
+    const ENTRY_TAG: u8 = 0x08;
+    pub const ENTRY_LEN: usize = 0x3c;
+
+    /// Parse the payload of a configuration entry, as sent or read back.
+    pub fn parse(entry: &[u8]) -> Option<GrabberConfig> {
+        if entry.len() != Self::ENTRY_LEN || entry[0] != Self::ENTRY_TAG {
+            return None;
+        }
+        let word = |offset: usize| {
+            u32::from_le_bytes([
+                entry[offset],
+                entry[offset + 1],
+                entry[offset + 2],
+                entry[offset + 3],
+            ])
+        };
+        Some(GrabberConfig {
+            width: word(Self::WIDTH_OFFSET),
+            height: word(Self::HEIGHT_OFFSET),
+        })
+    }
+
+    /// Read back configuration entry `index` from `device`.
+    ///
+    /// Returns `None` when the answer does not mirror an entry, which is the
+    /// case of firmware not supporting the readback.
+    pub fn read(device: &Device, index: u32) -> Result<Option<GrabberConfig>, Error> {
+        let resp = device.transact(&device.factory().make_get_pc_grabber(index))?;
+        Ok(GrabberConfig::parse(&resp.payload))
+    }
+
+    /// Configuration matching the format of the input, `None` without a
+    /// signal.
+    pub fn for_signal(signal: &InputSignal) -> Option<GrabberConfig> {
+        if !signal.locked || signal.width == 0 || signal.height == 0 {
+            return None;
+        }
+        Some(GrabberConfig {
+            width: signal.width,
+            height: signal.height,
+        })
+    }
+}
+
+impl Default for GrabberConfig {
+    /// The configuration sent by the Windows driver.
+    fn default() -> Self {
+        GrabberConfig {
+            width: 1920,
+            height: 1080,
+        }
+    }
+}
+
+impl fmt::Display for GrabberConfig {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}x{}", self.width, self.height)
+    }
+}
+
+/// Bytes replacing those of the payload of a configuration entry, from
+/// `offset` on.
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct PayloadOverride {
+    pub offset: usize,
+    pub bytes: Cow<'static, [u8]>,
+}
+
+/// A configuration entry sent when the PC grabber is programmed.
+///
+/// The entries look like slots of internal parameters, all sent with the
+/// same payload by the Windows driver but for their index. Firmware
+/// revisions using other slots or values are described by other tables.
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct GrabberEntry {
+    pub index: u32,
+    /// Changes to the payload, applied after the index and the format.
+    #[serde(default, skip_serializing_if = "<[_]>::is_empty")]
+    pub overrides: Cow<'static, [PayloadOverride]>,
+}
+
+impl GrabberEntry {
+    pub const fn new(index: u32) -> GrabberEntry {
+        GrabberEntry {
+            index,
+            overrides: Cow::Borrowed(&[]),
+        }
+    }
+
+    /// Refuse overrides out of the payload.
+    pub fn check(&self) -> Result<(), String> {
+        for o in self.overrides.iter() {
+            if o.offset + o.bytes.len() > GrabberConfig::ENTRY_LEN {
+                return Err(format!(
+                    "entry {}: override of {} bytes at {:#x} past the end of the {:#x} byte payload",
+                    self.index,
+                    o.bytes.len(),
+                    o.offset,
+                    GrabberConfig::ENTRY_LEN
+                ));
+            }
+        }
+        Ok(())
+    }
+
+    /// Apply the overrides to `payload`. Those out of the payload are
+    /// skipped, see `check`.
+    pub fn apply(&self, payload: &mut [u8]) {
+        for o in self.overrides.iter() {
+            if let Some(dest) = payload.get_mut(o.offset..o.offset + o.bytes.len()) {
+                dest.copy_from_slice(&o.bytes);
+            }
+        }
+    }
+}
+
+/// The entries sent by the Windows driver: indices 0 to 21, unchanged.
+pub const DEFAULT_ENTRIES: &[GrabberEntry] = &[
+    GrabberEntry::new(0),
+    GrabberEntry::new(1),
+    GrabberEntry::new(2),
+    GrabberEntry::new(3),
+    GrabberEntry::new(4),
+    GrabberEntry::new(5),
+    GrabberEntry::new(6),
+    GrabberEntry::new(7),
+    GrabberEntry::new(8),
+    GrabberEntry::new(9),
+    GrabberEntry::new(10),
+    GrabberEntry::new(11),
+    GrabberEntry::new(12),
+    GrabberEntry::new(13),
+    GrabberEntry::new(14),
+    GrabberEntry::new(15),
+    GrabberEntry::new(16),
+    GrabberEntry::new(17),
+    GrabberEntry::new(18),
+    GrabberEntry::new(19),
+    GrabberEntry::new(20),
+    GrabberEntry::new(21),
+];
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::command::{CommandFactory, Operation};
+    use crate::protocol::{self, HEADER_LEN, OPCODE, OPERATION};
+
+    /// Payload of the configuration entries as first sent by this program,
+    /// copied from a trace of the Windows driver, the index at 0x0c.
+    const TRACED_ENTRY: [u8; 0x3c] = [
+        0x08, 0x20, 0x38, 0x38, 0x00, 0x00, 0x00, 0x00, 0x05, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+        0x00, 0x0f, 0x00, 0x00, 0x00, 0x80, 0x07, 0x00, 0x00, 0x38, 0x04, 0x00, 0x00, 0x10, 0x27,
+        0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x1e, 0x00, 0x00, 0x00, 0x1e,
+        0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+    ];
+
+    #[test]
+    fn default_entries_match_the_trace() {
+        assert_eq!(DEFAULT_ENTRIES.len(), 22);
+        let mut factory = CommandFactory::new();
+        for (i, entry) in DEFAULT_ENTRIES.iter().enumerate() {
+            let cmd = factory.make_set_pc_grabber(entry, &GrabberConfig::default());
+            assert_eq!(cmd[OPCODE.range()], protocol::PC_GRABBER.to_le_bytes());
+            assert_eq!(cmd[OPERATION.range()], Operation::Set.value().to_le_bytes());
+            let mut expected = TRACED_ENTRY;
+            expected[0x0c..0x10].copy_from_slice(&(i as u32).to_le_bytes());
+            assert_eq!(cmd[HEADER_LEN..], expected[..], "entry {}", i);
+        }
+    }
+}
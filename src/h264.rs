@@ -0,0 +1,286 @@
+//! H.264 elementary stream inspection.
+
+use serde::Serialize;
+
+pub const NAL_SLICE: u8 = 1;
+pub const NAL_IDR: u8 = 5;
+pub const NAL_SEI: u8 = 6;
+pub const NAL_SPS: u8 = 7;
+pub const NAL_PPS: u8 = 8;
+pub const NAL_AUD: u8 = 9;
+
+/// NAL units found in Annex B data, as the offset of their header byte and
+/// their type.
+pub fn nal_units(data: &[u8]) -> impl Iterator<Item = (usize, u8)> + '_ {
+    let mut pos = 0;
+    std::iter::from_fn(move || {
+        while pos + 3 < data.len() {
+            if data[pos] == 0 && data[pos + 1] == 0 && data[pos + 2] == 1 {
+                let header = pos + 3;
+                pos = header + 1;
+                return Some((header, data[header] & 0x1f));
+            }
+            pos += 1;
+        }
+        None
+    })
+}
+
+/// Whether the start of an access unit belongs to a keyframe.
+///
+/// Only the data in the first TS packet of the PES packet is usually
+/// available, so an SPS is taken as announcing an IDR picture, which is how
+/// the IT9910 encoder lays out its keyframes.
+pub fn starts_keyframe(es: &[u8]) -> bool {
+    nal_units(es).any(|(_, t)| t == NAL_IDR || t == NAL_SPS)
+}
+
+/// Reads the bits of a NAL unit payload, with the emulation prevention
+/// bytes removed.
+struct BitReader {
+    data: Vec<u8>,
+    pos: usize,
+}
+
+impl BitReader {
+    fn new(nal: &[u8]) -> BitReader {
+        let mut data = Vec::with_capacity(nal.len());
+        let mut zeros = 0;
+        for &b in nal {
+            if zeros >= 2 && b == 0x03 {
+                zeros = 0;
+                continue;
+            }
+            zeros = if b == 0 { zeros + 1 } else { 0 };
+            data.push(b);
+        }
+        BitReader { data, pos: 0 }
+    }
+
+    fn bit(&mut self) -> Option<bool> {
+        let byte = self.data.get(self.pos / 8)?;
+        let b = byte & (0x80 >> (self.pos % 8)) != 0;
+        self.pos += 1;
+        Some(b)
+    }
+
+    fn bits(&mut self, n: u32) -> Option<u32> {
+        let mut v = 0;
+        for _ in 0..n {
+            v = v << 1 | self.bit()? as u32;
+        }
+        Some(v)
+    }
+
+    /// Unsigned Exp-Golomb code.
+    fn ue(&mut self) -> Option<u32> {
+        let mut zeros = 0;
+        while !self.bit()? {
+            zeros += 1;
+            if zeros > 31 {
+                return None;
+            }
+        }
+        Some((1u32 << zeros) - 1 + self.bits(zeros)?)
+    }
+
+    /// Signed Exp-Golomb code.
+    fn se(&mut self) -> Option<i32> {
+        let v = self.ue()?;
+        Some(if v % 2 == 1 {
+            (v / 2 + 1) as i32
+        } else {
+            -((v / 2) as i32)
+        })
+    }
+}
+
+/// The fields of a sequence parameter set describing the video format.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+pub struct Sps {
+    pub profile_idc: u8,
+    pub level_idc: u8,
+    pub width: u32,
+    pub height: u32,
+    pub interlaced: bool,
+}
+
+impl Sps {
+    pub fn profile_name(&self) -> &'static str {
+        match self.profile_idc {
+            66 => "Baseline",
+            77 => "Main",
+            88 => "Extended",
+            100 => "High",
+            110 => "High 10",
+            122 => "High 4:2:2",
+            244 => "High 4:4:4",
+            _ => "unknown",
+        }
+    }
+}
+
+impl std::fmt::Display for Sps {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{}x{}{}, H.264 {} profile, level {}.{}",
+            self.width,
+            self.height,
+            if self.interlaced { "i" } else { "p" },
+            self.profile_name(),
+            self.level_idc / 10,
+            self.level_idc % 10
+        )
+    }
+}
+
+/// Parse an SPS NAL unit, header byte included.
+pub fn parse_sps(nal: &[u8]) -> Option<Sps> {
+    if nal.first()? & 0x1f != NAL_SPS {
+        return None;
+    }
+    let mut r = BitReader::new(&nal[1..]);
+    let profile_idc = r.bits(8)? as u8;
+    r.bits(8)?; // constraint flags
+    let level_idc = r.bits(8)? as u8;
+    r.ue()?; // seq_parameter_set_id
+    let mut chroma_format_idc = 1;
+    let mut separate_colour_plane = false;
+    if matches!(
+        profile_idc,
+        100 | 110 | 122 | 244 | 44 | 83 | 86 | 118 | 128 | 138 | 139 | 134 | 135
+    ) {
+        chroma_format_idc = r.ue()?;
+        if chroma_format_idc == 3 {
+            separate_colour_plane = r.bit()?;
+        }
+        r.ue()?; // bit_depth_luma_minus8
+        r.ue()?; // bit_depth_chroma_minus8
+        r.bit()?; // qpprime_y_zero_transform_bypass_flag
+        if r.bit()? {
+            let lists = if chroma_format_idc == 3 { 12 } else { 8 };
+            for i in 0..lists {
+                if r.bit()? {
+                    skip_scaling_list(&mut r, if i < 6 { 16 } else { 64 })?;
+                }
+            }
+        }
+    }
+    r.ue()?; // log2_max_frame_num_minus4
+    match r.ue()? {
+        0 => {
+            r.ue()?; // log2_max_pic_order_cnt_lsb_minus4
+        }
+        1 => {
+            r.bit()?; // delta_pic_order_always_zero_flag
+            r.se()?; // offset_for_non_ref_pic
+            r.se()?; // offset_for_top_to_bottom_field
+            for _ in 0..r.ue()? {
+                r.se()?;
+            }
+        }
+        _ => (),
+    }
+    r.ue()?; // max_num_ref_frames
+    r.bit()?; // gaps_in_frame_num_value_allowed_flag
+    let width_mbs = r.ue()?.checked_add(1)?;
+    let height_map_units = r.ue()?.checked_add(1)?;
+    let frame_mbs_only = r.bit()?;
+    if !frame_mbs_only {
+        r.bit()?; // mb_adaptive_frame_field_flag
+    }
+    r.bit()?; // direct_8x8_inference_flag
+    let (mut crop_x, mut crop_y) = (0, 0);
+    if r.bit()? {
+        crop_x = r.ue()?.checked_add(r.ue()?)?;
+        crop_y = r.ue()?.checked_add(r.ue()?)?;
+    }
+    let field_factor = if frame_mbs_only { 1 } else { 2 };
+    let (unit_x, unit_y): (u32, u32) = if chroma_format_idc == 0 || separate_colour_plane {
+        (1, field_factor)
+    } else {
+        let sub_width = if chroma_format_idc == 3 { 1 } else { 2 };
+        let sub_height = if chroma_format_idc == 1 { 2 } else { 1 };
+        (sub_width, sub_height * field_factor)
+    };
+    // The fields are not bounded, a corrupt SPS could overflow.
+    let width = width_mbs
+        .checked_mul(16)?
+        .checked_sub(unit_x.checked_mul(crop_x)?)?;
+    let height = height_map_units
+        .checked_mul(16 * field_factor)?
+        .checked_sub(unit_y.checked_mul(crop_y)?)?;
+    Some(Sps {
+        profile_idc,
+        level_idc,
+        width,
+        height,
+        interlaced: !frame_mbs_only,
+    })
+}
+
+fn skip_scaling_list(r: &mut BitReader, size: usize) -> Option<()> {
+    let (mut last, mut next) = (8i32, 8i32);
+    for _ in 0..size {
+        if next != 0 {
+            next = last.wrapping_add(r.se()?).rem_euclid(256);
+        }
+        if next != 0 {
+            last = next;
+        }
+    }
+    Some(())
+}
+
+/// The SPS of the access unit starting in `es`, if any.
+pub fn find_sps(es: &[u8]) -> Option<Sps> {
+    let units: Vec<_> = nal_units(es).collect();
+    units.iter().enumerate().find_map(|(i, &(start, t))| {
+        if t != NAL_SPS {
+            return None;
+        }
+        let end = units.get(i + 1).map(|u| u.0 - 3).unwrap_or(es.len());
+        parse_sps(&es[start..end])
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Annex B data made of NAL units of `types`, with a dummy byte of
+    /// payload each.
+    fn annex_b(types: &[u8]) -> Vec<u8> {
+        types
+            .iter()
+            .flat_map(|&t| vec![0, 0, 0, 1, 0x60 | t, 0x88])
+            .collect()
+    }
+
+    #[test]
+    fn idr_starts_a_keyframe() {
+        assert!(starts_keyframe(&annex_b(&[NAL_AUD, NAL_IDR])));
+        assert!(starts_keyframe(&annex_b(&[
+            NAL_AUD, NAL_SPS, NAL_PPS, NAL_IDR
+        ])));
+    }
+
+    #[test]
+    fn sps_announces_a_keyframe() {
+        // The IDR slice of a keyframe may only be in a later TS packet.
+        assert!(starts_keyframe(&annex_b(&[NAL_AUD, NAL_SPS])));
+        assert!(starts_keyframe(&annex_b(&[NAL_SPS, NAL_PPS, NAL_SEI])));
+    }
+
+    #[test]
+    fn other_pictures_are_not_keyframes() {
+        assert!(!starts_keyframe(&annex_b(&[NAL_AUD, NAL_SLICE])));
+        assert!(!starts_keyframe(&annex_b(&[
+            NAL_AUD, NAL_PPS, NAL_SEI, NAL_SLICE
+        ])));
+        assert!(!starts_keyframe(&[]));
+        // A slice with the type of an IDR in its payload only.
+        assert!(!starts_keyframe(&[0, 0, 1, 0x41, 0x65, 0x67]));
+    }
+}
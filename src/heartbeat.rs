@@ -0,0 +1,125 @@
+//! Periodic time query (opcode 0xf001).
+//!
+//! The Windows driver sends the host time, in milliseconds, to the device at
+//! regular intervals during capture. Some firmware revisions stop streaming
+//! after a while without it.
+
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime};
+
+use crate::clock::ClockModel;
+use crate::device::Device;
+use crate::worker::PeriodicWorker;
+
+pub struct Heartbeat {
+    worker: PeriodicWorker,
+    sent: Arc<AtomicU32>,
+    failures: Arc<AtomicU32>,
+}
+
+impl Heartbeat {
+    /// Start sending time queries to the device every `interval`.
+    ///
+    /// Failures are logged and counted, the heartbeat carrying on with the
+    /// next query. The timestamps returned by the device are fed to `clock`.
+    pub fn start(device: Device, interval: Duration, clock: Arc<Mutex<ClockModel>>) -> Heartbeat {
+        let sent = Arc::new(AtomicU32::new(0));
+        let failures = Arc::new(AtomicU32::new(0));
+        let worker = {
+            let sent = sent.clone();
+            let failures = failures.clone();
+            let mut factory = device.factory();
+            let start = Instant::now();
+            PeriodicWorker::spawn("heartbeat", interval, move || {
+                // Milliseconds since the start, wrapping like the device
+                // counter does.
+                let ts = start.elapsed().as_millis() as u32;
+                sent.fetch_add(1, Ordering::Relaxed);
+                let before = SystemTime::now();
+                match device.transact(&factory.make_time_query(ts)) {
+                    Ok(resp) => {
+                        eprintln!("Remote timestamp: {:02x?}", resp.payload);
+                        if let Some(remote) = resp.word(0) {
+                            // Assume the device sampled its clock halfway
+                            // through the exchange.
+                            let rtt = before.elapsed().unwrap_or_default();
+                            clock
+                                .lock()
+                                .unwrap()
+                                .add_device_sample(remote, before + rtt / 2);
+                        }
+                    }
+                    Err(e) => {
+                        failures.fetch_add(1, Ordering::Relaxed);
+                        eprintln!("Heartbeat failed: {}", e);
+                    }
+                }
+            })
+        };
+        Heartbeat {
+            worker,
+            sent,
+            failures,
+        }
+    }
+
+    pub fn sent(&self) -> u32 {
+        self.sent.load(Ordering::Relaxed)
+    }
+
+    pub fn failures(&self) -> u32 {
+        self.failures.load(Ordering::Relaxed)
+    }
+
+    /// Stop the heartbeat thread and wait for it to exit.
+    pub fn stop(&mut self) {
+        self.worker.stop();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::emulator::{Emulator, EmulatorConfig};
+    use crate::protocol;
+
+    fn emulated() -> (Arc<Emulator>, Device) {
+        let emulator = Arc::new(Emulator::new(EmulatorConfig::default()));
+        let device = Device::with_transport(emulator.clone());
+        (emulator, device)
+    }
+
+    #[test]
+    fn sends_at_interval() {
+        let (emulator, device) = emulated();
+        let clock = Arc::new(Mutex::new(ClockModel::new()));
+        let mut heartbeat = Heartbeat::start(device, Duration::from_millis(50), clock);
+        std::thread::sleep(Duration::from_millis(275));
+        heartbeat.stop();
+        // One query right away, then one every 50 ms.
+        assert!(
+            (4..=7).contains(&heartbeat.sent()),
+            "{} queries sent",
+            heartbeat.sent()
+        );
+        assert_eq!(heartbeat.failures(), 0);
+        assert_eq!(emulator.received(protocol::TIME), heartbeat.sent());
+    }
+
+    #[test]
+    fn stops_on_drop() {
+        let (emulator, device) = emulated();
+        let clock = Arc::new(Mutex::new(ClockModel::new()));
+        let heartbeat = Heartbeat::start(device, Duration::from_secs(10), clock);
+        std::thread::sleep(Duration::from_millis(50));
+        // The drop does not wait for the next query to be due.
+        let dropped = Instant::now();
+        drop(heartbeat);
+        assert!(dropped.elapsed() < Duration::from_secs(1));
+        let sent = emulator.received(protocol::TIME);
+        assert_eq!(sent, 1);
+        std::thread::sleep(Duration::from_millis(100));
+        assert_eq!(emulator.received(protocol::TIME), sent);
+    }
+}
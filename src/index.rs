@@ -0,0 +1,376 @@
+//! Seek index of the recorded files.
+//!
+//! The index of `out.ts` is written next to it as `out.ts.idx`, so that a
+//! player or a cutting tool can seek in a long recording without bisecting
+//! it. The file starts with the 8 bytes `IT9910IX`, followed by records of
+//! three little-endian `u64`, in the order of the file:
+//!
+//! - the byte offset of a packet in the file;
+//! - the last PCR seen at that packet, in 27 MHz units and unwrapped, or
+//!   `u64::MAX` before the first PCR;
+//! - the PTS of the keyframe starting at that packet, in 90 kHz units as
+//!   found in the stream, or `u64::MAX` if the packet does not start a
+//!   keyframe.
+//!
+//! There is a record at every keyframe, and at the first PCR packet after
+//! each second of PCR without one. The index is flushed every second, so
+//! that a crash leaves a usable prefix; a partial last record is to be
+//! ignored.
+
+use std::convert::TryInto;
+use std::fs::{File, OpenOptions};
+use std::io::{self, BufWriter, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+
+use crate::clock::Unwrapper;
+use crate::h264;
+use crate::pes;
+use crate::psi::{self, PAT_PID, STREAM_TYPE_H264};
+use crate::ts::{Aligner, Packet, PCR_HZ, PCR_MODULUS};
+
+pub const MAGIC: &[u8; 8] = b"IT9910IX";
+pub const RECORD_SIZE: usize = 24;
+/// Value of the fields not known for a record.
+pub const NONE: u64 = u64::MAX;
+
+const FLUSH_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Path of the index of a file.
+pub fn index_path(path: &Path) -> PathBuf {
+    let mut index = path.as_os_str().to_owned();
+    index.push(".idx");
+    PathBuf::from(index)
+}
+
+/// Builds the index of a file from the data written to it.
+pub struct SeekIndex {
+    file: BufWriter<File>,
+    aligner: Aligner,
+    /// Offset in the file of the first byte given to the aligner.
+    base: u64,
+    pmt_pid: Option<u16>,
+    video_pid: Option<u16>,
+    pcr_pid: Option<u16>,
+    pcr: Unwrapper,
+    last_pcr: Option<u64>,
+    /// PCR of the last record.
+    last_record: Option<u64>,
+    flushed: Instant,
+}
+
+impl SeekIndex {
+    /// Write a new index to `path`, replacing any existing one.
+    pub fn create(path: &Path) -> io::Result<SeekIndex> {
+        let mut file = File::create(path)?;
+        file.write_all(MAGIC)?;
+        Ok(SeekIndex::new(file, 0))
+    }
+
+    /// Continue the index at `path` of a file continued from `offset`,
+    /// dropping the records beyond it. The PCR of the new data is unwrapped
+    /// on from the last one kept, so that it stays in the same range across
+    /// the seam. An index which is missing or not valid is started anew,
+    /// covering only the new data.
+    pub fn append(path: &Path, offset: u64) -> io::Result<SeekIndex> {
+        let mut file = match OpenOptions::new().read(true).write(true).open(path) {
+            Ok(file) => file,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Self::create(path),
+            Err(e) => return Err(e),
+        };
+        let mut data = Vec::new();
+        file.read_to_end(&mut data)?;
+        if !data.starts_with(MAGIC) {
+            return Self::create(path);
+        }
+        let field = |record: &[u8], i: usize| {
+            u64::from_le_bytes(record[i * 8..(i + 1) * 8].try_into().unwrap())
+        };
+        let kept: Vec<&[u8]> = data[MAGIC.len()..]
+            .chunks_exact(RECORD_SIZE)
+            .take_while(|record| field(record, 0) < offset)
+            .collect();
+        let len = (MAGIC.len() + kept.len() * RECORD_SIZE) as u64;
+        file.set_len(len)?;
+        file.seek(SeekFrom::Start(len))?;
+        let mut index = SeekIndex::new(file, offset);
+        if let Some(pcr) = kept
+            .iter()
+            .rev()
+            .map(|record| field(record, 1))
+            .find(|&pcr| pcr != NONE)
+        {
+            index.pcr = Unwrapper::resume(PCR_MODULUS, pcr);
+        }
+        Ok(index)
+    }
+
+    fn new(file: File, base: u64) -> SeekIndex {
+        SeekIndex {
+            file: BufWriter::new(file),
+            aligner: Aligner::new(),
+            base,
+            pmt_pid: None,
+            video_pid: None,
+            pcr_pid: None,
+            pcr: Unwrapper::new(PCR_MODULUS),
+            last_pcr: None,
+            last_record: None,
+            flushed: Instant::now(),
+        }
+    }
+
+    /// Take over what `previous`, the index of the previous segment, knew
+    /// of the stream, as a segment starts at a keyframe before the next
+    /// PAT and PMT.
+    pub fn follow(&mut self, previous: SeekIndex) {
+        self.pmt_pid = previous.pmt_pid;
+        self.video_pid = previous.video_pid;
+        self.pcr_pid = previous.pcr_pid;
+        self.pcr = previous.pcr;
+        self.last_pcr = previous.last_pcr;
+    }
+
+    /// Index data written to the file after the previous one.
+    pub fn push(&mut self, data: &[u8]) -> io::Result<()> {
+        let mut records = Vec::new();
+        let base = self.base;
+        let mut aligner = std::mem::take(&mut self.aligner);
+        aligner.push(data, |offset, pkt| {
+            if let Some(record) = self.packet(base + offset, &pkt) {
+                records.push(record);
+            }
+        });
+        self.aligner = aligner;
+        for record in records {
+            for value in &record {
+                self.file.write_all(&value.to_le_bytes())?;
+            }
+        }
+        if self.flushed.elapsed() >= FLUSH_INTERVAL {
+            self.file.flush()?;
+            self.flushed = Instant::now();
+        }
+        Ok(())
+    }
+
+    fn packet(&mut self, offset: u64, pkt: &Packet) -> Option<[u64; 3]> {
+        let pid = pkt.pid();
+        if pid == PAT_PID {
+            if let Some(programs) = psi::parse_pat(pkt) {
+                self.pmt_pid = programs.iter().find(|p| p.0 != 0).map(|p| p.1);
+            }
+            return None;
+        }
+        if Some(pid) == self.pmt_pid {
+            if let Some(pmt) = psi::parse_pmt(pkt) {
+                self.pcr_pid = Some(pmt.pcr_pid);
+                self.video_pid = pmt
+                    .streams
+                    .iter()
+                    .find(|es| es.stream_type == STREAM_TYPE_H264)
+                    .map(|es| es.pid);
+            }
+            return None;
+        }
+        let mut second = false;
+        if Some(pid) == self.pcr_pid {
+            if let Some(pcr) = pkt.pcr() {
+                let pcr = self.pcr.unwrap(pcr);
+                self.last_pcr = Some(pcr);
+                second = self.last_record.is_none_or(|last| pcr >= last + PCR_HZ);
+            }
+        }
+        let keyframe_pts = if Some(pid) == self.video_pid && pkt.pusi() {
+            pkt.payload().and_then(|payload| {
+                let header = pes::parse_header(payload)?;
+                let es = payload.get(header.header_len..)?;
+                header.pts.filter(|_| h264::starts_keyframe(es))
+            })
+        } else {
+            None
+        };
+        if keyframe_pts.is_none() && !second {
+            return None;
+        }
+        self.last_record = self.last_pcr;
+        Some([
+            offset,
+            self.last_pcr.unwrap_or(NONE),
+            keyframe_pts.unwrap_or(NONE),
+        ])
+    }
+
+    pub fn finish(&mut self) -> io::Result<()> {
+        self.file.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::emulator::{TsGenerator, VIDEO_PID};
+    use crate::ts::{PACKET_SIZE, SYNC_BYTE};
+
+    /// A stream of the PAT and PMT of the generator, which carry the PCR on
+    /// the video PID, followed by crafted video packets.
+    struct Stream {
+        data: Vec<u8>,
+        cc: u8,
+    }
+
+    impl Stream {
+        fn new() -> Stream {
+            let mut generator = TsGenerator::new(2_000_000);
+            let mut data = generator.next_packet().to_vec();
+            data.extend_from_slice(&generator.next_packet());
+            Stream { data, cc: 0 }
+        }
+
+        /// Offset of the next packet.
+        fn offset(&self) -> u64 {
+            self.data.len() as u64
+        }
+
+        fn packet(&mut self, pcr: Option<u64>, pes: Option<&[u8]>) {
+            let mut pkt = vec![SYNC_BYTE, (VIDEO_PID >> 8) as u8, VIDEO_PID as u8, self.cc];
+            self.cc = (self.cc + 1) & 0x0f;
+            let payload = pes.unwrap_or_default();
+            if pes.is_some() {
+                pkt[1] |= 0x40;
+                pkt[3] |= 0x10;
+            }
+            let af_len = PACKET_SIZE - 4 - payload.len();
+            pkt[3] |= 0x20;
+            pkt.push((af_len - 1) as u8);
+            match pcr {
+                Some(pcr) => {
+                    let base = pcr / 300;
+                    let ext = pcr % 300;
+                    pkt.extend_from_slice(&[
+                        0x10,
+                        (base >> 25) as u8,
+                        (base >> 17) as u8,
+                        (base >> 9) as u8,
+                        (base >> 1) as u8,
+                        ((base & 1) << 7) as u8 | 0x7e | (ext >> 8) as u8,
+                        ext as u8,
+                    ]);
+                }
+                None => pkt.push(0x00),
+            }
+            pkt.resize(4 + af_len, 0xff);
+            pkt.extend_from_slice(payload);
+            self.data.extend_from_slice(&pkt);
+        }
+
+        fn pcr(&mut self, pcr: u64) {
+            self.packet(Some(pcr), None);
+        }
+
+        /// The start of a video frame, with an IDR slice if `keyframe`.
+        fn frame(&mut self, pts: u64, keyframe: bool) {
+            let nal = if keyframe { 0x65 } else { 0x41 };
+            let mut pes = vec![0x00, 0x00, 0x01, 0xe0, 0x00, 0x00, 0x80, 0x80, 5];
+            pes.extend_from_slice(&[
+                0x21 | ((pts >> 29) & 0x0e) as u8,
+                (pts >> 22) as u8,
+                ((pts >> 14) & 0xfe) as u8 | 0x01,
+                (pts >> 7) as u8,
+                ((pts << 1) & 0xfe) as u8 | 0x01,
+            ]);
+            pes.extend_from_slice(&[0x00, 0x00, 0x00, 0x01, nal, 0x88, 0x84]);
+            self.packet(None, Some(&pes));
+        }
+    }
+
+    fn records(path: &Path) -> Vec<[u64; 3]> {
+        let data = std::fs::read(path).unwrap();
+        assert_eq!(&data[..MAGIC.len()], MAGIC);
+        data[MAGIC.len()..]
+            .chunks_exact(RECORD_SIZE)
+            .map(|record| {
+                let field =
+                    |i: usize| u64::from_le_bytes(record[i * 8..(i + 1) * 8].try_into().unwrap());
+                [field(0), field(1), field(2)]
+            })
+            .collect()
+    }
+
+    fn index_file(name: &str) -> PathBuf {
+        let dir =
+            std::env::temp_dir().join(format!("it9910-index-{}-{}", name, std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        dir.join("out.ts.idx")
+    }
+
+    #[test]
+    fn records_at_keyframes_and_every_second() {
+        let path = index_file("records");
+        let mut stream = Stream::new();
+        let mut expected = Vec::new();
+        expected.push([stream.offset(), NONE, 900]);
+        stream.frame(900, true);
+        expected.push([stream.offset(), 0, NONE]);
+        stream.pcr(0);
+        stream.frame(4500, false);
+        stream.pcr(PCR_HZ / 2);
+        stream.pcr(PCR_HZ - 1);
+        expected.push([stream.offset(), PCR_HZ, NONE]);
+        stream.pcr(PCR_HZ);
+        stream.pcr(PCR_HZ * 3 / 2);
+        expected.push([stream.offset(), PCR_HZ * 3 / 2, 180_000]);
+        stream.frame(180_000, true);
+        stream.pcr(PCR_HZ * 2);
+        expected.push([stream.offset(), PCR_HZ * 5 / 2, NONE]);
+        stream.pcr(PCR_HZ * 5 / 2);
+
+        let mut index = SeekIndex::create(&path).unwrap();
+        let (head, tail) = stream.data.split_at(1000);
+        index.push(head).unwrap();
+        index.push(tail).unwrap();
+        index.finish().unwrap();
+        assert_eq!(records(&path), expected);
+        std::fs::remove_dir_all(path.parent().unwrap()).unwrap();
+    }
+
+    #[test]
+    fn append_drops_the_records_from_the_offset() {
+        let path = index_file("append");
+        let mut stream = Stream::new();
+        stream.pcr(PCR_MODULUS - PCR_HZ);
+        stream.pcr(PCR_HZ / 2);
+        let seam = stream.offset();
+        stream.frame(900, true);
+        stream.pcr(PCR_HZ * 2);
+        let mut index = SeekIndex::create(&path).unwrap();
+        index.push(&stream.data).unwrap();
+        index.finish().unwrap();
+        assert_eq!(records(&path).len(), 4);
+
+        // The new data has a PCR a bit behind the last one kept, which is
+        // still after the wrap.
+        let mut resumed = Stream::new();
+        resumed.pcr(PCR_HZ / 4);
+        resumed.pcr(PCR_HZ * 5 / 4);
+        let mut index = SeekIndex::append(&path, seam).unwrap();
+        index.push(&resumed.data).unwrap();
+        index.finish().unwrap();
+        let tables = 2 * PACKET_SIZE as u64;
+        assert_eq!(
+            records(&path),
+            vec![
+                [tables, PCR_MODULUS - PCR_HZ, NONE],
+                [tables + PACKET_SIZE as u64, PCR_MODULUS + PCR_HZ / 2, NONE],
+                [seam + tables, PCR_MODULUS + PCR_HZ / 4, NONE],
+                [
+                    seam + tables + PACKET_SIZE as u64,
+                    PCR_MODULUS + PCR_HZ * 5 / 4,
+                    NONE
+                ],
+            ]
+        );
+        std::fs::remove_dir_all(path.parent().unwrap()).unwrap();
+    }
+}
@@ -0,0 +1,62 @@
+//! Control of IT9910 based USB capture devices.
+//!
+//! The device is driven through a bulk command channel: each command is
+//! written to endpoint 0x02 and answered on endpoint 0x81, while the
+//! encoded MPEG TS stream is read from endpoint 0x83.
+
+pub mod analysis;
+pub mod audio;
+pub mod clock;
+pub mod command;
+pub mod config;
+pub mod control;
+pub mod device;
+pub mod dispatch;
+pub mod edid;
+pub mod emulator;
+pub mod encoder;
+pub mod error;
+pub mod firmware;
+#[cfg(feature = "rtmp")]
+pub mod flv;
+pub mod grabber;
+pub mod h264;
+pub mod heartbeat;
+pub mod index;
+pub mod metadata;
+pub mod monitor;
+pub mod notify;
+pub mod picture;
+pub mod pipeline;
+pub mod pes;
+pub mod probe;
+pub mod profile;
+pub mod protocol;
+pub mod psi;
+#[cfg(feature = "python")]
+pub mod python;
+pub mod replay;
+pub mod report;
+pub mod response;
+#[cfg(feature = "rtmp")]
+pub mod rtmp;
+pub mod session;
+pub mod settings;
+pub mod sink;
+pub mod snapshot;
+pub mod sources;
+pub mod split;
+pub mod status;
+pub mod tcp;
+pub mod timing;
+pub mod transport;
+pub mod ts;
+pub mod upload;
+pub mod validate;
+pub mod watchdog;
+pub mod worker;
+
+pub use command::CommandFactory;
+pub use device::Device;
+pub use error::Error;
+pub use response::Response;
@@ -1,415 +1,2392 @@
-use std::convert::{TryFrom, TryInto};
-use std::io::Write;
+use std::fs::File;
+use std::io::{IsTerminal, Read};
+use std::path::{Path, PathBuf};
 use std::process::exit;
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::{Arc, Mutex};
-use std::thread;
-use std::time::Duration;
+use std::time::{Duration, Instant, SystemTime};
 
-#[derive(Debug)]
-enum Error {
-    Io(std::io::Error),
-    Usb(rusb::Error),
+use clap::{ArgAction, Args, Parser, Subcommand, ValueEnum};
+use log::{debug, warn};
+
+use it9910_stream_example::analysis::Event;
+use it9910_stream_example::clock::{format_utc, ClockModel};
+use it9910_stream_example::command::Operation;
+use it9910_stream_example::config::Config;
+use it9910_stream_example::control::ControlFile;
+use it9910_stream_example::edid::{self, Edid};
+use it9910_stream_example::emulator::{Emulator, EmulatorConfig};
+use it9910_stream_example::encoder::{EncoderLimits, LiveChange};
+use it9910_stream_example::firmware::{self, FirmwareVersion, Quirks};
+use it9910_stream_example::grabber::{GrabberConfig, GrabberEntry};
+use it9910_stream_example::heartbeat::Heartbeat;
+use it9910_stream_example::index::{self, SeekIndex};
+use it9910_stream_example::metadata::Metadata;
+use it9910_stream_example::monitor::{FirmwareMonitor, SignalChangePolicy, SignalMonitor};
+use it9910_stream_example::notify::NotificationListener;
+use it9910_stream_example::picture::Control;
+use it9910_stream_example::pipeline::Pipeline;
+use it9910_stream_example::probe::{self, Readiness};
+use it9910_stream_example::profile::{SourceProfile, StreamProfile};
+use it9910_stream_example::protocol;
+use it9910_stream_example::replay::{FileInput, InputFile};
+use it9910_stream_example::report::{Report, ReportBuilder};
+#[cfg(feature = "rtmp")]
+use it9910_stream_example::rtmp::{RtmpOutput, RtmpUrl};
+use it9910_stream_example::session::CaptureSession;
+use it9910_stream_example::sink::{
+    self, FanOut, FileOutput, LeftoverPolicy, OverflowPolicy, QueuedSink, StreamOutput,
+};
+use it9910_stream_example::sources::{Available, Capabilities, VIDEO_SOURCES};
+use it9910_stream_example::split::SplitReason;
+use it9910_stream_example::status::InputSignal;
+use it9910_stream_example::tcp::{TcpOptions, TcpOutput};
+use it9910_stream_example::timing::{DebugTiming, Stage, TimingReporter};
+use it9910_stream_example::upload::Uploader;
+use it9910_stream_example::validate::{self, Thresholds};
+use it9910_stream_example::watchdog::StallWatchdog;
+use it9910_stream_example::{settings, CommandFactory, Device, Error, Response};
+
+/// Acquire the MPEG TS stream from a IT9910 USB device and write it to
+/// stdout.
+#[derive(Parser)]
+#[command(version, about)]
+struct Cli {
+    /// Print debugging messages
+    #[arg(short, long, global = true)]
+    verbose: bool,
+    /// Override the firmware quirks: comma separated names, prefixed with
+    /// `-` to disable (heartbeat, legacy-blob, full-restart, commit-grabber)
+    #[arg(long, global = true, value_name = "LIST", allow_hyphen_values = true)]
+    quirks: Option<String>,
+    /// Read the configuration from FILE (TOML)
+    #[arg(long, global = true, value_name = "FILE")]
+    config: Option<PathBuf>,
+    /// Use a software emulation of the device instead of the hardware
+    #[arg(long, global = true)]
+    emulate: bool,
+    /// Bitrate of the emulated stream
+    #[arg(long, global = true, value_name = "KBPS", default_value_t = 4000)]
+    emulate_bitrate: u64,
+    /// Print the effective configuration, with the built-in source settings
+    /// and those of the capture, and exit
+    #[arg(long, global = true)]
+    dump_config: bool,
+    #[command(subcommand)]
+    command: Option<Command>,
+    #[command(flatten)]
+    capture: CaptureArgs,
+}
+
+#[derive(Args)]
+struct CaptureArgs {
+    /// Interval of the time queries sent during capture, 0 to disable.
+    /// Disabled by default, unless the firmware is known to need them.
+    #[arg(long, value_name = "SECONDS")]
+    heartbeat: Option<u64>,
+    /// Interval of the firmware status polls during capture, 0 to disable
+    #[arg(long, value_name = "SECONDS", default_value_t = 0)]
+    firmware_status: u64,
+    /// Restart the encoder when the firmware status polls report an error
+    /// state, splitting the file outputs
+    #[arg(long)]
+    recover_on_firmware_error: bool,
+    /// Interval of the input signal polls during capture, 0 to disable
+    #[arg(long, value_name = "SECONDS", default_value_t = 2)]
+    signal_poll: u64,
+    /// What to do when the input signal changes: continue, split the file
+    /// outputs, or restart the encoder for the new format
+    #[arg(long, value_name = "POLICY", default_value = "continue")]
+    on_signal_change: SignalChangePolicy,
+    /// Listen for asynchronous notifications on the event endpoint of the
+    /// device, and record them in the metadata
+    #[arg(long)]
+    notifications: bool,
+    /// Apply the encoder settings of the [stream0] and [stream1] sections of
+    /// FILE whenever it changes during capture
+    #[arg(long, value_name = "FILE", conflicts_with = "input_file")]
+    control: Option<PathBuf>,
+    /// Timeout of the stream reads. Reads are done in slices of at most one
+    /// second so that a lost device is still noticed quickly.
+    #[arg(long, value_name = "MS", default_value_t = 1000)]
+    stream_timeout_ms: u64,
+    /// Report a stalled stream every SECONDS without data
+    #[arg(long, value_name = "SECONDS", default_value_t = 5)]
+    stall_report: u64,
+    /// Stop the capture after N consecutive stream read timeouts
+    #[arg(long, value_name = "N")]
+    max_timeouts: Option<u32>,
+    /// Stop the capture when no data was received for SECONDS
+    #[arg(long, value_name = "SECONDS")]
+    max_idle: Option<u64>,
+    /// Print capture statistics every SECONDS, 0 to disable
+    #[arg(long, value_name = "SECONDS", default_value_t = 0)]
+    stats: u64,
+    /// Record the time each transfer spends in the stream read, the
+    /// pipeline, the queue and the write of each output, and log their
+    /// percentiles every 10 seconds
+    #[arg(long)]
+    debug_timing: bool,
+    /// With --debug-timing, also write every sample to FILE as CSV
+    #[arg(long, value_name = "FILE", requires = "debug_timing")]
+    debug_timing_csv: Option<PathBuf>,
+    /// Withhold the output until the stream is aligned and a valid PAT was
+    /// received, discarding the leftovers of the previous encoder run. Set
+    /// to false to write every byte received.
+    #[arg(long, value_name = "BOOL", default_value_t = true, action = ArgAction::Set)]
+    clean_start: bool,
+    /// Also withhold the video until the first keyframe, so that the
+    /// output can be decoded from its start. Ignored with --clean-start
+    /// false.
+    #[arg(long)]
+    start_at_keyframe: bool,
+    /// With --start-at-keyframe, keep the audio preceding the keyframe
+    /// instead of dropping it with the video
+    #[arg(long, requires = "start_at_keyframe")]
+    keep_preroll_audio: bool,
+    /// Measure the audio levels, show them in the statistics and warn about
+    /// sustained silence or clipping. Levels are only measured for MPEG
+    /// audio Layer II; for other codecs, the bitrate is shown.
+    #[arg(long)]
+    audio_meter: bool,
+    /// Leave the packets with the transport error indicator, which the
+    /// device knew to be corrupt, out of the outputs
+    #[arg(long)]
+    drop_tei: bool,
+    /// Rewrite the continuity counters of the outputs so that they follow
+    /// each other after lost or dropped packets, setting the discontinuity
+    /// indicator where data was lost
+    #[arg(long)]
+    fix_cc: bool,
+    /// Read the stream from a recorded FILE instead of the device, paced to
+    /// real time by its PCR with FILE:realtime. No device is used.
+    #[arg(long, value_name = "FILE[:realtime]", conflicts_with = "emulate")]
+    input_file: Option<InputFile>,
+    /// With --input-file, read the file again from its start at its end
+    #[arg(long, requires = "input_file")]
+    input_loop: bool,
+    /// Write the stream to FILE, `-` for stdout (the default). May be given
+    /// several times. In file names, `{n}` is replaced by the segment number
+    /// and `{time}` by the UTC time the segment started.
+    #[arg(short, long, value_name = "FILE")]
+    output: Vec<PathBuf>,
+    /// Push the stream to a TCP server, as tcp://HOST:PORT. May be given
+    /// several times. The connection is retried when lost, the capture
+    /// going on meanwhile
+    #[arg(long, value_name = "URL", value_parser = parse_tcp_url)]
+    connect: Vec<String>,
+    /// Publish the stream to an RTMP server, as rtmp://HOST[:PORT]/APP/KEY.
+    /// May be given several times. The H.264 video and AAC ADTS audio are
+    /// sent as FLV; other codecs, or video with B-frames, end the capture.
+    /// The connection is retried as with --connect
+    #[cfg(feature = "rtmp")]
+    #[arg(long, value_name = "URL", value_parser = RtmpUrl::parse)]
+    rtmp: Vec<RtmpUrl>,
+    /// Timeout of the TCP and RTMP connections and writes
+    #[arg(long, value_name = "SECONDS", default_value_t = 5.0)]
+    connect_timeout: f64,
+    /// Disable Nagle's algorithm on the TCP connections
+    #[arg(long)]
+    tcp_nodelay: bool,
+    /// End the capture when a TCP or RTMP server cannot be connected to after
+    /// --connect-attempts consecutive attempts
+    #[arg(long)]
+    connect_required: bool,
+    /// Consecutive failed connection attempts ending the capture, with
+    /// --connect-required
+    #[arg(
+        long,
+        value_name = "N",
+        default_value_t = 10,
+        requires = "connect_required"
+    )]
+    connect_attempts: u32,
+    /// Start a new segment of the file outputs every SECONDS, 0 to disable.
+    /// SIGHUP also starts a new segment.
+    #[arg(long, value_name = "SECONDS", default_value_t = 0)]
+    segment: u64,
+    /// How long a segment split waits for a keyframe before being forced
+    #[arg(long, value_name = "SECONDS", default_value_t = 2.0)]
+    split_window: f64,
+    /// Number of chunks queued for each output
+    #[arg(long, value_name = "N", default_value_t = 64)]
+    queue_size: usize,
+    /// What to do when an output queue is full: block or drop
+    #[arg(long, value_name = "POLICY", default_value = "block")]
+    overflow: OverflowPolicy,
+    /// Write each output file as NAME.part, renamed to NAME once complete
+    #[arg(long)]
+    atomic: bool,
+    /// Overwrite existing output files
+    #[arg(long)]
+    force: bool,
+    /// Continue existing output files, after trimming their last partial
+    /// packet
+    #[arg(long, conflicts_with = "atomic")]
+    append: bool,
+    /// With --append, mark the seam with discontinuity packets
+    #[arg(long, requires = "append")]
+    mark_seam: bool,
+    /// What to do with the .part files left by a crash: keep them, recover
+    /// them under a -recovered name, or resume the most recent one
+    #[arg(
+        long,
+        value_name = "ACTION",
+        default_value = "keep",
+        requires = "atomic"
+    )]
+    leftover_parts: LeftoverPolicy,
+    /// Write a seek index of each output file to NAME.idx, with the byte
+    /// offset of every keyframe and of every second of the stream
+    #[arg(long)]
+    index: bool,
+    /// Write the first complete keyframe (SPS, PPS and IDR, Annex B) to
+    /// FILE and stop the capture
+    #[arg(long, value_name = "FILE")]
+    snapshot: Option<PathBuf>,
+    /// Fail when no keyframe came within SECONDS of a snapshot request. With
+    /// --snapshot-on-signal, the request is dropped and the capture goes on
+    #[arg(long, value_name = "SECONDS", default_value_t = 10)]
+    snapshot_timeout: u64,
+    /// Keep capturing after the snapshot, and take a new one on SIGUSR1
+    #[arg(long, requires = "snapshot")]
+    snapshot_on_signal: bool,
+    /// Reboot the device when the stream fails, and resume the capture once
+    /// it is back. Exits with 4 if it does not come back.
+    #[arg(long)]
+    reboot_on_failure: bool,
+    /// Minimum time between two automatic reboots. A failure within that
+    /// time of the last reboot ends the capture.
+    #[arg(long, value_name = "SECONDS", default_value_t = 600)]
+    reboot_cooldown: u64,
+    /// Close and reopen the device when both its stream and command
+    /// endpoints keep failing while it is still attached, at most N times
+    /// per capture, 0 to disable
+    #[arg(long, value_name = "N", default_value_t = 3)]
+    max_reopens: u32,
+    /// Video input, by ID or name (see list-sources)
+    #[arg(long, value_name = "SOURCE")]
+    video_source: Option<String>,
+    /// Audio input, by ID or name (see list-sources)
+    #[arg(long, value_name = "SOURCE")]
+    audio_source: Option<String>,
+    /// Brightness of the picture, 0 to 100 %, 50 being the factory setting
+    #[arg(long, value_name = "PERCENT", value_parser = parse_brightness)]
+    brightness: Option<i32>,
+    /// Contrast of the picture, 0 to 100 %, 50 being the factory setting
+    #[arg(long, value_name = "PERCENT", value_parser = parse_contrast)]
+    contrast: Option<i32>,
+    /// Hue rotation of the picture, -180 to 180 degrees
+    #[arg(long, value_name = "DEGREES", allow_hyphen_values = true, value_parser = parse_hue)]
+    hue: Option<i32>,
+    /// Saturation of the picture, 0 to 100 %, 50 being the factory setting
+    #[arg(long, value_name = "PERCENT", value_parser = parse_saturation)]
+    saturation: Option<i32>,
+    /// Quality of the encoded video, within the limits of the firmware
+    /// (1 to 100 for most revisions)
+    #[arg(long, alias = "stream0-quality", value_name = "N")]
+    quality: Option<u32>,
+    /// Keyframe interval, in frames, within the limits of the firmware (1 to
+    /// 300 for most revisions)
+    #[arg(long, alias = "stream0-keyframe-rate", value_name = "FRAMES")]
+    keyframe_rate: Option<u32>,
+    /// Quality of the second stream, with dual-stream firmware
+    #[arg(long, value_name = "N")]
+    stream1_quality: Option<u32>,
+    /// Keyframe interval of the second stream, with dual-stream firmware
+    #[arg(long, value_name = "FRAMES")]
+    stream1_keyframe_rate: Option<u32>,
+    /// Write capture metadata (time mapping...) as JSON to FILE
+    #[arg(long, value_name = "FILE")]
+    metadata: Option<PathBuf>,
 }
 
-impl std::convert::From<std::io::Error> for Error {
-    fn from(ioerr: std::io::Error) -> Self {
-        Error::Io(ioerr)
+#[derive(Subcommand)]
+enum Command {
+    #[command(flatten)]
+    Device(DeviceCommand),
+    /// Analyze a recorded TS file, or stdin when FILE is `-`
+    Analyze {
+        file: PathBuf,
+        /// Print the report as JSON
+        #[arg(long)]
+        json: bool,
+        /// Also write the seek index of the file to FILE.idx, replacing
+        /// the existing one
+        #[arg(long)]
+        index: bool,
+    },
+    /// Print what is known of the command protocol
+    #[command(subcommand)]
+    Protocol(ProtocolCommand),
+    /// Check that a recorded TS file is usable. Exits with 1 if it is not.
+    Validate {
+        file: PathBuf,
+        /// Maximum number of continuity errors
+        #[arg(long, value_name = "N")]
+        max_cc_errors: Option<u64>,
+        /// Maximum number of PCR gaps or jumps
+        #[arg(long, value_name = "N")]
+        max_gaps: Option<u64>,
+        /// Maximum number of scrambled packets
+        #[arg(long, value_name = "N")]
+        max_scrambled: Option<u64>,
+        /// Maximum number of packets with the transport error indicator
+        #[arg(long, value_name = "N")]
+        max_tei: Option<u64>,
+    },
+    /// Check whether a capture could start now, without changing the state
+    /// of the device. Exits with 0 when ready with an input signal, 5 when
+    /// ready without signal, 6 when the device is not ready (firmware not
+    /// answering, encoder in use) and 7 when there is no device or it
+    /// cannot be opened.
+    Probe {
+        /// Print the findings as JSON
+        #[arg(long)]
+        json: bool,
+    },
+}
+
+/// The subcommands run on an opened device.
+#[derive(Subcommand)]
+enum DeviceCommand {
+    /// Print information about the device
+    Info {
+        /// Also read back the PC grabber configuration
+        #[arg(long)]
+        full: bool,
+    },
+    /// List the audio and video inputs of the device
+    ListSources,
+    /// Manage the device firmware
+    #[command(subcommand)]
+    Firmware(FirmwareCommand),
+    /// Inspect the device settings
+    #[command(subcommand)]
+    Settings(SettingsCommand),
+    /// Read or replace the EDID presented on the HDMI input
+    #[command(subcommand)]
+    Edid(EdidCommand),
+    /// Send a single command and print the answer, for experiments with
+    /// opcodes and operations not understood yet
+    Send {
+        #[arg(value_parser = parse_opcode)]
+        opcode: u16,
+        /// Operation of the command: get, set, commit or any value, decimal
+        /// or prefixed with 0x
+        #[arg(long, default_value = "get")]
+        operation: Operation,
+        /// Payload of the command, as hexadecimal bytes
+        #[arg(long, value_parser = parse_payload, default_value = "")]
+        payload: Payload,
+    },
+}
+
+#[derive(Subcommand)]
+enum ProtocolCommand {
+    /// Write the opcodes, header layout and payload structures in FORMAT
+    Export {
+        #[arg(value_enum)]
+        format: ExportFormat,
+    },
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+enum ExportFormat {
+    Json,
+    CHeader,
+}
+
+#[derive(Subcommand)]
+enum SettingsCommand {
+    /// Print the current settings, or save them to FILE
+    Dump { file: Option<PathBuf> },
+    /// Show the settings which differ from the defaults or from a dumped
+    /// FILE. Exits with 1 if differences were found.
+    Diff { file: Option<PathBuf> },
+}
+
+#[derive(Subcommand)]
+enum FirmwareCommand {
+    /// Upload a firmware or configuration blob. A bad upload may leave the
+    /// device unusable until it is power cycled or recovered.
+    Upload {
+        file: PathBuf,
+        /// Opcode of the upload commands, as seen in a trace of the vendor
+        /// driver
+        #[arg(long, value_parser = parse_opcode)]
+        opcode: u16,
+        /// Do not ask for confirmation
+        #[arg(long)]
+        yes: bool,
+        /// Acknowledge that the chunk format and acknowledgement of the
+        /// upload are guessed, not yet confirmed by a vendor trace
+        #[arg(long, required = true)]
+        experimental: bool,
+    },
+}
+
+#[derive(Subcommand)]
+enum EdidCommand {
+    /// Save the EDID of the HDMI input to FILE
+    Read {
+        file: PathBuf,
+        /// Opcode of the EDID commands, as seen in a trace of the vendor
+        /// driver
+        #[arg(long, value_parser = parse_opcode)]
+        opcode: u16,
+    },
+    /// Replace the EDID of the HDMI input with the content of FILE. The
+    /// source has to be reconnected to see the change.
+    Write {
+        file: PathBuf,
+        /// Opcode of the EDID commands, as seen in a trace of the vendor
+        /// driver
+        #[arg(long, value_parser = parse_opcode)]
+        opcode: u16,
+        /// Confirm that the EDID should be replaced
+        #[arg(long)]
+        confirm: bool,
+    },
+    /// Decode an EDID saved in FILE, or read from the device with --opcode
+    Show {
+        #[arg(required_unless_present = "opcode")]
+        file: Option<PathBuf>,
+        /// Opcode of the EDID commands, as seen in a trace of the vendor
+        /// driver
+        #[arg(long, value_parser = parse_opcode, conflicts_with = "file")]
+        opcode: Option<u16>,
+    },
+}
+
+fn parse_opcode(s: &str) -> Result<u16, String> {
+    let res = match s.strip_prefix("0x") {
+        Some(hex) => u16::from_str_radix(hex, 16),
+        None => s.parse(),
+    };
+    res.map_err(|e| format!("invalid opcode `{}`: {}", s, e))
+}
+
+/// Bytes of a command payload, given in hexadecimal.
+#[derive(Clone, Debug, PartialEq, Eq)]
+struct Payload(Vec<u8>);
+
+fn parse_payload(s: &str) -> Result<Payload, String> {
+    if !s.len().is_multiple_of(2) {
+        return Err(format!("odd number of hexadecimal digits in `{}`", s));
     }
+    (0..s.len())
+        .step_by(2)
+        .map(|i| {
+            s.get(i..i + 2)
+                .and_then(|byte| u8::from_str_radix(byte, 16).ok())
+                .ok_or_else(|| format!("invalid hexadecimal payload `{}`", s))
+        })
+        .collect::<Result<_, _>>()
+        .map(Payload)
 }
 
-impl std::convert::From<rusb::Error> for Error {
-    fn from(err: rusb::Error) -> Self {
-        Error::Usb(err)
+fn parse_tcp_url(s: &str) -> Result<String, String> {
+    match s.strip_prefix("tcp://") {
+        Some(address) if address.contains(':') => Ok(address.to_string()),
+        _ => Err(format!("expected tcp://HOST:PORT, got `{}`", s)),
     }
 }
 
-pub struct CommandFactory {
-    seq: Arc<Mutex<u16>>,
+fn parse_brightness(s: &str) -> Result<i32, String> {
+    Control::Brightness.parse(s)
 }
 
-impl CommandFactory {
-    const OPERATION_GET: u32 = 1;
-    const OPERATION_SET: u32 = 2;
+fn parse_contrast(s: &str) -> Result<i32, String> {
+    Control::Contrast.parse(s)
+}
+
+fn parse_hue(s: &str) -> Result<i32, String> {
+    Control::Hue.parse(s)
+}
 
-    pub fn new() -> CommandFactory {
-        CommandFactory {
-            seq: Arc::new(Mutex::new(0u16)),
+fn parse_saturation(s: &str) -> Result<i32, String> {
+    Control::Saturation.parse(s)
+}
+
+/// Logger printing the messages to stderr.
+struct StderrLogger;
+
+impl log::Log for StderrLogger {
+    fn enabled(&self, metadata: &log::Metadata) -> bool {
+        metadata.level() <= log::max_level()
+    }
+
+    fn log(&self, record: &log::Record) {
+        if self.enabled(record.metadata()) {
+            eprintln!(
+                "{}: {}",
+                record.level().as_str().to_lowercase(),
+                record.args()
+            );
         }
     }
 
-    pub fn make_command(&mut self, opcode: u16, operation: u32, data: &[u8]) -> Vec<u8> {
-        let len = 0x10 + u16::try_from(data.len()).unwrap();
-        let seq = {
-            let mut guard = self.seq.lock().unwrap();
-            let previous = *guard;
-            *guard = previous.overflowing_add(1).0;
-            previous
-        };
-        let mut cmd = vec![0u8; len.try_into().unwrap()];
-        cmd[0x00..=0x01].copy_from_slice(&len.to_le_bytes());
-        cmd[0x04..=0x05].copy_from_slice(&opcode.to_le_bytes());
-        cmd[0x06] = 0x10;
-        cmd[0x07] = 0x99;
-        cmd[0x08..=0x0b].copy_from_slice(&operation.to_le_bytes());
-        cmd[0x0c..=0x0d].copy_from_slice(&seq.to_le_bytes());
-        cmd[0x0e] = 0x10;
-        cmd[0x0f] = 0x99;
-        cmd[0x10..].copy_from_slice(data);
-        cmd
+    fn flush(&self) {}
+}
+
+fn print_resp_data(datatype: &str, resp: &Response) {
+    if resp.payload.is_empty() {
+        eprintln!("{}: No data", datatype);
+        return;
     }
+    eprintln!("{}: {:02x?}", datatype, &resp.payload);
+}
+
+/// Longest single read of the stream endpoint.
+const MAX_READ_SLICE: Duration = Duration::from_secs(1);
 
-    pub fn make_reboot(&mut self) -> Vec<u8> {
-        self.make_command(0x0001, Self::OPERATION_SET, &[])
+/// Size of the stream reads, allocated once per capture.
+const STREAM_CHUNK_SIZE: usize = 0x4000;
+
+/// Exit code when the capture stopped because no data was received.
+const EXIT_NO_DATA: i32 = 3;
+
+/// Exit code when the device was lost.
+const EXIT_DEVICE_LOST: i32 = 4;
+
+/// Exit codes of `probe`, besides 0 when ready.
+const EXIT_PROBE_NO_SIGNAL: i32 = 5;
+const EXIT_PROBE_NOT_READY: i32 = 6;
+const EXIT_PROBE_NO_DEVICE: i32 = 7;
+
+/// Exit code of `settings diff` when the settings differ.
+const EXIT_SETTINGS_DIFFER: i32 = 8;
+
+/// Exit codes of the writes to the device (firmware upload, EDID write)
+/// when not confirmed, and when the result could not be verified.
+const EXIT_NOT_CONFIRMED: i32 = 9;
+const EXIT_NOT_VERIFIED: i32 = 10;
+
+/// Outcome of a write to the device which asks for confirmation.
+enum WriteOutcome {
+    Done,
+    NotConfirmed,
+    NotVerified,
+}
+
+impl WriteOutcome {
+    fn exit_code(&self) -> i32 {
+        match self {
+            WriteOutcome::Done => 0,
+            WriteOutcome::NotConfirmed => EXIT_NOT_CONFIRMED,
+            WriteOutcome::NotVerified => EXIT_NOT_VERIFIED,
+        }
     }
+}
+
+enum CaptureEnd {
+    /// The stream could not be read anymore.
+    StreamError,
+    /// The --max-timeouts or --max-idle limit was reached.
+    NoData,
+    /// The snapshot was taken.
+    Snapshot,
+    /// The device did not come back after a reboot.
+    DeviceLost,
+    /// The whole input file was read.
+    EndOfInput,
+}
 
-    pub fn make_set_state(&mut self, word1: u32) -> Vec<u8> {
-        let mut data = [0u8; 4];
-        data[0..=3].copy_from_slice(&word1.to_le_bytes());
-        self.make_command(0x0002, Self::OPERATION_SET, &data)
+/// Longest wait for the device to come back after a reboot.
+const REBOOT_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Consecutive stream read errors, other than timeouts, after which the
+/// command endpoint is checked for a wedged handle.
+const WEDGED_ERRORS: u32 = 3;
+
+/// Delay before reading the stream again after an error.
+const STREAM_RETRY_DELAY: Duration = Duration::from_millis(100);
+
+/// Time over which the bitrate of the stream is measured before and after
+/// an encoder change.
+const BITRATE_WINDOW: Duration = Duration::from_secs(5);
+
+/// Default heartbeat interval for the firmware needing it.
+const QUIRK_HEARTBEAT_INTERVAL: u64 = 10;
+
+/// Query the firmware version and find its quirks, applying `overrides`.
+fn detect_firmware(
+    device: &Device,
+    overrides: Option<&str>,
+) -> Result<(Option<FirmwareVersion>, Quirks), Error> {
+    let version = FirmwareVersion::query(device)?;
+    match version {
+        Some(v) => match v.known() {
+            Some(fw) => eprintln!("Firmware version: {}, {}", v, fw.name),
+            None => {
+                eprintln!("Firmware version: {}", v);
+                warn!(
+                    "Unknown firmware version {}, please report it upstream \
+                     with the output of the info command",
+                    v
+                );
+            }
+        },
+        None => warn!("Could not read the firmware version"),
     }
+    let mut quirks = firmware::quirks_for(version);
+    if let Some(list) = overrides {
+        quirks.apply_overrides(list).map_err(Error::Config)?;
+    }
+    eprintln!("Firmware quirks: {}", quirks);
+    Ok((version, quirks))
+}
 
-    pub fn make_get_source(&mut self) -> Vec<u8> {
-        const GET_SOURCE_DATA: [u8; 8] = [0u8; 8];
-        self.make_command(0x0003, Self::OPERATION_GET, &GET_SOURCE_DATA)
+/// Settings of the command line, completed by those of the `[stream0]`
+/// section and of the selected video source.
+fn capture_settings(args: &CaptureArgs, config: &Config, source: Option<&str>) -> SourceProfile {
+    let flags = SourceProfile::from(config.stream0.clone()).merge(&SourceProfile {
+        brightness: args.brightness,
+        contrast: args.contrast,
+        hue: args.hue,
+        saturation: args.saturation,
+        quality: args.quality,
+        keyframe_rate: args.keyframe_rate,
+    });
+    let source = match source {
+        Some(source) => source,
+        None => return flags,
+    };
+    let profile = config.source_profile(source);
+    for (name, from_source, from_flag) in profile.conflicts(&flags) {
+        debug!(
+            "{} {} given on the command line overrides {} of the {} source settings",
+            name, from_flag, from_source, source
+        );
     }
+    profile.merge(&flags)
+}
 
-    pub fn make_set_source(&mut self, audio_src: u32, video_src: u32) -> Vec<u8> {
-        let mut data = [0u8; 8];
-        data[0..=3].copy_from_slice(&audio_src.to_le_bytes());
-        data[4..=7].copy_from_slice(&video_src.to_le_bytes());
-        self.make_command(0x0003, Self::OPERATION_SET, &data)
-    }
-
-    pub fn make_set_brightness(&mut self, brightness: u32) -> Vec<u8> {
-        let mut data = [0u8; 8];
-        data[0..=3].copy_from_slice(&0u32.to_le_bytes());
-        data[4..=7].copy_from_slice(&brightness.to_le_bytes());
-        self.make_command(0x0101, Self::OPERATION_SET, &data)
-    }
-
-    pub fn make_set_contrast(&mut self, contrast: u32) -> Vec<u8> {
-        let mut data = [0u8; 8];
-        data[0..=3].copy_from_slice(&0u32.to_le_bytes());
-        data[4..=7].copy_from_slice(&contrast.to_le_bytes());
-        self.make_command(0x0102, Self::OPERATION_SET, &data)
-    }
-
-    pub fn make_set_hue(&mut self, hue: u32) -> Vec<u8> {
-        let mut data = [0u8; 8];
-        data[0..=3].copy_from_slice(&0u32.to_le_bytes());
-        data[4..=7].copy_from_slice(&hue.to_le_bytes());
-        self.make_command(0x0103, Self::OPERATION_SET, &data)
-    }
-
-    pub fn make_set_saturation(&mut self, saturation: u32) -> Vec<u8> {
-        let mut data = [0u8; 8];
-        data[0..=3].copy_from_slice(&0u32.to_le_bytes());
-        data[4..=7].copy_from_slice(&saturation.to_le_bytes());
-        self.make_command(0x0104, Self::OPERATION_SET, &data)
-    }
-
-    pub fn make_set_video_compression_keyframe_rate(&mut self, stream_idx: u32, rate: u32) -> Vec<u8> {
-        let mut data = [0u8; 8];
-        data[0..=3].copy_from_slice(&stream_idx.to_le_bytes());
-        data[4..=7].copy_from_slice(&rate.to_le_bytes());
-        self.make_command(0x0202, Self::OPERATION_SET, &data)
-    }
-
-    pub fn make_set_video_compression_quality(&mut self, stream_idx: u32, quality: u32) -> Vec<u8> {
-        let mut data = [0u8; 8];
-        data[0..=3].copy_from_slice(&stream_idx.to_le_bytes());
-        data[4..=7].copy_from_slice(&quality.to_le_bytes());
-        self.make_command(0x0203, Self::OPERATION_SET, &data)
-    }
-
-    pub fn make_get_firmware_status(&mut self) -> Vec<u8> {
-        self.make_command(0x0008, Self::OPERATION_GET, &[])
-    }
-
-    pub fn make_get_profile(&mut self) -> Vec<u8> {
-        self.make_command(0x000a, Self::OPERATION_GET, &[])
-    }
-
-    pub fn make_get_pc_grabber_small(&mut self) -> Vec<u8> {
-        let dummy = [
-            0x01u8, 0x40, 0x38, 0x38, 0x3c, 0xc6, 0xb0, 0x93, 0xba, 0xc1, 0xb0, 0x93,
-        ];
-        self.make_command(0xe001, Self::OPERATION_GET, &dummy)
-    }
-
-    pub fn make_set_pc_grabber_small(&mut self, enable: bool) -> Vec<u8> {
-        let data: [u8; 0x0c] = [
-            0x01, 0x40, 0x38, 0x38, 0x51, 0xd3, 0xcf, 0x77, if enable { 0x01 } else { 0x00 }, 0x00, 0x00, 0x00,
-        ];
-        self.make_command(0xe001, Self::OPERATION_SET, &data)
-    }
-
-    pub fn make_set_pc_grabber(&mut self, index: u32) -> Vec<u8> {
-        let mut data: [u8; 0x3c] = [
-            0x08, 0x20, 0x38, 0x38, 0x00, 0x00, 0x00, 0x00, 0x05, 0x00, 0x00, 0x00, 0x00, 0x00,
-            0x00, 0x00, 0x0f, 0x00, 0x00, 0x00, 0x80, 0x07, 0x00, 0x00, 0x38, 0x04, 0x00, 0x00,
-            0x10, 0x27, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x1e, 0x00,
-            0x00, 0x00, 0x1e, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
-            0x00, 0x00, 0x00, 0x00,
-        ];
-        data[0xc..=0xf].copy_from_slice(&index.to_le_bytes());
-        self.make_command(0xe001, Self::OPERATION_SET, &data)
+/// Switch the video input to the source `name` of the control file, its
+/// settings applying under those of the command line as at the start.
+/// Returns the source switched to, `None` when it is the current one.
+fn switch_source(
+    device: &Device,
+    session: &mut CaptureSession,
+    args: &CaptureArgs,
+    config: &Config,
+    current: Option<&str>,
+    name: &str,
+    limits: &EncoderLimits,
+) -> Result<Option<&'static str>, Error> {
+    let caps = Capabilities::query(device)?;
+    let source = caps.video.find(name).map_err(Error::Config)?;
+    if current == Some(source.name) {
+        return Ok(None);
     }
+    let audio = device
+        .transact(&device.factory().make_get_source())?
+        .word(0)
+        .unwrap_or(0);
+    let settings = capture_settings(args, config, Some(source.name));
+    eprintln!("Switching the video input to {}", source.name);
+    session.switch_source(
+        audio,
+        source.id,
+        settings.picture(),
+        settings.encoder(),
+        limits,
+    )?;
+    Ok(Some(source.name))
+}
 
-    pub fn make_set_pc_grabber_large(&mut self) -> Vec<u8> {
-        let mut data: [u8; 0x200] = [
-            0x00, 0x02, 0x00, 0x00, 0x01, 0xe0, 0x10, 0x99, 0x01, 0x00, 0x00, 0x00, 0x36, 0x00,
-            0x10, 0x99, 0x02, 0x00, 0x38, 0x38, 0x3c, 0xc6, 0xb0, 0x93, 0xba, 0xc1, 0xb0, 0x93,
-            0x00, 0x00, 0x00, 0x00, 0x28, 0x8b, 0x5d, 0x8a, 0x5d, 0x6b, 0xb0, 0x93, 0x74, 0xd0,
-            0xcc, 0x84, 0xb8, 0x63, 0xdf, 0x84, 0xb8, 0x65, 0xdf, 0x84, 0x48, 0xce, 0xd8, 0x84,
-            0x07, 0x00, 0x00, 0x00, 0x3c, 0xc6, 0xb0, 0x93, 0xae, 0xba, 0xb0, 0x93, 0x24, 0x8b,
-            0x5d, 0x8a, 0x98, 0xc6, 0xb0, 0x93, 0xc0, 0xa8, 0x98, 0x84, 0x01, 0x00, 0x00, 0xc0,
-            0x78, 0x8b, 0x5d, 0x8a, 0x21, 0x61, 0x22, 0x8d, 0x74, 0xd0, 0xcc, 0x84, 0xb8, 0x65,
-            0xdf, 0x84, 0xb8, 0x63, 0xdf, 0x84, 0xac, 0xaa, 0x7f, 0x07, 0xd0, 0x12, 0x22, 0x8d,
-            0x28, 0x00, 0x00, 0x00, 0x05, 0xce, 0xd8, 0x84, 0x00, 0x00, 0x00, 0x00, 0x20, 0x00,
-            0x00, 0x00, 0x00, 0x02, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00, 0x00, 0x02, 0x00, 0x00,
-            0x3c, 0x8b, 0x5d, 0x8a, 0x00, 0x00, 0x00, 0x00, 0xc0, 0x8c, 0x5d, 0x8a, 0xea, 0x0a,
-            0x22, 0x8d, 0xd4, 0x3b, 0x00, 0x00, 0xfe, 0xff, 0xff, 0xff, 0xac, 0x8b, 0x5d, 0x8a,
-            0x85, 0x5a, 0x22, 0x8d, 0x48, 0xce, 0xd8, 0x84, 0x05, 0x00, 0x00, 0x00, 0xb0, 0x38,
-            0xcb, 0x95, 0xb8, 0x63, 0xdf, 0x84, 0x28, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
-            0x00, 0x00, 0x00, 0x00, 0xe8, 0xe2, 0xd8, 0x84, 0x48, 0xce, 0xd8, 0x84, 0x48, 0xce,
-            0xd8, 0x84, 0x25, 0x02, 0x00, 0xc0, 0xd4, 0x8b, 0x5d, 0x8a, 0x43, 0x6c, 0x22, 0x8d,
-            0x48, 0xce, 0xd8, 0x84, 0x60, 0x38, 0xcb, 0x95, 0x30, 0x52, 0xd8, 0x84, 0x38, 0x52,
-            0xd8, 0x84, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0xe8, 0xe2, 0xd8, 0x84,
-            0x08, 0xd0, 0xcc, 0x84, 0xe4, 0x8b, 0x5d, 0x8a, 0x8f, 0x54, 0x22, 0x8d, 0x70, 0x5c,
-            0x3e, 0x84, 0x48, 0xce, 0xd8, 0x84, 0xfc, 0x8b, 0x5d, 0x8a, 0xba, 0x50, 0x21, 0x8d,
-            0x70, 0x5c, 0x3e, 0x84, 0x48, 0xce, 0xd8, 0x84, 0x70, 0x5c, 0x3e, 0x84, 0x00, 0x00,
-            0x00, 0x00, 0x14, 0x8c, 0x5d, 0x8a, 0x47, 0x20, 0x83, 0x82, 0x70, 0x5c, 0x3e, 0x84,
-            0x48, 0xce, 0xd8, 0x84, 0x48, 0xce, 0xd8, 0x84, 0x70, 0x5c, 0x3e, 0x84, 0x34, 0x8c,
-            0x5d, 0x8a, 0xd5, 0x89, 0xa0, 0x82, 0xe8, 0xe2, 0xd8, 0x84, 0x48, 0xce, 0xd8, 0x84,
-            0x48, 0xcf, 0xd8, 0x84, 0xb4, 0x01, 0x00, 0x00, 0x8c, 0x8c, 0x5d, 0x04, 0x44, 0x8c,
-            0x5d, 0x8a, 0xd0, 0x8c, 0x5d, 0x8a, 0xc8, 0xad, 0xa0, 0x82, 0x70, 0x5c, 0x3e, 0x84,
-            0xe8, 0xe2, 0xd8, 0x84, 0x00, 0x00, 0x00, 0x00, 0x01, 0xf1, 0xa4, 0x82, 0x00, 0x7a,
-            0x6b, 0x20, 0x02, 0x00, 0x00, 0x00, 0xf4, 0x7d, 0x6b, 0x20, 0x44, 0x04, 0x00, 0x00,
-            0xc8, 0xfb, 0x25, 0x09, 0x73, 0x1d, 0xa1, 0x82, 0x00, 0x00, 0x00, 0x00, 0x9f, 0x01,
-            0x12, 0x00, 0x00, 0x00, 0x00, 0x00, 0x10, 0x00, 0x00, 0x00, 0xe8, 0xe2, 0xd8, 0x84,
-            0x00, 0x00, 0x00, 0x00, 0xd5, 0x74, 0xa5, 0x08, 0xc0, 0x0a, 0xd8, 0x84, 0x84, 0x75,
-            0xa5, 0x82, 0x01, 0x8e, 0x8b, 0x82, 0xc8, 0xf5, 0x42, 0x84, 0x10, 0x00, 0x00, 0x00,
-            0xa4, 0x8c, 0x5d, 0x8a, 0x30, 0xfc, 0x25, 0x09, 0x00, 0x7a, 0x6b, 0x20, 0x03, 0x00,
-            0x00, 0x00, 0x01, 0xf1, 0xa4, 0x82, 0xc8, 0xf5, 0x42, 0x84, 0xe8, 0xe2, 0xd8, 0x84,
-            0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x54, 0x8c, 0x5d, 0x8a, 0x18, 0x8d,
-            0x5d, 0x8a, 0xff, 0xff, 0xff, 0xff, 0x0b, 0x8e, 0x8b, 0x82, 0x7c, 0xf2, 0xb3, 0x28,
-            0xfe, 0xff, 0xff, 0xff, 0x04, 0x8d, 0x5d, 0x8a,
-        ];
-        self.make_command(0xe001, Self::OPERATION_SET, &data)
-    }
-
-    pub fn make_time_query(&mut self, ts: u32) -> Vec<u8> {
-        let mut data = [0u8; 4];
-        data[0..=3].copy_from_slice(&ts.to_le_bytes());
-        self.make_command(0xf001, Self::OPERATION_GET, &data)
-    }
-
-    pub fn make_get_hw_grabber(&mut self) -> Vec<u8> {
-        self.make_command(0xf002, Self::OPERATION_GET, &[])
-    }
-}
-
-impl Clone for CommandFactory {
-    fn clone(&self) -> Self {
-        Self {
-            seq: self.seq.clone(),
-        }
-    }
-}
-
-fn print_resp_data(datatype: &str, data: &[u8]) {
-    if data.len() < 0x10 {
-        eprintln!("{}: Short response!", datatype);
-        return;
+/// Encoder settings of stream 1, from the command line and the `[stream1]`
+/// section.
+fn stream1_settings(args: &CaptureArgs, config: &Config) -> StreamProfile {
+    config.stream1.merge(&StreamProfile {
+        quality: args.stream1_quality,
+        keyframe_rate: args.stream1_keyframe_rate,
+    })
+}
+
+fn capture(
+    device: &Device,
+    args: &CaptureArgs,
+    config: &Config,
+    quirk_overrides: Option<&str>,
+) -> Result<CaptureEnd, Error> {
+    device.reset()?;
+    device.claim()?;
+
+    let mut factory = device.factory();
+    let resp = device.transact(&factory.make_get_profile())?;
+    print_resp_data("Profile", &resp);
+    let input = InputSignal::parse(&resp);
+    if let Some(signal) = &input {
+        eprintln!("Input signal: {}", signal);
     }
-    if data.len() == 0x10 {
-        eprintln!("{}: No data", datatype);
-        return;
+    let (version, quirks) = detect_firmware(device, quirk_overrides)?;
+    let clock = Arc::new(Mutex::new(ClockModel::new()));
+    let heartbeat_interval = match args.heartbeat {
+        Some(secs) => secs,
+        None if quirks.needs_heartbeat => QUIRK_HEARTBEAT_INTERVAL,
+        None => 0,
+    };
+    let mut heartbeat = match heartbeat_interval {
+        0 => None,
+        secs => Some(Heartbeat::start(
+            device.clone(),
+            Duration::from_secs(secs),
+            clock.clone(),
+        )),
+    };
+    let resp = device.transact(&factory.make_get_source())?;
+    print_resp_data("Source", &resp);
+    let mut session = CaptureSession::new(device.clone(), quirks);
+    session.set_grabber_entries(grabber_entries(config, version));
+    let mut video_source = None;
+    if args.video_source.is_some() || args.audio_source.is_some() {
+        let caps = Capabilities::query(device)?;
+        let current = device.transact(&factory.make_get_source())?;
+        let audio = match &args.audio_source {
+            Some(name) => caps.audio.find(name).map_err(Error::Config)?.id,
+            None => current.word(0).unwrap_or(0),
+        };
+        let video = match &args.video_source {
+            Some(name) => {
+                let source = caps.video.find(name).map_err(Error::Config)?;
+                video_source = Some(source.name);
+                source.id
+            }
+            None => current.word(4).unwrap_or(0),
+        };
+        session.set_source(audio, video);
+    }
+    let settings = capture_settings(args, config, video_source);
+    session.set_picture(settings.picture())?;
+    let limits = firmware::limits_for(version);
+    session.set_encoder(settings.encoder(), &limits)?;
+    session.set_stream_encoder(1, stream1_settings(args, config).encoder(), &limits)?;
+    session.start(GrabberConfig::default())?;
+
+    let status_errors = firmware::status_errors_for(version);
+    if args.recover_on_firmware_error {
+        if args.firmware_status == 0 {
+            warn!("--recover-on-firmware-error has no effect without --firmware-status");
+        } else if status_errors.is_empty() {
+            warn!("The error states of this firmware revision are not known, it will not be recovered");
+        }
+    }
+    let mut fw_monitor = match args.firmware_status {
+        0 => None,
+        secs => Some(FirmwareMonitor::start(
+            device.clone(),
+            Duration::from_secs(secs),
+            status_errors,
+        )),
+    };
+
+    let mut signal_monitor = match args.signal_poll {
+        0 => None,
+        secs => Some(SignalMonitor::start(
+            device.clone(),
+            Duration::from_secs(secs),
+            input,
+        )),
+    };
+
+    let mut notifications = if args.notifications {
+        let listener = NotificationListener::start(device.clone());
+        if listener.is_none() {
+            eprintln!("The device has no event endpoint, not listening for notifications");
+        }
+        listener
+    } else {
+        None
+    };
+
+    let mut control = args.control.clone().map(ControlFile::start);
+
+    let restart = Arc::new(AtomicBool::new(false));
+    #[cfg(unix)]
+    signal_hook::flag::register(signal_hook::consts::SIGUSR2, restart.clone())?;
+    let metadata = Metadata {
+        started: format_utc(SystemTime::now()),
+        firmware: version.map(|v| v.to_string()),
+        streams: vec![session.read_stream(0), session.read_stream(1)],
+        ..Default::default()
+    };
+    let mut stream = StreamLoop::open(args, clock.clone(), metadata)?;
+    let stream_timeout = Duration::from_millis(args.stream_timeout_ms.max(1));
+    let read_timeout = stream_timeout.min(MAX_READ_SLICE);
+    let mut watchdog = StallWatchdog::new(
+        Duration::from_secs(args.stall_report.max(1)),
+        stream_timeout,
+    );
+    let mut waited = Duration::ZERO;
+    let mut consecutive_timeouts = 0u32;
+    let mut stream_errors = 0u32;
+    let mut last_reboot: Option<Instant> = None;
+    // Start and offset of the current bitrate window, and the bitrate of the
+    // previous one in kbit/s.
+    let mut bitrate_window = (stream.started, 0u64);
+    let mut last_bitrate = None;
+    // Encoder change whose effect on the bitrate is still to be reported.
+    let mut bitrate_check: Option<(String, Option<f64>, Instant, u64)> = None;
+    let mut end = CaptureEnd::StreamError;
+    // Error ending the capture, returned once the outputs, monitors and
+    // metadata are finished as for any other end.
+    let mut failure = None;
+    'capture: loop {
+        stream.print_stats(|stream| {
+            format!(
+                "{} consecutive timeouts, {} reopens, {} queue full, responses: {}",
+                consecutive_timeouts,
+                stream.metadata.reopens,
+                stream.queue_full(),
+                device.protocol_stats()
+            )
+        });
+        let offset = stream.offset;
+        let changes = signal_monitor
+            .as_ref()
+            .map(|m| m.take_changes())
+            .unwrap_or_default();
+        for change in changes {
+            stream.event(Event::InputChange {
+                offset,
+                time: format_utc(change.time),
+                signal: change.signal.to_string(),
+            });
+            match args.on_signal_change {
+                SignalChangePolicy::Continue => (),
+                SignalChangePolicy::Split => {
+                    stream.pipeline.request_split(SplitReason::InputChange)
+                }
+                SignalChangePolicy::Restart => match GrabberConfig::for_signal(&change.signal) {
+                    Some(grabber) => {
+                        eprintln!(
+                            "Restarting the encoder for {}x{}...",
+                            grabber.width, grabber.height
+                        );
+                        if let Err(e) = session.restart(Some(grabber)) {
+                            failure = Some(e);
+                            break 'capture;
+                        }
+                        stream.pipeline.request_split(SplitReason::InputChange);
+                    }
+                    None => eprintln!("No input signal, not restarting the encoder"),
+                },
+            }
+        }
+        let fw_errors = fw_monitor
+            .as_ref()
+            .map(|m| m.take_errors())
+            .unwrap_or_default();
+        for error in &fw_errors {
+            stream.metadata.stream_events.push(Event::FirmwareError {
+                offset,
+                time: format_utc(error.time),
+                error: error.error.name.to_string(),
+            });
+        }
+        if !fw_errors.is_empty() && args.recover_on_firmware_error {
+            warn!("Restarting the encoder after a firmware error");
+            // When the restart fails, the stream failure is handled as any
+            // other: reopen, then reboot with --reboot-on-failure.
+            match session.restart(None) {
+                Ok(()) => stream.pipeline.request_split(SplitReason::Restart),
+                Err(e) => warn!("The encoder could not be restarted: {}", e),
+            }
+        }
+        let received = notifications.as_ref().map(|l| l.take()).unwrap_or_default();
+        for notification in received {
+            // None of the notifications is identified yet.
+            debug!("Notification: {:02x?}", notification.data);
+            stream.metadata.stream_events.push(Event::Notification {
+                offset,
+                time: format_utc(notification.time),
+                length: notification.data.len(),
+            });
+        }
+        let requested = control.as_ref().map(|c| c.take()).unwrap_or_default();
+        if let Some(name) = &requested.source {
+            match switch_source(
+                device,
+                &mut session,
+                args,
+                config,
+                video_source,
+                name,
+                &limits,
+            ) {
+                Ok(Some(source)) => {
+                    video_source = Some(source);
+                    stream.pipeline.request_split(SplitReason::Restart);
+                }
+                Ok(None) => (),
+                Err(Error::Config(e)) => eprintln!("Ignoring the control file source: {}", e),
+                Err(e) => warn!("The video input could not be switched to {}: {}", name, e),
+            }
+        }
+        for change in requested.changes {
+            if session.encoder_value(change.stream, change.param) == Some(change.value) {
+                continue;
+            }
+            let live = firmware::live_change_for(version, change.param);
+            match session.change_encoder(change.stream, change.param, change.value, &limits, live) {
+                Ok(applied) => {
+                    if live.is_none() {
+                        warn!(
+                            "The {} of firmware version {} is changed {}, please report it \
+                             upstream with the output of the info command",
+                            change.param,
+                            version.map_or("unknown".to_string(), |v| v.to_string()),
+                            match applied {
+                                LiveChange::Live => "live",
+                                LiveChange::Restart => "with a restart",
+                            }
+                        );
+                    }
+                    if applied == LiveChange::Restart {
+                        stream.pipeline.request_split(SplitReason::Restart);
+                    }
+                    bitrate_check = Some((
+                        change.param.to_string(),
+                        last_bitrate,
+                        Instant::now(),
+                        offset,
+                    ));
+                }
+                Err(Error::Config(e)) => eprintln!("Ignoring the control file setting: {}", e),
+                Err(e) => warn!("The {} could not be changed: {}", change.param, e),
+            }
+        }
+        let now = Instant::now();
+        if now.duration_since(bitrate_window.0) >= BITRATE_WINDOW {
+            let secs = now.duration_since(bitrate_window.0).as_secs_f64();
+            last_bitrate = Some((offset - bitrate_window.1) as f64 * 8.0 / secs / 1000.0);
+            bitrate_window = (now, offset);
+        }
+        if let Some((param, before, since, from)) = &bitrate_check {
+            if since.elapsed() >= BITRATE_WINDOW {
+                let after = (offset - from) as f64 * 8.0 / since.elapsed().as_secs_f64() / 1000.0;
+                match before {
+                    Some(before) => eprintln!(
+                        "Bitrate after the {} change: {:.0} kbit/s, {:.0} kbit/s before",
+                        param, after, before
+                    ),
+                    None => eprintln!("Bitrate after the {} change: {:.0} kbit/s", param, after),
+                }
+                bitrate_check = None;
+            }
+        }
+        if restart.swap(false, Ordering::Relaxed) {
+            eprintln!("Restarting the encoder...");
+            if let Err(e) = session.restart(None) {
+                failure = Some(e);
+                break;
+            }
+            stream.pipeline.request_split(SplitReason::Restart);
+        }
+        if let Err(e) = stream.tick() {
+            failure = Some(e);
+            break;
+        }
+        let recvd = match stream.read(|buf| device.read_stream(buf, read_timeout)) {
+            Err(rusb::Error::Timeout) => {
+                waited += read_timeout;
+                if waited < stream_timeout {
+                    continue;
+                }
+                waited = Duration::ZERO;
+                consecutive_timeouts += 1;
+                if let Some(msg) = watchdog.timeout() {
+                    eprintln!(
+                        "{} at offset {}{}",
+                        msg,
+                        offset,
+                        wall_clock_note(&clock, offset)
+                    );
+                }
+                let too_many = args
+                    .max_timeouts
+                    .is_some_and(|max| consecutive_timeouts >= max);
+                let idle = args
+                    .max_idle
+                    .is_some_and(|max| watchdog.idle() >= Duration::from_secs(max));
+                if too_many || idle {
+                    eprintln!("No data received, stopping the capture");
+                    end = CaptureEnd::NoData;
+                    break;
+                }
+                continue;
+            }
+            Err(e) => {
+                eprintln!(
+                    "Failed to read TS stream at offset {}{}: {}",
+                    offset,
+                    wall_clock_note(&clock, offset),
+                    &e
+                );
+                if e != rusb::Error::NoDevice && stream.metadata.reopens < args.max_reopens {
+                    stream_errors += 1;
+                    if stream_errors < WEDGED_ERRORS {
+                        std::thread::sleep(STREAM_RETRY_DELAY);
+                        continue;
+                    }
+                    stream_errors = 0;
+                    if is_wedged(device, &mut factory) {
+                        stream.metadata.reopens += 1;
+                        warn!(
+                            "The device handle stopped working, reopening the device (reopen {} of {})",
+                            stream.metadata.reopens, args.max_reopens
+                        );
+                        match session.reopen() {
+                            Ok(()) => {
+                                warn!("The device was reopened");
+                                stream.pipeline.request_split(SplitReason::Reopen);
+                                continue;
+                            }
+                            Err(e) => warn!("The device could not be reopened: {}", e),
+                        }
+                    }
+                }
+                let cooldown = Duration::from_secs(args.reboot_cooldown);
+                if args.reboot_on_failure && last_reboot.is_none_or(|t| t.elapsed() >= cooldown) {
+                    stream.metadata.reboots += 1;
+                    last_reboot = Some(Instant::now());
+                    warn!(
+                        "Rebooting the device after a stream failure (reboot {})",
+                        stream.metadata.reboots
+                    );
+                    match session.reboot(REBOOT_TIMEOUT) {
+                        Ok(()) => {
+                            warn!("The device is back after the reboot");
+                            stream.pipeline.request_split(SplitReason::Reboot);
+                            continue;
+                        }
+                        Err(e) => {
+                            warn!("The device did not come back: {}", e);
+                            end = CaptureEnd::DeviceLost;
+                        }
+                    }
+                }
+                break;
+            }
+            Ok(len) => len,
+        };
+        waited = Duration::ZERO;
+        consecutive_timeouts = 0;
+        stream_errors = 0;
+        if let Some(gap) = watchdog.data(offset) {
+            stream.event(gap);
+        }
+        match stream.process(recvd) {
+            Ok(Some(done)) => {
+                end = done;
+                break;
+            }
+            Ok(None) => (),
+            Err(e) => {
+                failure = Some(e);
+                break;
+            }
+        }
+    }
+    if let Some(heartbeat) = heartbeat.as_mut() {
+        heartbeat.stop();
+        eprintln!(
+            "Heartbeat: {} sent, {} failed",
+            heartbeat.sent(),
+            heartbeat.failures()
+        );
+    }
+    let finished = stream.finish();
+    if let Some(listener) = notifications.as_mut() {
+        listener.stop();
+    }
+    if let Some(control) = control.as_mut() {
+        control.stop();
+    }
+    if let Some(monitor) = signal_monitor.as_mut() {
+        monitor.stop();
+    }
+    if stream.metadata.reboots > 0 {
+        eprintln!("Device reboots: {}", stream.metadata.reboots);
+    }
+    if stream.metadata.reopens > 0 {
+        eprintln!("Device reopens: {}", stream.metadata.reopens);
+    }
+    if let Some(monitor) = fw_monitor.as_mut() {
+        monitor.stop();
+        eprintln!("Firmware status: {} warnings", monitor.warnings());
+    }
+    if let Some(path) = &args.metadata {
+        if let Some(drift) = clock.lock().unwrap().device_drift_ppm() {
+            eprintln!("Device clock drift: {:.1} ppm", drift);
+        }
+        if let Err(e) = stream.write_metadata(path) {
+            failure.get_or_insert(e);
+        }
+    }
+    match failure {
+        Some(e) => Err(e),
+        None => finished.map(|()| end),
     }
-    eprintln!("{}: {:02x?}", datatype, &data[0x10..data.len()]);
 }
 
-fn wait_pc_grabber_ready(
-    devhnd: Arc<Mutex<rusb::DeviceHandle<rusb::GlobalContext>>>,
-    factory: &mut CommandFactory,
-) -> Result<(), Error> {
-    const USB_TIMEOUT: Duration = Duration::from_secs(2);
+/// Run the capture pipeline on a recorded file instead of the device.
+fn replay(args: &CaptureArgs, input: &InputFile) -> Result<CaptureEnd, Error> {
+    let mut reader = FileInput::open(input, args.input_loop)?;
+    eprintln!(
+        "Reading the stream from {}{}{}",
+        reader.path().display(),
+        if input.realtime { " in real time" } else { "" },
+        if args.input_loop { ", looping" } else { "" }
+    );
+    let clock = Arc::new(Mutex::new(ClockModel::new()));
+    let metadata = Metadata {
+        started: format_utc(SystemTime::now()),
+        ..Default::default()
+    };
+    let mut stream = StreamLoop::open(args, clock, metadata)?;
+    let mut loops = 0;
+    let mut end = CaptureEnd::EndOfInput;
     loop {
-        let mut respbuf = [0u8; 0x200];
-        let recvd = {
-            let devhnd = devhnd.lock().unwrap();
-            devhnd.write_bulk(2, &factory.make_get_pc_grabber_small(), USB_TIMEOUT)?;
-            devhnd.read_bulk(0x81, &mut respbuf, USB_TIMEOUT)?
-        };
-        print_resp_data("PC grabber state", &respbuf[0..recvd]);
-        if recvd == 0x1c && respbuf[0x18] == 0x01 {
+        stream.print_stats(|stream| {
+            format!(
+                "{} loops, {} queue full",
+                reader.loops(),
+                stream.queue_full()
+            )
+        });
+        stream.tick()?;
+        let recvd = stream.read(|buf| reader.read(buf))?;
+        if recvd == 0 {
+            if stream.snapshot_requested.is_some() {
+                eprintln!("No keyframe in the rest of the input, no snapshot taken");
+                end = CaptureEnd::NoData;
+            }
             break;
-        };
-        thread::sleep(Duration::from_secs(1));
+        }
+        if reader.loops() > loops {
+            loops = reader.loops();
+            eprintln!(
+                "Input file read again from its start at offset {}",
+                stream.offset
+            );
+        }
+        if let Some(done) = stream.process(recvd)? {
+            end = done;
+            break;
+        }
     }
-    Ok(())
+    stream.finish()?;
+    if loops > 0 {
+        eprintln!("Input file loops: {}", loops);
+    }
+    if let Some(path) = &args.metadata {
+        stream.write_metadata(path)?;
+    }
+    Ok(end)
 }
 
-fn timer_thread(
-    devhnd: Arc<Mutex<rusb::DeviceHandle<rusb::GlobalContext>>>,
-    mut factory: CommandFactory,
-) {
-    use std::time::Instant;
+/// The reading of the stream shared by the capture and the replay: each
+/// chunk read, from the device or a file, is fed to the pipeline, and the
+/// requests for splits and snapshots are handled between the reads.
+struct StreamLoop<'a> {
+    args: &'a CaptureArgs,
+    clock: Arc<Mutex<ClockModel>>,
+    pipeline: Pipeline,
+    metadata: Metadata,
+    /// Reused for every read: the reads only report the bytes they wrote.
+    buf: Vec<u8>,
+    /// Stream offset of the next chunk.
+    offset: u64,
+    started: Instant,
+    read_stages: Option<(Arc<Stage>, Arc<Stage>)>,
+    /// When the last read completed, if timed.
+    read_done: Option<Instant>,
+    timing_reporter: Option<TimingReporter>,
+    rotate: Arc<AtomicBool>,
+    snapshot_signal: Arc<AtomicBool>,
+    snapshot_requested: Option<Instant>,
+    segment_started: Instant,
+    metadata_written: Instant,
+    stats_printed: Instant,
+    tei_reported: (Instant, u64),
+}
 
-    const USB_TIMEOUT: Duration = Duration::from_secs(5);
-    let mut respbuf = [0u8; 0x200];
-    let mut ts = 0u32;
-    let mut now = Instant::now();
-    let mut last = now;
+impl<'a> StreamLoop<'a> {
+    fn open(
+        args: &'a CaptureArgs,
+        clock: Arc<Mutex<ClockModel>>,
+        metadata: Metadata,
+    ) -> Result<StreamLoop<'a>, Error> {
+        let rotate = Arc::new(AtomicBool::new(false));
+        #[cfg(unix)]
+        signal_hook::flag::register(signal_hook::consts::SIGHUP, rotate.clone())?;
+        let timing = if args.debug_timing {
+            Some(DebugTiming::new())
+        } else {
+            None
+        };
+        let read_stages = timing
+            .as_ref()
+            .map(|t| (t.stage("read"), t.stage("pipeline")));
+        let mut pipeline = open_pipeline(args, clock.clone(), timing.as_deref())?;
+        let timing_reporter = match timing {
+            Some(timing) => Some(TimingReporter::start(
+                timing,
+                TIMING_REPORT_INTERVAL,
+                args.debug_timing_csv.as_deref(),
+            )?),
+            None => None,
+        };
+        let snapshot_signal = Arc::new(AtomicBool::new(false));
+        #[cfg(unix)]
+        if args.snapshot_on_signal {
+            signal_hook::flag::register(signal_hook::consts::SIGUSR1, snapshot_signal.clone())?;
+        }
+        let mut snapshot_requested = None;
+        if args.snapshot.is_some() {
+            pipeline.request_snapshot();
+            snapshot_requested = Some(Instant::now());
+        }
+        let started = Instant::now();
+        Ok(StreamLoop {
+            args,
+            clock,
+            pipeline,
+            metadata,
+            buf: vec![0u8; STREAM_CHUNK_SIZE],
+            offset: 0,
+            started,
+            read_stages,
+            read_done: None,
+            timing_reporter,
+            rotate,
+            snapshot_signal,
+            snapshot_requested,
+            segment_started: started,
+            metadata_written: started,
+            stats_printed: started,
+            tei_reported: (started, 0),
+        })
+    }
 
-    loop {
-        ts = ts
-            .overflowing_add(u32::try_from(now.duration_since(last).as_millis()).unwrap())
-            .0;
-        last = now;
-        let recvd = {
-            let devhnd = devhnd.lock().unwrap();
-            let res = devhnd.write_bulk(2, &factory.make_time_query(ts), USB_TIMEOUT);
-            if res.is_err() {
-                eprintln!("Failed to write timestamp request: {}", &res.unwrap_err());
-                continue;
+    /// Number of times the queue of an output was full.
+    fn queue_full(&self) -> u64 {
+        self.pipeline
+            .outputs()
+            .sinks()
+            .iter()
+            .map(|s| s.stats().queue_full.load(Ordering::Relaxed))
+            .sum()
+    }
+
+    /// Print the statistics every --stats seconds, `input` giving those of
+    /// the input after the byte count and rate.
+    fn print_stats(&mut self, input: impl FnOnce(&Self) -> String) {
+        let args = self.args;
+        if args.stats == 0 || self.stats_printed.elapsed() < Duration::from_secs(args.stats) {
+            return;
+        }
+        let secs = self.started.elapsed().as_secs_f64();
+        eprintln!(
+            "Stats: {} bytes, {:.1} kB/s, {}",
+            self.offset,
+            self.offset as f64 / secs / 1000.0,
+            input(self)
+        );
+        if let Some(meter) = self.pipeline.audio_meter() {
+            if let Some(status) = meter.status() {
+                eprintln!("Audio: {}", status);
             }
-            let res = devhnd.read_bulk(0x81, &mut respbuf, USB_TIMEOUT);
-            if res.is_err() {
-                eprintln!(
-                    "Failed to read timestamp request response: {}",
-                    &res.unwrap_err()
+        }
+        self.stats_printed = Instant::now();
+    }
+
+    /// Log an event of the stream.
+    fn event(&mut self, event: Event) {
+        eprintln!("{}", event);
+        self.metadata.stream_events.push(event);
+    }
+
+    /// Handle what is due before the next read: the periodic warnings, the
+    /// split and snapshot requests and the snapshot timeout, which only ends
+    /// the capture when the snapshot is all it is for.
+    fn tick(&mut self) -> Result<(), Error> {
+        let args = self.args;
+        periodic_warnings(&mut self.pipeline, &mut self.tei_reported);
+        if self.rotate.swap(false, Ordering::Relaxed) {
+            self.pipeline.request_split(SplitReason::Rotate);
+        }
+        if self.snapshot_signal.swap(false, Ordering::Relaxed) && self.snapshot_requested.is_none()
+        {
+            self.pipeline.request_snapshot();
+            self.snapshot_requested = Some(Instant::now());
+        }
+        if let Some(requested) = self.snapshot_requested {
+            if requested.elapsed() >= Duration::from_secs(args.snapshot_timeout) {
+                if !args.snapshot_on_signal {
+                    return Err(Error::NoKeyframe(args.snapshot_timeout));
+                }
+                warn!(
+                    "No keyframe received within {} s of the snapshot request, no snapshot taken",
+                    args.snapshot_timeout
                 );
-                continue;
+                self.pipeline.cancel_snapshot();
+                self.snapshot_requested = None;
+            }
+        }
+        let segment = Duration::from_secs(args.segment);
+        if !segment.is_zero() && self.segment_started.elapsed() >= segment {
+            self.pipeline.request_split(SplitReason::Segment);
+            self.segment_started = Instant::now();
+        }
+        Ok(())
+    }
+
+    /// Read the next chunk into the buffer with `read`, which returns its
+    /// length.
+    fn read<E>(&mut self, read: impl FnOnce(&mut [u8]) -> Result<usize, E>) -> Result<usize, E> {
+        let started = self.read_stages.as_ref().map(|_| Instant::now());
+        let len = read(&mut self.buf)?;
+        if let (Some((stage, _)), Some(started)) = (&self.read_stages, started) {
+            let done = Instant::now();
+            stage.record(started, done);
+            self.read_done = Some(done);
+        }
+        Ok(len)
+    }
+
+    /// Feed the `len` bytes just read to the pipeline. Returns how the
+    /// capture ends if it is complete.
+    fn process(&mut self, len: usize) -> Result<Option<CaptureEnd>, Error> {
+        self.pipeline.push(&self.buf[..len])?;
+        if let (Some((_, stage)), Some(done)) = (&self.read_stages, self.read_done.take()) {
+            stage.record(done, Instant::now());
+        }
+        for event in self.pipeline.take_events() {
+            self.event(event);
+        }
+        self.offset += len as u64;
+        if let (Some(frame), Some(path)) = (self.pipeline.take_snapshot(), &self.args.snapshot) {
+            std::fs::write(path, &frame)?;
+            eprintln!(
+                "Snapshot: {} bytes written to {}",
+                frame.len(),
+                path.display()
+            );
+            self.snapshot_requested = None;
+            if !self.args.snapshot_on_signal {
+                return Ok(Some(CaptureEnd::Snapshot));
+            }
+        }
+        if let Some(path) = &self.args.metadata {
+            if self.metadata_written.elapsed() >= METADATA_INTERVAL {
+                if let Err(e) = self.write_metadata(path) {
+                    eprintln!("Failed to write metadata: {}", e);
+                }
+                self.metadata_written = Instant::now();
             }
-            res.unwrap()
+        }
+        Ok(None)
+    }
+
+    /// Write the metadata file, with the current time mapping.
+    fn write_metadata(&mut self, path: &Path) -> Result<(), Error> {
+        self.metadata.time_mapping = self.clock.lock().unwrap().table().to_vec();
+        self.metadata.write(path)
+    }
+
+    /// Flush the pipeline at the end of the stream and print its statistics.
+    fn finish(&mut self) -> Result<(), Error> {
+        finish_pipeline(&mut self.pipeline, &mut self.metadata)?;
+        if let Some(reporter) = self.timing_reporter.as_mut() {
+            reporter.stop();
+        }
+        Ok(())
+    }
+}
+
+/// Interval of the metadata file updates during capture.
+const METADATA_INTERVAL: Duration = Duration::from_secs(30);
+
+/// Interval of the warnings about packets with the transport error indicator.
+const TEI_REPORT_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Interval of the percentiles logged with --debug-timing.
+const TIMING_REPORT_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Whether the handle of the device is wedged after stream read errors:
+/// the command endpoint fails as well, with other errors than a timeout,
+/// while the device is still attached.
+fn is_wedged(device: &Device, factory: &mut CommandFactory) -> bool {
+    let failed = match device.transact(&factory.make_get_state()) {
+        Err(Error::Usb(e)) => e != rusb::Error::Timeout && e != rusb::Error::NoDevice,
+        _ => false,
+    };
+    failed && device.is_attached()
+}
+
+/// Pipeline from the stream to the outputs of `args`.
+fn open_pipeline(
+    args: &CaptureArgs,
+    clock: Arc<Mutex<ClockModel>>,
+    timing: Option<&DebugTiming>,
+) -> Result<Pipeline, Error> {
+    let mut pipeline = Pipeline::new(
+        open_outputs(args, timing)?,
+        clock,
+        Duration::from_secs_f64(args.split_window.max(0.0)),
+    );
+    if args.clean_start && args.start_at_keyframe {
+        pipeline.start_at_keyframe(args.keep_preroll_audio);
+    } else if args.clean_start {
+        pipeline.clean_start();
+    } else if args.start_at_keyframe {
+        warn!("Writing every byte received, --start-at-keyframe is ignored");
+    }
+    if args.audio_meter {
+        pipeline.meter_audio();
+    }
+    if args.drop_tei {
+        pipeline.drop_transport_errors();
+    }
+    if args.fix_cc {
+        pipeline.fix_continuity();
+    }
+    Ok(pipeline)
+}
+
+/// Warn about the transport errors seen since the last report and the
+/// audio alerts. `tei_reported` holds the time and count of the last report.
+fn periodic_warnings(pipeline: &mut Pipeline, tei_reported: &mut (Instant, u64)) {
+    if tei_reported.0.elapsed() >= TEI_REPORT_INTERVAL {
+        let total: u64 = pipeline.analyzer().transport_errors().values().sum();
+        if total > tei_reported.1 {
+            warn!(
+                "{} packets with the transport error indicator in the last {} s, {} in total",
+                total - tei_reported.1,
+                tei_reported.0.elapsed().as_secs(),
+                total
+            );
+        }
+        *tei_reported = (Instant::now(), total);
+    }
+    if let Some(meter) = pipeline.audio_meter() {
+        meter.tick(Instant::now());
+        for alert in meter.take_alerts() {
+            warn!("{}", alert);
+        }
+    }
+}
+
+/// Flush the pipeline at the end of the capture and print its statistics.
+fn finish_pipeline(pipeline: &mut Pipeline, metadata: &mut Metadata) -> Result<(), Error> {
+    pipeline.finish()?;
+    for event in pipeline.take_events() {
+        eprintln!("{}", event);
+        metadata.stream_events.push(event);
+    }
+    for sink in pipeline.outputs().sinks() {
+        let stats = sink.stats();
+        eprintln!(
+            "Output {}: {} bytes written, queue full {} times, {} bytes dropped",
+            sink.name(),
+            stats.written_bytes.load(Ordering::Relaxed),
+            stats.queue_full.load(Ordering::Relaxed),
+            stats.dropped_bytes.load(Ordering::Relaxed)
+        );
+        if let Some(connection) = sink.connection() {
+            eprintln!(
+                "Output {}: {} connections, {} disconnections, {} failed attempts",
+                sink.name(),
+                connection.connects.load(Ordering::Relaxed),
+                connection.disconnects.load(Ordering::Relaxed),
+                connection.failed_attempts.load(Ordering::Relaxed)
+            );
+        }
+    }
+    eprintln!(
+        "Timestamp discontinuities: {}",
+        pipeline.analyzer().timestamp_discontinuities()
+    );
+    eprintln!(
+        "Continuity errors: {}",
+        pipeline.analyzer().continuity_errors()
+    );
+    if let Some(fixes) = pipeline.continuity_fixes() {
+        eprintln!("Losses marked in the outputs by --fix-cc: {}", fixes);
+    }
+    let analyzer = pipeline.analyzer();
+    for (name, counts) in [
+        ("Scrambled packets", analyzer.scrambled_packets()),
+        ("Transport errors (TEI)", analyzer.transport_errors()),
+    ] {
+        eprint!("{}: {}", name, counts.values().sum::<u64>());
+        for (pid, count) in counts {
+            eprint!(", {} on PID {:#06x}", count, pid);
+        }
+        eprintln!();
+    }
+    Ok(())
+}
+
+fn open_outputs(args: &CaptureArgs, timing: Option<&DebugTiming>) -> Result<FanOut, Error> {
+    let mut outputs = FanOut::new();
+    let default = [PathBuf::from("-")];
+    let paths = if args.output.is_empty() && args.snapshot.is_some() && !args.snapshot_on_signal {
+        // Only the snapshot is wanted.
+        &[][..]
+    } else if args.output.is_empty() && !has_network_outputs(args) {
+        &default[..]
+    } else {
+        &args.output[..]
+    };
+    for path in paths {
+        let sink = if path.as_os_str() == "-" {
+            QueuedSink::spawn(
+                "stdout",
+                Box::new(StreamOutput(std::io::stdout())),
+                args.queue_size,
+                args.overflow,
+                timing,
+            )
+        } else {
+            let name = path.to_string_lossy();
+            let output = if args.append {
+                FileOutput::append(&name, args.force, outputs.events(), args.mark_seam)
+            } else if args.atomic {
+                let resume = handle_leftover_parts(&name, args.leftover_parts, &outputs)?;
+                FileOutput::create_atomic(&name, args.force, outputs.events(), resume.as_deref())
+            } else {
+                FileOutput::create(&name, args.force)
+            };
+            let output = output
+                .and_then(|output| {
+                    if args.index {
+                        output.with_index()
+                    } else {
+                        Ok(output)
+                    }
+                })
+                .map_err(|e| match e.kind() {
+                    std::io::ErrorKind::AlreadyExists => Error::OutputExists(path.clone()),
+                    _ => e.into(),
+                })?;
+            QueuedSink::spawn(
+                &name,
+                Box::new(output),
+                args.queue_size,
+                args.overflow,
+                timing,
+            )
         };
-        print_resp_data("Remote timestamp", &respbuf[0..recvd]);
-        thread::sleep(Duration::from_secs(10));
-        now = Instant::now();
+        outputs.add(sink);
+    }
+    let options = TcpOptions {
+        timeout: Duration::from_secs_f64(args.connect_timeout.max(0.001)),
+        nodelay: args.tcp_nodelay,
+        max_attempts: Some(args.connect_attempts).filter(|_| args.connect_required),
+    };
+    for address in &args.connect {
+        outputs.add(QueuedSink::spawn(
+            address,
+            Box::new(TcpOutput::new(address, options.clone())),
+            args.queue_size,
+            args.overflow,
+            timing,
+        ));
+    }
+    #[cfg(feature = "rtmp")]
+    for url in &args.rtmp {
+        outputs.add(QueuedSink::spawn(
+            &url.to_string(),
+            Box::new(RtmpOutput::new(
+                url.clone(),
+                options.timeout,
+                options.max_attempts,
+            )),
+            args.queue_size,
+            args.overflow,
+            timing,
+        ));
+    }
+    Ok(outputs)
+}
+
+/// Whether the stream is sent over the network, in which case stdout is
+/// not the default output.
+fn has_network_outputs(args: &CaptureArgs) -> bool {
+    #[cfg(feature = "rtmp")]
+    if !args.rtmp.is_empty() {
+        return true;
+    }
+    !args.connect.is_empty()
+}
+
+/// Refuse to start when an output file would replace an existing one, or
+/// could not be appended to, before anything is sent to the device.
+/// `open_outputs` checks again when creating the files, in case one appeared
+/// meanwhile.
+fn check_outputs(args: &CaptureArgs) -> Result<(), Error> {
+    if args.force && !args.append {
+        return Ok(());
+    }
+    for path in &args.output {
+        if path.as_os_str() == "-" {
+            continue;
+        }
+        let path = sink::expand_template(&path.to_string_lossy(), 0, SystemTime::now());
+        if args.append {
+            sink::append_point(&path)?;
+            continue;
+        }
+        if path.exists() {
+            return Err(Error::OutputExists(path));
+        }
+        let part = sink::part_path(&path);
+        if args.atomic && args.leftover_parts == LeftoverPolicy::Keep && part.exists() {
+            return Err(Error::OutputExists(part));
+        }
+    }
+    Ok(())
+}
+
+/// Apply `policy` to the partial files left by a previous atomic write of
+/// `template`, returning the one to resume.
+fn handle_leftover_parts(
+    template: &str,
+    policy: LeftoverPolicy,
+    outputs: &FanOut,
+) -> Result<Option<PathBuf>, Error> {
+    let mut parts = sink::leftover_parts(template)?;
+    let resume = match policy {
+        LeftoverPolicy::Resume => parts.pop(),
+        _ => None,
+    };
+    if let Some(part) = &resume {
+        outputs
+            .events()
+            .lock()
+            .unwrap()
+            .push(Event::PartialOutputResumed {
+                path: part.display().to_string(),
+                time: format_utc(SystemTime::now()),
+            });
+    }
+    for part in parts {
+        let recovered = sink::recovered_path(&part);
+        if policy != LeftoverPolicy::Recover || recovered.exists() {
+            warn!(
+                "{} was left by an interrupted capture, leaving it alone",
+                part.display()
+            );
+            continue;
+        }
+        std::fs::rename(&part, &recovered)?;
+        outputs.events().lock().unwrap().push(Event::OutputRenamed {
+            from: part.display().to_string(),
+            to: recovered.display().to_string(),
+            time: format_utc(SystemTime::now()),
+        });
     }
+    Ok(resume)
 }
 
-fn main() -> Result<(), Error> {
-    const USB_TIMEOUT: Duration = Duration::from_secs(2);
-    let devhnd = if let Some(hnd) = rusb::open_device_with_vid_pid(0x048d, 0x9910) {
-        Arc::new(Mutex::new(hnd))
+/// Wall clock time of a byte offset, formatted to be appended to a log
+/// message.
+fn wall_clock_note(clock: &Mutex<ClockModel>, offset: u64) -> String {
+    match clock.lock().unwrap().offset_to_wall(offset) {
+        Some(t) => format!(" ({})", format_utc(t)),
+        None => String::new(),
+    }
+}
+
+/// PC grabber configuration entries: those of the configuration file, or
+/// else those of the firmware revision.
+fn grabber_entries(config: &Config, version: Option<FirmwareVersion>) -> Vec<GrabberEntry> {
+    match &config.grabber_entries {
+        Some(entries) => entries.clone(),
+        None => firmware::grabber_entries_for(version).to_vec(),
+    }
+}
+
+fn info(
+    device: &Device,
+    config: &Config,
+    quirk_overrides: Option<&str>,
+    full: bool,
+) -> Result<(), Error> {
+    device.claim()?;
+    let mut factory = device.factory();
+    let (version, quirks) = detect_firmware(device, quirk_overrides)?;
+    match version {
+        Some(v) => println!("Firmware version: {}", v),
+        None => println!("Firmware version: unknown"),
+    }
+    println!(
+        "Known firmware: {}",
+        version.and_then(|v| v.known()).map_or("no", |fw| fw.name)
+    );
+    println!("Quirks: {}", quirks);
+    let resp = device.transact(&factory.make_get_profile())?;
+    println!("Profile: {:02x?}", resp.payload);
+    let resp = device.transact(&factory.make_get_source())?;
+    println!("Source: {:02x?}", resp.payload);
+    if full {
+        for i in grabber_entries(config, version).iter().map(|e| e.index) {
+            match GrabberConfig::read(device, i) {
+                Ok(Some(config)) => println!("PC grabber entry {}: {}", i, config),
+                Ok(None) | Err(_) => {
+                    println!("PC grabber configuration: readback not supported");
+                    break;
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Ask the user to confirm on the terminal.
+fn confirm(question: &str) -> bool {
+    if !std::io::stdin().is_terminal() {
+        eprintln!("Not asking for confirmation without a terminal, use --yes");
+        return false;
+    }
+    eprint!("{} Type `yes` to continue: ", question);
+    let mut answer = String::new();
+    std::io::stdin().read_line(&mut answer).is_ok() && answer.trim() == "yes"
+}
+
+fn firmware_upload(
+    device: &Device,
+    file: &Path,
+    opcode: u16,
+    yes: bool,
+) -> Result<WriteOutcome, Error> {
+    let image = std::fs::read(file)?;
+    warn!(
+        "Uploading {} ({} bytes) with opcode {:#06x}.",
+        file.display(),
+        image.len(),
+        opcode
+    );
+    warn!("A wrong or interrupted upload may leave the device unusable.");
+    if !yes && !confirm("Upload to the device?") {
+        eprintln!("Upload cancelled");
+        return Ok(WriteOutcome::NotConfirmed);
+    }
+    device.claim()?;
+    let progress = std::io::stderr().is_terminal();
+    let mut shown = Instant::now();
+    Uploader::new(opcode).upload(device, &image, |done| {
+        if progress && (shown.elapsed() >= Duration::from_millis(200) || done == image.len()) {
+            eprint!("\rUploaded {} / {} bytes", done, image.len());
+            shown = Instant::now();
+        }
+    })?;
+    if progress {
+        eprintln!();
+    }
+    eprintln!("Upload complete, checking the firmware status...");
+    match FirmwareVersion::query(device)? {
+        Some(v) => eprintln!("Firmware version: {}", v),
+        None => {
+            eprintln!("The device did not return a valid firmware status after the upload");
+            return Ok(WriteOutcome::NotVerified);
+        }
+    }
+    Ok(WriteOutcome::Done)
+}
+
+fn edid_uploader(opcode: u16) -> Uploader {
+    Uploader {
+        chunk_size: edid::BLOCK_SIZE,
+        ..Uploader::new(opcode)
+    }
+}
+
+/// Read the base block, then the extension blocks it announces.
+fn edid_read(device: &Device, opcode: u16) -> Result<Edid, Error> {
+    let uploader = edid_uploader(opcode);
+    let base = uploader.download(device, edid::BLOCK_SIZE)?;
+    let len = Edid::announced_len(&base).unwrap_or(edid::BLOCK_SIZE);
+    let data = if len > base.len() {
+        uploader.download(device, len)?
     } else {
-        println!("No device found.");
-        exit(1);
+        base
     };
+    Ok(Edid::parse(&data)?)
+}
 
-    {
-        let mut devhnd = devhnd.lock().unwrap();
-        devhnd.reset()?;
-        devhnd.claim_interface(0)?;
-        devhnd.set_alternate_setting(0, 0)?;
-        devhnd.clear_halt(0x81)?;
-        devhnd.clear_halt(0x83)?;
-    }
-
-    let mut respbuf = [0u8; 0x200];
-    let mut factory = CommandFactory::new();
-    let recvd = {
-        let devhnd = devhnd.lock().unwrap();
-        devhnd.write_bulk(2, &factory.make_get_profile(), USB_TIMEOUT)?;
-        devhnd.read_bulk(0x81, &mut respbuf, USB_TIMEOUT)?
+fn edid_write(
+    device: &Device,
+    file: &Path,
+    opcode: u16,
+    confirm: bool,
+) -> Result<WriteOutcome, Error> {
+    let edid = Edid::parse(&std::fs::read(file)?)?;
+    if !confirm {
+        eprintln!(
+            "Not replacing the EDID without --confirm. The new EDID would be:\n{}",
+            edid
+        );
+        return Ok(WriteOutcome::NotConfirmed);
+    }
+    edid_uploader(opcode).upload(device, edid.data(), |_| ())?;
+    let written = edid_read(device, opcode)?;
+    if written.data() != edid.data() {
+        eprintln!(
+            "The EDID read back from the device differs from {}",
+            file.display()
+        );
+        return Ok(WriteOutcome::NotVerified);
+    }
+    eprintln!("EDID written and verified");
+    Ok(WriteOutcome::Done)
+}
+
+fn dump_config(config: &Config, args: &CaptureArgs) -> Result<(), Error> {
+    fn to_toml<T: serde::Serialize>(value: &T) -> Result<String, Error> {
+        toml::to_string(value).map_err(|e| Error::Config(e.to_string()))
+    }
+    print!("{}", to_toml(&config.effective())?);
+    // Without the device, the source is looked up among all the known ones.
+    let known = Available {
+        sources: VIDEO_SOURCES.to_vec(),
+        decoded: false,
     };
-    print_resp_data("Profile", &respbuf[0..recvd]);
-    //let timer_hnd = {
-    //    let factory = factory.clone();
-    //    let devhnd = devhnd.clone();
-    //    thread::spawn(move || {
-    //        timer_thread(devhnd, factory);
-    //    })
-    //};
-    let recvd = {
-        let devhnd = devhnd.lock().unwrap();
-        devhnd.write_bulk(2, &factory.make_get_source(), USB_TIMEOUT)?;
-        devhnd.read_bulk(0x81, &mut respbuf, USB_TIMEOUT)?
+    let source = match &args.video_source {
+        Some(name) => Some(known.find(name).map_err(Error::Config)?.name),
+        None => None,
     };
-    print_resp_data("Source", &respbuf[0..recvd]);
-    //    let recvd = {
-    //        let devhnd = devhnd.lock().unwrap();
-    //        devhnd.write_bulk(2, &factory.make_get_firmware_status(), USB_TIMEOUT)?;
-    //        devhnd.read_bulk(0x81, &mut respbuf, USB_TIMEOUT)?
-    //    };
-    //    print_resp_data("Firmware status", &respbuf[0..recvd]);
-    //    eprintln!("Setting initial PC grabber...");
-
-    let recvd = {
-        let devhnd = devhnd.lock().unwrap();
-        devhnd.write_bulk(2, &factory.make_set_pc_grabber_small(false), USB_TIMEOUT)?;
-        devhnd.read_bulk(0x81, &mut respbuf, USB_TIMEOUT)?
+    let capture = capture_settings(args, config, source);
+    println!("\n# Settings of the capture");
+    println!("[capture]");
+    print!("{}", to_toml(&capture)?);
+    let stream1 = stream1_settings(args, config);
+    if !stream1.is_empty() {
+        println!("\n[capture.stream1]");
+        print!("{}", to_toml(&stream1)?);
+    }
+    Ok(())
+}
+
+fn list_sources(device: &Device) -> Result<(), Error> {
+    device.claim()?;
+    let caps = Capabilities::query(device)?;
+    for (kind, available) in [("Video", &caps.video), ("Audio", &caps.audio)] {
+        println!("{} sources:", kind);
+        for source in &available.sources {
+            println!("  {}", source);
+        }
+        if !available.decoded {
+            println!("  (the inputs of this device are not known, all sources are listed)");
+        }
+    }
+    Ok(())
+}
+
+fn send_command(
+    device: &Device,
+    opcode: u16,
+    operation: Operation,
+    payload: &[u8],
+) -> Result<(), Error> {
+    let cmd = device.factory().make_command(opcode, operation, payload);
+    eprintln!("Sending {:#06x} {}: {:02x?}", opcode, operation, payload);
+    match device.transact(&cmd) {
+        Ok(resp) => println!("Answer {}: {:02x?}", operation, resp.payload),
+        // Any other operation in the answer comes out as a rejection.
+        Err(Error::DeviceRejected {
+            status, payload, ..
+        }) => println!("Answer {}: {:02x?}", Operation::from(status), payload),
+        Err(e) => return Err(e),
+    }
+    Ok(())
+}
+
+fn settings_dump(device: &Device, file: Option<PathBuf>) -> Result<(), Error> {
+    let text = settings::format(&settings::read_all(device));
+    match file {
+        Some(path) => std::fs::write(path, text)?,
+        None => print!("{}", text),
+    }
+    Ok(())
+}
+
+/// Returns whether differences were found.
+fn settings_diff(device: &Device, file: Option<PathBuf>) -> Result<bool, Error> {
+    let reference = match file {
+        Some(path) => Some(settings::parse(&std::fs::read_to_string(path)?)?),
+        None => None,
     };
-    print_resp_data("Returned PC grabber state", &respbuf[0..recvd]);
-
-    // Alter some settings _before_ starting capture
-    /*{
-        let devhnd = devhnd.lock().unwrap();
-        devhnd.write_bulk(2, &factory.make_set_brightness(0), USB_TIMEOUT)?;
-        devhnd.read_bulk(0x81, &mut respbuf, USB_TIMEOUT)?;
-        devhnd.write_bulk(2, &factory.make_set_contrast(100), USB_TIMEOUT)?;
-        devhnd.read_bulk(0x81, &mut respbuf, USB_TIMEOUT)?;
-        devhnd.write_bulk(2, &factory.make_set_hue(0), USB_TIMEOUT)?;
-        devhnd.read_bulk(0x81, &mut respbuf, USB_TIMEOUT)?;
-        devhnd.write_bulk(2, &factory.make_set_saturation(100), USB_TIMEOUT)?;
-        devhnd.read_bulk(0x81, &mut respbuf, USB_TIMEOUT)?;
-    }*/
-
-    let recvd = {
-        let devhnd = devhnd.lock().unwrap();
-        devhnd.write_bulk(2, &factory.make_set_pc_grabber_small(true), USB_TIMEOUT)?;
-        devhnd.read_bulk(0x81, &mut respbuf, USB_TIMEOUT)?
+    let values = settings::read_all(device);
+    let mut changed = false;
+    for diff in settings::diff(&values, reference.as_ref()) {
+        match diff.device {
+            Some(v) => println!("{}: device={} reference={}", diff.name, v, diff.reference),
+            None => println!("{}: device=unknown reference={}", diff.name, diff.reference),
+        }
+        changed |= diff.is_change();
+    }
+    Ok(changed)
+}
+
+/// Run the stream analysis over a file, or stdin for `-`, feeding the data
+/// to `index` as well if given.
+fn read_report(file: &Path, mut index: Option<&mut SeekIndex>) -> Result<Report, Error> {
+    let (mut input, size): (Box<dyn Read>, Option<u64>) = if file.as_os_str() == "-" {
+        (Box::new(std::io::stdin()), None)
+    } else {
+        let f = File::open(file)?;
+        let size = f.metadata()?.len();
+        (Box::new(f), Some(size))
     };
-    print_resp_data("Returned PC grabber state", &respbuf[0..recvd]);
-    eprintln!("Waiting for PC grabber...");
-    wait_pc_grabber_ready(devhnd.clone(), &mut factory)?;
-    eprintln!("Setting PC grabber state...");
-    for i in 0u32..=21u32 {
-        let recvd = {
-            let devhnd = devhnd.lock().unwrap();
-            devhnd.write_bulk(2, &factory.make_set_pc_grabber(i), USB_TIMEOUT)?;
-            devhnd.read_bulk(0x81, &mut respbuf, USB_TIMEOUT)?
+    let progress = std::io::stderr().is_terminal();
+    let mut builder = ReportBuilder::new();
+    let mut buf = vec![0u8; 1 << 16];
+    let mut done = 0u64;
+    let mut shown = Instant::now();
+    loop {
+        let len = match input.read(&mut buf) {
+            Ok(0) => break,
+            Ok(len) => len,
+            Err(e) if e.kind() == std::io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e.into()),
         };
+        builder.push(&buf[..len]);
+        if let Some(index) = index.as_mut() {
+            index.push(&buf[..len])?;
+        }
+        done += len as u64;
+        if progress && shown.elapsed() >= Duration::from_millis(200) {
+            match size {
+                Some(size) if size > 0 => eprint!("\r{:.0}%", done as f64 * 100.0 / size as f64),
+                _ => eprint!("\r{} MB", done / 1_000_000),
+            }
+            shown = Instant::now();
+        }
     }
-    eprintln!("Starting capture...");
-    let recvd = {
-        let devhnd = devhnd.lock().unwrap();
-        devhnd.write_bulk(2, &factory.make_set_state(0x2), USB_TIMEOUT)?;
-        devhnd.read_bulk(0x81, &mut respbuf, USB_TIMEOUT)?
-    };
-    print_resp_data("State", &respbuf[0..recvd]);
-    let recvd = {
-        let devhnd = devhnd.lock().unwrap();
-        devhnd.write_bulk(2, &factory.make_set_pc_grabber_large(), USB_TIMEOUT)?;
-        devhnd.read_bulk(0x81, &mut respbuf, USB_TIMEOUT)?
+    if progress {
+        eprint!("\r\x1b[K");
+    }
+    Ok(builder.finish())
+}
+
+fn analyze(file: &Path, json: bool, index: bool) -> Result<(), Error> {
+    if index && file.as_os_str() == "-" {
+        let msg = "--index needs a file, not stdin";
+        return Err(std::io::Error::new(std::io::ErrorKind::InvalidInput, msg).into());
+    }
+    let mut index = match index {
+        true => Some(SeekIndex::create(&index::index_path(file))?),
+        false => None,
     };
+    let report = read_report(file, index.as_mut())?;
+    if let Some(index) = index.as_mut() {
+        index.finish()?;
+    }
+    if json {
+        println!(
+            "{}",
+            serde_json::to_string_pretty(&report).map_err(std::io::Error::from)?
+        );
+    } else {
+        print!("{}", report);
+    }
+    Ok(())
+}
 
-    loop {
-        const TS_TIMEOUT: Duration = Duration::from_secs(1);
-        let mut tsbuf = vec![0u8; 0x4000];
-        let recvd = {
-            let mut devhnd = devhnd.lock().unwrap();
-            let res = devhnd.read_bulk(0x83, &mut tsbuf, TS_TIMEOUT);
-            match res {
-                Err(rusb::Error::Timeout) => {
-                    eprintln!("Timeout");
-                    continue;
-                },
-                Err(e) => {
-                    eprintln!("Failed to read TS stream: {}", &e);
-                    break;
+/// Returns whether the file passed.
+fn validate(file: &Path, thresholds: &Thresholds) -> Result<bool, Error> {
+    let report = read_report(file, None)?;
+    let failures = validate::check(&report, thresholds);
+    if failures.is_empty() {
+        println!("PASS {}", file.display());
+    } else {
+        println!("FAIL {}: {}", file.display(), failures.join("; "));
+    }
+    Ok(failures.is_empty())
+}
+
+fn run(cli: Cli) -> Result<i32, Error> {
+    let config = match &cli.config {
+        Some(path) => Config::load(path)?,
+        None => Config::default(),
+    };
+    if cli.dump_config {
+        dump_config(&config, &cli.capture)?;
+        return Ok(0);
+    }
+    if cli.capture.input_file.is_some() && cli.command.is_some() {
+        return Err(Error::Config(
+            "--input-file only replaces the device for the capture".to_string(),
+        ));
+    }
+    // The subcommands which do not need the device are run first, so that
+    // only the device commands are left once it is opened.
+    let command = match cli.command {
+        None => None,
+        Some(Command::Device(DeviceCommand::Edid(EdidCommand::Show {
+            file: Some(file), ..
+        }))) => {
+            print!("{}", Edid::parse(&std::fs::read(file)?)?);
+            return Ok(0);
+        }
+        Some(Command::Device(command)) => Some(command),
+        Some(Command::Analyze { file, json, index }) => {
+            analyze(&file, json, index)?;
+            return Ok(0);
+        }
+        Some(Command::Validate {
+            file,
+            max_cc_errors,
+            max_gaps,
+            max_scrambled,
+            max_tei,
+        }) => {
+            let mut thresholds = config.validate.clone();
+            if let Some(max) = max_cc_errors {
+                thresholds.max_cc_errors = max;
+            }
+            if let Some(max) = max_gaps {
+                thresholds.max_gaps = max;
+            }
+            if let Some(max) = max_scrambled {
+                thresholds.max_scrambled = max;
+            }
+            if let Some(max) = max_tei {
+                thresholds.max_tei = max;
+            }
+            return Ok(if validate(&file, &thresholds)? { 0 } else { 1 });
+        }
+        Some(Command::Protocol(ProtocolCommand::Export { format })) => {
+            match format {
+                ExportFormat::Json => println!(
+                    "{}",
+                    serde_json::to_string_pretty(&protocol::to_json()).unwrap()
+                ),
+                ExportFormat::CHeader => print!("{}", protocol::to_c_header()),
+            }
+            return Ok(0);
+        }
+        Some(Command::Probe { json }) => {
+            let probe = if cli.emulate {
+                probe::probe(&Device::with_transport(Arc::new(Emulator::new(
+                    EmulatorConfig::default(),
+                ))))?
+            } else {
+                probe::probe_usb()?
+            };
+            if json {
+                println!(
+                    "{}",
+                    serde_json::to_string_pretty(&probe).map_err(std::io::Error::from)?
+                );
+            } else {
+                print!("{}", probe);
+            }
+            return Ok(match probe.readiness {
+                Readiness::Ready => 0,
+                Readiness::NoSignal => EXIT_PROBE_NO_SIGNAL,
+                Readiness::NotReady => EXIT_PROBE_NOT_READY,
+                Readiness::NoDevice | Readiness::NoAccess => EXIT_PROBE_NO_DEVICE,
+            });
+        }
+    };
+    if command.is_none() {
+        check_outputs(&cli.capture)?;
+    }
+    if let Some(input) = &cli.capture.input_file {
+        return Ok(match replay(&cli.capture, input)? {
+            CaptureEnd::NoData => EXIT_NO_DATA,
+            _ => 0,
+        });
+    }
+    let device = if cli.emulate {
+        let config = EmulatorConfig {
+            bitrate: cli.emulate_bitrate * 1000,
+            ..Default::default()
+        };
+        Device::with_transport(Arc::new(Emulator::new(config)))
+    } else {
+        Device::open()?
+    };
+    match command {
+        None => match capture(&device, &cli.capture, &config, cli.quirks.as_deref())? {
+            CaptureEnd::NoData => return Ok(EXIT_NO_DATA),
+            CaptureEnd::DeviceLost => return Ok(EXIT_DEVICE_LOST),
+            CaptureEnd::StreamError | CaptureEnd::Snapshot | CaptureEnd::EndOfInput => (),
+        },
+        Some(DeviceCommand::Info { full }) => info(&device, &config, cli.quirks.as_deref(), full)?,
+        Some(DeviceCommand::ListSources) => list_sources(&device)?,
+        Some(DeviceCommand::Firmware(FirmwareCommand::Upload {
+            file, opcode, yes, ..
+        })) => {
+            return Ok(firmware_upload(&device, &file, opcode, yes)?.exit_code());
+        }
+        Some(DeviceCommand::Edid(cmd)) => {
+            device.claim()?;
+            match cmd {
+                EdidCommand::Read { file, opcode } => {
+                    std::fs::write(file, edid_read(&device, opcode)?.data())?
+                }
+                EdidCommand::Write {
+                    file,
+                    opcode,
+                    confirm,
+                } => {
+                    return Ok(edid_write(&device, &file, opcode, confirm)?.exit_code());
+                }
+                EdidCommand::Show {
+                    opcode: Some(opcode),
+                    ..
+                } => print!("{}", edid_read(&device, opcode)?),
+                EdidCommand::Show { opcode: None, .. } => {
+                    return Err(Error::Config(
+                        "edid show needs a FILE or --opcode".to_string(),
+                    ))
+                }
+            }
+        }
+        Some(DeviceCommand::Send {
+            opcode,
+            operation,
+            payload,
+        }) => {
+            device.claim()?;
+            send_command(&device, opcode, operation, &payload.0)?;
+        }
+        Some(DeviceCommand::Settings(cmd)) => {
+            device.claim()?;
+            match cmd {
+                SettingsCommand::Dump { file } => settings_dump(&device, file)?,
+                SettingsCommand::Diff { file } => {
+                    if settings_diff(&device, file)? {
+                        return Ok(EXIT_SETTINGS_DIFFER);
+                    }
                 }
-                Ok(len) => len
             }
+        }
+    }
+    Ok(0)
+}
+
+fn main() {
+    let cli = Cli::parse();
+    log::set_logger(&StderrLogger).unwrap();
+    log::set_max_level(if cli.verbose {
+        log::LevelFilter::Debug
+    } else {
+        log::LevelFilter::Info
+    });
+    match run(cli) {
+        Ok(code) => exit(code),
+        Err(Error::NoDevice) => {
+            println!("No device found.");
+            exit(1);
+        }
+        Err(e) => {
+            eprintln!("{}", e);
+            exit(2);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use it9910_stream_example::emulator::TsGenerator;
+    use it9910_stream_example::ts::PACKET_SIZE;
+
+    #[test]
+    fn replay_writes_the_input_unchanged() {
+        let dir = std::env::temp_dir().join(format!("it9910-replay-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        let (input, output) = (dir.join("in.ts"), dir.join("out.ts"));
+        // The last read is shorter than the buffer, which holds the end of
+        // the previous one.
+        let mut generator = TsGenerator::new(2_000_000);
+        let data: Vec<u8> = (0..1000).flat_map(|_| generator.next_packet()).collect();
+        assert_ne!(data.len() % STREAM_CHUNK_SIZE, 0);
+        assert_ne!(STREAM_CHUNK_SIZE % PACKET_SIZE, 0);
+        std::fs::write(&input, &data).unwrap();
+
+        let cli = Cli::try_parse_from([
+            "it9910-stream-example".as_ref(),
+            "--input-file".as_ref(),
+            input.as_os_str(),
+            "-o".as_ref(),
+            output.as_os_str(),
+        ])
+        .unwrap();
+        let file = cli.capture.input_file.as_ref().unwrap();
+        let end = replay(&cli.capture, file).unwrap();
+        assert!(matches!(end, CaptureEnd::EndOfInput));
+        assert_eq!(std::fs::read(&output).unwrap(), data);
+    }
+
+    #[test]
+    fn send_takes_any_operation() {
+        let parse = |argv: &[&str]| match Cli::try_parse_from(argv).map(|cli| cli.command) {
+            Ok(Some(Command::Device(DeviceCommand::Send {
+                opcode,
+                operation,
+                payload,
+            }))) => Ok((opcode, operation, payload.0)),
+            Ok(_) => panic!("not a send command"),
+            Err(e) => Err(e),
         };
-        {
-            let stdout = std::io::stdout();
-            let mut handle = stdout.lock();
-            handle.write_all(&tsbuf[..recvd])?;
+        let cmd = ["it9910-stream-example", "send", "0x0101"];
+        assert_eq!(parse(&cmd).unwrap(), (0x0101, Operation::Get, vec![]));
+        let with = |args: &[&str]| parse(&[&cmd[..], args].concat());
+        assert_eq!(
+            with(&["--operation", "0x10", "--payload", "02ff"]).unwrap(),
+            (0x0101, Operation::Raw(0x10), vec![0x02, 0xff])
+        );
+        assert_eq!(with(&["--operation", "3"]).unwrap().1, Operation::Commit);
+        assert!(with(&["--operation", "apply"]).is_err());
+        assert!(with(&["--payload", "2ff"]).is_err());
+        assert!(with(&["--payload", "zz"]).is_err());
+    }
+
+    /// Partial files of the template `out-{n}.ts` in a directory for test
+    /// `name`, the oldest first.
+    fn leftover_parts(name: &str) -> (PathBuf, String) {
+        let dir = std::env::temp_dir().join(format!("it9910-{}-{}", name, std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        let start = SystemTime::now();
+        for (i, age) in [30, 20, 10].iter().enumerate() {
+            let part = dir.join(format!("out-000{}.ts.part", i));
+            let file = std::fs::File::create(part).unwrap();
+            file.set_modified(start - Duration::from_secs(*age))
+                .unwrap();
         }
+        let template = dir.join("out-{n}.ts").to_string_lossy().into_owned();
+        (dir, template)
+    }
+
+    #[test]
+    fn leftover_parts_are_recovered() {
+        let (dir, template) = leftover_parts("recover");
+        std::fs::write(dir.join("out-0001-recovered.ts"), b"kept").unwrap();
+        let outputs = FanOut::new();
+        let resume = handle_leftover_parts(&template, LeftoverPolicy::Recover, &outputs).unwrap();
+        assert_eq!(resume, None);
+        assert!(dir.join("out-0000-recovered.ts").exists());
+        assert!(dir.join("out-0002-recovered.ts").exists());
+        // Not over an earlier recovery.
+        assert!(dir.join("out-0001.ts.part").exists());
+        assert_eq!(
+            std::fs::read(dir.join("out-0001-recovered.ts")).unwrap(),
+            b"kept"
+        );
+        let events = outputs.take_events();
+        assert_eq!(events.len(), 2);
+        assert!(events
+            .iter()
+            .all(|e| matches!(e, Event::OutputRenamed { .. })));
+    }
+
+    #[test]
+    fn latest_leftover_part_is_resumed() {
+        let (dir, template) = leftover_parts("resume");
+        let outputs = FanOut::new();
+        let resume = handle_leftover_parts(&template, LeftoverPolicy::Resume, &outputs).unwrap();
+        assert_eq!(resume, Some(dir.join("out-0002.ts.part")));
+        // The others are left alone.
+        assert!(dir.join("out-0000.ts.part").exists());
+        assert!(dir.join("out-0001.ts.part").exists());
+        match &outputs.take_events()[..] {
+            [Event::PartialOutputResumed { path, .. }] => {
+                assert_eq!(Path::new(path), dir.join("out-0002.ts.part"))
+            }
+            other => panic!("unexpected events {:?}", other),
+        }
+    }
+
+    #[test]
+    fn flags_win_over_the_source_bundle() {
+        let mut config = Config::default();
+        config.sources.insert(
+            "composite".to_string(),
+            SourceProfile {
+                brightness: Some(5),
+                contrast: Some(7),
+                ..Default::default()
+            },
+        );
+        config.stream0.keyframe_rate = Some(15);
+        let cli = Cli::try_parse_from(["it9910-stream-example"]).unwrap();
+        let settings = capture_settings(&cli.capture, &config, Some("composite"));
+        assert_eq!(settings.quality, Some(70));
+        assert_eq!(settings.brightness, Some(5));
+        assert_eq!(settings.keyframe_rate, Some(15));
+
+        let cli = Cli::try_parse_from([
+            "it9910-stream-example",
+            "--quality",
+            "50",
+            "--brightness",
+            "30",
+        ])
+        .unwrap();
+        let settings = capture_settings(&cli.capture, &config, Some("composite"));
+        assert_eq!(settings.quality, Some(50));
+        assert_eq!(settings.brightness, Some(30));
+        assert_eq!(settings.contrast, Some(7));
+        assert_eq!(settings.keyframe_rate, Some(15));
+        // Without a source, only the flags and [stream0].
+        let settings = capture_settings(&cli.capture, &config, None);
+        assert_eq!(settings.contrast, None);
+        assert_eq!(settings.quality, Some(50));
+    }
+
+    /// Arguments of a replay to a file with `args`, whose snapshot request
+    /// expires at once.
+    fn expired_snapshot(name: &str, args: &[&str]) -> Cli {
+        let dir = std::env::temp_dir().join(format!("it9910-{}-{}", name, std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        let mut argv = vec!["it9910-stream-example".to_string()];
+        for (option, file) in [
+            ("--input-file", "in.ts"),
+            ("--snapshot", "frame.h264"),
+            ("-o", "out.ts"),
+        ] {
+            argv.push(option.to_string());
+            argv.push(dir.join(file).display().to_string());
+        }
+        argv.extend(["--snapshot-timeout", "0"].iter().map(|a| a.to_string()));
+        argv.extend(args.iter().map(|a| a.to_string()));
+        Cli::try_parse_from(argv).unwrap()
+    }
+
+    fn open_loop(cli: &Cli) -> StreamLoop<'_> {
+        let clock = Arc::new(Mutex::new(ClockModel::new()));
+        StreamLoop::open(&cli.capture, clock, Metadata::default()).unwrap()
+    }
+
+    #[test]
+    fn snapshot_timeout_ends_a_one_shot_capture() {
+        let cli = expired_snapshot("snapshot-once", &[]);
+        let mut stream = open_loop(&cli);
+        assert!(matches!(stream.tick(), Err(Error::NoKeyframe(0))));
+        stream.finish().unwrap();
+    }
+
+    #[test]
+    fn snapshot_timeout_keeps_a_running_capture() {
+        let cli = expired_snapshot("snapshot-signal", &["--snapshot-on-signal"]);
+        let mut stream = open_loop(&cli);
+        assert!(stream.pipeline.snapshot_pending());
+        stream.tick().unwrap();
+        assert!(stream.snapshot_requested.is_none());
+        assert!(!stream.pipeline.snapshot_pending());
+        stream.finish().unwrap();
     }
-    Ok(())
 }
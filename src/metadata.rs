@@ -0,0 +1,45 @@
+//! Metadata sidecar written alongside a capture.
+
+use std::fs;
+use std::path::Path;
+
+use serde::Serialize;
+
+use crate::analysis::Event;
+use crate::clock::MappingEntry;
+use crate::error::Error;
+use crate::profile::StreamProfile;
+
+#[derive(Default, Serialize)]
+pub struct Metadata {
+    /// Wall clock time of the start of the capture.
+    pub started: String,
+    /// Firmware version of the device, when known.
+    pub firmware: Option<String>,
+    /// Encoder settings of each stream, read back after the start.
+    pub streams: Vec<StreamProfile>,
+    /// Correspondence between byte offsets, PCR and wall clock.
+    pub time_mapping: Vec<MappingEntry>,
+    /// Problems found in the stream, with their byte offsets.
+    pub stream_events: Vec<Event>,
+    /// Number of automatic reboots of the device.
+    pub reboots: u32,
+    /// Number of times the device was reopened after its handle stopped
+    /// working.
+    pub reopens: u32,
+}
+
+impl Metadata {
+    /// Write the metadata as JSON.
+    ///
+    /// The file is replaced atomically so that readers never see a partial
+    /// write.
+    pub fn write(&self, path: &Path) -> Result<(), Error> {
+        let mut tmp = path.as_os_str().to_owned();
+        tmp.push(".tmp");
+        let json = serde_json::to_string_pretty(self).expect("metadata serialization");
+        fs::write(&tmp, json)?;
+        fs::rename(&tmp, path)?;
+        Ok(())
+    }
+}
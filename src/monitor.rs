@@ -0,0 +1,256 @@
+//! Background polling of the firmware status and of the input signal during
+//! capture.
+
+use std::collections::BTreeSet;
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime};
+
+use log::warn;
+
+use crate::device::Device;
+use crate::status::{FirmwareStatus, InputSignal, StatusError};
+use crate::worker::PeriodicWorker;
+
+/// An error state of the firmware, seen by `FirmwareMonitor`.
+#[derive(Clone, Copy, Debug)]
+pub struct FirmwareError {
+    pub time: SystemTime,
+    pub error: &'static StatusError,
+}
+
+/// Polls the firmware status and warns when it changes unexpectedly.
+///
+/// The error states known for the firmware revision are decoded, and queued
+/// for the capture loop when they appear. Of the other words, those which
+/// change on every one of the first polls are taken to be counters and are
+/// ignored afterwards.
+pub struct FirmwareMonitor {
+    worker: PeriodicWorker,
+    warnings: Arc<AtomicU32>,
+    errors: Arc<Mutex<Vec<FirmwareError>>>,
+}
+
+impl FirmwareMonitor {
+    /// Polls closer together than this would disturb the capture.
+    pub const MIN_INTERVAL: Duration = Duration::from_secs(1);
+    /// Number of polls used to find out the counters.
+    const LEARNING_POLLS: u32 = 3;
+
+    /// Start polling, `layout` being the error states of the firmware
+    /// revision.
+    pub fn start(
+        device: Device,
+        interval: Duration,
+        layout: &'static [StatusError],
+    ) -> FirmwareMonitor {
+        let interval = interval.max(Self::MIN_INTERVAL);
+        let warnings = Arc::new(AtomicU32::new(0));
+        let errors = Arc::new(Mutex::new(Vec::new()));
+        let worker = {
+            let warnings = warnings.clone();
+            let errors = errors.clone();
+            let mut factory = device.factory();
+            let mut previous: Option<FirmwareStatus> = None;
+            let mut polls = 0u32;
+            let mut volatile: Option<BTreeSet<usize>> = None;
+            PeriodicWorker::spawn("firmware-status", interval, move || {
+                let status = match device.transact(&factory.make_get_firmware_status()) {
+                    Ok(resp) => match FirmwareStatus::parse(&resp) {
+                        Some(status) => status,
+                        None => {
+                            warnings.fetch_add(1, Ordering::Relaxed);
+                            eprintln!("Unexpected firmware status response: {:02x?}", resp);
+                            return;
+                        }
+                    },
+                    Err(e) => {
+                        warnings.fetch_add(1, Ordering::Relaxed);
+                        eprintln!("Failed to query the firmware status: {}", e);
+                        return;
+                    }
+                };
+                polls += 1;
+                let flagged = status.errors(layout);
+                let before = previous
+                    .as_ref()
+                    .map(|prev| prev.errors(layout))
+                    .unwrap_or_default();
+                for error in flagged.iter().filter(|e| !before.contains(e)) {
+                    warnings.fetch_add(1, Ordering::Relaxed);
+                    warn!("Firmware error state: {}", error.name);
+                    errors.lock().unwrap().push(FirmwareError {
+                        time: SystemTime::now(),
+                        error,
+                    });
+                }
+                if let Some(prev) = &previous {
+                    let changed: BTreeSet<usize> =
+                        status.changes(prev).iter().map(|c| c.0).collect();
+                    if polls <= Self::LEARNING_POLLS {
+                        volatile = Some(match volatile.take() {
+                            None => changed,
+                            Some(v) => v.intersection(&changed).copied().collect(),
+                        });
+                    } else {
+                        let volatile = volatile.get_or_insert_with(BTreeSet::new);
+                        for (idx, old, new) in status.changes(prev) {
+                            if volatile.contains(&idx) || layout.iter().any(|e| e.word == idx) {
+                                continue;
+                            }
+                            warnings.fetch_add(1, Ordering::Relaxed);
+                            eprintln!(
+                                "Firmware status word {} changed: {:#010x} -> {:#010x}",
+                                idx, old, new
+                            );
+                        }
+                    }
+                }
+                previous = Some(status);
+            })
+        };
+        FirmwareMonitor {
+            worker,
+            warnings,
+            errors,
+        }
+    }
+
+    pub fn warnings(&self) -> u32 {
+        self.warnings.load(Ordering::Relaxed)
+    }
+
+    /// Error states which appeared since the last call.
+    pub fn take_errors(&self) -> Vec<FirmwareError> {
+        std::mem::take(&mut *self.errors.lock().unwrap())
+    }
+
+    pub fn stop(&mut self) {
+        self.worker.stop();
+    }
+}
+
+/// A change of the input signal seen by `SignalMonitor`.
+#[derive(Clone, Copy, Debug)]
+pub struct SignalChange {
+    pub time: SystemTime,
+    pub signal: InputSignal,
+}
+
+/// Polls the input signal and records its changes, for the capture loop to
+/// pick up.
+///
+/// The poll is a single profile GET through the shared command path, which
+/// the device answers without disturbing the stream.
+pub struct SignalMonitor {
+    worker: PeriodicWorker,
+    changes: Arc<Mutex<Vec<SignalChange>>>,
+}
+
+impl SignalMonitor {
+    pub const MIN_INTERVAL: Duration = Duration::from_millis(500);
+
+    /// Start polling, `initial` being the signal the capture was set up for.
+    pub fn start(
+        device: Device,
+        interval: Duration,
+        initial: Option<InputSignal>,
+    ) -> SignalMonitor {
+        let interval = interval.max(Self::MIN_INTERVAL);
+        let changes = Arc::new(Mutex::new(Vec::new()));
+        let worker = {
+            let changes = changes.clone();
+            let mut factory = device.factory();
+            let mut previous = initial;
+            let mut failed = false;
+            PeriodicWorker::spawn("input-signal", interval, move || {
+                let signal = match device.transact(&factory.make_get_profile()) {
+                    Ok(resp) => InputSignal::parse(&resp),
+                    Err(e) => {
+                        // Only report the first of a series of failures.
+                        if !failed {
+                            eprintln!("Failed to query the input signal: {}", e);
+                        }
+                        failed = true;
+                        return;
+                    }
+                };
+                failed = false;
+                let signal = match signal {
+                    Some(signal) => signal,
+                    None => return,
+                };
+                if previous.is_some_and(|p| p != signal) {
+                    changes.lock().unwrap().push(SignalChange {
+                        time: SystemTime::now(),
+                        signal,
+                    });
+                }
+                previous = Some(signal);
+            })
+        };
+        SignalMonitor { worker, changes }
+    }
+
+    /// Changes seen since the last call.
+    pub fn take_changes(&self) -> Vec<SignalChange> {
+        std::mem::take(&mut *self.changes.lock().unwrap())
+    }
+
+    pub fn stop(&mut self) {
+        self.worker.stop();
+    }
+}
+
+/// What the capture does when the input signal changes.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SignalChangePolicy {
+    /// Only log the change.
+    Continue,
+    /// Split the file outputs at the change.
+    Split,
+    /// Restart the encoder with a grabber configuration matching the new
+    /// signal, and split the file outputs.
+    Restart,
+}
+
+impl std::str::FromStr for SignalChangePolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "continue" => Ok(SignalChangePolicy::Continue),
+            "split" => Ok(SignalChangePolicy::Split),
+            "restart" => Ok(SignalChangePolicy::Restart),
+            _ => Err(format!("unknown signal change policy `{}`", s)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::emulator::{Emulator, EmulatorConfig};
+    use crate::firmware::{self, FirmwareVersion};
+
+    #[test]
+    fn reports_new_error_states_once() {
+        let emulator = Arc::new(Emulator::new(EmulatorConfig::default()));
+        let device = Device::with_transport(emulator.clone());
+        let version = FirmwareVersion::query(&device).unwrap();
+        let layout = firmware::status_errors_for(version);
+        let mut monitor = FirmwareMonitor::start(device, Duration::from_secs(1), layout);
+        std::thread::sleep(Duration::from_millis(200));
+        assert!(monitor.take_errors().is_empty());
+        emulator.raise_status_errors(0x1);
+        std::thread::sleep(Duration::from_secs(1));
+        let errors = monitor.take_errors();
+        assert_eq!(errors.len(), 1);
+        assert_eq!(errors[0].error.name, "encoder overload");
+        // Still flagged at the next poll, but no longer new.
+        std::thread::sleep(Duration::from_secs(1));
+        monitor.stop();
+        assert!(monitor.take_errors().is_empty());
+        assert_eq!(monitor.warnings(), 1);
+    }
+}
@@ -0,0 +1,173 @@
+//! Listener of the asynchronous notifications of the device.
+//!
+//! The Windows driver keeps an interrupt transfer pending on the event
+//! endpoint, presumably for events such as a signal loss. The content of the
+//! notifications is not understood yet, so they are only recorded.
+
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime};
+
+use crate::device::Device;
+use crate::worker::PeriodicWorker;
+
+/// A notification received on the event endpoint.
+#[derive(Clone, Debug)]
+pub struct Notification {
+    pub time: SystemTime,
+    pub data: Vec<u8>,
+}
+
+/// Keeps a read pending on the event endpoint and queues what arrives, for
+/// the capture loop to pick up.
+pub struct NotificationListener {
+    worker: PeriodicWorker,
+    received: Arc<Mutex<Vec<Notification>>>,
+}
+
+impl NotificationListener {
+    /// Timeout of each read, bounding the time taken to stop the listener.
+    const READ_TIMEOUT: Duration = Duration::from_millis(500);
+
+    /// Start listening, `None` if the device has no event endpoint.
+    pub fn start(device: Device) -> Option<NotificationListener> {
+        if !device.has_events() {
+            return None;
+        }
+        let received = Arc::new(Mutex::new(Vec::new()));
+        let worker = {
+            let received = received.clone();
+            let mut failed = false;
+            // The reads pace the worker.
+            PeriodicWorker::spawn("notifications", Duration::ZERO, move || {
+                let mut buf = [0u8; 0x40];
+                match device.read_event(&mut buf, Self::READ_TIMEOUT) {
+                    Ok(len) => {
+                        failed = false;
+                        received.lock().unwrap().push(Notification {
+                            time: SystemTime::now(),
+                            data: buf[..len].to_vec(),
+                        });
+                    }
+                    Err(rusb::Error::Timeout) => (),
+                    Err(e) => {
+                        // Only report the first of a series of failures.
+                        if !failed {
+                            eprintln!("Failed to read the event endpoint: {}", e);
+                        }
+                        failed = true;
+                        std::thread::sleep(Self::READ_TIMEOUT);
+                    }
+                }
+            })
+        };
+        Some(NotificationListener { worker, received })
+    }
+
+    /// Notifications received since the last call.
+    pub fn take(&self) -> Vec<Notification> {
+        std::mem::take(&mut *self.received.lock().unwrap())
+    }
+
+    pub fn stop(&mut self) {
+        self.worker.stop();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::emulator::{Emulator, EmulatorConfig};
+    use crate::transport::Transport;
+    use std::collections::VecDeque;
+    use std::sync::atomic::{AtomicU32, Ordering};
+
+    /// A device with or without event endpoint, counting the reads of it.
+    struct Events {
+        endpoint: bool,
+        reads: AtomicU32,
+        pending: Mutex<VecDeque<Vec<u8>>>,
+    }
+
+    impl Events {
+        fn new(endpoint: bool, pending: &[&[u8]]) -> Arc<Events> {
+            Arc::new(Events {
+                endpoint,
+                reads: AtomicU32::new(0),
+                pending: Mutex::new(pending.iter().map(|n| n.to_vec()).collect()),
+            })
+        }
+    }
+
+    impl Transport for Events {
+        fn reset(&self) -> rusb::Result<()> {
+            Ok(())
+        }
+
+        fn claim(&self) -> rusb::Result<()> {
+            Ok(())
+        }
+
+        fn write_command(&self, _data: &[u8], _timeout: Duration) -> rusb::Result<usize> {
+            Err(rusb::Error::NotSupported)
+        }
+
+        fn read_response(&self, _buf: &mut [u8], timeout: Duration) -> rusb::Result<usize> {
+            std::thread::sleep(timeout);
+            Err(rusb::Error::Timeout)
+        }
+
+        fn read_stream(&self, _buf: &mut [u8], _timeout: Duration) -> rusb::Result<usize> {
+            Err(rusb::Error::Timeout)
+        }
+
+        fn has_events(&self) -> bool {
+            self.endpoint
+        }
+
+        fn read_event(&self, buf: &mut [u8], timeout: Duration) -> rusb::Result<usize> {
+            self.reads.fetch_add(1, Ordering::Relaxed);
+            match self.pending.lock().unwrap().pop_front() {
+                Some(n) => {
+                    buf[..n.len()].copy_from_slice(&n);
+                    Ok(n.len())
+                }
+                None => {
+                    std::thread::sleep(timeout.min(Duration::from_millis(50)));
+                    Err(rusb::Error::Timeout)
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn no_listener_on_the_emulator() {
+        let device = Device::with_transport(Arc::new(Emulator::new(EmulatorConfig::default())));
+        assert!(!device.has_events());
+        assert!(NotificationListener::start(device).is_none());
+    }
+
+    #[test]
+    fn no_read_without_event_endpoint() {
+        let events = Events::new(false, &[b"\x01\x02"]);
+        assert!(NotificationListener::start(Device::with_transport(events.clone())).is_none());
+        std::thread::sleep(Duration::from_millis(100));
+        assert_eq!(events.reads.load(Ordering::Relaxed), 0);
+    }
+
+    #[test]
+    fn queues_the_notifications() {
+        let events = Events::new(true, &[b"\x01\x02", b"\x03"]);
+        let mut listener =
+            NotificationListener::start(Device::with_transport(events.clone())).unwrap();
+        std::thread::sleep(Duration::from_millis(200));
+        listener.stop();
+        let data: Vec<Vec<u8>> = listener.take().into_iter().map(|n| n.data).collect();
+        assert_eq!(data, vec![vec![0x01, 0x02], vec![0x03]]);
+        assert!(listener.take().is_empty());
+        // Reading goes on until stopped, and not after.
+        let reads = events.reads.load(Ordering::Relaxed);
+        assert!(reads > 2);
+        std::thread::sleep(Duration::from_millis(100));
+        assert_eq!(events.reads.load(Ordering::Relaxed), reads);
+    }
+}
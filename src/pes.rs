@@ -0,0 +1,110 @@
+//! PES packet headers.
+
+/// PTS and DTS are 33-bit counters of a 90 kHz clock.
+pub const TIMESTAMP_HZ: u64 = 90_000;
+pub const TIMESTAMP_MODULUS: u64 = 1 << 33;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct PesHeader {
+    pub stream_id: u8,
+    pub pts: Option<u64>,
+    pub dts: Option<u64>,
+    /// Length of the whole header, offset of the elementary stream data.
+    pub header_len: usize,
+}
+
+fn timestamp(b: &[u8]) -> u64 {
+    u64::from(b[0] & 0x0e) << 29
+        | u64::from(b[1]) << 22
+        | u64::from(b[2] & 0xfe) << 14
+        | u64::from(b[3]) << 7
+        | u64::from(b[4]) >> 1
+}
+
+/// Parse the header at the start of a PES packet.
+///
+/// `data` is the payload of the TS packet starting the PES packet.
+pub fn parse_header(data: &[u8]) -> Option<PesHeader> {
+    if data.len() < 9 || data[0..3] != [0x00, 0x00, 0x01] {
+        return None;
+    }
+    let stream_id = data[3];
+    // Streams without the optional header (padding, private stream 2...).
+    if matches!(
+        stream_id,
+        0xbc | 0xbe | 0xbf | 0xf0 | 0xf1 | 0xff | 0xf2 | 0xf8
+    ) {
+        return Some(PesHeader {
+            stream_id,
+            pts: None,
+            dts: None,
+            header_len: 6,
+        });
+    }
+    let flags = data[7] >> 6;
+    let header_len = 9 + usize::from(data[8]);
+    let pts = if flags & 0x2 != 0 {
+        Some(timestamp(data.get(9..14)?))
+    } else {
+        None
+    };
+    let dts = if flags == 0x3 {
+        Some(timestamp(data.get(14..19)?))
+    } else {
+        None
+    };
+    Some(PesHeader {
+        stream_id,
+        pts,
+        dts,
+        header_len,
+    })
+}
+
+/// Signed difference `b - a` between two 33-bit timestamps, taking the
+/// wrap-around into account.
+pub fn timestamp_delta(a: u64, b: u64) -> i64 {
+    let d = (b.wrapping_sub(a)) % TIMESTAMP_MODULUS;
+    if d >= TIMESTAMP_MODULUS / 2 {
+        d as i64 - TIMESTAMP_MODULUS as i64
+    } else {
+        d as i64
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn delta_wraps_around() {
+        let last = TIMESTAMP_MODULUS - 1000;
+        assert_eq!(timestamp_delta(last, 2600), 3600);
+        assert_eq!(timestamp_delta(2600, last), -3600);
+        assert_eq!(timestamp_delta(5000, 1400), -3600);
+        assert_eq!(
+            timestamp_delta(0, TIMESTAMP_MODULUS / 2 - 1),
+            (TIMESTAMP_MODULUS / 2 - 1) as i64
+        );
+    }
+
+    #[test]
+    fn header_timestamps() {
+        let pts = TIMESTAMP_MODULUS - 1;
+        let dts = 0x1_2345_6789;
+        let mut data = vec![0x00, 0x00, 0x01, 0xe0, 0x00, 0x00, 0x80, 0xc0, 10];
+        for (prefix, ts) in [(0x31, pts), (0x11, dts)] {
+            data.extend_from_slice(&[
+                prefix | (ts >> 29) as u8 & 0x0e,
+                (ts >> 22) as u8,
+                (ts >> 14) as u8 | 1,
+                (ts >> 7) as u8,
+                (ts << 1) as u8 | 1,
+            ]);
+        }
+        let header = parse_header(&data).unwrap();
+        assert_eq!((header.pts, header.dts), (Some(pts), Some(dts)));
+        assert_eq!(header.header_len, 19);
+        assert_eq!(parse_header(&data[..16]).map(|h| h.dts), None);
+    }
+}
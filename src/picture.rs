@@ -0,0 +1,191 @@
+//! Picture controls of the video input, and their mapping to device values.
+//!
+//! Users give the brightness, contrast and saturation in percent, 50 being
+//! the factory setting, and the hue in signed degrees. The device takes
+//! brightness values from -100 to 100 around a default of 0, contrast and
+//! saturation values from 0 to 200 around a default of 100, and the hue in
+//! degrees as a two's complement word. Values out of these ranges leave the
+//! device in a bad state until it is rebooted, so they are refused before
+//! anything is sent.
+
+use std::fmt;
+use std::ops::RangeInclusive;
+
+use crate::command::CommandFactory;
+use crate::protocol;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Control {
+    Brightness,
+    Contrast,
+    Hue,
+    Saturation,
+}
+
+impl Control {
+    pub const ALL: [Control; 4] = [
+        Control::Brightness,
+        Control::Contrast,
+        Control::Hue,
+        Control::Saturation,
+    ];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Control::Brightness => "brightness",
+            Control::Contrast => "contrast",
+            Control::Hue => "hue",
+            Control::Saturation => "saturation",
+        }
+    }
+
+    pub fn opcode(&self) -> u16 {
+        match self {
+            Control::Brightness => protocol::BRIGHTNESS,
+            Control::Contrast => protocol::CONTRAST,
+            Control::Hue => protocol::HUE,
+            Control::Saturation => protocol::SATURATION,
+        }
+    }
+
+    /// Accepted values, in percent or in degrees for the hue.
+    pub fn range(&self) -> RangeInclusive<i32> {
+        match self {
+            Control::Hue => -180..=180,
+            _ => 0..=100,
+        }
+    }
+
+    pub fn unit(&self) -> &'static str {
+        match self {
+            Control::Hue => "degrees",
+            _ => "%",
+        }
+    }
+
+    /// Parse and check a user value.
+    pub fn parse(&self, s: &str) -> Result<i32, String> {
+        let value = s
+            .trim()
+            .parse::<i32>()
+            .map_err(|e| format!("invalid {} `{}`: {}", self.name(), s, e))?;
+        self.to_device(value)?;
+        Ok(value)
+    }
+
+    /// Device value of a user value, which must be within `range()`.
+    pub fn to_device(&self, value: i32) -> Result<u32, String> {
+        let range = self.range();
+        if !range.contains(&value) {
+            return Err(format!(
+                "{} must be between {} and {} {}",
+                self.name(),
+                range.start(),
+                range.end(),
+                self.unit()
+            ));
+        }
+        let raw = match self {
+            Control::Brightness => 2 * value - 100,
+            Control::Contrast | Control::Saturation => 2 * value,
+            Control::Hue => value,
+        };
+        Ok(raw as u32)
+    }
+
+    /// User value of a device value, rounded down.
+    pub fn from_device(&self, raw: u32) -> i32 {
+        let raw = raw as i32;
+        match self {
+            Control::Brightness => (raw + 100).div_euclid(2),
+            Control::Contrast | Control::Saturation => raw.div_euclid(2),
+            Control::Hue => raw,
+        }
+    }
+
+    /// Command setting the control to a device value.
+    pub fn make_set(&self, factory: &mut CommandFactory, raw: u32) -> Vec<u8> {
+        match self {
+            Control::Brightness => factory.make_set_brightness(raw),
+            Control::Contrast => factory.make_set_contrast(raw),
+            Control::Hue => factory.make_set_hue(raw),
+            Control::Saturation => factory.make_set_saturation(raw),
+        }
+    }
+}
+
+impl fmt::Display for Control {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.name())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::response::HEADER_LEN;
+
+    /// Value word of the command setting `control` to user `value`.
+    fn sent(control: Control, value: i32) -> [u8; 4] {
+        let raw = control.to_device(value).unwrap();
+        let cmd = control.make_set(&mut CommandFactory::new(), raw);
+        assert_eq!(u16::from_le_bytes([cmd[4], cmd[5]]), control.opcode());
+        let word = &cmd[HEADER_LEN + 4..HEADER_LEN + 8];
+        [word[0], word[1], word[2], word[3]]
+    }
+
+    #[test]
+    fn percent_controls() {
+        let brightness: Vec<u32> = [0, 50, 100]
+            .iter()
+            .map(|&v| Control::Brightness.to_device(v).unwrap())
+            .collect();
+        assert_eq!(brightness, vec![-100i32 as u32, 0, 100]);
+        for control in [Control::Contrast, Control::Saturation] {
+            let raw: Vec<u32> = [0, 50, 100]
+                .iter()
+                .map(|&v| control.to_device(v).unwrap())
+                .collect();
+            assert_eq!(raw, vec![0, 100, 200], "{}", control);
+        }
+        assert_eq!(sent(Control::Brightness, 0), [0x9c, 0xff, 0xff, 0xff]);
+        assert_eq!(sent(Control::Brightness, 50), [0; 4]);
+        assert_eq!(sent(Control::Contrast, 100), [200, 0, 0, 0]);
+    }
+
+    #[test]
+    fn hue_is_twos_complement() {
+        assert_eq!(Control::Hue.to_device(-180), Ok(0xffff_ff4c));
+        assert_eq!(Control::Hue.to_device(0), Ok(0));
+        assert_eq!(Control::Hue.to_device(180), Ok(180));
+        assert_eq!(sent(Control::Hue, -180), [0x4c, 0xff, 0xff, 0xff]);
+        assert_eq!(sent(Control::Hue, -1), [0xff; 4]);
+        assert_eq!(sent(Control::Hue, 180), [0xb4, 0, 0, 0]);
+    }
+
+    #[test]
+    fn device_values_read_back() {
+        for control in Control::ALL {
+            for value in control.range() {
+                let raw = control.to_device(value).unwrap();
+                assert_eq!(control.from_device(raw), value, "{} {}", control, value);
+            }
+        }
+        // Odd device values, set by other software, round down.
+        assert_eq!(Control::Brightness.from_device(-99i32 as u32), 0);
+        assert_eq!(Control::Contrast.from_device(199), 99);
+    }
+
+    #[test]
+    fn out_of_range_is_refused() {
+        for control in [Control::Brightness, Control::Contrast, Control::Saturation] {
+            assert!(control.to_device(-1).is_err());
+            assert!(control.to_device(101).is_err());
+            assert!(control.parse("101").is_err());
+        }
+        assert!(Control::Hue.to_device(-181).is_err());
+        assert!(Control::Hue.to_device(181).is_err());
+        assert_eq!(Control::Hue.parse(" -90 "), Ok(-90));
+        assert!(Control::Hue.parse("ninety").is_err());
+    }
+}
@@ -0,0 +1,505 @@
+//! Path of the stream from the device to the outputs.
+//!
+//! The received data goes through the aligner and the analyzer before being
+//! written out, so that the outputs can be split on packet boundaries, and
+//! preferably on keyframes. With a clean start, nothing is written before
+//! the first PAT, dropping the leftovers of a previous encoder run, and the
+//! output may further wait for the first keyframe. The packets with the
+//! transport error indicator can be left out of the output, and the
+//! continuity counters of the output rewritten.
+
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime};
+
+use crate::analysis::{Event, StreamAnalyzer};
+use crate::audio::AudioMeter;
+use crate::clock::ClockModel;
+use crate::error::Error;
+use crate::psi::Pmt;
+use crate::psi::{self, PAT_PID, STREAM_TYPE_H264};
+use crate::sink::FanOut;
+use crate::snapshot::KeyframeExtractor;
+use crate::split::{Split, SplitReason, SplitScheduler};
+use crate::ts::{Aligner, ContinuityFixer, Packet, PACKET_SIZE};
+
+/// Where the output stands at the start of the stream.
+enum Start {
+    Writing,
+    WaitingForPat,
+    WaitingForKeyframe(Preroll),
+}
+
+/// What is kept of the stream between the first PAT and the first
+/// keyframe: the last PAT and PMT, and optionally the audio.
+struct Preroll {
+    /// Offset of the first PAT.
+    since: u64,
+    pat: Vec<u8>,
+    pmt: Vec<u8>,
+    /// Audio packets, from the start of a PES packet, if kept.
+    audio: Option<Vec<u8>>,
+}
+
+impl Preroll {
+    fn new(since: u64, keep_audio: bool) -> Preroll {
+        Preroll {
+            since,
+            pat: Vec::new(),
+            pmt: Vec::new(),
+            audio: if keep_audio { Some(Vec::new()) } else { None },
+        }
+    }
+
+    fn packet(&mut self, pkt: &Packet, pmt: Option<&Pmt>) {
+        let pid = pkt.pid();
+        if pid == PAT_PID {
+            self.pat = pkt.data().to_vec();
+        } else if psi::parse_pmt(pkt).is_some() {
+            self.pmt = pkt.data().to_vec();
+        } else if let Some(audio) = self.audio.as_mut() {
+            let is_audio =
+                pmt.is_some_and(|pmt| pmt.streams.iter().any(|es| es.is_audio() && es.pid == pid));
+            if is_audio && (pkt.pusi() || !audio.is_empty()) {
+                audio.extend_from_slice(pkt.data());
+            }
+        }
+    }
+
+    fn into_data(self) -> Vec<u8> {
+        let mut data = self.pat;
+        data.extend_from_slice(&self.pmt);
+        data.extend_from_slice(&self.audio.unwrap_or_default());
+        data
+    }
+}
+
+pub struct Pipeline {
+    aligner: Aligner,
+    analyzer: StreamAnalyzer,
+    clock: Arc<Mutex<ClockModel>>,
+    scheduler: SplitScheduler,
+    outputs: FanOut,
+    /// Data received but not yet consumed by the aligner.
+    pending: Vec<u8>,
+    /// Stream offset of the first byte of `pending`.
+    pending_offset: u64,
+    events: Vec<Event>,
+    snapshot: Option<KeyframeExtractor>,
+    keyframe: Option<Vec<u8>>,
+    audio: Option<AudioMeter>,
+    start: Start,
+    /// Whether the output waits for a keyframe after the first PAT, and
+    /// keeps the audio meanwhile.
+    keyframe_start: Option<bool>,
+    drop_transport_errors: bool,
+    /// Offsets of the packets of `pending` left out of the output.
+    dropped: Vec<u64>,
+    fixer: Option<ContinuityFixer>,
+}
+
+impl Pipeline {
+    /// `split_window` is how long a split waits for a keyframe.
+    pub fn new(outputs: FanOut, clock: Arc<Mutex<ClockModel>>, split_window: Duration) -> Pipeline {
+        Pipeline {
+            aligner: Aligner::new(),
+            analyzer: StreamAnalyzer::new(),
+            clock,
+            scheduler: SplitScheduler::new(split_window),
+            outputs,
+            pending: Vec::new(),
+            pending_offset: 0,
+            events: Vec::new(),
+            snapshot: None,
+            keyframe: None,
+            audio: None,
+            start: Start::Writing,
+            keyframe_start: None,
+            drop_transport_errors: false,
+            dropped: Vec::new(),
+            fixer: None,
+        }
+    }
+
+    pub fn analyzer(&self) -> &StreamAnalyzer {
+        &self.analyzer
+    }
+
+    pub fn outputs(&self) -> &FanOut {
+        &self.outputs
+    }
+
+    /// Split the outputs at the next keyframe.
+    pub fn request_split(&mut self, reason: SplitReason) {
+        self.scheduler.request(reason, Instant::now());
+    }
+
+    /// Extract the next complete keyframe, see `take_snapshot`.
+    pub fn request_snapshot(&mut self) {
+        if self.snapshot.is_none() {
+            self.snapshot = Some(KeyframeExtractor::new());
+        }
+    }
+
+    /// Give up the pending snapshot request.
+    pub fn cancel_snapshot(&mut self) {
+        self.snapshot = None;
+    }
+
+    pub fn snapshot_pending(&self) -> bool {
+        self.snapshot.is_some()
+    }
+
+    /// The keyframe extracted after a request, as Annex B data.
+    pub fn take_snapshot(&mut self) -> Option<Vec<u8>> {
+        self.keyframe.take()
+    }
+
+    /// Withhold the output until the first valid PAT, discarding what comes
+    /// before it.
+    pub fn clean_start(&mut self) {
+        self.start = Start::WaitingForPat;
+    }
+
+    /// After the first PAT, withhold the video until the first keyframe,
+    /// so that the output can be decoded from its start. Only the last PAT
+    /// and PMT are kept meanwhile, and the audio if `keep_audio`.
+    pub fn start_at_keyframe(&mut self, keep_audio: bool) {
+        self.clean_start();
+        self.keyframe_start = Some(keep_audio);
+    }
+
+    /// Leave the packets with the transport error indicator out of the
+    /// output.
+    pub fn drop_transport_errors(&mut self) {
+        self.drop_transport_errors = true;
+    }
+
+    /// Rewrite the continuity counters of the output so that they follow
+    /// each other, marking the losses with the discontinuity indicator. The
+    /// analyzer still sees the stream as received.
+    pub fn fix_continuity(&mut self) {
+        if self.fixer.is_none() {
+            self.fixer = Some(ContinuityFixer::new());
+        }
+    }
+
+    /// Number of losses marked in the output, when fixing the continuity.
+    pub fn continuity_fixes(&self) -> Option<u64> {
+        self.fixer.as_ref().map(ContinuityFixer::marked)
+    }
+
+    /// Measure the audio levels, see `audio_meter`.
+    pub fn meter_audio(&mut self) {
+        if self.audio.is_none() {
+            self.audio = Some(AudioMeter::new());
+        }
+    }
+
+    pub fn audio_meter(&mut self) -> Option<&mut AudioMeter> {
+        self.audio.as_mut()
+    }
+
+    pub fn push(&mut self, data: &[u8]) -> Result<(), Error> {
+        let now = SystemTime::now();
+        let instant = Instant::now();
+        let mut splits = Vec::new();
+        let mut first_pat = None;
+        let mut preroll = None;
+        {
+            let analyzer = &mut self.analyzer;
+            let scheduler = &mut self.scheduler;
+            let snapshot = &mut self.snapshot;
+            let keyframe = &mut self.keyframe;
+            let audio = &mut self.audio;
+            let start = &mut self.start;
+            let keyframe_start = self.keyframe_start;
+            let drop_transport_errors = self.drop_transport_errors;
+            let dropped = &mut self.dropped;
+            let mut clock = self.clock.lock().unwrap();
+            self.aligner.push(data, |offset, pkt| {
+                let info = analyzer.packet(offset, &pkt);
+                if pkt.tei() {
+                    if drop_transport_errors {
+                        dropped.push(offset);
+                    }
+                    return;
+                }
+                if let Some(pcr) = pkt.pcr() {
+                    clock.add_pcr_sample(pcr, offset, now);
+                }
+                if matches!(start, Start::WaitingForPat)
+                    && pkt.pid() == PAT_PID
+                    && psi::crc_ok(&pkt)
+                    && psi::parse_pat(&pkt).is_some()
+                {
+                    first_pat = Some(offset);
+                    *start = match keyframe_start {
+                        Some(keep_audio) => {
+                            Start::WaitingForKeyframe(Preroll::new(offset, keep_audio))
+                        }
+                        None => Start::Writing,
+                    };
+                }
+                if let Start::WaitingForKeyframe(kept) = start {
+                    if info.keyframe {
+                        if let Start::WaitingForKeyframe(kept) =
+                            std::mem::replace(start, Start::Writing)
+                        {
+                            preroll = Some((offset, kept));
+                        }
+                    } else {
+                        kept.packet(&pkt, analyzer.pmt());
+                    }
+                }
+                if let Some(split) = scheduler.packet(offset, info.keyframe, instant) {
+                    splits.push(split);
+                }
+                let video = analyzer.pmt().and_then(|pmt| {
+                    pmt.streams
+                        .iter()
+                        .find(|es| es.stream_type == STREAM_TYPE_H264)
+                });
+                if let (Some(extractor), Some(video)) = (snapshot.as_mut(), video) {
+                    if pkt.pid() == video.pid {
+                        if let Some(frame) = extractor.packet(&pkt) {
+                            *keyframe = Some(frame);
+                            *snapshot = None;
+                        }
+                    }
+                }
+                if let Some(meter) = audio.as_mut() {
+                    let es = analyzer.pmt().and_then(|pmt| {
+                        pmt.streams
+                            .iter()
+                            .find(|es| es.is_audio() && es.pid == pkt.pid())
+                    });
+                    if let Some(es) = es {
+                        meter.packet(es, &pkt, instant);
+                    }
+                }
+            });
+        }
+        self.events.extend(self.analyzer.take_events());
+        self.pending.extend_from_slice(data);
+        if let Some(offset) = first_pat {
+            self.discard_up_to(offset);
+            self.events.push(Event::LeadingDataDiscarded { offset });
+        }
+        let preroll_offset = preroll.as_ref().map(|(offset, _)| *offset);
+        if let Some((offset, kept)) = preroll {
+            self.discard_up_to(offset);
+            let since = kept.since;
+            let data = kept.into_data();
+            self.events.push(Event::PrerollDiscarded {
+                offset,
+                bytes: offset - since - data.len() as u64,
+            });
+            write_out(&mut self.outputs, self.fixer.as_mut(), &data)?;
+        }
+        if !matches!(self.start, Start::Writing) {
+            self.discard_up_to(self.aligner.offset());
+            return Ok(());
+        }
+        if let Some(started) = preroll_offset.or(first_pat) {
+            // Splits requested before the output started.
+            splits.retain(|split| split.offset > started);
+        }
+        for split in splits {
+            self.write_up_to(split.offset)?;
+            self.split(split)?;
+        }
+        self.write_up_to(self.aligner.offset())
+    }
+
+    fn split(&mut self, split: Split) -> Result<(), Error> {
+        self.outputs.split()?;
+        self.events.push(Event::Split {
+            offset: split.offset,
+            aligned: split.aligned,
+            reason: split.reason.to_string(),
+        });
+        Ok(())
+    }
+
+    fn write_up_to(&mut self, offset: u64) -> Result<(), Error> {
+        let len = (offset - self.pending_offset) as usize;
+        if len == 0 {
+            return Ok(());
+        }
+        let dropped = self.dropped.iter().take_while(|&&o| o < offset).count();
+        if dropped == 0 {
+            write_out(&mut self.outputs, self.fixer.as_mut(), &self.pending[..len])?;
+        } else {
+            let mut data = Vec::with_capacity(len);
+            let mut start = 0;
+            for &packet in &self.dropped[..dropped] {
+                let at = (packet - self.pending_offset) as usize;
+                data.extend_from_slice(&self.pending[start..at]);
+                start = at + PACKET_SIZE;
+            }
+            data.extend_from_slice(&self.pending[start..len]);
+            write_out(&mut self.outputs, self.fixer.as_mut(), &data)?;
+        }
+        self.discard_up_to(offset);
+        Ok(())
+    }
+
+    fn discard_up_to(&mut self, offset: u64) {
+        let len = (offset - self.pending_offset) as usize;
+        self.pending.drain(..len);
+        self.pending_offset = offset;
+        self.dropped.retain(|&o| o >= offset);
+    }
+
+    /// Events found in the stream or logged by the outputs since the last
+    /// call.
+    pub fn take_events(&mut self) -> Vec<Event> {
+        self.events.extend(self.outputs.take_events());
+        std::mem::take(&mut self.events)
+    }
+
+    /// Write out the data still held and close the outputs.
+    pub fn finish(&mut self) -> Result<(), Error> {
+        if !self.pending.is_empty() && matches!(self.start, Start::Writing) {
+            self.write_up_to(self.pending_offset + self.pending.len() as u64)?;
+        }
+        self.outputs.close()
+    }
+}
+
+/// Write to the outputs, through the continuity fixer if any.
+fn write_out(
+    outputs: &mut FanOut,
+    fixer: Option<&mut ContinuityFixer>,
+    data: &[u8],
+) -> Result<(), Error> {
+    match fixer {
+        Some(fixer) => outputs.write(&fixer.fix(data)),
+        None => outputs.write(data),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::emulator::{TsGenerator, VIDEO_PID};
+    use crate::h264;
+    use crate::sink::{FileOutput, Output, OverflowPolicy, QueuedSink};
+    use std::io;
+
+    #[test]
+    fn split_lands_on_the_next_keyframe_between_chunks() {
+        let dir = std::env::temp_dir().join(format!("it9910-pipeline-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        let template = dir.join("out-{n}.ts");
+        let output = FileOutput::create(template.to_str().unwrap(), false).unwrap();
+        let mut outputs = FanOut::new();
+        outputs.add(QueuedSink::spawn(
+            "file",
+            Box::new(output),
+            16,
+            OverflowPolicy::Block,
+            None,
+        ));
+        let clock = Arc::new(Mutex::new(ClockModel::new()));
+        let mut pipeline = Pipeline::new(outputs, clock, Duration::from_secs(60));
+
+        let mut generator = TsGenerator::new(2_000_000);
+        let data: Vec<u8> = (0..4000).flat_map(|_| generator.next_packet()).collect();
+        // Chunks which do not end on packet boundaries.
+        let (first, rest) = data.split_at(5000);
+        pipeline.push(first).unwrap();
+        pipeline.request_split(SplitReason::Segment);
+        for chunk in rest.chunks(1000) {
+            pipeline.push(chunk).unwrap();
+        }
+        pipeline.finish().unwrap();
+
+        let requested = (first.len() / PACKET_SIZE * PACKET_SIZE) as u64;
+        let keyframe = data
+            .chunks(PACKET_SIZE)
+            .enumerate()
+            .map(|(i, pkt)| ((i * PACKET_SIZE) as u64, Packet::new(pkt).unwrap()))
+            .find(|(offset, pkt)| {
+                *offset >= requested
+                    && pkt.pid() == VIDEO_PID
+                    && pkt.pusi()
+                    && pkt.payload().is_some_and(h264::starts_keyframe)
+            })
+            .map(|(offset, _)| offset)
+            .unwrap();
+        let splits: Vec<(u64, bool)> = pipeline
+            .take_events()
+            .into_iter()
+            .filter_map(|event| match event {
+                Event::Split {
+                    offset, aligned, ..
+                } => Some((offset, aligned)),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(splits, vec![(keyframe, true)]);
+        let at = keyframe as usize;
+        assert_eq!(std::fs::read(dir.join("out-0000.ts")).unwrap(), &data[..at]);
+        assert_eq!(std::fs::read(dir.join("out-0001.ts")).unwrap(), &data[at..]);
+    }
+
+    /// Output collecting the stream in memory.
+    struct Collect(Arc<Mutex<Vec<u8>>>);
+
+    impl Output for Collect {
+        fn write_all(&mut self, data: &[u8]) -> io::Result<()> {
+            self.0.lock().unwrap().extend_from_slice(data);
+            Ok(())
+        }
+
+        fn finish(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    /// Push `data` through a pipeline set up by `setup`, in chunks which do
+    /// not end on packet boundaries, and return the output.
+    fn run(data: &[u8], setup: impl FnOnce(&mut Pipeline)) -> Vec<u8> {
+        let collected = Arc::new(Mutex::new(Vec::new()));
+        let mut outputs = FanOut::new();
+        outputs.add(QueuedSink::spawn(
+            "memory",
+            Box::new(Collect(collected.clone())),
+            16,
+            OverflowPolicy::Block,
+            None,
+        ));
+        let clock = Arc::new(Mutex::new(ClockModel::new()));
+        let mut pipeline = Pipeline::new(outputs, clock, Duration::from_secs(60));
+        setup(&mut pipeline);
+        for chunk in data.chunks(1000) {
+            pipeline.push(chunk).unwrap();
+        }
+        pipeline.finish().unwrap();
+        let out = collected.lock().unwrap().clone();
+        out
+    }
+
+    #[test]
+    fn transport_errors_are_dropped_on_request() {
+        let mut generator = TsGenerator::new(2_000_000);
+        let mut packets: Vec<[u8; PACKET_SIZE]> =
+            (0..200).map(|_| generator.next_packet()).collect();
+        for i in [17, 18, 120] {
+            packets[i][1] |= 0x80;
+        }
+        let data: Vec<u8> = packets.iter().flatten().copied().collect();
+
+        assert_eq!(run(&data, |_| ()), data);
+        let kept: Vec<u8> = packets
+            .iter()
+            .filter(|pkt| !Packet::new(&pkt[..]).unwrap().tei())
+            .flatten()
+            .copied()
+            .collect();
+        assert_eq!(kept.len(), data.len() - 3 * PACKET_SIZE);
+        assert_eq!(run(&data, Pipeline::drop_transport_errors), kept);
+    }
+}
@@ -0,0 +1,182 @@
+//! Readiness check of a device, without changing its state.
+//!
+//! Only GET commands are sent, with short timeouts, and the device is
+//! neither reset nor are its endpoints cleared.
+
+use std::fmt;
+use std::sync::Arc;
+use std::time::Duration;
+
+use log::debug;
+use serde::Serialize;
+
+use crate::device::{Device, PRODUCT_ID, VENDOR_ID};
+use crate::error::Error;
+use crate::firmware::FirmwareVersion;
+use crate::status::{EncoderState, FirmwareStatus, InputSignal, PcGrabberState};
+use crate::transport::UsbTransport;
+
+/// Longest wait for each response, to keep the probe short when the
+/// firmware does not answer.
+const PROBE_TIMEOUT: Duration = Duration::from_millis(500);
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Readiness {
+    /// A capture can start, and the input has a signal.
+    Ready,
+    /// A capture can start, but the input has no signal.
+    NoSignal,
+    /// The device is there but cannot capture now.
+    NotReady,
+    NoDevice,
+    /// The device is there but cannot be opened, usually for lack of
+    /// permissions.
+    NoAccess,
+}
+
+impl fmt::Display for Readiness {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Readiness::Ready => "ready",
+            Readiness::NoSignal => "ready, no input signal",
+            Readiness::NotReady => "not ready",
+            Readiness::NoDevice => "no device",
+            Readiness::NoAccess => "no access to the device",
+        })
+    }
+}
+
+/// What the probe found. Fields are `None` when they could not be read.
+#[derive(Clone, Debug, Serialize)]
+pub struct Probe {
+    pub readiness: Readiness,
+    /// Why the device is not ready.
+    pub reason: Option<String>,
+    pub firmware_version: Option<String>,
+    pub firmware_status: Option<Vec<u32>>,
+    pub encoder_running: Option<bool>,
+    pub pc_grabber_ready: Option<bool>,
+    pub signal: Option<InputSignal>,
+}
+
+impl Probe {
+    fn new(readiness: Readiness) -> Probe {
+        Probe {
+            readiness,
+            reason: None,
+            firmware_version: None,
+            firmware_status: None,
+            encoder_running: None,
+            pc_grabber_ready: None,
+            signal: None,
+        }
+    }
+
+    fn not_ready(mut self, reason: &str) -> Probe {
+        self.readiness = Readiness::NotReady;
+        self.reason = Some(reason.to_string());
+        self
+    }
+}
+
+impl fmt::Display for Probe {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Status: {}", self.readiness)?;
+        if let Some(reason) = &self.reason {
+            write!(f, " ({})", reason)?;
+        }
+        writeln!(f)?;
+        if let Some(version) = &self.firmware_version {
+            writeln!(f, "Firmware version: {}", version)?;
+        }
+        if let Some(running) = self.encoder_running {
+            writeln!(f, "Encoder: {}", if running { "running" } else { "idle" })?;
+        }
+        if let Some(ready) = self.pc_grabber_ready {
+            writeln!(
+                f,
+                "PC grabber: {}",
+                if ready { "ready" } else { "not ready" }
+            )?;
+        }
+        if let Some(signal) = &self.signal {
+            writeln!(f, "Input signal: {}", signal)?;
+        }
+        Ok(())
+    }
+}
+
+/// Probe the first IT9910 device on the system.
+pub fn probe_usb() -> Result<Probe, Error> {
+    // The global context panics when libusb cannot be initialized, as
+    // happens without access to the USB device files.
+    if let Err(e) = rusb::Context::new() {
+        let mut probe = Probe::new(Readiness::NoAccess);
+        probe.reason = Some(format!("USB is not available: {}", e));
+        return Ok(probe);
+    }
+    for dev in rusb::devices()?.iter() {
+        let desc = dev.device_descriptor()?;
+        if desc.vendor_id() != VENDOR_ID || desc.product_id() != PRODUCT_ID {
+            continue;
+        }
+        return match dev.open() {
+            Ok(handle) => probe(&Device::with_transport(Arc::new(UsbTransport::new(handle)))),
+            Err(rusb::Error::Access) => Ok(Probe::new(Readiness::NoAccess)),
+            Err(e) => Err(e.into()),
+        };
+    }
+    Ok(Probe::new(Readiness::NoDevice))
+}
+
+/// Probe an opened device.
+pub fn probe(device: &Device) -> Result<Probe, Error> {
+    let mut probe = Probe::new(Readiness::Ready);
+    match device.claim_interface() {
+        Ok(()) => (),
+        Err(Error::Usb(rusb::Error::Busy)) => {
+            return Ok(probe.not_ready("the device is in use by another program"))
+        }
+        Err(Error::Usb(rusb::Error::Access)) => return Ok(Probe::new(Readiness::NoAccess)),
+        Err(e) => return Err(e),
+    }
+    let mut factory = device.factory();
+    let query = |cmd: Vec<u8>| device.transact_timeout(&cmd, PROBE_TIMEOUT);
+
+    match query(factory.make_get_firmware_status()) {
+        Ok(resp) => {
+            let status = FirmwareStatus::parse(&resp);
+            probe.firmware_version = status
+                .as_ref()
+                .and_then(|s| s.words.first().copied())
+                .map(|v| FirmwareVersion(v).to_string());
+            probe.firmware_status = status.map(|s| s.words);
+        }
+        Err(e) => {
+            debug!("Firmware status: {}", e);
+            return Ok(probe.not_ready("the firmware does not answer"));
+        }
+    }
+    match query(factory.make_get_state()) {
+        Ok(resp) => probe.encoder_running = EncoderState::parse(&resp).map(|s| s.running),
+        Err(e) => debug!("Encoder state: {}", e),
+    }
+    match query(factory.make_get_pc_grabber_small()) {
+        Ok(resp) => probe.pc_grabber_ready = PcGrabberState::parse(&resp).map(|s| s.ready),
+        Err(e) => debug!("PC grabber state: {}", e),
+    }
+    match query(factory.make_get_profile()) {
+        Ok(resp) => probe.signal = InputSignal::parse(&resp),
+        Err(e) => debug!("Profile: {}", e),
+    }
+    if probe.encoder_running == Some(true) {
+        return Ok(probe.not_ready("the encoder is running"));
+    }
+    match probe.signal {
+        Some(signal) if signal.locked => (),
+        Some(_) => probe.readiness = Readiness::NoSignal,
+        None => return Ok(probe.not_ready("the input signal cannot be read")),
+    }
+    Ok(probe)
+}
@@ -0,0 +1,225 @@
+//! Default capture settings of each video source.
+//!
+//! Every input gets a bundle of settings, applied when the input is selected
+//! for a capture. The built-in bundles can be overridden per source in the
+//! `[sources.<name>]` sections of the configuration file, and settings given
+//! on the command line take precedence over both. With dual-stream firmware,
+//! the encoder settings of each stream can also be given on their own.
+
+use serde::{Deserialize, Serialize};
+
+use crate::encoder::EncoderParam;
+use crate::picture::Control;
+
+#[derive(Clone, Debug, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct SourceProfile {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub brightness: Option<i32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub contrast: Option<i32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub hue: Option<i32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub saturation: Option<i32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub quality: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub keyframe_rate: Option<u32>,
+}
+
+impl SourceProfile {
+    /// Built-in bundle of a video source. The analog inputs get a lower
+    /// quality, their picture not being worth the bitrate.
+    ///
+    /// Only the quality is set: deinterlacing, RGB range and bitrate would
+    /// belong here, but no command of the protocol is known for them.
+    pub fn builtin(source: &str) -> SourceProfile {
+        let quality = match source {
+            "hdmi" => Some(90),
+            "component" => Some(80),
+            "composite" | "s-video" => Some(70),
+            _ => None,
+        };
+        SourceProfile {
+            quality,
+            ..Default::default()
+        }
+    }
+
+    /// The settings of `self`, replaced by those set in `other`.
+    pub fn merge(&self, other: &SourceProfile) -> SourceProfile {
+        SourceProfile {
+            brightness: other.brightness.or(self.brightness),
+            contrast: other.contrast.or(self.contrast),
+            hue: other.hue.or(self.hue),
+            saturation: other.saturation.or(self.saturation),
+            quality: other.quality.or(self.quality),
+            keyframe_rate: other.keyframe_rate.or(self.keyframe_rate),
+        }
+    }
+
+    /// Names and values of the settings set in both `self` and `other`
+    /// with a different value.
+    pub fn conflicts(&self, other: &SourceProfile) -> Vec<(&'static str, String, String)> {
+        fn check<T: PartialEq + ToString>(
+            out: &mut Vec<(&'static str, String, String)>,
+            name: &'static str,
+            a: Option<T>,
+            b: Option<T>,
+        ) {
+            if let (Some(a), Some(b)) = (a, b) {
+                if a != b {
+                    out.push((name, a.to_string(), b.to_string()));
+                }
+            }
+        }
+        let mut out = Vec::new();
+        check(&mut out, "brightness", self.brightness, other.brightness);
+        check(&mut out, "contrast", self.contrast, other.contrast);
+        check(&mut out, "hue", self.hue, other.hue);
+        check(&mut out, "saturation", self.saturation, other.saturation);
+        check(&mut out, "quality", self.quality, other.quality);
+        check(
+            &mut out,
+            "keyframe_rate",
+            self.keyframe_rate,
+            other.keyframe_rate,
+        );
+        out
+    }
+
+    /// Check the picture controls, whose limits do not depend on the
+    /// firmware.
+    pub fn check(&self) -> Result<(), String> {
+        for (control, value) in self.picture() {
+            control.to_device(value)?;
+        }
+        Ok(())
+    }
+
+    pub fn picture(&self) -> Vec<(Control, i32)> {
+        [
+            (Control::Brightness, self.brightness),
+            (Control::Contrast, self.contrast),
+            (Control::Hue, self.hue),
+            (Control::Saturation, self.saturation),
+        ]
+        .iter()
+        .filter_map(|(control, value)| Some((*control, (*value)?)))
+        .collect()
+    }
+
+    pub fn encoder(&self) -> Vec<(EncoderParam, u32)> {
+        [
+            (EncoderParam::KeyframeRate, self.keyframe_rate),
+            (EncoderParam::Quality, self.quality),
+        ]
+        .iter()
+        .filter_map(|(param, value)| Some((*param, (*value)?)))
+        .collect()
+    }
+
+    /// The encoder settings, which go to stream 0.
+    pub fn stream(&self) -> StreamProfile {
+        StreamProfile {
+            quality: self.quality,
+            keyframe_rate: self.keyframe_rate,
+        }
+    }
+}
+
+/// Encoder settings of one of the streams, with dual-stream firmware.
+///
+/// The settings of a source bundle and of the command line without a
+/// stream apply to stream 0; those of the `[stream0]` and `[stream1]`
+/// sections, and of the `--stream1-*` options, to the given stream.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct StreamProfile {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub quality: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub keyframe_rate: Option<u32>,
+}
+
+impl StreamProfile {
+    pub fn is_empty(&self) -> bool {
+        *self == StreamProfile::default()
+    }
+
+    /// The settings of `self`, replaced by those set in `other`.
+    pub fn merge(&self, other: &StreamProfile) -> StreamProfile {
+        StreamProfile {
+            quality: other.quality.or(self.quality),
+            keyframe_rate: other.keyframe_rate.or(self.keyframe_rate),
+        }
+    }
+
+    pub fn encoder(&self) -> Vec<(EncoderParam, u32)> {
+        SourceProfile::from(self.clone()).encoder()
+    }
+}
+
+impl From<StreamProfile> for SourceProfile {
+    fn from(stream: StreamProfile) -> SourceProfile {
+        SourceProfile {
+            quality: stream.quality,
+            keyframe_rate: stream.keyframe_rate,
+            ..Default::default()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn merge_keeps_what_other_leaves_unset() {
+        let base = SourceProfile {
+            brightness: Some(10),
+            quality: Some(70),
+            ..Default::default()
+        };
+        let other = SourceProfile {
+            quality: Some(50),
+            hue: Some(-5),
+            ..Default::default()
+        };
+        let merged = base.merge(&other);
+        assert_eq!(merged.brightness, Some(10));
+        assert_eq!(merged.quality, Some(50));
+        assert_eq!(merged.hue, Some(-5));
+        assert_eq!(merged.contrast, None);
+        assert_eq!(base.merge(&SourceProfile::default()), base);
+    }
+
+    #[test]
+    fn conflicts_are_differing_values_set_in_both() {
+        let a = SourceProfile {
+            brightness: Some(10),
+            quality: Some(70),
+            keyframe_rate: Some(30),
+            ..Default::default()
+        };
+        let b = SourceProfile {
+            brightness: Some(10),
+            quality: Some(50),
+            hue: Some(3),
+            ..Default::default()
+        };
+        assert_eq!(
+            a.conflicts(&b),
+            vec![("quality", "70".to_string(), "50".to_string())]
+        );
+        assert!(a.conflicts(&SourceProfile::default()).is_empty());
+    }
+
+    #[test]
+    fn builtin_bundles() {
+        assert_eq!(SourceProfile::builtin("hdmi").quality, Some(90));
+        assert_eq!(SourceProfile::builtin("composite").quality, Some(70));
+        assert_eq!(SourceProfile::builtin("unknown"), SourceProfile::default());
+    }
+}
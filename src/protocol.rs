@@ -0,0 +1,374 @@
+//! Tables of what is known of the command protocol: opcodes, operations,
+//! header layout and payload structures.
+//!
+//! The command builders and parsers take their values from here, and the
+//! `protocol export` subcommand writes the same tables out as JSON or as a
+//! C header for other implementations.
+
+use std::fmt::Write as _;
+use std::ops::Range;
+
+use serde_json::{json, Value};
+
+use crate::command::Operation;
+use crate::grabber::GrabberConfig;
+
+pub const REBOOT: u16 = 0x0001;
+pub const STATE: u16 = 0x0002;
+pub const SOURCE: u16 = 0x0003;
+pub const FIRMWARE_STATUS: u16 = 0x0008;
+pub const PROFILE: u16 = 0x000a;
+pub const BRIGHTNESS: u16 = 0x0101;
+pub const CONTRAST: u16 = 0x0102;
+pub const HUE: u16 = 0x0103;
+pub const SATURATION: u16 = 0x0104;
+pub const KEYFRAME_RATE: u16 = 0x0202;
+pub const QUALITY: u16 = 0x0203;
+pub const PC_GRABBER: u16 = 0xe001;
+pub const TIME: u16 = 0xf001;
+pub const HW_GRABBER: u16 = 0xf002;
+
+pub struct Opcode {
+    pub name: &'static str,
+    pub value: u16,
+    pub description: &'static str,
+}
+
+const fn opcode(name: &'static str, value: u16, description: &'static str) -> Opcode {
+    Opcode {
+        name,
+        value,
+        description,
+    }
+}
+
+pub const OPCODES: &[Opcode] = &[
+    opcode("reboot", REBOOT, "Reboot the device"),
+    opcode("state", STATE, "Encoder state, 2 when running"),
+    opcode("source", SOURCE, "Audio and video sources"),
+    opcode(
+        "firmware_status",
+        FIRMWARE_STATUS,
+        "Firmware version and uptime",
+    ),
+    opcode("profile", PROFILE, "Input signal"),
+    opcode("brightness", BRIGHTNESS, "Picture control"),
+    opcode("contrast", CONTRAST, "Picture control"),
+    opcode("hue", HUE, "Picture control"),
+    opcode("saturation", SATURATION, "Picture control"),
+    opcode(
+        "keyframe_rate",
+        KEYFRAME_RATE,
+        "Encoder parameter, per stream",
+    ),
+    opcode("quality", QUALITY, "Encoder parameter, per stream"),
+    opcode(
+        "pc_grabber",
+        PC_GRABBER,
+        "PC grabber state and configuration",
+    ),
+    opcode("time", TIME, "Device time in milliseconds"),
+    opcode("hw_grabber", HW_GRABBER, "Inputs of the hardware grabber"),
+];
+
+/// Operations with a known meaning.
+pub const OPERATIONS: &[Operation] = &[Operation::Get, Operation::Set, Operation::Commit];
+
+/// A field of the header or of a payload.
+pub struct Field {
+    pub name: &'static str,
+    pub offset: usize,
+    pub size: usize,
+}
+
+impl Field {
+    pub const fn range(&self) -> Range<usize> {
+        self.offset..self.offset + self.size
+    }
+}
+
+const fn field(name: &'static str, offset: usize, size: usize) -> Field {
+    Field { name, offset, size }
+}
+
+/// Size of the header shared by commands and responses.
+pub const HEADER_LEN: usize = 0x10;
+/// Value of both magic fields.
+pub const MAGIC: [u8; 2] = [0x10, 0x99];
+
+/// Length of the command or response, header included.
+pub const LENGTH: Field = field("length", 0x00, 2);
+pub const OPCODE: Field = field("opcode", 0x04, 2);
+pub const MAGIC1: Field = field("magic1", 0x06, 2);
+pub const OPERATION: Field = field("operation", 0x08, 4);
+pub const SEQ: Field = field("seq", 0x0c, 2);
+pub const MAGIC2: Field = field("magic2", 0x0e, 2);
+
+pub const HEADER: &[Field] = &[LENGTH, OPCODE, MAGIC1, OPERATION, SEQ, MAGIC2];
+
+/// Index word of the parameters addressed by channel or stream number.
+pub const INDEX: Field = field("index", 0x00, 4);
+/// Value following the index word.
+pub const VALUE: Field = field("value", 0x04, 4);
+
+pub const AUDIO_SOURCE: Field = field("audio", 0x00, 4);
+pub const VIDEO_SOURCE: Field = field("video", 0x04, 4);
+
+/// Layout of a payload, for the given opcodes.
+pub struct Structure {
+    pub name: &'static str,
+    pub opcodes: &'static [u16],
+    pub size: usize,
+    pub fields: &'static [Field],
+}
+
+pub const STRUCTURES: &[Structure] = &[
+    Structure {
+        name: "indexed_value",
+        opcodes: &[
+            BRIGHTNESS,
+            CONTRAST,
+            HUE,
+            SATURATION,
+            KEYFRAME_RATE,
+            QUALITY,
+        ],
+        size: 8,
+        fields: &[INDEX, VALUE],
+    },
+    Structure {
+        name: "source",
+        opcodes: &[SOURCE],
+        size: 8,
+        fields: &[AUDIO_SOURCE, VIDEO_SOURCE],
+    },
+    Structure {
+        name: "pc_grabber_entry",
+        opcodes: &[PC_GRABBER],
+        size: GrabberConfig::ENTRY_LEN,
+        fields: &[
+            field("tag", 0x00, 1),
+            field("index", GrabberConfig::INDEX_OFFSET, 4),
+            field("width", GrabberConfig::WIDTH_OFFSET, 4),
+            field("height", GrabberConfig::HEIGHT_OFFSET, 4),
+        ],
+    },
+];
+
+fn fields_json(fields: &[Field]) -> Value {
+    fields
+        .iter()
+        .map(|f| json!({ "name": f.name, "offset": f.offset, "size": f.size }))
+        .collect()
+}
+
+/// The tables as a JSON document.
+pub fn to_json() -> Value {
+    json!({
+        "header_len": HEADER_LEN,
+        "magic": MAGIC,
+        "header": fields_json(HEADER),
+        "operations": OPERATIONS
+            .iter()
+            .map(|op| json!({ "name": op.to_string(), "value": op.value() }))
+            .collect::<Vec<_>>(),
+        "opcodes": OPCODES
+            .iter()
+            .map(|op| json!({
+                "name": op.name,
+                "value": op.value,
+                "description": op.description,
+            }))
+            .collect::<Vec<_>>(),
+        "structures": STRUCTURES
+            .iter()
+            .map(|s| json!({
+                "name": s.name,
+                "opcodes": s.opcodes,
+                "size": s.size,
+                "fields": fields_json(s.fields),
+            }))
+            .collect::<Vec<_>>(),
+    })
+}
+
+fn fields_c(out: &mut String, prefix: &str, fields: &[Field]) {
+    for f in fields {
+        let name = format!("{}_{}", prefix, f.name.to_uppercase());
+        writeln!(out, "#define {}_OFFSET 0x{:02x}", name, f.offset).unwrap();
+        writeln!(out, "#define {}_SIZE {}", name, f.size).unwrap();
+    }
+}
+
+/// The tables as C preprocessor definitions.
+pub fn to_c_header() -> String {
+    let mut out = String::new();
+    writeln!(
+        out,
+        "/* IT9910 command protocol, generated by `protocol export c-header`. */"
+    )
+    .unwrap();
+    writeln!(out, "#ifndef IT9910_PROTOCOL_H").unwrap();
+    writeln!(out, "#define IT9910_PROTOCOL_H").unwrap();
+    writeln!(out).unwrap();
+    writeln!(out, "#define IT9910_HEADER_LEN 0x{:02x}", HEADER_LEN).unwrap();
+    writeln!(out, "#define IT9910_MAGIC_BYTE0 0x{:02x}", MAGIC[0]).unwrap();
+    writeln!(out, "#define IT9910_MAGIC_BYTE1 0x{:02x}", MAGIC[1]).unwrap();
+    fields_c(&mut out, "IT9910_HEADER", HEADER);
+    writeln!(out).unwrap();
+    for op in OPERATIONS {
+        writeln!(
+            out,
+            "#define IT9910_OPERATION_{} {}",
+            op.to_string().to_uppercase(),
+            op.value()
+        )
+        .unwrap();
+    }
+    writeln!(out).unwrap();
+    for op in OPCODES {
+        writeln!(out, "/* {} */", op.description).unwrap();
+        writeln!(
+            out,
+            "#define IT9910_OPCODE_{} 0x{:04x}",
+            op.name.to_uppercase(),
+            op.value
+        )
+        .unwrap();
+    }
+    for s in STRUCTURES {
+        let prefix = format!("IT9910_{}", s.name.to_uppercase());
+        writeln!(out).unwrap();
+        writeln!(out, "#define {}_SIZE 0x{:02x}", prefix, s.size).unwrap();
+        fields_c(&mut out, &prefix, s.fields);
+    }
+    writeln!(out).unwrap();
+    writeln!(out, "#endif /* IT9910_PROTOCOL_H */").unwrap();
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::command::CommandFactory;
+
+    /// The JSON export, as printed and parsed back by another program.
+    fn exported() -> Value {
+        let text = serde_json::to_string_pretty(&to_json()).unwrap();
+        serde_json::from_str(&text).unwrap()
+    }
+
+    fn parse_fields(value: &Value) -> Vec<(String, usize, usize)> {
+        value
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|f| {
+                (
+                    f["name"].as_str().unwrap().to_string(),
+                    f["offset"].as_u64().unwrap() as usize,
+                    f["size"].as_u64().unwrap() as usize,
+                )
+            })
+            .collect()
+    }
+
+    fn fields(fields: &[Field]) -> Vec<(String, usize, usize)> {
+        fields
+            .iter()
+            .map(|f| (f.name.to_string(), f.offset, f.size))
+            .collect()
+    }
+
+    #[test]
+    fn json_round_trip() {
+        let json = exported();
+        assert_eq!(json["header_len"], HEADER_LEN);
+        assert_eq!(json["magic"], json!(MAGIC));
+        assert_eq!(parse_fields(&json["header"]), fields(HEADER));
+
+        let operations: Vec<(String, u64)> = json["operations"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|op| {
+                (
+                    op["name"].as_str().unwrap().to_string(),
+                    op["value"].as_u64().unwrap(),
+                )
+            })
+            .collect();
+        let expected: Vec<(String, u64)> = OPERATIONS
+            .iter()
+            .map(|op| (op.to_string(), u64::from(op.value())))
+            .collect();
+        assert_eq!(operations, expected);
+
+        let opcodes = json["opcodes"].as_array().unwrap();
+        assert_eq!(opcodes.len(), OPCODES.len());
+        for (parsed, op) in opcodes.iter().zip(OPCODES) {
+            assert_eq!(parsed["name"], op.name);
+            assert_eq!(parsed["value"], op.value);
+            assert_eq!(parsed["description"], op.description);
+        }
+
+        let structures = json["structures"].as_array().unwrap();
+        assert_eq!(structures.len(), STRUCTURES.len());
+        for (parsed, s) in structures.iter().zip(STRUCTURES) {
+            assert_eq!(parsed["name"], s.name);
+            assert_eq!(parsed["opcodes"], json!(s.opcodes));
+            assert_eq!(parsed["size"], s.size);
+            assert_eq!(parse_fields(&parsed["fields"]), fields(s.fields));
+        }
+    }
+
+    #[test]
+    fn exported_layout_decodes_commands() {
+        let json = exported();
+        let cmd = CommandFactory::new().make_set_hue(-10i32 as u32);
+        let read = |name: &str| {
+            let (_, offset, size) = parse_fields(&json["header"])
+                .into_iter()
+                .find(|f| f.0 == name)
+                .unwrap();
+            cmd[offset..offset + size]
+                .iter()
+                .rev()
+                .fold(0u64, |v, b| v << 8 | u64::from(*b))
+        };
+        assert_eq!(read("length"), cmd.len() as u64);
+        let hue = json["opcodes"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .find(|op| op["name"] == "hue")
+            .unwrap();
+        assert_eq!(json!(read("opcode")), hue["value"]);
+        let set = json["operations"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .find(|op| op["name"] == Operation::Set.to_string())
+            .unwrap();
+        assert_eq!(json!(read("operation")), set["value"]);
+        let magic = u64::from(u16::from_le_bytes(MAGIC));
+        assert_eq!(read("magic1"), magic);
+        assert_eq!(read("magic2"), magic);
+    }
+
+    #[test]
+    fn c_header_matches_the_tables() {
+        let header = to_c_header();
+        for op in OPCODES {
+            let define = format!("{:#06x}", op.value);
+            assert!(
+                header
+                    .lines()
+                    .any(|l| l.contains(&op.name.to_uppercase()) && l.ends_with(&define)),
+                "{}",
+                op.name
+            );
+        }
+        assert!(header.contains("_SEQ_OFFSET 0x0c"));
+    }
+}
@@ -0,0 +1,195 @@
+//! Program specific information: PAT and PMT sections.
+//!
+//! Only sections contained in a single packet are handled, which is what the
+//! IT9910 produces.
+
+use crate::ts::Packet;
+
+pub const PAT_PID: u16 = 0x0000;
+
+pub const STREAM_TYPE_MPEG1_AUDIO: u8 = 0x03;
+pub const STREAM_TYPE_MPEG2_AUDIO: u8 = 0x04;
+pub const STREAM_TYPE_AAC_ADTS: u8 = 0x0f;
+pub const STREAM_TYPE_AAC_LATM: u8 = 0x11;
+pub const STREAM_TYPE_H264: u8 = 0x1b;
+
+/// An elementary stream declared in the PMT.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ElementaryStream {
+    pub stream_type: u8,
+    pub pid: u16,
+}
+
+impl ElementaryStream {
+    pub fn is_video(&self) -> bool {
+        self.stream_type == STREAM_TYPE_H264 || self.stream_type == 0x02
+    }
+
+    pub fn is_audio(&self) -> bool {
+        matches!(
+            self.stream_type,
+            STREAM_TYPE_MPEG1_AUDIO
+                | STREAM_TYPE_MPEG2_AUDIO
+                | STREAM_TYPE_AAC_ADTS
+                | STREAM_TYPE_AAC_LATM
+        )
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Pmt {
+    pub program: u16,
+    pub pcr_pid: u16,
+    pub streams: Vec<ElementaryStream>,
+}
+
+/// CRC of the PSI sections (CRC-32/MPEG-2).
+pub fn crc32(data: &[u8]) -> u32 {
+    let mut crc = 0xffff_ffffu32;
+    for &b in data {
+        crc ^= u32::from(b) << 24;
+        for _ in 0..8 {
+            crc = if crc & 0x8000_0000 != 0 {
+                (crc << 1) ^ 0x04c1_1db7
+            } else {
+                crc << 1
+            };
+        }
+    }
+    crc
+}
+
+/// Section carried by a packet starting a payload unit, up to and excluding
+/// the CRC.
+fn section(pkt: &Packet, table_id: u8) -> Option<Vec<u8>> {
+    if !pkt.pusi() {
+        return None;
+    }
+    let payload = pkt.payload()?;
+    let pointer = usize::from(*payload.first()?);
+    let sect = payload.get(1 + pointer..)?;
+    if sect.len() < 3 || sect[0] != table_id {
+        return None;
+    }
+    let len = usize::from(sect[1] & 0x0f) << 8 | usize::from(sect[2]);
+    if len < 9 {
+        return None;
+    }
+    sect.get(..3 + len - 4).map(|s| s.to_vec())
+}
+
+/// Whether the section starting in `pkt` is complete in the packet and its
+/// CRC is correct.
+pub fn crc_ok(pkt: &Packet) -> bool {
+    let sect = pkt
+        .payload()
+        .filter(|_| pkt.pusi())
+        .and_then(|payload| payload.get(1 + usize::from(*payload.first()?)..));
+    let sect = match sect {
+        Some(sect) if sect.len() >= 3 => sect,
+        _ => return false,
+    };
+    let len = usize::from(sect[1] & 0x0f) << 8 | usize::from(sect[2]);
+    sect.get(..3 + len).is_some_and(|s| crc32(s) == 0)
+}
+
+/// Parse a PAT, returning the (program number, PMT PID) pairs.
+pub fn parse_pat(pkt: &Packet) -> Option<Vec<(u16, u16)>> {
+    let sect = section(pkt, 0x00)?;
+    let programs = sect
+        .get(8..)?
+        .chunks_exact(4)
+        .map(|p| {
+            let program = u16::from_be_bytes([p[0], p[1]]);
+            let pid = u16::from(p[2] & 0x1f) << 8 | u16::from(p[3]);
+            (program, pid)
+        })
+        .collect();
+    Some(programs)
+}
+
+pub fn parse_pmt(pkt: &Packet) -> Option<Pmt> {
+    let sect = section(pkt, 0x02)?;
+    if sect.len() < 12 {
+        return None;
+    }
+    let program = u16::from_be_bytes([sect[3], sect[4]]);
+    let pcr_pid = u16::from(sect[8] & 0x1f) << 8 | u16::from(sect[9]);
+    let info_len = usize::from(sect[10] & 0x0f) << 8 | usize::from(sect[11]);
+    let mut pos = 12 + info_len;
+    let mut streams = Vec::new();
+    while pos + 5 <= sect.len() {
+        let stream_type = sect[pos];
+        let pid = u16::from(sect[pos + 1] & 0x1f) << 8 | u16::from(sect[pos + 2]);
+        let es_info_len = usize::from(sect[pos + 3] & 0x0f) << 8 | usize::from(sect[pos + 4]);
+        streams.push(ElementaryStream { stream_type, pid });
+        pos += 5 + es_info_len;
+    }
+    Some(Pmt {
+        program,
+        pcr_pid,
+        streams,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::emulator::{TsGenerator, PMT_PID, VIDEO_PID};
+    use crate::ts::{PACKET_SIZE, SYNC_BYTE};
+    use proptest::collection::vec;
+    use proptest::prelude::*;
+
+    #[test]
+    fn generated_tables_are_parsed() {
+        let mut generator = TsGenerator::new(1_000_000);
+        let (pat, pmt) = (generator.next_packet(), generator.next_packet());
+        let (pat, pmt) = (Packet::new(&pat).unwrap(), Packet::new(&pmt).unwrap());
+        assert!(crc_ok(&pat) && crc_ok(&pmt));
+        assert_eq!(parse_pat(&pat), Some(vec![(1, PMT_PID)]));
+        let pmt = parse_pmt(&pmt).unwrap();
+        assert_eq!(pmt.pcr_pid, VIDEO_PID);
+        assert!(pmt
+            .streams
+            .iter()
+            .any(|es| es.pid == VIDEO_PID && es.is_video()));
+    }
+
+    fn check(pkt: &Packet) -> Result<(), TestCaseError> {
+        let _ = crc_ok(pkt);
+        // At most one entry per 4 bytes of the packet.
+        if let Some(programs) = parse_pat(pkt) {
+            prop_assert!(programs.len() <= PACKET_SIZE / 4);
+        }
+        if let Some(pmt) = parse_pmt(pkt) {
+            prop_assert!(pmt.streams.len() <= PACKET_SIZE / 5);
+        }
+        Ok(())
+    }
+
+    proptest! {
+        #[test]
+        fn arbitrary_packets_never_panic(body in vec(any::<u8>(), PACKET_SIZE - 1)) {
+            let mut data = vec![SYNC_BYTE];
+            data.extend_from_slice(&body);
+            check(&Packet::new(&data).unwrap())?;
+        }
+
+        #[test]
+        fn arbitrary_sections_never_panic(
+            body in vec(any::<u8>(), PACKET_SIZE - 1),
+            pointer in 0u8..8,
+            table_id in prop::sample::select(vec![0x00, 0x02]),
+        ) {
+            let mut data = vec![SYNC_BYTE];
+            data.extend_from_slice(&body);
+            // Start of a payload unit without adaptation field, with the
+            // pointer and table id set to reach the section parsing.
+            data[1] |= 0x40;
+            data[3] = data[3] & 0xcf | 0x10;
+            data[4] = pointer;
+            data[5 + usize::from(pointer)] = table_id;
+            check(&Packet::new(&data).unwrap())?;
+        }
+    }
+}
@@ -0,0 +1,264 @@
+//! Python bindings, built with the `python` feature.
+//!
+//! The `It9910` class wraps a device and its capture session. Blocking calls
+//! release the GIL, and errors are raised as `It9910Error`, or one of the
+//! standard exceptions when one fits.
+
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::Duration;
+
+use pyo3::create_exception;
+use pyo3::exceptions::{PyException, PyOSError, PyTimeoutError, PyValueError};
+use pyo3::prelude::*;
+use pyo3::types::PyBytes;
+
+use crate::device::Device;
+use crate::emulator::{Emulator, EmulatorConfig};
+use crate::encoder::EncoderParam;
+use crate::error::Error;
+use crate::firmware::{self, FirmwareVersion};
+use crate::grabber::GrabberConfig;
+use crate::picture::Control;
+use crate::session::CaptureSession;
+use crate::settings;
+use crate::sources::Capabilities;
+
+create_exception!(it9910_stream_example, It9910Error, PyException);
+create_exception!(it9910_stream_example, DeviceRejectedError, It9910Error);
+
+impl From<Error> for PyErr {
+    fn from(err: Error) -> PyErr {
+        let msg = err.to_string();
+        match err {
+            Error::Usb(rusb::Error::Timeout) => PyTimeoutError::new_err(msg),
+            Error::Io(_) => PyOSError::new_err(msg),
+            Error::Config(_) | Error::SettingsFile(_) => PyValueError::new_err(msg),
+            Error::DeviceRejected { .. } => DeviceRejectedError::new_err(msg),
+            _ => It9910Error::new_err(msg),
+        }
+    }
+}
+
+fn control(name: &str) -> PyResult<Control> {
+    Control::ALL
+        .iter()
+        .find(|c| c.name() == name)
+        .copied()
+        .ok_or_else(|| PyValueError::new_err(format!("unknown picture control `{}`", name)))
+}
+
+#[pyclass(name = "It9910")]
+pub struct PyIt9910 {
+    device: Device,
+    session: CaptureSession,
+    version: Option<FirmwareVersion>,
+    grabber: GrabberConfig,
+    picture: Vec<(Control, i32)>,
+    encoder: Vec<(EncoderParam, u32)>,
+}
+
+/// Replace the values of `list` given in `changes`.
+fn merge<K: PartialEq + Copy, V: Copy>(list: &mut Vec<(K, V)>, changes: &[(K, Option<V>)]) {
+    for (key, value) in changes {
+        if let Some(value) = value {
+            list.retain(|(k, _)| k != key);
+            list.push((*key, *value));
+        }
+    }
+}
+
+#[pymethods]
+impl PyIt9910 {
+    /// Open the first device found, or the software emulation of one.
+    #[staticmethod]
+    #[pyo3(signature = (emulate = false))]
+    fn open(py: Python<'_>, emulate: bool) -> PyResult<PyIt9910> {
+        py.allow_threads(|| {
+            let device = if emulate {
+                Device::with_transport(Arc::new(Emulator::new(EmulatorConfig::default())))
+            } else {
+                Device::open()?
+            };
+            device.reset()?;
+            device.claim()?;
+            let version = FirmwareVersion::query(&device)?;
+            let mut session = CaptureSession::new(device.clone(), firmware::quirks_for(version));
+            session.set_grabber_entries(firmware::grabber_entries_for(version).to_vec());
+            Ok(PyIt9910 {
+                device,
+                session,
+                version,
+                grabber: GrabberConfig::default(),
+                picture: Vec::new(),
+                encoder: Vec::new(),
+            })
+        })
+    }
+
+    /// Firmware version, `None` if it could not be read.
+    #[getter]
+    fn firmware_version(&self) -> Option<u32> {
+        self.version.map(|v| v.0)
+    }
+
+    /// Set what is applied when the capture starts, keeping what earlier
+    /// calls set. Picture controls are in percent, the hue in degrees;
+    /// sources are given by ID or name.
+    #[pyo3(signature = (
+        *,
+        width = None,
+        height = None,
+        video_source = None,
+        audio_source = None,
+        brightness = None,
+        contrast = None,
+        hue = None,
+        saturation = None,
+        quality = None,
+        keyframe_rate = None,
+    ))]
+    #[allow(clippy::too_many_arguments)]
+    fn configure(
+        &mut self,
+        py: Python<'_>,
+        width: Option<u32>,
+        height: Option<u32>,
+        video_source: Option<String>,
+        audio_source: Option<String>,
+        brightness: Option<i32>,
+        contrast: Option<i32>,
+        hue: Option<i32>,
+        saturation: Option<i32>,
+        quality: Option<u32>,
+        keyframe_rate: Option<u32>,
+    ) -> PyResult<()> {
+        if let Some(width) = width {
+            self.grabber.width = width;
+        }
+        if let Some(height) = height {
+            self.grabber.height = height;
+        }
+        if video_source.is_some() || audio_source.is_some() {
+            let device = &self.device;
+            let (caps, current) = py.allow_threads(|| -> Result<_, Error> {
+                let caps = Capabilities::query(device)?;
+                let current = device.transact(&device.factory().make_get_source())?;
+                Ok((caps, current))
+            })?;
+            let audio = match audio_source {
+                Some(name) => caps.audio.find(&name).map_err(Error::Config)?.id,
+                None => current.word(0).unwrap_or(0),
+            };
+            let video = match video_source {
+                Some(name) => caps.video.find(&name).map_err(Error::Config)?.id,
+                None => current.word(4).unwrap_or(0),
+            };
+            self.session.set_source(audio, video);
+        }
+        let mut picture = self.picture.clone();
+        merge(
+            &mut picture,
+            &[
+                (Control::Brightness, brightness),
+                (Control::Contrast, contrast),
+                (Control::Hue, hue),
+                (Control::Saturation, saturation),
+            ],
+        );
+        let mut encoder = self.encoder.clone();
+        merge(
+            &mut encoder,
+            &[
+                (EncoderParam::Quality, quality),
+                (EncoderParam::KeyframeRate, keyframe_rate),
+            ],
+        );
+        self.session.set_picture(picture.clone())?;
+        self.session
+            .set_encoder(encoder.clone(), &firmware::limits_for(self.version))?;
+        self.picture = picture;
+        self.encoder = encoder;
+        Ok(())
+    }
+
+    /// Program the PC grabber and start the encoder.
+    fn start(&mut self, py: Python<'_>) -> PyResult<()> {
+        let grabber = self.grabber;
+        let session = &mut self.session;
+        py.allow_threads(|| session.start(grabber))?;
+        Ok(())
+    }
+
+    /// Read up to `size` bytes of the MPEG TS stream, empty if none came
+    /// within `timeout_ms`.
+    #[pyo3(signature = (size = 0x10000, timeout_ms = 1000))]
+    fn read(&self, py: Python<'_>, size: usize, timeout_ms: u64) -> PyResult<Py<PyBytes>> {
+        let device = &self.device;
+        let data = py.allow_threads(|| {
+            let mut buf = vec![0u8; size];
+            match device.read_stream(&mut buf, Duration::from_millis(timeout_ms)) {
+                Ok(len) => {
+                    buf.truncate(len);
+                    Ok(buf)
+                }
+                Err(rusb::Error::Timeout) => Ok(Vec::new()),
+                Err(e) => Err(Error::Usb(e)),
+            }
+        })?;
+        Ok(PyBytes::new(py, &data).unbind())
+    }
+
+    /// Stop the encoder and wait until it reports being idle.
+    fn stop(&mut self, py: Python<'_>) -> PyResult<()> {
+        let session = &mut self.session;
+        py.allow_threads(|| session.stop())?;
+        Ok(())
+    }
+
+    /// Current settings, as raw device values, `None` for those which
+    /// could not be read.
+    fn settings(&self, py: Python<'_>) -> HashMap<&'static str, Option<u32>> {
+        let device = &self.device;
+        py.allow_threads(|| {
+            settings::read_all(device)
+                .into_iter()
+                .map(|(setting, value)| (setting.name, value))
+                .collect()
+        })
+    }
+
+    /// Read a picture control, in percent or degrees.
+    fn get_control(&self, py: Python<'_>, name: &str) -> PyResult<i32> {
+        let control = control(name)?;
+        let device = &self.device;
+        let resp = py.allow_threads(|| {
+            device.transact(&device.factory().make_get_indexed(control.opcode(), 0))
+        })?;
+        let raw = resp
+            .word(4)
+            .ok_or_else(|| It9910Error::new_err(format!("short {} response", control)))?;
+        Ok(control.from_device(raw))
+    }
+
+    /// Change a picture control right away, in percent or degrees.
+    /// The value is also applied again when the capture restarts.
+    fn set_control(&mut self, py: Python<'_>, name: &str, value: i32) -> PyResult<()> {
+        let control = control(name)?;
+        let session = &mut self.session;
+        py.allow_threads(|| session.set_control(control, value))?;
+        merge(&mut self.picture, &[(control, Some(value))]);
+        Ok(())
+    }
+}
+
+#[pymodule]
+fn it9910_stream_example(m: &Bound<'_, PyModule>) -> PyResult<()> {
+    m.add_class::<PyIt9910>()?;
+    m.add("It9910Error", m.py().get_type::<It9910Error>())?;
+    m.add(
+        "DeviceRejectedError",
+        m.py().get_type::<DeviceRejectedError>(),
+    )?;
+    Ok(())
+}
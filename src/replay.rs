@@ -0,0 +1,194 @@
+//! Reading a recorded stream in place of the device.
+//!
+//! The file is read in chunks as the stream endpoint would give them, for
+//! the same pipeline and outputs. It can be paced to real time by its PCR,
+//! so that the network outputs see the data come as it would live, and read
+//! again from the start at its end for long runs. At each loop, a packet
+//! setting the discontinuity indicator is inserted for every PID, so that
+//! the continuity counters may start over without reporting errors.
+
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io::{self, Read, Seek, SeekFrom};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::clock::Unwrapper;
+use crate::ts::{self, Aligner, NULL_PID, PCR_HZ, PCR_MODULUS};
+
+/// PCR jump after which the pacing starts over from the current time.
+const MAX_PCR_JUMP: Duration = Duration::from_secs(10);
+
+/// A recorded stream given as input, as `FILE[:realtime]`.
+#[derive(Clone, Debug)]
+pub struct InputFile {
+    pub path: PathBuf,
+    /// Pace the reads to real time by the PCR.
+    pub realtime: bool,
+}
+
+impl FromStr for InputFile {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<InputFile, String> {
+        let (path, realtime) = match s.strip_suffix(":realtime") {
+            Some(path) => (path, true),
+            None => (s, false),
+        };
+        if path.is_empty() {
+            return Err("missing file name".to_string());
+        }
+        Ok(InputFile {
+            path: PathBuf::from(path),
+            realtime,
+        })
+    }
+}
+
+/// Paces the reads by the PCR of the first PID carrying one.
+struct Pacing {
+    pid: Option<u16>,
+    pcr: Unwrapper,
+    /// First PCR and when it was read.
+    start: Option<(u64, Instant)>,
+    last: u64,
+}
+
+impl Pacing {
+    fn new() -> Pacing {
+        Pacing {
+            pid: None,
+            pcr: Unwrapper::new(PCR_MODULUS),
+            start: None,
+            last: 0,
+        }
+    }
+
+    /// Start over from the next PCR, as after a loop.
+    fn restart(&mut self) {
+        self.pcr = Unwrapper::new(PCR_MODULUS);
+        self.start = None;
+    }
+
+    fn pcr(&mut self, pid: u16, pcr: u64) {
+        if *self.pid.get_or_insert(pid) != pid {
+            return;
+        }
+        let pcr = self.pcr.unwrap(pcr);
+        let max_jump = MAX_PCR_JUMP.as_secs() * PCR_HZ;
+        let jumped = pcr < self.last || pcr - self.last > max_jump;
+        if self.start.is_none() || jumped {
+            self.start = Some((pcr, Instant::now()));
+        }
+        self.last = pcr;
+    }
+
+    /// Wait until the last PCR is due.
+    fn wait(&self) {
+        if let Some((first, started)) = self.start {
+            let due = started + Duration::from_nanos((self.last - first) * 1000 / 27);
+            let now = Instant::now();
+            if due > now {
+                thread::sleep(due - now);
+            }
+        }
+    }
+}
+
+/// Reads a recorded stream as the device would send it.
+pub struct FileInput {
+    path: PathBuf,
+    file: File,
+    looping: bool,
+    loops: u64,
+    /// Bytes read from the file since its start.
+    read: u64,
+    aligner: Aligner,
+    pacing: Option<Pacing>,
+    /// Last continuity counter of each PID, to mark the loops.
+    counters: BTreeMap<u16, u8>,
+    /// Data to give before reading the file again.
+    pending: Vec<u8>,
+}
+
+impl FileInput {
+    pub fn open(input: &InputFile, looping: bool) -> io::Result<FileInput> {
+        Ok(FileInput {
+            path: input.path.clone(),
+            file: File::open(&input.path)?,
+            looping,
+            loops: 0,
+            read: 0,
+            aligner: Aligner::new(),
+            pacing: if input.realtime {
+                Some(Pacing::new())
+            } else {
+                None
+            },
+            counters: BTreeMap::new(),
+            pending: Vec::new(),
+        })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Number of times the file was read again from its start.
+    pub fn loops(&self) -> u64 {
+        self.loops
+    }
+
+    /// Read the next chunk into `buf`, returning 0 at the end of the input.
+    pub fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if !self.pending.is_empty() {
+            let len = self.pending.len().min(buf.len());
+            buf[..len].copy_from_slice(&self.pending[..len]);
+            self.pending.drain(..len);
+            return Ok(len);
+        }
+        let len = self.file.read(buf)?;
+        if len == 0 {
+            // An empty file is not looped over.
+            if !self.looping || self.read == 0 {
+                return Ok(0);
+            }
+            self.restart()?;
+            return self.read(buf);
+        }
+        let pacing = &mut self.pacing;
+        let counters = &mut self.counters;
+        self.aligner.push(&buf[..len], |_, pkt| {
+            if pkt.pid() != NULL_PID && pkt.has_payload() {
+                counters.insert(pkt.pid(), pkt.cc());
+            }
+            if let (Some(pacing), Some(pcr)) = (pacing.as_mut(), pkt.pcr()) {
+                pacing.pcr(pkt.pid(), pcr);
+            }
+        });
+        if let Some(pacing) = &self.pacing {
+            pacing.wait();
+        }
+        self.read += len as u64;
+        Ok(len)
+    }
+
+    /// Read the file again from its start, marking the discontinuity.
+    fn restart(&mut self) -> io::Result<()> {
+        self.file.seek(SeekFrom::Start(0))?;
+        self.loops += 1;
+        self.read = 0;
+        self.aligner = Aligner::new();
+        if let Some(pacing) = self.pacing.as_mut() {
+            pacing.restart();
+        }
+        for (&pid, &cc) in &self.counters {
+            self.pending
+                .extend_from_slice(&ts::discontinuity_packet(pid, cc));
+        }
+        self.counters.clear();
+        Ok(())
+    }
+}
@@ -0,0 +1,283 @@
+//! Analysis report of a recorded stream.
+
+use std::collections::BTreeMap;
+use std::fmt;
+
+use serde::Serialize;
+
+use crate::analysis::{Event, StreamAnalyzer};
+use crate::clock::Unwrapper;
+use crate::h264::Sps;
+use crate::psi::{ElementaryStream, PAT_PID};
+use crate::ts::{Aligner, PACKET_SIZE, PCR_HZ, PCR_MODULUS, SYNC_BYTE};
+
+#[derive(Clone, Debug, Default, Serialize)]
+pub struct PidStats {
+    pub packets: u64,
+    /// Stream type declared in the PMT.
+    pub stream_type: Option<u8>,
+    /// Average bitrate over the duration of the stream, in bits per second.
+    pub bitrate: Option<u64>,
+    /// Packets with the transport scrambling control bits set.
+    pub scrambled: u64,
+    /// Packets with the transport error indicator set.
+    pub transport_errors: u64,
+}
+
+#[derive(Clone, Debug, Default, Serialize)]
+pub struct PcrStats {
+    pub pid: Option<u16>,
+    pub count: u64,
+    pub min_interval_ms: Option<f64>,
+    pub max_interval_ms: Option<f64>,
+    pub mean_interval_ms: Option<f64>,
+    /// Number of times the PCR went backwards or jumped by more than a
+    /// second.
+    pub discontinuities: u64,
+}
+
+#[derive(Clone, Debug, Default, Serialize)]
+pub struct Report {
+    pub bytes: u64,
+    pub packets: u64,
+    pub starts_with_sync: bool,
+    /// Bytes dropped while looking for packet boundaries.
+    pub skipped_bytes: u64,
+    /// Bytes after the last complete packet.
+    pub trailing_bytes: u64,
+    pub has_pat: bool,
+    pub has_pmt: bool,
+    /// Duration covered by the PCR, in seconds.
+    pub duration: Option<f64>,
+    pub video_format: Option<Sps>,
+    pub pids: BTreeMap<u16, PidStats>,
+    pub pcr: PcrStats,
+    pub continuity_errors: u64,
+    pub timestamp_discontinuities: u64,
+    pub scrambled_packets: u64,
+    pub transport_errors: u64,
+    /// The first problems found, up to `ReportBuilder::MAX_EVENTS`.
+    pub events: Vec<Event>,
+}
+
+impl Report {
+    fn streams(&self) -> impl Iterator<Item = ElementaryStream> + '_ {
+        self.pids.iter().filter_map(|(&pid, stats)| {
+            Some(ElementaryStream {
+                stream_type: stats.stream_type?,
+                pid,
+            })
+        })
+    }
+
+    pub fn has_video(&self) -> bool {
+        self.streams().any(|es| es.is_video())
+    }
+
+    pub fn has_audio(&self) -> bool {
+        self.streams().any(|es| es.is_audio())
+    }
+}
+
+/// Runs the stream analysis over data fed in chunks, with bounded memory.
+#[derive(Default)]
+pub struct ReportBuilder {
+    aligner: Aligner,
+    analyzer: StreamAnalyzer,
+    pids: BTreeMap<u16, u64>,
+    pcr_pid: Option<u16>,
+    pcr: Option<Unwrapper>,
+    first_pcr: Option<u64>,
+    last_pcr: Option<u64>,
+    pcr_count: u64,
+    min_interval: Option<u64>,
+    max_interval: Option<u64>,
+    pcr_discontinuities: u64,
+    events: Vec<Event>,
+    bytes: u64,
+    first_byte: Option<u8>,
+}
+
+impl ReportBuilder {
+    pub const MAX_EVENTS: usize = 1000;
+
+    pub fn new() -> ReportBuilder {
+        ReportBuilder::default()
+    }
+
+    pub fn push(&mut self, data: &[u8]) {
+        self.bytes += data.len() as u64;
+        if self.first_byte.is_none() {
+            self.first_byte = data.first().copied();
+        }
+        let analyzer = &mut self.analyzer;
+        let pids = &mut self.pids;
+        let mut pcrs = Vec::new();
+        self.aligner.push(data, |offset, pkt| {
+            *pids.entry(pkt.pid()).or_default() += 1;
+            analyzer.packet(offset, &pkt);
+            if let Some(pcr) = pkt.pcr() {
+                pcrs.push((pkt.pid(), pcr));
+            }
+        });
+        for (pid, pcr) in pcrs {
+            self.pcr_sample(pid, pcr);
+        }
+        let room = Self::MAX_EVENTS.saturating_sub(self.events.len());
+        self.events
+            .extend(self.analyzer.take_events().into_iter().take(room));
+    }
+
+    fn pcr_sample(&mut self, pid: u16, pcr: u64) {
+        // Only follow the PCR PID of the program, or the first one seen.
+        let pcr_pid = self.analyzer.pmt().map(|pmt| pmt.pcr_pid).or(self.pcr_pid);
+        if pcr_pid.is_some_and(|p| p != pid) {
+            return;
+        }
+        self.pcr_pid = Some(pid);
+        let pcr = self
+            .pcr
+            .get_or_insert_with(|| Unwrapper::new(PCR_MODULUS))
+            .unwrap(pcr);
+        self.pcr_count += 1;
+        if let Some(last) = self.last_pcr {
+            if pcr < last || pcr - last > PCR_HZ {
+                self.pcr_discontinuities += 1;
+            } else {
+                let interval = pcr - last;
+                self.min_interval = Some(self.min_interval.map_or(interval, |m| m.min(interval)));
+                self.max_interval = Some(self.max_interval.map_or(interval, |m| m.max(interval)));
+            }
+        }
+        self.first_pcr.get_or_insert(pcr);
+        self.last_pcr = Some(pcr);
+    }
+
+    pub fn finish(self) -> Report {
+        let to_ms = |ticks: u64| ticks as f64 * 1000.0 / PCR_HZ as f64;
+        let duration = match (self.first_pcr, self.last_pcr) {
+            (Some(first), Some(last)) if last > first => {
+                Some((last - first) as f64 / PCR_HZ as f64)
+            }
+            _ => None,
+        };
+        let pmt = self.analyzer.pmt();
+        let scrambled = self.analyzer.scrambled_packets();
+        let transport_errors = self.analyzer.transport_errors();
+        let pids = self
+            .pids
+            .iter()
+            .map(|(&pid, &packets)| {
+                let stats = PidStats {
+                    packets,
+                    stream_type: pmt
+                        .and_then(|pmt| pmt.streams.iter().find(|es| es.pid == pid))
+                        .map(|es| es.stream_type),
+                    bitrate: duration
+                        .map(|secs| (packets * PACKET_SIZE as u64 * 8) as f64 / secs)
+                        .map(|rate| rate as u64),
+                    scrambled: scrambled.get(&pid).copied().unwrap_or_default(),
+                    transport_errors: transport_errors.get(&pid).copied().unwrap_or_default(),
+                };
+                (pid, stats)
+            })
+            .collect();
+        let mean_interval_ms = match (self.first_pcr, self.last_pcr) {
+            (Some(first), Some(last)) if self.pcr_count > 1 && last > first => {
+                Some(to_ms(last - first) / (self.pcr_count - 1) as f64)
+            }
+            _ => None,
+        };
+        let packets: u64 = self.pids.values().sum();
+        let skipped = self.aligner.skipped();
+        Report {
+            bytes: self.bytes,
+            packets,
+            starts_with_sync: self.first_byte == Some(SYNC_BYTE),
+            skipped_bytes: skipped,
+            trailing_bytes: self.bytes - skipped - packets * PACKET_SIZE as u64,
+            has_pat: self.pids.contains_key(&PAT_PID),
+            has_pmt: pmt.is_some(),
+            duration,
+            video_format: self.analyzer.video_format(),
+            pids,
+            pcr: PcrStats {
+                pid: self.pcr_pid,
+                count: self.pcr_count,
+                min_interval_ms: self.min_interval.map(to_ms),
+                max_interval_ms: self.max_interval.map(to_ms),
+                mean_interval_ms,
+                discontinuities: self.pcr_discontinuities,
+            },
+            continuity_errors: self.analyzer.continuity_errors(),
+            timestamp_discontinuities: self.analyzer.timestamp_discontinuities(),
+            scrambled_packets: scrambled.values().sum(),
+            transport_errors: transport_errors.values().sum(),
+            events: self.events,
+        }
+    }
+}
+
+impl fmt::Display for Report {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(
+            f,
+            "Size: {} bytes, {} packets, {} bytes skipped, {} trailing",
+            self.bytes, self.packets, self.skipped_bytes, self.trailing_bytes
+        )?;
+        match self.duration {
+            Some(secs) => writeln!(f, "Duration: {:.3} s", secs)?,
+            None => writeln!(f, "Duration: unknown")?,
+        }
+        if let Some(sps) = &self.video_format {
+            writeln!(f, "Video: {}", sps)?;
+        }
+        writeln!(f, "PIDs:")?;
+        for (pid, stats) in &self.pids {
+            write!(f, "  {:#06x}: {} packets", pid, stats.packets)?;
+            if let Some(rate) = stats.bitrate {
+                write!(f, ", {:.1} kbit/s", rate as f64 / 1000.0)?;
+            }
+            if let Some(t) = stats.stream_type {
+                write!(f, ", stream type {:#04x}", t)?;
+            }
+            if stats.scrambled > 0 {
+                write!(f, ", {} scrambled", stats.scrambled)?;
+            }
+            if stats.transport_errors > 0 {
+                write!(f, ", {} with TEI", stats.transport_errors)?;
+            }
+            writeln!(f)?;
+        }
+        match self.pcr.pid {
+            Some(pid) => {
+                write!(f, "PCR: PID {:#06x}, {} samples", pid, self.pcr.count)?;
+                if let (Some(min), Some(mean), Some(max)) = (
+                    self.pcr.min_interval_ms,
+                    self.pcr.mean_interval_ms,
+                    self.pcr.max_interval_ms,
+                ) {
+                    write!(
+                        f,
+                        ", interval {:.1}/{:.1}/{:.1} ms (min/mean/max)",
+                        min, mean, max
+                    )?;
+                }
+                writeln!(f, ", {} discontinuities", self.pcr.discontinuities)?;
+            }
+            None => writeln!(f, "PCR: none")?,
+        }
+        writeln!(f, "Continuity errors: {}", self.continuity_errors)?;
+        writeln!(
+            f,
+            "Timestamp discontinuities: {}",
+            self.timestamp_discontinuities
+        )?;
+        writeln!(f, "Scrambled packets: {}", self.scrambled_packets)?;
+        writeln!(f, "Transport errors (TEI): {}", self.transport_errors)?;
+        for event in &self.events {
+            writeln!(f, "  {}", event)?;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,156 @@
+use std::convert::TryInto;
+use std::fmt;
+
+use crate::protocol::{LENGTH, MAGIC, MAGIC1, MAGIC2, OPCODE, OPERATION, SEQ};
+
+pub use crate::protocol::HEADER_LEN;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ParseError {
+    /// Fewer bytes than a header were received.
+    Short(usize),
+    /// The magic bytes at 0x06 and 0x0e do not read 0x10 0x99.
+    BadMagic,
+    /// The length field does not match the bytes received.
+    Length { declared: usize, received: usize },
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ParseError::Short(len) => write!(f, "short response ({} bytes)", len),
+            ParseError::BadMagic => write!(f, "bad header magic"),
+            ParseError::Length { declared, received } => write!(
+                f,
+                "length field of {} bytes, {} bytes received",
+                declared, received
+            ),
+        }
+    }
+}
+
+/// A response received on the command endpoint.
+///
+/// The device answers with the same header layout as the command it
+/// received, followed by the payload.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Response {
+    pub length: u16,
+    pub opcode: u16,
+    pub operation: u32,
+    pub seq: u16,
+    pub payload: Vec<u8>,
+}
+
+/// Whether `data` starts with a response header.
+pub fn is_header(data: &[u8]) -> bool {
+    data.len() >= HEADER_LEN && data[MAGIC1.range()] == MAGIC && data[MAGIC2.range()] == MAGIC
+}
+
+impl Response {
+    /// Length of the response starting `data`, header included, as given by
+    /// its length field.
+    pub fn declared_len(data: &[u8]) -> Result<usize, ParseError> {
+        if data.len() < HEADER_LEN {
+            return Err(ParseError::Short(data.len()));
+        }
+        if !is_header(data) {
+            return Err(ParseError::BadMagic);
+        }
+        let declared = usize::from(u16::from_le_bytes(data[LENGTH.range()].try_into().unwrap()));
+        if declared < HEADER_LEN {
+            return Err(ParseError::Length {
+                declared,
+                received: data.len(),
+            });
+        }
+        Ok(declared)
+    }
+
+    pub fn parse(data: &[u8]) -> Result<Response, ParseError> {
+        if data.len() < HEADER_LEN {
+            return Err(ParseError::Short(data.len()));
+        }
+        if !is_header(data) {
+            return Err(ParseError::BadMagic);
+        }
+        Ok(Response {
+            length: u16::from_le_bytes(data[LENGTH.range()].try_into().unwrap()),
+            opcode: u16::from_le_bytes(data[OPCODE.range()].try_into().unwrap()),
+            operation: u32::from_le_bytes(data[OPERATION.range()].try_into().unwrap()),
+            seq: u16::from_le_bytes(data[SEQ.range()].try_into().unwrap()),
+            payload: data[HEADER_LEN..].to_vec(),
+        })
+    }
+
+    /// Status of a command rejected by the device, `operation` being the
+    /// operation of the command.
+    ///
+    /// The firmware is thought not to stall a command it refuses, but to
+    /// echo it with another value in the operation field, returned here.
+    /// This is unverified: no refusal has been captured from a real device,
+    /// so any operation differing from that of the command is taken as a
+    /// rejection, and what the values mean is not known.
+    pub fn rejection(&self, operation: u32) -> Option<u32> {
+        if self.operation == operation {
+            None
+        } else {
+            Some(self.operation)
+        }
+    }
+
+    /// Little-endian word at `offset` in the payload, if present.
+    pub fn word(&self, offset: usize) -> Option<u32> {
+        let bytes = self.payload.get(offset..offset + 4)?;
+        Some(u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::command::{CommandFactory, Operation};
+    use proptest::collection::vec;
+    use proptest::prelude::*;
+
+    /// The echo of a SET command, with `operation` in place of its own.
+    fn echo(operation: u32) -> Response {
+        let mut data = CommandFactory::new().make_command(0x0101, Operation::Set, &[0; 8]);
+        data[OPERATION.range()].copy_from_slice(&operation.to_le_bytes());
+        Response::parse(&data).unwrap()
+    }
+
+    #[test]
+    fn echo_is_not_a_rejection() {
+        let set = Operation::Set.value();
+        assert_eq!(echo(set).rejection(set), None);
+    }
+
+    #[test]
+    fn other_operation_is_a_rejection() {
+        let set = Operation::Set.value();
+        for status in [0, Operation::Get.value(), 0x8000_0001, u32::MAX] {
+            assert_eq!(echo(status).rejection(set), Some(status));
+        }
+    }
+
+    proptest! {
+        #[test]
+        fn parse_never_panics(mut data in vec(any::<u8>(), 0..256), magic: bool) {
+            // Half the inputs get past the magic check.
+            if magic && data.len() >= HEADER_LEN {
+                data[MAGIC1.range()].copy_from_slice(&MAGIC);
+                data[MAGIC2.range()].copy_from_slice(&MAGIC);
+            }
+            if let Ok(declared) = Response::declared_len(&data) {
+                prop_assert!(declared >= HEADER_LEN);
+            }
+            if let Ok(resp) = Response::parse(&data) {
+                prop_assert_eq!(resp.payload.len(), data.len() - HEADER_LEN);
+                for offset in 0..resp.payload.len() + 4 {
+                    prop_assert_eq!(resp.word(offset).is_some(), offset + 4 <= resp.payload.len());
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,854 @@
+//! Output publishing the stream to an RTMP server.
+//!
+//! The client does the plain handshake, then `connect`, `createStream` and
+//! `publish`, and sends the FLV tags of the `flv` muxer as audio and video
+//! messages. Messages from the server are read between the tags, to answer
+//! its pings and acknowledge what it sent. A lost connection is retried as
+//! for the TCP output, the stream restarting at the next keyframe with its
+//! sequence headers.
+
+use std::convert::TryInto;
+use std::fmt;
+use std::io::{self, Read, Write};
+use std::net::TcpStream;
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime};
+
+use log::debug;
+
+use crate::flv::{FlvMuxer, Tag, TAG_AUDIO};
+use crate::sink::{Connection, Output};
+use crate::tcp::{self, Retry};
+
+const DEFAULT_PORT: u16 = 1935;
+const HANDSHAKE_SIZE: usize = 1536;
+/// Chunk size of the messages sent, announced after the handshake.
+const CHUNK_SIZE: usize = 4096;
+
+/// Message types.
+const MSG_SET_CHUNK_SIZE: u8 = 1;
+const MSG_ACK: u8 = 3;
+const MSG_USER_CONTROL: u8 = 4;
+const MSG_WINDOW_ACK_SIZE: u8 = 5;
+const MSG_AUDIO: u8 = 8;
+const MSG_VIDEO: u8 = 9;
+const MSG_COMMAND_AMF0: u8 = 20;
+
+/// User control events.
+const EVENT_PING_REQUEST: u16 = 6;
+const EVENT_PING_RESPONSE: u16 = 7;
+
+/// Chunk streams of the messages sent.
+const CSID_CONTROL: u8 = 2;
+const CSID_COMMAND: u8 = 3;
+const CSID_AUDIO: u8 = 4;
+const CSID_VIDEO: u8 = 6;
+
+/// Location of a stream to publish: `rtmp://host[:port]/app/key`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct RtmpUrl {
+    pub host: String,
+    pub port: u16,
+    pub app: String,
+    pub key: String,
+}
+
+impl RtmpUrl {
+    pub fn parse(url: &str) -> Result<RtmpUrl, String> {
+        let invalid = || format!("expected rtmp://HOST[:PORT]/APP/KEY, got `{}`", url);
+        let rest = url.strip_prefix("rtmp://").ok_or_else(invalid)?;
+        let (authority, path) = rest.split_once('/').ok_or_else(invalid)?;
+        let (app, key) = path.split_once('/').ok_or_else(invalid)?;
+        if authority.is_empty() || app.is_empty() || key.is_empty() {
+            return Err(invalid());
+        }
+        let (host, port) = match authority.rsplit_once(':') {
+            Some((host, port)) => (host, port.parse().map_err(|_| invalid())?),
+            None => (authority, DEFAULT_PORT),
+        };
+        Ok(RtmpUrl {
+            host: host.to_string(),
+            port,
+            app: app.to_string(),
+            key: key.to_string(),
+        })
+    }
+
+    fn address(&self) -> String {
+        format!("{}:{}", self.host, self.port)
+    }
+
+    fn tc_url(&self) -> String {
+        format!("rtmp://{}:{}/{}", self.host, self.port, self.app)
+    }
+}
+
+/// The stream key is left out, being a secret.
+impl fmt::Display for RtmpUrl {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.tc_url())
+    }
+}
+
+/// AMF0 values.
+#[derive(Clone, Debug, PartialEq)]
+enum Amf {
+    Number(f64),
+    Bool(bool),
+    String(String),
+    Object(Vec<(String, Amf)>),
+    Null,
+}
+
+impl Amf {
+    fn encode(&self, out: &mut Vec<u8>) {
+        match self {
+            Amf::Number(n) => {
+                out.push(0x00);
+                out.extend_from_slice(&n.to_be_bytes());
+            }
+            Amf::Bool(b) => out.extend_from_slice(&[0x01, u8::from(*b)]),
+            Amf::String(s) => {
+                out.push(0x02);
+                encode_key(s, out);
+            }
+            Amf::Object(props) => {
+                out.push(0x03);
+                for (key, value) in props {
+                    encode_key(key, out);
+                    value.encode(out);
+                }
+                out.extend_from_slice(&[0x00, 0x00, 0x09]);
+            }
+            Amf::Null => out.push(0x05),
+        }
+    }
+
+    /// Decode the value at the start of `data`, advancing it. Types not
+    /// used by the commands answered to the client are not decoded.
+    fn decode(data: &mut &[u8]) -> Option<Amf> {
+        let (&marker, rest) = data.split_first()?;
+        *data = rest;
+        match marker {
+            0x00 => {
+                let n = f64::from_be_bytes(take(data, 8)?.try_into().ok()?);
+                Some(Amf::Number(n))
+            }
+            0x01 => Some(Amf::Bool(take(data, 1)?[0] != 0)),
+            0x02 => Some(Amf::String(decode_key(data)?)),
+            0x03 => decode_props(data).map(Amf::Object),
+            // ECMA array: a count, then properties as in an object.
+            0x08 => {
+                take(data, 4)?;
+                decode_props(data).map(Amf::Object)
+            }
+            0x05 | 0x06 => Some(Amf::Null),
+            _ => None,
+        }
+    }
+
+    fn get(&self, key: &str) -> Option<&Amf> {
+        match self {
+            Amf::Object(props) => props.iter().find(|(k, _)| k == key).map(|(_, v)| v),
+            _ => None,
+        }
+    }
+
+    fn as_str(&self) -> Option<&str> {
+        match self {
+            Amf::String(s) => Some(s),
+            _ => None,
+        }
+    }
+}
+
+fn encode_key(s: &str, out: &mut Vec<u8>) {
+    out.extend_from_slice(&(s.len() as u16).to_be_bytes());
+    out.extend_from_slice(s.as_bytes());
+}
+
+fn take<'a>(data: &mut &'a [u8], n: usize) -> Option<&'a [u8]> {
+    if data.len() < n {
+        return None;
+    }
+    let (head, rest) = data.split_at(n);
+    *data = rest;
+    Some(head)
+}
+
+fn decode_key(data: &mut &[u8]) -> Option<String> {
+    let len = take(data, 2)?;
+    let len = usize::from(u16::from_be_bytes([len[0], len[1]]));
+    Some(String::from_utf8_lossy(take(data, len)?).into_owned())
+}
+
+fn decode_props(data: &mut &[u8]) -> Option<Vec<(String, Amf)>> {
+    let mut props = Vec::new();
+    loop {
+        let key = decode_key(data)?;
+        if key.is_empty() && data.first() == Some(&0x09) {
+            *data = &data[1..];
+            return Some(props);
+        }
+        props.push((key, Amf::decode(data)?));
+    }
+}
+
+/// A message received from the server.
+struct Message {
+    kind: u8,
+    payload: Vec<u8>,
+}
+
+impl Message {
+    /// The values of a command message.
+    fn command(&self) -> Vec<Amf> {
+        let mut data = &self.payload[..];
+        let mut values = Vec::new();
+        while let Some(value) = Amf::decode(&mut data) {
+            values.push(value);
+        }
+        values
+    }
+}
+
+/// State of a chunk stream of the server.
+#[derive(Clone, Default)]
+struct ChunkStream {
+    length: usize,
+    kind: u8,
+    extended: bool,
+    payload: Vec<u8>,
+}
+
+/// Reassembles the messages of the server from its chunks.
+struct ChunkReader {
+    buf: Vec<u8>,
+    chunk_size: usize,
+    streams: Vec<(u32, ChunkStream)>,
+}
+
+impl ChunkReader {
+    fn new() -> ChunkReader {
+        ChunkReader {
+            buf: Vec::new(),
+            chunk_size: 128,
+            streams: Vec::new(),
+        }
+    }
+
+    /// The next complete message, if its chunks have all been received.
+    fn next(&mut self) -> Option<Message> {
+        loop {
+            let (len, csid, header) = self.parse_chunk()?;
+            let chunk_size = self.chunk_size;
+            let stream = match self.streams.iter_mut().find(|(id, _)| *id == csid) {
+                Some((_, stream)) => stream,
+                None => {
+                    self.streams.push((csid, ChunkStream::default()));
+                    &mut self.streams.last_mut().unwrap().1
+                }
+            };
+            if let Some((length, kind, extended)) = header {
+                stream.length = length;
+                stream.kind = kind;
+                stream.extended = extended;
+            }
+            let size = stream
+                .length
+                .saturating_sub(stream.payload.len())
+                .min(chunk_size);
+            stream.payload.extend_from_slice(&self.buf[len - size..len]);
+            self.buf.drain(..len);
+            if stream.payload.len() >= stream.length {
+                let message = Message {
+                    kind: stream.kind,
+                    payload: std::mem::take(&mut stream.payload),
+                };
+                if message.kind == MSG_SET_CHUNK_SIZE && message.payload.len() >= 4 {
+                    let size = u32::from_be_bytes(message.payload[..4].try_into().unwrap());
+                    self.chunk_size = (size & 0x7fff_ffff).max(1) as usize;
+                }
+                return Some(message);
+            }
+        }
+    }
+
+    /// Length of the complete chunk at the start of the buffer, with its
+    /// chunk stream and the message header it sets.
+    #[allow(clippy::type_complexity)]
+    fn parse_chunk(&self) -> Option<(usize, u32, Option<(usize, u8, bool)>)> {
+        let buf = &self.buf;
+        let first = *buf.first()?;
+        let fmt = first >> 6;
+        let (csid, mut pos) = match first & 0x3f {
+            0 => (64 + u32::from(*buf.get(1)?), 2),
+            1 => (
+                64 + u32::from(*buf.get(1)?) + 256 * u32::from(*buf.get(2)?),
+                3,
+            ),
+            id => (u32::from(id), 1),
+        };
+        let previous = self
+            .streams
+            .iter()
+            .find(|(id, _)| *id == csid)
+            .map(|(_, s)| s);
+        let header_len = [11, 7, 3, 0][usize::from(fmt)];
+        let header = buf.get(pos..pos + header_len)?;
+        let extended = match fmt {
+            3 => previous.is_some_and(|s| s.extended),
+            _ => header[..3] == [0xff, 0xff, 0xff],
+        };
+        let (length, kind) = match fmt {
+            0 | 1 => (
+                usize::from(header[3]) << 16 | usize::from(header[4]) << 8 | usize::from(header[5]),
+                header[6],
+            ),
+            _ => {
+                let previous = previous?;
+                (previous.length, previous.kind)
+            }
+        };
+        pos += header_len + if extended { 4 } else { 0 };
+        let received = previous.map_or(0, |s| s.payload.len());
+        let size = length.saturating_sub(received).min(self.chunk_size);
+        if buf.len() < pos + size {
+            return None;
+        }
+        Some((pos + size, csid, Some((length, kind, extended))))
+    }
+}
+
+/// A connection publishing a stream.
+struct Session {
+    stream: TcpStream,
+    reader: ChunkReader,
+    stream_id: u32,
+    transaction: f64,
+    /// Acknowledgement window of the server, and bytes received.
+    window: u32,
+    received: u32,
+    acknowledged: u32,
+}
+
+impl Session {
+    fn open(url: &RtmpUrl, timeout: Duration) -> io::Result<Session> {
+        let stream = tcp::connect(&url.address(), timeout)?;
+        stream.set_read_timeout(Some(timeout))?;
+        let mut session = Session {
+            stream,
+            reader: ChunkReader::new(),
+            stream_id: 0,
+            transaction: 0.0,
+            window: 2_500_000,
+            received: 0,
+            acknowledged: 0,
+        };
+        session.handshake()?;
+        session.send(
+            CSID_CONTROL,
+            MSG_SET_CHUNK_SIZE,
+            0,
+            0,
+            &(CHUNK_SIZE as u32).to_be_bytes(),
+        )?;
+        session.call(
+            "connect",
+            vec![Amf::Object(vec![
+                ("app".into(), Amf::String(url.app.clone())),
+                ("type".into(), Amf::String("nonprivate".into())),
+                (
+                    "flashVer".into(),
+                    Amf::String("FMLE/3.0 (compatible; it9910)".into()),
+                ),
+                ("tcUrl".into(), Amf::String(url.tc_url())),
+            ])],
+        )?;
+        session.result("connect", timeout)?;
+        let key = Amf::String(url.key.clone());
+        session.call("releaseStream", vec![Amf::Null, key.clone()])?;
+        session.call("FCPublish", vec![Amf::Null, key.clone()])?;
+        session.call("createStream", vec![Amf::Null])?;
+        let values = session.result("createStream", timeout)?;
+        session.stream_id = match values.get(3) {
+            Some(Amf::Number(id)) => *id as u32,
+            _ => return Err(protocol_error("createStream result without stream ID")),
+        };
+        session.call("publish", vec![Amf::Null, key, Amf::String("live".into())])?;
+        session.status("NetStream.Publish.Start", timeout)?;
+        Ok(session)
+    }
+
+    /// Plain handshake, without the digest some servers accept.
+    fn handshake(&mut self) -> io::Result<()> {
+        let mut c0c1 = vec![0u8; 1 + HANDSHAKE_SIZE];
+        c0c1[0] = 3;
+        let mut seed = SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .map_or(1, |d| d.subsec_nanos() | 1);
+        for b in &mut c0c1[9..] {
+            // xorshift, the content only has to be echoed back.
+            seed ^= seed << 13;
+            seed ^= seed >> 17;
+            seed ^= seed << 5;
+            *b = seed as u8;
+        }
+        self.stream.write_all(&c0c1)?;
+        let mut s0s1s2 = vec![0u8; 1 + 2 * HANDSHAKE_SIZE];
+        self.stream.read_exact(&mut s0s1s2)?;
+        if s0s1s2[0] != 3 {
+            return Err(protocol_error(&format!(
+                "unsupported RTMP version {}",
+                s0s1s2[0]
+            )));
+        }
+        self.stream.write_all(&s0s1s2[1..1 + HANDSHAKE_SIZE])
+    }
+
+    /// Send a message in chunks.
+    fn send(
+        &mut self,
+        csid: u8,
+        kind: u8,
+        stream_id: u32,
+        timestamp: u32,
+        payload: &[u8],
+    ) -> io::Result<()> {
+        let extended = timestamp >= 0xff_ffff;
+        let ts = if extended { 0xff_ffff } else { timestamp };
+        let mut out = Vec::with_capacity(payload.len() + 16 + payload.len() / CHUNK_SIZE * 5);
+        out.push(csid);
+        out.extend_from_slice(&ts.to_be_bytes()[1..]);
+        out.extend_from_slice(&(payload.len() as u32).to_be_bytes()[1..]);
+        out.push(kind);
+        out.extend_from_slice(&stream_id.to_le_bytes());
+        for (i, chunk) in payload.chunks(CHUNK_SIZE).enumerate() {
+            if i > 0 {
+                out.push(0xc0 | csid);
+            }
+            if extended {
+                out.extend_from_slice(&timestamp.to_be_bytes());
+            }
+            out.extend_from_slice(chunk);
+        }
+        self.stream.write_all(&out)
+    }
+
+    fn call(&mut self, name: &str, args: Vec<Amf>) -> io::Result<()> {
+        self.transaction += 1.0;
+        let mut payload = Vec::new();
+        Amf::String(name.to_string()).encode(&mut payload);
+        Amf::Number(self.transaction).encode(&mut payload);
+        for arg in args {
+            arg.encode(&mut payload);
+        }
+        let stream_id = if name == "publish" { self.stream_id } else { 0 };
+        self.send(CSID_COMMAND, MSG_COMMAND_AMF0, stream_id, 0, &payload)
+    }
+
+    /// Wait for the result of the last call.
+    fn result(&mut self, name: &str, timeout: Duration) -> io::Result<Vec<Amf>> {
+        let transaction = self.transaction;
+        self.wait(timeout, |values| match values {
+            [Amf::String(cmd), Amf::Number(id), ..] if *id == transaction => match &cmd[..] {
+                "_result" => Some(Ok(())),
+                "_error" => Some(Err(protocol_error(&format!(
+                    "{} refused: {}",
+                    name,
+                    describe(values)
+                )))),
+                _ => None,
+            },
+            _ => None,
+        })
+    }
+
+    /// Wait for an `onStatus` with `code`.
+    fn status(&mut self, code: &str, timeout: Duration) -> io::Result<Vec<Amf>> {
+        self.wait(timeout, |values| match values {
+            [Amf::String(cmd), ..] if cmd == "onStatus" => {
+                let info = values.get(3);
+                match info.and_then(|i| i.get("code")).and_then(Amf::as_str) {
+                    Some(c) if c == code => Some(Ok(())),
+                    _ if info.and_then(|i| i.get("level")).and_then(Amf::as_str)
+                        == Some("error") =>
+                    {
+                        Some(Err(protocol_error(&describe(values))))
+                    }
+                    _ => None,
+                }
+            }
+            _ => None,
+        })
+    }
+
+    /// Read messages until a command `check` accepts or rejects.
+    fn wait<F>(&mut self, timeout: Duration, check: F) -> io::Result<Vec<Amf>>
+    where
+        F: Fn(&[Amf]) -> Option<io::Result<()>>,
+    {
+        let deadline = Instant::now() + timeout;
+        loop {
+            while let Some(message) = self.reader.next() {
+                if let Some(values) = self.handle(message)? {
+                    if let Some(res) = check(&values) {
+                        return res.map(|_| values);
+                    }
+                }
+            }
+            if Instant::now() >= deadline {
+                return Err(io::Error::new(
+                    io::ErrorKind::TimedOut,
+                    "no answer from the RTMP server",
+                ));
+            }
+            self.read(true)?;
+        }
+    }
+
+    /// Read what the server sent, waiting for it if `block`.
+    fn read(&mut self, block: bool) -> io::Result<()> {
+        let mut buf = [0u8; 4096];
+        self.stream.set_nonblocking(!block)?;
+        let res = self.stream.read(&mut buf);
+        self.stream.set_nonblocking(false)?;
+        match res {
+            Ok(0) => Err(io::ErrorKind::UnexpectedEof.into()),
+            Ok(n) => {
+                self.reader.buf.extend_from_slice(&buf[..n]);
+                self.received = self.received.wrapping_add(n as u32);
+                if self.received.wrapping_sub(self.acknowledged) >= self.window / 2 {
+                    self.acknowledged = self.received;
+                    let seq = self.received.to_be_bytes();
+                    self.send(CSID_CONTROL, MSG_ACK, 0, 0, &seq)?;
+                }
+                Ok(())
+            }
+            Err(e) if !block && e.kind() == io::ErrorKind::WouldBlock => Ok(()),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Handle the protocol messages, returning the values of the commands.
+    fn handle(&mut self, message: Message) -> io::Result<Option<Vec<Amf>>> {
+        let payload = &message.payload;
+        match message.kind {
+            MSG_WINDOW_ACK_SIZE if payload.len() >= 4 => {
+                self.window = u32::from_be_bytes(payload[..4].try_into().unwrap()).max(2);
+            }
+            MSG_USER_CONTROL if payload.len() >= 6 => {
+                let event = u16::from_be_bytes([payload[0], payload[1]]);
+                if event == EVENT_PING_REQUEST {
+                    let mut pong = EVENT_PING_RESPONSE.to_be_bytes().to_vec();
+                    pong.extend_from_slice(&payload[2..6]);
+                    self.send(CSID_CONTROL, MSG_USER_CONTROL, 0, 0, &pong)?;
+                }
+            }
+            MSG_COMMAND_AMF0 => {
+                let values = message.command();
+                debug!("RTMP server: {}", describe(&values));
+                return Ok(Some(values));
+            }
+            _ => (),
+        }
+        Ok(None)
+    }
+
+    /// Send a tag, after handling what the server sent meanwhile.
+    fn publish(&mut self, tag: &Tag) -> io::Result<()> {
+        self.read(false)?;
+        while let Some(message) = self.reader.next() {
+            if let Some(values) = self.handle(message)? {
+                let info = values.get(3);
+                if info.and_then(|i| i.get("level")).and_then(Amf::as_str) == Some("error") {
+                    return Err(protocol_error(&describe(&values)));
+                }
+            }
+        }
+        let (csid, kind) = if tag.kind == TAG_AUDIO {
+            (CSID_AUDIO, MSG_AUDIO)
+        } else {
+            (CSID_VIDEO, MSG_VIDEO)
+        };
+        self.send(csid, kind, self.stream_id, tag.timestamp, &tag.data)
+    }
+}
+
+fn protocol_error(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg.to_string())
+}
+
+/// Short description of a command, with the code and description of its
+/// status object if any.
+fn describe(values: &[Amf]) -> String {
+    let name = values.first().and_then(Amf::as_str).unwrap_or("?");
+    let info = values.iter().skip(2).find(|v| v.get("code").is_some());
+    match info {
+        Some(info) => format!(
+            "{} {} {}",
+            name,
+            info.get("code").and_then(Amf::as_str).unwrap_or(""),
+            info.get("description").and_then(Amf::as_str).unwrap_or("")
+        )
+        .trim_end()
+        .to_string(),
+        None => name.to_string(),
+    }
+}
+
+pub struct RtmpOutput {
+    url: RtmpUrl,
+    timeout: Duration,
+    session: Option<Session>,
+    muxer: FlvMuxer,
+    connection: Arc<Connection>,
+    retry: Retry,
+}
+
+impl RtmpOutput {
+    /// Publish to `url`. A first connection is attempted right away,
+    /// without failing when the server is not there.
+    pub fn new(url: RtmpUrl, timeout: Duration, max_attempts: Option<u32>) -> RtmpOutput {
+        let mut output = RtmpOutput {
+            retry: Retry::new(&url.to_string(), max_attempts),
+            url,
+            timeout,
+            session: None,
+            muxer: FlvMuxer::new(),
+            connection: Arc::new(Connection::default()),
+        };
+        let _ = output.attempt();
+        output
+    }
+
+    fn attempt(&mut self) -> io::Result<()> {
+        match Session::open(&self.url, self.timeout) {
+            Ok(session) => {
+                self.retry.connected(&self.connection);
+                self.session = Some(session);
+                self.muxer.restart();
+                Ok(())
+            }
+            Err(e) => self.retry.failed(&self.connection, &e),
+        }
+    }
+}
+
+impl Output for RtmpOutput {
+    fn write_all(&mut self, data: &[u8]) -> io::Result<()> {
+        let tags = self
+            .muxer
+            .push(data)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, format!("RTMP: {}", e)))?;
+        for tag in tags {
+            while self.session.is_none() {
+                if !self.retry.wait(&self.connection) {
+                    // Closing without a server, the rest of the queue is lost.
+                    return Ok(());
+                }
+                self.attempt()?;
+                if self.session.is_some() {
+                    // The stream starts over at the next keyframe.
+                    return Ok(());
+                }
+            }
+            let session = self.session.as_mut().expect("connected");
+            if let Err(e) = session.publish(&tag) {
+                self.retry.lost(&self.connection, &e);
+                self.session = None;
+            }
+        }
+        Ok(())
+    }
+
+    fn split(&mut self) -> io::Result<()> {
+        self.muxer.split();
+        Ok(())
+    }
+
+    fn finish(&mut self) -> io::Result<()> {
+        if let Some(mut session) = self.session.take() {
+            let key = Amf::String(self.url.key.clone());
+            let _ = session.call("FCUnpublish", vec![Amf::Null, key]);
+            let stream_id = Amf::Number(f64::from(session.stream_id));
+            let _ = session.call("deleteStream", vec![Amf::Null, stream_id]);
+        }
+        Ok(())
+    }
+
+    fn connection(&self) -> Option<Arc<Connection>> {
+        Some(self.connection.clone())
+    }
+}
+
+impl fmt::Debug for RtmpOutput {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "RtmpOutput({})", self.url)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A message on chunk stream `csid` cut in chunks of `chunk_size`, as
+    /// sent by `Session::send`.
+    fn chunks(csid: u8, kind: u8, payload: &[u8], chunk_size: usize) -> Vec<u8> {
+        let mut out = vec![csid, 0, 0, 0];
+        out.extend_from_slice(&(payload.len() as u32).to_be_bytes()[1..]);
+        out.push(kind);
+        out.extend_from_slice(&[0; 4]);
+        for (i, chunk) in payload.chunks(chunk_size).enumerate() {
+            if i > 0 {
+                out.push(0xc0 | csid);
+            }
+            out.extend_from_slice(chunk);
+        }
+        out
+    }
+
+    fn received(data: &[u8]) -> ChunkReader {
+        let mut reader = ChunkReader::new();
+        reader.buf.extend_from_slice(data);
+        reader
+    }
+
+    #[test]
+    fn messages_across_chunks() {
+        let payload: Vec<u8> = (0..300).map(|i| i as u8).collect();
+        let data = chunks(CSID_COMMAND, MSG_COMMAND_AMF0, &payload, 128);
+        assert_eq!(data.len(), 12 + 300 + 2);
+        // Received a byte at a time.
+        let mut reader = ChunkReader::new();
+        for (i, &byte) in data.iter().enumerate() {
+            reader.buf.push(byte);
+            match reader.next() {
+                Some(message) => {
+                    assert_eq!(i, data.len() - 1);
+                    assert_eq!(message.kind, MSG_COMMAND_AMF0);
+                    assert_eq!(message.payload, payload);
+                }
+                None => assert!(i < data.len() - 1),
+            }
+        }
+        assert!(reader.buf.is_empty());
+    }
+
+    #[test]
+    fn interleaved_chunk_streams() {
+        let long = chunks(CSID_COMMAND, MSG_COMMAND_AMF0, &[1; 200], 128);
+        let short = chunks(CSID_CONTROL, MSG_ACK, &[2; 4], 128);
+        let (first, second) = long.split_at(12 + 128);
+        let mut reader = received(&[first, &short, second].concat());
+        let message = reader.next().unwrap();
+        assert_eq!((message.kind, message.payload), (MSG_ACK, vec![2; 4]));
+        let message = reader.next().unwrap();
+        assert_eq!(message.kind, MSG_COMMAND_AMF0);
+        assert_eq!(message.payload, vec![1; 200]);
+        assert!(reader.next().is_none());
+    }
+
+    #[test]
+    fn set_chunk_size_applies_to_the_next_chunks() {
+        let set = chunks(
+            CSID_CONTROL,
+            MSG_SET_CHUNK_SIZE,
+            &4096u32.to_be_bytes(),
+            128,
+        );
+        let big = chunks(CSID_COMMAND, MSG_COMMAND_AMF0, &[7; 1000], 4096);
+        let mut reader = received(&[set, big].concat());
+        assert_eq!(reader.next().unwrap().kind, MSG_SET_CHUNK_SIZE);
+        assert_eq!(reader.chunk_size, 4096);
+        assert_eq!(reader.next().unwrap().payload, vec![7; 1000]);
+        // The top bit is reserved.
+        let mut reserved = received(&chunks(
+            CSID_CONTROL,
+            MSG_SET_CHUNK_SIZE,
+            &0x8000_0100u32.to_be_bytes(),
+            128,
+        ));
+        reserved.next().unwrap();
+        assert_eq!(reserved.chunk_size, 0x100);
+    }
+
+    #[test]
+    fn extended_timestamp_is_skipped() {
+        let mut data = vec![
+            CSID_COMMAND,
+            0xff,
+            0xff,
+            0xff,
+            0,
+            0,
+            200,
+            MSG_VIDEO,
+            0,
+            0,
+            0,
+            0,
+        ];
+        data.extend_from_slice(&0x0100_0000u32.to_be_bytes());
+        data.extend_from_slice(&[1; 128]);
+        // Continuation chunks repeat it.
+        data.push(0xc0 | CSID_COMMAND);
+        data.extend_from_slice(&0x0100_0000u32.to_be_bytes());
+        data.extend_from_slice(&[2; 72]);
+        let message = received(&data).next().unwrap();
+        assert_eq!(message.payload, [vec![1; 128], vec![2; 72]].concat());
+    }
+
+    #[test]
+    fn amf_round_trip() {
+        let values = vec![
+            Amf::String("_result".into()),
+            Amf::Number(1.0),
+            Amf::Null,
+            Amf::Object(vec![
+                ("level".into(), Amf::String("status".into())),
+                ("code".into(), Amf::String("NetStream.Publish.Start".into())),
+                ("clientid".into(), Amf::Number(-2.5)),
+                ("secure".into(), Amf::Bool(true)),
+                ("nested".into(), Amf::Object(vec![("".into(), Amf::Null)])),
+            ]),
+        ];
+        let mut payload = Vec::new();
+        for value in &values {
+            value.encode(&mut payload);
+        }
+        let message = Message {
+            kind: MSG_COMMAND_AMF0,
+            payload: payload.clone(),
+        };
+        assert_eq!(message.command(), values);
+        assert_eq!(
+            values[3].get("code").and_then(Amf::as_str),
+            Some("NetStream.Publish.Start")
+        );
+
+        // Truncated, the values before are kept.
+        let message = Message {
+            kind: MSG_COMMAND_AMF0,
+            payload: payload[..payload.len() - 1].to_vec(),
+        };
+        assert_eq!(message.command(), &values[..3]);
+    }
+
+    #[test]
+    fn amf_decodes_ecma_arrays_and_undefined() {
+        let mut data = vec![0x08, 0, 0, 0, 1];
+        encode_key("duration", &mut data);
+        Amf::Number(0.0).encode(&mut data);
+        data.extend_from_slice(&[0x00, 0x00, 0x09, 0x06]);
+        let mut rest = &data[..];
+        assert_eq!(
+            Amf::decode(&mut rest),
+            Some(Amf::Object(vec![("duration".into(), Amf::Number(0.0))]))
+        );
+        assert_eq!(Amf::decode(&mut rest), Some(Amf::Null));
+        assert!(rest.is_empty());
+        // Unknown types stop the decoding.
+        assert_eq!(Amf::decode(&mut &[0x0a, 0, 0, 0, 0][..]), None);
+    }
+}
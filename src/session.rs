@@ -0,0 +1,638 @@
+//! Start and stop of the encoder on an open device.
+
+use std::thread;
+use std::time::{Duration, Instant};
+
+use log::{debug, warn};
+
+use crate::command::CommandFactory;
+use crate::device::Device;
+use crate::encoder::{EncoderLimits, EncoderParam, LiveChange};
+use crate::error::Error;
+use crate::firmware::Quirks;
+use crate::grabber::{GrabberConfig, GrabberEntry, DEFAULT_ENTRIES};
+use crate::picture::Control;
+use crate::profile::StreamProfile;
+use crate::response::Response;
+use crate::status::{EncoderState, PcGrabberState};
+
+/// The encoder of a device, with the configuration it was started with.
+///
+/// The sources, picture controls and encoder parameters applied through the
+/// session, at start or later on, are kept and replayed when the device is
+/// initialized again after a reboot.
+pub struct CaptureSession {
+    device: Device,
+    factory: CommandFactory,
+    quirks: Quirks,
+    grabber: GrabberConfig,
+    /// PC grabber configuration entries sent at each start.
+    grabber_entries: Vec<GrabberEntry>,
+    /// Audio and video sources selected at each start.
+    source: Option<(u32, u32)>,
+    /// Picture controls applied at each start, in user units.
+    picture: Vec<(Control, i32)>,
+    /// Encoder parameters applied at each start, as (stream, parameter,
+    /// value).
+    encoder: Vec<(u32, EncoderParam, u32)>,
+}
+
+impl CaptureSession {
+    /// Longest wait for the encoder to report that it stopped.
+    const STOP_TIMEOUT: Duration = Duration::from_secs(5);
+    /// Longest wait for the device to leave the bus after a reboot.
+    const DROP_TIMEOUT: Duration = Duration::from_secs(5);
+    /// Number of times a command of the start sequence is sent again after
+    /// a transport error.
+    const START_RETRIES: u32 = 2;
+
+    pub fn new(device: Device, quirks: Quirks) -> CaptureSession {
+        let factory = device.factory();
+        CaptureSession {
+            device,
+            factory,
+            quirks,
+            grabber: GrabberConfig::default(),
+            grabber_entries: DEFAULT_ENTRIES.to_vec(),
+            source: None,
+            picture: Vec::new(),
+            encoder: Vec::new(),
+        }
+    }
+
+    /// Set the encoder parameters of stream 0 applied when the encoder
+    /// starts, checking them against the `limits` of the firmware.
+    pub fn set_encoder(
+        &mut self,
+        encoder: Vec<(EncoderParam, u32)>,
+        limits: &EncoderLimits,
+    ) -> Result<(), Error> {
+        self.set_stream_encoder(0, encoder, limits)
+    }
+
+    /// `set_encoder` for the given stream, with dual-stream firmware.
+    pub fn set_stream_encoder(
+        &mut self,
+        stream: u32,
+        encoder: Vec<(EncoderParam, u32)>,
+        limits: &EncoderLimits,
+    ) -> Result<(), Error> {
+        for (param, value) in &encoder {
+            param.check(*value, limits).map_err(Error::Config)?;
+        }
+        self.encoder.retain(|(s, _, _)| *s != stream);
+        self.encoder.extend(
+            encoder
+                .into_iter()
+                .map(|(param, value)| (stream, param, value)),
+        );
+        Ok(())
+    }
+
+    /// Send the encoder parameters, and read them back to check that the
+    /// firmware took them as they are. A parameter the device may have
+    /// refused is skipped with a warning.
+    fn apply_encoder(&mut self) -> Result<(), Error> {
+        for (stream, param, value) in self.encoder.clone() {
+            let name = stream_param_name(stream, param);
+            if self
+                .send(&name, |f| param.make_set(f, stream, value))?
+                .is_none()
+            {
+                continue;
+            }
+            eprintln!("Set {} to {}", name, value);
+            match self.read_back(stream, param) {
+                Some(read) if read != value => {
+                    warn!("The {} reads back as {}, {} was set", name, read, value)
+                }
+                _ => (),
+            }
+        }
+        Ok(())
+    }
+
+    /// Value of an encoder parameter as read from the device, `None` if it
+    /// cannot be read.
+    fn read_back(&mut self, stream: u32, param: EncoderParam) -> Option<u32> {
+        let name = stream_param_name(stream, param);
+        match self
+            .device
+            .transact(&param.make_get(&mut self.factory, stream))
+        {
+            Ok(resp) => {
+                let read = resp.word(4);
+                if read.is_none() {
+                    debug!("Unexpected {} response: {:02x?}", name, resp.payload);
+                }
+                read
+            }
+            Err(e) => {
+                debug!("Could not read the {} back: {}", name, e);
+                None
+            }
+        }
+    }
+
+    /// Value of an encoder parameter set through the session.
+    pub fn encoder_value(&self, stream: u32, param: EncoderParam) -> Option<u32> {
+        self.encoder
+            .iter()
+            .find(|(s, p, _)| (*s, *p) == (stream, param))
+            .map(|(_, _, value)| *value)
+    }
+
+    /// Change an encoder parameter while the encoder runs, checking it
+    /// against the `limits` of the firmware. The value is kept and applied
+    /// again at the next start.
+    ///
+    /// Unless the firmware is known to need a restart for it (`live`), the
+    /// parameter is sent right away, and read back to tell whether the
+    /// firmware took it. When it did not, or refused it, the encoder is
+    /// stopped, the parameter sent, and the encoder started again. Returns
+    /// how the change was applied.
+    pub fn change_encoder(
+        &mut self,
+        stream: u32,
+        param: EncoderParam,
+        value: u32,
+        limits: &EncoderLimits,
+        live: Option<LiveChange>,
+    ) -> Result<LiveChange, Error> {
+        param.check(value, limits).map_err(Error::Config)?;
+        self.encoder.retain(|(s, p, _)| (*s, *p) != (stream, param));
+        self.encoder.push((stream, param, value));
+        let name = stream_param_name(stream, param);
+        if live != Some(LiveChange::Restart) {
+            match self.device.transact(&param.make_set(&mut self.factory, stream, value)) {
+                Ok(_) => match self.read_back(stream, param) {
+                    Some(read) if read == value => {
+                        eprintln!("Set {} to {} while the encoder runs", name, value);
+                        return Ok(LiveChange::Live);
+                    }
+                    Some(read) => eprintln!(
+                        "The {} reads back as {} while the encoder runs, restarting it",
+                        name, read
+                    ),
+                    None => eprintln!(
+                        "The {} cannot be read back while the encoder runs, restarting it",
+                        name
+                    ),
+                },
+                Err(Error::DeviceRejected { status, .. }) => eprintln!(
+                    "The device refused the {} while the encoder runs (status {:#x}), restarting it",
+                    name, status
+                ),
+                Err(e) => return Err(e),
+            }
+        }
+        self.stop()?;
+        if self.quirks.full_restart {
+            // The whole initialization sends the parameters again.
+            self.start(self.grabber)?;
+        } else {
+            self.send(&name, |f| param.make_set(f, stream, value))?;
+            self.start_encoder()?;
+        }
+        eprintln!("Set {} to {} with a restart of the encoder", name, value);
+        Ok(LiveChange::Restart)
+    }
+
+    /// Set the PC grabber configuration entries sent when the encoder
+    /// starts, those of the Windows driver by default.
+    pub fn set_grabber_entries(&mut self, entries: Vec<GrabberEntry>) {
+        self.grabber_entries = entries;
+    }
+
+    /// Select the audio and video sources when the encoder starts.
+    pub fn set_source(&mut self, audio: u32, video: u32) {
+        self.source = Some((audio, video));
+    }
+
+    /// Set the picture controls applied when the encoder starts. Values
+    /// out of range are refused before anything is sent.
+    pub fn set_picture(&mut self, picture: Vec<(Control, i32)>) -> Result<(), Error> {
+        for (control, value) in &picture {
+            control.to_device(*value).map_err(Error::Config)?;
+        }
+        self.picture = picture;
+        Ok(())
+    }
+
+    /// Change a picture control while the encoder runs. The value is kept
+    /// and applied again at the next start.
+    pub fn set_control(&mut self, control: Control, value: i32) -> Result<(), Error> {
+        let raw = control.to_device(value).map_err(Error::Config)?;
+        self.device
+            .transact(&control.make_set(&mut self.factory, raw))?;
+        self.picture.retain(|(c, _)| *c != control);
+        self.picture.push((control, value));
+        Ok(())
+    }
+
+    fn apply_picture(&mut self) -> Result<(), Error> {
+        for (control, value) in self.picture.clone() {
+            let raw = control.to_device(value).map_err(Error::Config)?;
+            let resp = match self.send(control.name(), |f| control.make_set(f, raw))? {
+                Some(resp) => resp,
+                None => continue,
+            };
+            eprintln!("Set {} to {} {}", control, value, control.unit());
+            if resp.word(4).is_some_and(|w| w != raw) {
+                warn!(
+                    "The device answered {} {} for the {}",
+                    control.from_device(resp.word(4).unwrap()),
+                    control.unit(),
+                    control
+                );
+            }
+        }
+        Ok(())
+    }
+
+    pub fn grabber(&self) -> &GrabberConfig {
+        &self.grabber
+    }
+
+    /// Encoder settings of `stream` as read from the device, for the
+    /// record. Settings that cannot be read are left out.
+    pub fn read_stream(&mut self, stream: u32) -> StreamProfile {
+        let mut read = |param: EncoderParam| {
+            let cmd = param.make_get(&mut self.factory, stream);
+            match self.device.transact(&cmd) {
+                Ok(resp) => resp.word(4),
+                Err(e) => {
+                    debug!(
+                        "Could not read the {}: {}",
+                        stream_param_name(stream, param),
+                        e
+                    );
+                    None
+                }
+            }
+        };
+        StreamProfile {
+            quality: read(EncoderParam::Quality),
+            keyframe_rate: read(EncoderParam::KeyframeRate),
+        }
+    }
+
+    /// Select the sources, and read the selection back to check it.
+    fn apply_source(&mut self) -> Result<(), Error> {
+        let (audio, video) = match self.source {
+            Some(source) => source,
+            None => return Ok(()),
+        };
+        let resp = match self.send("source selection", |f| f.make_set_source(audio, video))? {
+            Some(resp) => resp,
+            None => return Ok(()),
+        };
+        print_resp_data("Source", &resp);
+        match self.device.transact(&self.factory.make_get_source()) {
+            Ok(resp) => match (resp.word(0), resp.word(4)) {
+                (Some(a), Some(v)) if (a, v) != (audio, video) => warn!(
+                    "The sources read back as audio {} and video {}, \
+                     audio {} and video {} were selected",
+                    a, v, audio, video
+                ),
+                (Some(_), Some(_)) => (),
+                _ => debug!("Unexpected source response: {:02x?}", resp.payload),
+            },
+            Err(e) => debug!("Could not read the sources back: {}", e),
+        }
+        Ok(())
+    }
+
+    /// Program the PC grabber with `grabber` and start the encoder.
+    pub fn start(&mut self, grabber: GrabberConfig) -> Result<(), Error> {
+        self.apply_source()?;
+        if let Some(resp) =
+            self.send("PC grabber disable", |f| f.make_set_pc_grabber_small(false))?
+        {
+            print_resp_data("Returned PC grabber state", &resp);
+        }
+
+        // Alter some settings _before_ starting capture
+        self.apply_picture()?;
+        self.apply_encoder()?;
+
+        if let Some(resp) = self.send("PC grabber enable", |f| f.make_set_pc_grabber_small(true))? {
+            print_resp_data("Returned PC grabber state", &resp);
+        }
+        eprintln!("Waiting for PC grabber...");
+        self.wait_pc_grabber_ready()?;
+        eprintln!("Setting PC grabber state...");
+        for entry in self.grabber_entries.clone() {
+            debug!("PC grabber entry {}", entry.index);
+            self.send("PC grabber configuration", |f| {
+                f.make_set_pc_grabber(&entry, &grabber)
+            })?;
+        }
+        if self.quirks.commit_grabber {
+            self.send("PC grabber commit", |f| f.make_commit_pc_grabber())?;
+        }
+        self.verify_grabber(&grabber);
+        self.grabber = grabber;
+        self.start_encoder()
+    }
+
+    fn start_encoder(&mut self) -> Result<(), Error> {
+        eprintln!("Starting capture...");
+        if let Some(resp) = self.send("encoder start", |f| f.make_set_state(0x2))? {
+            print_resp_data("State", &resp);
+        }
+        if self.quirks.legacy_blob {
+            self.send("legacy configuration blob", |f| {
+                f.make_set_pc_grabber_large()
+            })?;
+        }
+        Ok(())
+    }
+
+    /// Send a command of the start sequence, made by `make`.
+    ///
+    /// Transport errors are retried. An answer taken as a rejection is
+    /// logged and `None` returned, the start going on: the rejection is
+    /// only guessed from the operation field of the answer (see
+    /// `Response::rejection`), no refusal having been captured yet.
+    fn send<F>(&mut self, step: &str, make: F) -> Result<Option<Response>, Error>
+    where
+        F: Fn(&mut CommandFactory) -> Vec<u8>,
+    {
+        let mut attempt = 0;
+        loop {
+            match self.device.transact(&make(&mut self.factory)) {
+                Ok(resp) => return Ok(Some(resp)),
+                Err(Error::DeviceRejected { status, .. }) => {
+                    warn!(
+                        "The device may have refused the {} (operation {:#x} in the answer), \
+                         going on without it",
+                        step, status
+                    );
+                    return Ok(None);
+                }
+                Err(e @ Error::Usb(_)) | Err(e @ Error::Response(_))
+                    if attempt < Self::START_RETRIES =>
+                {
+                    attempt += 1;
+                    eprintln!("{}: {}, retrying", step, e);
+                }
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    /// Read the configuration entries back and warn about those which
+    /// differ from `grabber`.
+    fn verify_grabber(&self, grabber: &GrabberConfig) {
+        for i in self.grabber_entries.iter().map(|e| e.index) {
+            match GrabberConfig::read(&self.device, i) {
+                Ok(Some(read)) if read == *grabber => (),
+                Ok(Some(read)) => warn!(
+                    "PC grabber entry {} reads back as {}, {} was set",
+                    i, read, grabber
+                ),
+                Ok(None) | Err(_) => {
+                    eprintln!(
+                        "PC grabber configuration readback not supported, \
+                         skipping the verification"
+                    );
+                    return;
+                }
+            }
+        }
+    }
+
+    fn wait_pc_grabber_ready(&mut self) -> Result<(), Error> {
+        loop {
+            let resp = self
+                .device
+                .transact(&self.factory.make_get_pc_grabber_small())?;
+            eprintln!("PC grabber state: {:02x?}", resp.payload);
+            match PcGrabberState::parse(&resp) {
+                Some(state) if state.ready => break,
+                Some(_) => (),
+                None => debug!(
+                    "PC grabber state with unknown length {:#x}: {:02x?}",
+                    resp.length, resp.payload
+                ),
+            }
+            thread::sleep(Duration::from_secs(1));
+        }
+        Ok(())
+    }
+
+    /// Stop the encoder and wait until it reports being idle.
+    ///
+    /// Firmware not answering the state GET, or not reporting idle within
+    /// `STOP_TIMEOUT`, is given that long and then assumed to be stopped.
+    pub fn stop(&mut self) -> Result<(), Error> {
+        self.device.transact(&self.factory.make_set_state(0x0))?;
+        let started = Instant::now();
+        while started.elapsed() < Self::STOP_TIMEOUT {
+            match self.device.transact(&self.factory.make_get_state()) {
+                Ok(resp) => match EncoderState::parse(&resp) {
+                    Some(state) if !state.running => return Ok(()),
+                    Some(_) => (),
+                    None => {
+                        debug!("Unexpected encoder state: {:02x?}", resp.payload);
+                        break;
+                    }
+                },
+                Err(e) => {
+                    debug!("Encoder state not available: {}", e);
+                    break;
+                }
+            }
+            thread::sleep(Duration::from_millis(100));
+        }
+        eprintln!("The encoder did not report being stopped, waiting");
+        thread::sleep(Self::STOP_TIMEOUT.saturating_sub(started.elapsed()));
+        Ok(())
+    }
+
+    /// Stop the encoder and start it again, with `grabber` if given or else
+    /// the current configuration.
+    ///
+    /// The PC grabber is only programmed again when its configuration
+    /// changes, or when the firmware has the `full-restart` quirk.
+    pub fn restart(&mut self, grabber: Option<GrabberConfig>) -> Result<(), Error> {
+        let grabber = grabber.unwrap_or(self.grabber);
+        self.stop()?;
+        if grabber != self.grabber || self.quirks.full_restart {
+            self.start(grabber)
+        } else {
+            self.start_encoder()
+        }
+    }
+
+    /// Switch to the `audio` and `video` sources during capture, with the
+    /// `picture` and stream 0 `encoder` settings going with them.
+    ///
+    /// The settings are checked before the encoder is stopped, and a source
+    /// change always takes a whole start.
+    pub fn switch_source(
+        &mut self,
+        audio: u32,
+        video: u32,
+        picture: Vec<(Control, i32)>,
+        encoder: Vec<(EncoderParam, u32)>,
+        limits: &EncoderLimits,
+    ) -> Result<(), Error> {
+        for (param, value) in &encoder {
+            param.check(*value, limits).map_err(Error::Config)?;
+        }
+        self.set_picture(picture)?;
+        self.set_encoder(encoder, limits)?;
+        self.set_source(audio, video);
+        self.stop()?;
+        self.start(self.grabber)
+    }
+
+    /// Reboot the device, wait up to `timeout` for it to come back, and
+    /// start the encoder again with the current configuration.
+    ///
+    /// The settings of the session are replayed, those the device refuses
+    /// now being skipped with a warning.
+    pub fn reboot(&mut self, timeout: Duration) -> Result<(), Error> {
+        // The device may leave the bus before answering.
+        if let Err(e) = self.device.transact(&self.factory.make_reboot()) {
+            debug!("Reboot command: {}", e);
+        }
+        let started = Instant::now();
+        while self.device.is_attached() && started.elapsed() < Self::DROP_TIMEOUT {
+            thread::sleep(Duration::from_millis(200));
+        }
+        loop {
+            match self.device.reopen() {
+                Ok(()) => break,
+                Err(e) if started.elapsed() >= timeout => {
+                    debug!("Reopening the device: {}", e);
+                    return Err(Error::NoDevice);
+                }
+                Err(_) => thread::sleep(Duration::from_millis(500)),
+            }
+        }
+        self.reinit()
+    }
+
+    /// Close the device and open it again, for a handle which stopped
+    /// working while the device is still there, and start the encoder
+    /// again as after a reboot.
+    pub fn reopen(&mut self) -> Result<(), Error> {
+        self.device.reopen()?;
+        self.reinit()
+    }
+
+    /// Bring a freshly opened device back to the state of the session.
+    fn reinit(&mut self) -> Result<(), Error> {
+        self.device.reset()?;
+        self.device.claim()?;
+        let mut settings: Vec<String> = self
+            .picture
+            .iter()
+            .map(|(control, value)| format!("{} {} {}", control, value, control.unit()))
+            .chain(self.encoder.iter().map(|(stream, param, value)| {
+                format!("{} {}", stream_param_name(*stream, *param), value)
+            }))
+            .collect();
+        if let Some((audio, video)) = self.source {
+            settings.push(format!("audio source {}, video source {}", audio, video));
+        }
+        if settings.is_empty() {
+            settings.push("none".to_string());
+        }
+        eprintln!("Re-applying the session settings: {}", settings.join(", "));
+        self.start(self.grabber)
+    }
+}
+
+/// Name of an encoder parameter, with the stream for streams other than
+/// the main one.
+fn stream_param_name(stream: u32, param: EncoderParam) -> String {
+    match stream {
+        0 => param.to_string(),
+        _ => format!("stream {} {}", stream, param),
+    }
+}
+
+fn print_resp_data(datatype: &str, resp: &Response) {
+    if resp.payload.is_empty() {
+        eprintln!("{}: No data", datatype);
+        return;
+    }
+    eprintln!("{}: {:02x?}", datatype, &resp.payload);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::emulator::{Emulator, EmulatorConfig};
+    use crate::protocol;
+    use std::sync::Arc;
+
+    const STATUS: u32 = 0x8000_0001;
+
+    fn session() -> (Arc<Emulator>, CaptureSession) {
+        let emulator = Arc::new(Emulator::new(EmulatorConfig::default()));
+        let device = Device::with_transport(emulator.clone());
+        let mut session = CaptureSession::new(device, crate::firmware::quirks_for(None));
+        session
+            .set_picture(vec![(Control::Brightness, 60), (Control::Hue, 10)])
+            .unwrap();
+        (emulator, session)
+    }
+
+    #[test]
+    fn refused_setting_does_not_fail_the_start() {
+        let (emulator, mut session) = session();
+        emulator.reject(protocol::BRIGHTNESS, STATUS);
+        session.start(GrabberConfig::default()).unwrap();
+        // Not retried, and the start went on.
+        assert_eq!(emulator.received(protocol::BRIGHTNESS), 1);
+        assert_eq!(emulator.received(protocol::HUE), 1);
+        assert_eq!(emulator.received(protocol::STATE), 1);
+    }
+
+    #[test]
+    fn refused_setting_is_skipped_on_replay() {
+        let (emulator, mut session) = session();
+        session.start(GrabberConfig::default()).unwrap();
+        emulator.reject(protocol::BRIGHTNESS, STATUS);
+        session.reopen().unwrap();
+        assert_eq!(emulator.received(protocol::BRIGHTNESS), 2);
+        assert_eq!(emulator.received(protocol::HUE), 2);
+        // Started twice.
+        assert_eq!(emulator.received(protocol::STATE), 2);
+    }
+
+    #[test]
+    fn source_switch_restarts_with_the_new_settings() {
+        let (emulator, mut session) = session();
+        let limits = crate::firmware::limits_for(None);
+        session.start(GrabberConfig::default()).unwrap();
+        assert_eq!(emulator.received(protocol::SOURCE), 0);
+
+        // Refused settings leave the encoder running as it was.
+        let refused = vec![(EncoderParam::Quality, u32::MAX)];
+        assert!(matches!(
+            session.switch_source(0, 2, vec![], refused, &limits),
+            Err(Error::Config(_))
+        ));
+        assert_eq!(emulator.received(protocol::SOURCE), 0);
+
+        let encoder = vec![(EncoderParam::Quality, 50)];
+        session
+            .switch_source(0, 2, vec![(Control::Hue, 20)], encoder, &limits)
+            .unwrap();
+        assert_eq!(emulator.received(protocol::SOURCE), 2);
+        assert_eq!(emulator.received(protocol::HUE), 2);
+        assert_eq!(emulator.received(protocol::BRIGHTNESS), 1);
+        assert_eq!(session.encoder_value(0, EncoderParam::Quality), Some(50));
+        let resp = session
+            .device
+            .transact(&session.factory.make_get_source())
+            .unwrap();
+        assert_eq!(resp.word(4), Some(2));
+    }
+}
@@ -0,0 +1,276 @@
+//! Reading, saving and comparing the device settings.
+//!
+//! Settings are read with GET commands carrying an index word, the device
+//! answering with a payload made of little-endian words. Each setting of the
+//! table below tells which word of which response holds its value.
+
+use std::collections::HashMap;
+use std::fmt::Write as _;
+
+use crate::device::Device;
+use crate::error::Error;
+use crate::protocol;
+
+pub struct Setting {
+    pub name: &'static str,
+    pub opcode: u16,
+    /// Index word sent with the GET command (channel or stream number).
+    pub index: u32,
+    /// Offset of the value in the response payload.
+    pub offset: usize,
+    /// Factory default, when known.
+    pub default: Option<u32>,
+}
+
+pub const SETTINGS: &[Setting] = &[
+    Setting {
+        name: "audio_source",
+        opcode: protocol::SOURCE,
+        index: 0,
+        offset: protocol::AUDIO_SOURCE.offset,
+        default: None,
+    },
+    Setting {
+        name: "video_source",
+        opcode: protocol::SOURCE,
+        index: 0,
+        offset: protocol::VIDEO_SOURCE.offset,
+        default: None,
+    },
+    Setting {
+        name: "brightness",
+        opcode: protocol::BRIGHTNESS,
+        index: 0,
+        offset: protocol::VALUE.offset,
+        default: Some(0),
+    },
+    Setting {
+        name: "contrast",
+        opcode: protocol::CONTRAST,
+        index: 0,
+        offset: protocol::VALUE.offset,
+        default: Some(100),
+    },
+    Setting {
+        name: "hue",
+        opcode: protocol::HUE,
+        index: 0,
+        offset: protocol::VALUE.offset,
+        default: Some(0),
+    },
+    Setting {
+        name: "saturation",
+        opcode: protocol::SATURATION,
+        index: 0,
+        offset: protocol::VALUE.offset,
+        default: Some(100),
+    },
+    Setting {
+        name: "stream0_keyframe_rate",
+        opcode: protocol::KEYFRAME_RATE,
+        index: 0,
+        offset: protocol::VALUE.offset,
+        default: None,
+    },
+    Setting {
+        name: "stream0_quality",
+        opcode: protocol::QUALITY,
+        index: 0,
+        offset: protocol::VALUE.offset,
+        default: None,
+    },
+];
+
+/// Setting values, `None` standing for a value which could not be read.
+pub type Values = Vec<(&'static Setting, Option<u32>)>;
+
+/// Read every setting of the table from the device.
+///
+/// Settings the device fails to answer for are reported as `None` instead of
+/// aborting the whole read.
+pub fn read_all(device: &Device) -> Values {
+    let mut factory = device.factory();
+    let mut responses = HashMap::new();
+    SETTINGS
+        .iter()
+        .map(|setting| {
+            let resp = responses
+                .entry((setting.opcode, setting.index))
+                .or_insert_with(|| {
+                    device
+                        .transact(&factory.make_get_indexed(setting.opcode, setting.index))
+                        .ok()
+                });
+            let value = resp.as_ref().and_then(|r| r.word(setting.offset));
+            (setting, value)
+        })
+        .collect()
+}
+
+/// Format values in the settings file format, one `name = value` per line.
+pub fn format(values: &[(&'static Setting, Option<u32>)]) -> String {
+    let mut out = String::new();
+    for (setting, value) in values {
+        match value {
+            Some(v) => writeln!(out, "{} = {}", setting.name, v).unwrap(),
+            None => writeln!(out, "{} = unknown", setting.name).unwrap(),
+        }
+    }
+    out
+}
+
+/// Parse a settings file as written by `format`.
+///
+/// Blank lines and lines starting with `#` are ignored.
+pub fn parse(text: &str) -> Result<HashMap<String, Option<u32>>, Error> {
+    let mut values = HashMap::new();
+    for (lineno, line) in text.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let (name, value) = line.split_once('=').ok_or_else(|| {
+            Error::SettingsFile(format!("line {}: expected `name = value`", lineno + 1))
+        })?;
+        let (name, value) = (name.trim(), value.trim());
+        if !SETTINGS.iter().any(|s| s.name == name) {
+            return Err(Error::SettingsFile(format!(
+                "line {}: unknown setting `{}`",
+                lineno + 1,
+                name
+            )));
+        }
+        let value = if value == "unknown" {
+            None
+        } else {
+            Some(value.parse::<u32>().map_err(|e| {
+                Error::SettingsFile(format!("line {}: {}: {}", lineno + 1, value, e))
+            })?)
+        };
+        values.insert(name.to_string(), value);
+    }
+    Ok(values)
+}
+
+pub struct Difference {
+    pub name: &'static str,
+    pub device: Option<u32>,
+    pub reference: u32,
+}
+
+impl Difference {
+    /// Whether the value actually differs, as opposed to being unreadable
+    /// on the device.
+    pub fn is_change(&self) -> bool {
+        self.device.is_some()
+    }
+}
+
+/// Compare the device values against reference values.
+///
+/// Without a reference, the factory defaults are used. Settings with no
+/// reference value are skipped, and settings the device could not report
+/// are returned with an unknown device value.
+pub fn diff(
+    values: &[(&'static Setting, Option<u32>)],
+    reference: Option<&HashMap<String, Option<u32>>>,
+) -> Vec<Difference> {
+    values
+        .iter()
+        .filter_map(|(setting, device)| {
+            let reference = match reference {
+                Some(map) => map.get(setting.name).copied().flatten(),
+                None => setting.default,
+            }?;
+            if *device == Some(reference) {
+                return None;
+            }
+            Some(Difference {
+                name: setting.name,
+                device: *device,
+                reference,
+            })
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn setting(name: &str) -> &'static Setting {
+        SETTINGS.iter().find(|s| s.name == name).unwrap()
+    }
+
+    fn values(list: &[(&str, Option<u32>)]) -> Values {
+        list.iter()
+            .map(|&(name, value)| (setting(name), value))
+            .collect()
+    }
+
+    #[test]
+    fn format_parses_back() {
+        let values = values(&[
+            ("brightness", Some(7)),
+            ("contrast", None),
+            ("stream0_quality", Some(4_000_000)),
+        ]);
+        let text = format(&values);
+        assert_eq!(
+            text,
+            "brightness = 7\ncontrast = unknown\nstream0_quality = 4000000\n"
+        );
+        let parsed = parse(&text).unwrap();
+        assert_eq!(parsed.len(), 3);
+        for (setting, value) in &values {
+            assert_eq!(parsed[setting.name], *value);
+        }
+    }
+
+    #[test]
+    fn parse_skips_comments_and_rejects_errors() {
+        let parsed = parse("# saved\n\n  hue = 3  \n").unwrap();
+        assert_eq!(parsed["hue"], Some(3));
+        for text in ["hue 3", "volume = 3", "hue = -1", "hue = high"] {
+            assert!(
+                matches!(parse(text), Err(Error::SettingsFile(_))),
+                "{}",
+                text
+            );
+        }
+    }
+
+    #[test]
+    fn equal_values_are_no_difference() {
+        let values = values(&[("brightness", Some(0)), ("contrast", Some(100))]);
+        assert!(diff(&values, None).is_empty());
+        let reference = parse("brightness = 0\ncontrast = 100\n").unwrap();
+        assert!(diff(&values, Some(&reference)).is_empty());
+    }
+
+    #[test]
+    fn differing_values_are_changes() {
+        let values = values(&[("brightness", Some(5)), ("hue", Some(0))]);
+        let reference = parse("brightness = 0\nhue = 0\n").unwrap();
+        let diffs = diff(&values, Some(&reference));
+        assert_eq!(diffs.len(), 1);
+        assert_eq!(
+            (diffs[0].name, diffs[0].device, diffs[0].reference),
+            ("brightness", Some(5), 0)
+        );
+        assert!(diffs[0].is_change());
+    }
+
+    #[test]
+    fn unreadable_values_are_not_changes() {
+        let values = values(&[("saturation", None), ("stream0_quality", Some(9))]);
+        // No factory default for the quality, nor reference for it.
+        let diffs = diff(&values, None);
+        assert_eq!(diffs.len(), 1);
+        assert_eq!((diffs[0].name, diffs[0].device), ("saturation", None));
+        assert!(!diffs[0].is_change());
+        // Nor is an unknown reference value compared.
+        let reference = parse("saturation = unknown\n").unwrap();
+        assert!(diff(&values, Some(&reference)).is_empty());
+    }
+}
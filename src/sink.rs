@@ -0,0 +1,1066 @@
+//! Output of the stream.
+//!
+//! Each output is written by its own thread fed through a bounded queue, so
+//! that a slow consumer does not stall the USB reads. When a queue is full,
+//! the overflow policy tells whether to wait for room or drop the data.
+//!
+//! Files can be written atomically: each one is written as `<name>.part`
+//! and renamed to its name once complete, so that a program watching the
+//! directory never picks up a file still being written.
+//!
+//! Files can also be appended to, continuing a recording interrupted by a
+//! crash: the partial packet at the end of the file is trimmed first, and
+//! the seam can be marked in the stream.
+//!
+//! Each file can have a seek index written along, see `index`.
+
+use std::collections::HashSet;
+use std::fs::{File, OpenOptions};
+use std::io::{self, BufWriter, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::mpsc::{self, SyncSender, TrySendError};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::{Instant, SystemTime};
+
+use crate::analysis::Event;
+use crate::clock::{format_utc, format_utc_compact};
+use crate::error::Error;
+use crate::index::{self, SeekIndex};
+use crate::psi::PAT_PID;
+use crate::timing::DebugTiming;
+use crate::ts::{self, Packet, NULL_PID, PACKET_SIZE, SYNC_BYTE};
+
+/// Events of the outputs, such as the renames of the completed files,
+/// logged by their writer threads.
+pub type OutputEvents = Arc<Mutex<Vec<Event>>>;
+
+/// Destination of the stream, driven by the writer thread of a sink.
+pub trait Output: Send {
+    fn write_all(&mut self, data: &[u8]) -> io::Result<()>;
+
+    /// Start a new segment. Outputs which cannot be split ignore it.
+    fn split(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+
+    fn finish(&mut self) -> io::Result<()>;
+
+    /// State of the connection, for network outputs.
+    fn connection(&self) -> Option<Arc<Connection>> {
+        None
+    }
+}
+
+/// Connection state shared between a network output and its sink.
+#[derive(Default)]
+pub struct Connection {
+    pub connects: AtomicU64,
+    pub disconnects: AtomicU64,
+    pub failed_attempts: AtomicU64,
+    /// Set when the sink closes, so that the output stops waiting for a
+    /// connection.
+    pub closing: AtomicBool,
+}
+
+/// Output to a plain writer, such as stdout.
+pub struct StreamOutput<W: Write + Send>(pub W);
+
+impl<W: Write + Send> Output for StreamOutput<W> {
+    fn write_all(&mut self, data: &[u8]) -> io::Result<()> {
+        self.0.write_all(data)
+    }
+
+    fn finish(&mut self) -> io::Result<()> {
+        self.0.flush()
+    }
+}
+
+/// Output to a file, split in segments.
+///
+/// The path is a template in which `{n}` is replaced by the segment number
+/// and `{time}` by the UTC time at which the segment was opened. Without
+/// any placeholder, the segments after the first get the segment number as
+/// a suffix, see `segment_path`, unless an external tool rotating the
+/// file has moved it away beforehand.
+///
+/// With atomic writes, each segment is written to its `.part` file and
+/// renamed once complete.
+///
+/// Unless `overwrite` is set, the segments are never opened over an
+/// existing file. Their seek indexes, when written, always replace the
+/// existing ones, except when continuing a file.
+pub struct FileOutput {
+    template: String,
+    index: u32,
+    path: PathBuf,
+    file: BufWriter<File>,
+    overwrite: bool,
+    /// Where the renames are logged, with atomic writes.
+    atomic: Option<OutputEvents>,
+    /// Marks the seam of an appended file, until done.
+    seam: Option<SeamMarker>,
+    seek_index: Option<SeekIndex>,
+}
+
+impl FileOutput {
+    pub fn create(template: &str, overwrite: bool) -> io::Result<FileOutput> {
+        let path = expand_template(template, 0, SystemTime::now());
+        let file = BufWriter::new(create_file(&path, overwrite)?);
+        Ok(FileOutput {
+            template: template.to_string(),
+            index: 0,
+            path,
+            file,
+            overwrite,
+            atomic: None,
+            seam: None,
+            seek_index: None,
+        })
+    }
+
+    /// Continue the first segment in the existing file, if any, after
+    /// trimming its last partial packet, and log where the new data starts
+    /// to `events`. With `mark_seam`, the first packet of each PID after
+    /// the seam is preceded by a packet setting the discontinuity
+    /// indicator.
+    pub fn append(
+        template: &str,
+        overwrite: bool,
+        events: OutputEvents,
+        mark_seam: bool,
+    ) -> io::Result<FileOutput> {
+        let path = expand_template(template, 0, SystemTime::now());
+        let (file, seam) = match append_point(&path)? {
+            None => (create_file(&path, overwrite)?, None),
+            Some(end) => (
+                open_at(&path, end, &events)?,
+                Some(SeamMarker::default()).filter(|_| mark_seam),
+            ),
+        };
+        Ok(FileOutput {
+            template: template.to_string(),
+            index: 0,
+            path,
+            file: BufWriter::new(file),
+            overwrite,
+            atomic: None,
+            seam,
+            seek_index: None,
+        })
+    }
+
+    /// Write the segments atomically, logging the renames to `events`.
+    /// `resume` is a partial file left by a previous run, continued as the
+    /// first segment after trimming its last partial packet.
+    pub fn create_atomic(
+        template: &str,
+        overwrite: bool,
+        events: OutputEvents,
+        resume: Option<&Path>,
+    ) -> io::Result<FileOutput> {
+        let (path, file) = match resume {
+            Some(part) => {
+                let end = append_point(part)?.unwrap_or_default();
+                (final_path(part), open_at(part, end, &events)?)
+            }
+            None => {
+                let path = expand_template(template, 0, SystemTime::now());
+                let file = create_part(&path, overwrite)?;
+                (path, file)
+            }
+        };
+        Ok(FileOutput {
+            template: template.to_string(),
+            index: 0,
+            path,
+            file: BufWriter::new(file),
+            overwrite,
+            atomic: Some(events),
+            seam: None,
+            seek_index: None,
+        })
+    }
+
+    /// Write the seek index of each segment along, see `index`.
+    pub fn with_index(mut self) -> io::Result<FileOutput> {
+        self.open_index()?;
+        Ok(self)
+    }
+
+    pub fn path(&self) -> &PathBuf {
+        &self.path
+    }
+
+    /// Start the index of the current segment, continuing the existing one
+    /// if the segment does not start empty. With atomic writes, the index
+    /// is renamed along with the segment.
+    fn open_index(&mut self) -> io::Result<()> {
+        let mut path = index::index_path(&self.path);
+        if self.atomic.is_some() {
+            path = part_path(&path);
+        }
+        let offset = self.file.get_ref().metadata()?.len();
+        let mut index = if offset == 0 {
+            SeekIndex::create(&path)?
+        } else {
+            SeekIndex::append(&path, offset)?
+        };
+        if let Some(previous) = self.seek_index.take() {
+            index.follow(previous);
+        }
+        self.seek_index = Some(index);
+        Ok(())
+    }
+
+    /// Give the current segment its final name.
+    fn complete(&mut self) -> io::Result<()> {
+        if let Some(index) = self.seek_index.as_mut() {
+            index.finish()?;
+        }
+        let events = match &self.atomic {
+            Some(events) => events,
+            None => return Ok(()),
+        };
+        self.file.get_ref().sync_all()?;
+        if self.seek_index.is_some() {
+            let index = index::index_path(&self.path);
+            std::fs::rename(part_path(&index), index)?;
+        }
+        let part = part_path(&self.path);
+        std::fs::rename(&part, &self.path)?;
+        events.lock().unwrap().push(Event::OutputRenamed {
+            from: part.display().to_string(),
+            to: self.path.display().to_string(),
+            time: format_utc(SystemTime::now()),
+        });
+        Ok(())
+    }
+}
+
+impl Output for FileOutput {
+    fn write_all(&mut self, data: &[u8]) -> io::Result<()> {
+        let marked;
+        let data = match self.seam.as_mut() {
+            Some(marker) => {
+                marked = marker.mark(data);
+                if marker.done {
+                    self.seam = None;
+                }
+                &marked[..]
+            }
+            None => data,
+        };
+        self.file.write_all(data)?;
+        match self.seek_index.as_mut() {
+            Some(index) => index.push(data),
+            None => Ok(()),
+        }
+    }
+
+    fn split(&mut self) -> io::Result<()> {
+        self.file.flush()?;
+        self.complete()?;
+        self.index += 1;
+        self.path = segment_path(&self.template, self.index, SystemTime::now());
+        let file = if self.atomic.is_some() {
+            create_part(&self.path, self.overwrite)?
+        } else {
+            create_file(&self.path, self.overwrite)?
+        };
+        self.file = BufWriter::new(file);
+        if self.seek_index.is_some() {
+            self.open_index()?;
+        }
+        Ok(())
+    }
+
+    fn finish(&mut self) -> io::Result<()> {
+        self.file.flush()?;
+        self.complete()
+    }
+}
+
+pub fn has_placeholder(template: &str) -> bool {
+    template.contains("{n}") || template.contains("{time}")
+}
+
+/// Path of segment `index` of `template`. Without placeholder, `out.ts` is
+/// reused if it has been moved away, and becomes `out-0001.ts`,
+/// `out-0002.ts`... otherwise, so that a segment never lands in the
+/// previous one.
+fn segment_path(template: &str, index: u32, time: SystemTime) -> PathBuf {
+    let path = expand_template(template, index, time);
+    if has_placeholder(template) || index == 0 || !path.exists() {
+        return path;
+    }
+    with_suffix(&path, &format!("-{:04}", index))
+}
+
+/// Path of segment `index` of an output template.
+pub fn expand_template(template: &str, index: u32, time: SystemTime) -> PathBuf {
+    PathBuf::from(
+        template
+            .replace("{n}", &format!("{:04}", index))
+            .replace("{time}", &format_utc_compact(time)),
+    )
+}
+
+/// Create a file, failing if it exists unless `overwrite`. The check and
+/// the creation are a single operation, so that a file appearing in the
+/// meantime is not clobbered either.
+fn create_file(path: &Path, overwrite: bool) -> io::Result<File> {
+    let mut options = OpenOptions::new();
+    options.write(true);
+    if overwrite {
+        options.create(true).truncate(true);
+    } else {
+        options.create_new(true);
+    }
+    options.open(path).map_err(|e| match e.kind() {
+        io::ErrorKind::AlreadyExists => exists_error(path),
+        _ => e,
+    })
+}
+
+/// Create the `.part` file of `path`, which must not exist either as it
+/// would be replaced by the rename, unless `overwrite`.
+fn create_part(path: &Path, overwrite: bool) -> io::Result<File> {
+    if !overwrite && path.exists() {
+        return Err(exists_error(path));
+    }
+    create_file(&part_path(path), overwrite)
+}
+
+fn exists_error(path: &Path) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::AlreadyExists,
+        format!("{} already exists", path.display()),
+    )
+}
+
+/// Where a transport stream file can be continued: the end of its last
+/// full packet, or `None` if the file does not exist. Fails on files which
+/// do not look like a transport stream.
+pub fn append_point(path: &Path) -> io::Result<Option<u64>> {
+    let mut file = match File::open(path) {
+        Ok(file) => file,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e),
+    };
+    let len = file.metadata()?.len();
+    if len == 0 {
+        return Ok(Some(0));
+    }
+    let mut head = Vec::new();
+    (&mut file)
+        .take(3 * PACKET_SIZE as u64)
+        .read_to_end(&mut head)?;
+    // The first packet, followed by sync bytes at packet intervals as far
+    // as the head goes.
+    let start = (0..PACKET_SIZE.min(head.len()))
+        .find(|&p| {
+            head[p..]
+                .iter()
+                .step_by(PACKET_SIZE)
+                .all(|&b| b == SYNC_BYTE)
+        })
+        .ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("{} does not look like a transport stream", path.display()),
+            )
+        })? as u64;
+    // Back from the last packet boundary to a packet starting with a sync
+    // byte.
+    let size = PACKET_SIZE as u64;
+    let mut end = start + (len - start) / size * size;
+    let mut byte = [0];
+    while end > start {
+        file.seek(SeekFrom::Start(end - size))?;
+        file.read_exact(&mut byte)?;
+        if byte[0] == SYNC_BYTE {
+            break;
+        }
+        end -= size;
+    }
+    Ok(Some(end))
+}
+
+/// Open `path` to write from `end`, as given by `append_point`, dropping
+/// what follows, and log it to `events`.
+fn open_at(path: &Path, end: u64, events: &OutputEvents) -> io::Result<File> {
+    let mut file = OpenOptions::new().write(true).open(path)?;
+    let len = file.metadata()?.len();
+    file.set_len(end)?;
+    file.seek(SeekFrom::End(0))?;
+    events.lock().unwrap().push(Event::OutputAppended {
+        path: path.display().to_string(),
+        file_offset: end,
+        trimmed: len - end,
+    });
+    Ok(file)
+}
+
+/// Precedes the first packet of each PID after the seam of an appended file
+/// with a discontinuity packet, until the second PAT, by when all the PIDs
+/// of the program have been seen.
+#[derive(Default)]
+struct SeamMarker {
+    marked: HashSet<u16>,
+    pats: u32,
+    done: bool,
+}
+
+impl SeamMarker {
+    fn mark(&mut self, data: &[u8]) -> Vec<u8> {
+        let mut out = Vec::with_capacity(data.len() + PACKET_SIZE);
+        let mut chunks = data.chunks(PACKET_SIZE);
+        for chunk in chunks.by_ref() {
+            let pkt = match Packet::new(chunk) {
+                Some(pkt) => pkt,
+                None => {
+                    // Not aligned on packets, nothing more to mark.
+                    out.extend_from_slice(chunk);
+                    self.done = true;
+                    break;
+                }
+            };
+            let pid = pkt.pid();
+            if pid == PAT_PID {
+                self.pats += 1;
+                if self.pats == 2 {
+                    out.extend_from_slice(chunk);
+                    self.done = true;
+                    break;
+                }
+            }
+            if pid != NULL_PID && self.marked.insert(pid) {
+                // Packets without payload repeat the counter of the previous
+                // one.
+                let cc = if pkt.has_payload() {
+                    pkt.cc().wrapping_sub(1)
+                } else {
+                    pkt.cc()
+                };
+                out.extend_from_slice(&ts::discontinuity_packet(pid, cc));
+            }
+            out.extend_from_slice(chunk);
+        }
+        for chunk in chunks {
+            out.extend_from_slice(chunk);
+        }
+        out
+    }
+}
+
+/// Name under which a file is written atomically.
+pub fn part_path(path: &Path) -> PathBuf {
+    let mut part = path.as_os_str().to_owned();
+    part.push(".part");
+    PathBuf::from(part)
+}
+
+/// Name of a `.part` file once complete.
+pub fn final_path(part: &Path) -> PathBuf {
+    path_with_suffix(part, "")
+}
+
+/// Name given to a `.part` file left by a crash: `out.ts.part` becomes
+/// `out-recovered.ts`.
+pub fn recovered_path(part: &Path) -> PathBuf {
+    path_with_suffix(part, "-recovered")
+}
+
+/// Name of a `.part` file once complete, with `suffix` inserted before the
+/// extension.
+fn path_with_suffix(part: &Path, suffix: &str) -> PathBuf {
+    with_suffix(&part.with_extension(""), suffix)
+}
+
+/// `path` with `suffix` inserted before its extension.
+fn with_suffix(path: &Path, suffix: &str) -> PathBuf {
+    match path.extension() {
+        Some(ext) => {
+            let mut name = path.file_stem().unwrap_or_default().to_owned();
+            name.push(suffix);
+            name.push(".");
+            name.push(ext);
+            path.with_file_name(name)
+        }
+        None => {
+            let mut name = path.as_os_str().to_owned();
+            name.push(suffix);
+            PathBuf::from(name)
+        }
+    }
+}
+
+/// Partial files left in its directory by a previous run writing the
+/// segments of `template` atomically, the most recent last. Placeholders
+/// are only looked for in the file name.
+pub fn leftover_parts(template: &str) -> io::Result<Vec<PathBuf>> {
+    let template = Path::new(template);
+    let dir = match template.parent() {
+        Some(dir) if dir.as_os_str().is_empty() => Path::new("."),
+        Some(dir) => dir,
+        None => return Ok(Vec::new()),
+    };
+    let pattern = match template.file_name() {
+        Some(name) => format!("{}.part", name.to_string_lossy()),
+        None => return Ok(Vec::new()),
+    };
+    let pieces: Vec<&str> = pattern.split(['{', '}']).collect();
+    let mut found = Vec::new();
+    for entry in std::fs::read_dir(dir)? {
+        let entry = entry?;
+        let name = entry.file_name();
+        if template_matches(&pieces, &name.to_string_lossy()) {
+            let modified = entry.metadata()?.modified()?;
+            found.push((modified, template.with_file_name(name)));
+        }
+    }
+    found.sort();
+    Ok(found.into_iter().map(|(_, path)| path).collect())
+}
+
+/// Whether a file name could have been produced by a template, split in
+/// `pieces` around its braces: the literal text at even indices, and the
+/// placeholders between them.
+fn template_matches(pieces: &[&str], name: &str) -> bool {
+    let mut rest = name;
+    for (i, piece) in pieces.iter().enumerate() {
+        if i % 2 == 1 {
+            continue;
+        }
+        if i == 0 {
+            match rest.strip_prefix(piece) {
+                Some(r) => rest = r,
+                None => return false,
+            }
+        } else if i == pieces.len() - 1 {
+            return rest.ends_with(piece);
+        } else {
+            match rest.find(piece) {
+                Some(at) => rest = &rest[at + piece.len()..],
+                None => return false,
+            }
+        }
+    }
+    rest.is_empty()
+}
+
+/// What to do with the partial files left by a crash.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum LeftoverPolicy {
+    /// Leave them alone, with a warning.
+    Keep,
+    /// Give them their final name with a `-recovered` suffix.
+    Recover,
+    /// Continue writing the most recent one.
+    Resume,
+}
+
+impl std::str::FromStr for LeftoverPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "keep" => Ok(LeftoverPolicy::Keep),
+            "recover" => Ok(LeftoverPolicy::Recover),
+            "resume" => Ok(LeftoverPolicy::Resume),
+            _ => Err(format!("unknown leftover policy `{}`", s)),
+        }
+    }
+}
+
+enum Item {
+    /// A chunk, with the time it was queued when timed.
+    Data(Arc<[u8]>, Option<Instant>),
+    Split,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum OverflowPolicy {
+    /// Wait for the writer to make room, stalling the capture.
+    Block,
+    /// Drop the chunk for this output.
+    Drop,
+}
+
+impl std::str::FromStr for OverflowPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "block" => Ok(OverflowPolicy::Block),
+            "drop" => Ok(OverflowPolicy::Drop),
+            _ => Err(format!("unknown overflow policy `{}`", s)),
+        }
+    }
+}
+
+#[derive(Default)]
+pub struct SinkStats {
+    /// Number of times a chunk found the queue full.
+    pub queue_full: AtomicU64,
+    pub dropped_bytes: AtomicU64,
+    pub written_bytes: AtomicU64,
+}
+
+/// An output written by a dedicated thread.
+pub struct QueuedSink {
+    name: String,
+    tx: Option<SyncSender<Item>>,
+    policy: OverflowPolicy,
+    stats: Arc<SinkStats>,
+    connection: Option<Arc<Connection>>,
+    error: Arc<Mutex<Option<io::Error>>>,
+    thread: Option<JoinHandle<()>>,
+    timed: bool,
+}
+
+impl QueuedSink {
+    /// Start writing to `output`, with a queue of `capacity` chunks. With
+    /// `timing`, the time the chunks wait in the queue and take to write
+    /// is recorded.
+    pub fn spawn(
+        name: &str,
+        mut output: Box<dyn Output>,
+        capacity: usize,
+        policy: OverflowPolicy,
+        timing: Option<&DebugTiming>,
+    ) -> QueuedSink {
+        let (tx, rx) = mpsc::sync_channel::<Item>(capacity);
+        let stats = Arc::new(SinkStats::default());
+        let connection = output.connection();
+        let error = Arc::new(Mutex::new(None));
+        let stages = timing.map(|t| {
+            (
+                t.stage(&format!("queue {}", name)),
+                t.stage(&format!("write {}", name)),
+            )
+        });
+        let timed = stages.is_some();
+        let thread = {
+            let stats = stats.clone();
+            let error = error.clone();
+            thread::Builder::new()
+                .name(format!("sink {}", name))
+                .spawn(move || {
+                    let res = rx
+                        .iter()
+                        .try_for_each(|item| match item {
+                            Item::Data(chunk, enqueued) => {
+                                let dequeued = enqueued.map(|_| Instant::now());
+                                output.write_all(&chunk)?;
+                                if let (Some((queue, write)), Some(enqueued), Some(dequeued)) =
+                                    (&stages, enqueued, dequeued)
+                                {
+                                    queue.record(enqueued, dequeued);
+                                    write.record(dequeued, Instant::now());
+                                }
+                                stats
+                                    .written_bytes
+                                    .fetch_add(chunk.len() as u64, Ordering::Relaxed);
+                                Ok(())
+                            }
+                            Item::Split => output.split(),
+                        })
+                        .and_then(|_| output.finish());
+                    if let Err(e) = res {
+                        *error.lock().unwrap() = Some(e);
+                    }
+                })
+                .expect("failed to spawn sink thread")
+        };
+        QueuedSink {
+            name: name.to_string(),
+            tx: Some(tx),
+            policy,
+            stats,
+            connection,
+            error,
+            thread: Some(thread),
+            timed,
+        }
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn stats(&self) -> &SinkStats {
+        &self.stats
+    }
+
+    pub fn connection(&self) -> Option<&Connection> {
+        self.connection.as_deref()
+    }
+
+    /// Queue a chunk for writing.
+    ///
+    /// Fails if the writer thread stopped on an error.
+    pub fn send(&self, chunk: Arc<[u8]>) -> Result<(), Error> {
+        let tx = self.tx.as_ref().expect("send on a closed sink");
+        let len = chunk.len() as u64;
+        let enqueued = if self.timed {
+            Some(Instant::now())
+        } else {
+            None
+        };
+        let chunk = match tx.try_send(Item::Data(chunk, enqueued)) {
+            Ok(()) => return Ok(()),
+            Err(TrySendError::Disconnected(_)) => return Err(self.failure()),
+            Err(TrySendError::Full(chunk)) => {
+                self.stats.queue_full.fetch_add(1, Ordering::Relaxed);
+                chunk
+            }
+        };
+        match self.policy {
+            OverflowPolicy::Block => tx.send(chunk).map_err(|_| self.failure()),
+            OverflowPolicy::Drop => {
+                self.stats.dropped_bytes.fetch_add(len, Ordering::Relaxed);
+                Ok(())
+            }
+        }
+    }
+
+    /// Queue a split of the output. Splits are never dropped.
+    pub fn split(&self) -> Result<(), Error> {
+        let tx = self.tx.as_ref().expect("split on a closed sink");
+        tx.send(Item::Split).map_err(|_| self.failure())
+    }
+
+    fn failure(&self) -> Error {
+        let err = self.error.lock().unwrap().take();
+        Error::Io(err.unwrap_or_else(|| io::Error::other(format!("{}: writer stopped", self.name))))
+    }
+
+    /// Write out the queued data and stop the writer thread.
+    pub fn close(&mut self) -> Result<(), Error> {
+        self.tx.take();
+        if let Some(connection) = &self.connection {
+            connection.closing.store(true, Ordering::Relaxed);
+        }
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+        match self.error.lock().unwrap().take() {
+            Some(e) => Err(Error::Io(e)),
+            None => Ok(()),
+        }
+    }
+}
+
+impl Drop for QueuedSink {
+    fn drop(&mut self) {
+        let _ = self.close();
+    }
+}
+
+/// Distributes the stream to all the outputs.
+#[derive(Default)]
+pub struct FanOut {
+    sinks: Vec<QueuedSink>,
+    events: OutputEvents,
+}
+
+impl FanOut {
+    pub fn new() -> FanOut {
+        FanOut::default()
+    }
+
+    pub fn add(&mut self, sink: QueuedSink) {
+        self.sinks.push(sink);
+    }
+
+    pub fn sinks(&self) -> &[QueuedSink] {
+        &self.sinks
+    }
+
+    /// Log shared with the outputs, see `take_events`.
+    pub fn events(&self) -> OutputEvents {
+        self.events.clone()
+    }
+
+    /// Events logged by the outputs since the last call.
+    pub fn take_events(&self) -> Vec<Event> {
+        std::mem::take(&mut *self.events.lock().unwrap())
+    }
+
+    pub fn write(&self, data: &[u8]) -> Result<(), Error> {
+        let chunk: Arc<[u8]> = Arc::from(data);
+        for sink in &self.sinks {
+            sink.send(chunk.clone())?;
+        }
+        Ok(())
+    }
+
+    pub fn split(&self) -> Result<(), Error> {
+        for sink in &self.sinks {
+            sink.split()?;
+        }
+        Ok(())
+    }
+
+    /// Close all the outputs, returning the first error.
+    pub fn close(&mut self) -> Result<(), Error> {
+        let mut res = Ok(());
+        for sink in &mut self.sinks {
+            let r = sink.close();
+            if res.is_ok() {
+                res = r;
+            }
+        }
+        res
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    /// An empty directory for the files of test `name`.
+    fn test_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("it9910-sink-{}-{}", name, std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    /// Write each of `segments` to `output`, splitting between them.
+    fn write_segments(mut output: FileOutput, segments: &[&[u8]]) {
+        for (i, data) in segments.iter().enumerate() {
+            if i > 0 {
+                output.split().unwrap();
+            }
+            output.write_all(data).unwrap();
+        }
+        output.finish().unwrap();
+    }
+
+    fn read(path: PathBuf) -> Vec<u8> {
+        std::fs::read(path).unwrap()
+    }
+
+    #[test]
+    fn split_numbers_the_segments_without_placeholder() {
+        let dir = test_dir("numbered");
+        let template = dir.join("out.ts");
+        let output = FileOutput::create(template.to_str().unwrap(), false).unwrap();
+        write_segments(output, &[b"first", b"second", b"third"]);
+        assert_eq!(read(dir.join("out.ts")), b"first");
+        assert_eq!(read(dir.join("out-0001.ts")), b"second");
+        assert_eq!(read(dir.join("out-0002.ts")), b"third");
+    }
+
+    #[test]
+    fn split_reuses_the_path_of_a_rotated_file() {
+        let dir = test_dir("rotated");
+        let template = dir.join("out.ts");
+        let mut output = FileOutput::create(template.to_str().unwrap(), false).unwrap();
+        output.write_all(b"first").unwrap();
+        output.file.flush().unwrap();
+        std::fs::rename(&template, dir.join("out.ts.1")).unwrap();
+        write_segments(output, &[b"", b"second"]);
+        assert_eq!(read(dir.join("out.ts.1")), b"first");
+        assert_eq!(read(dir.join("out.ts")), b"second");
+        assert!(!dir.join("out-0001.ts").exists());
+    }
+
+    #[test]
+    fn split_expands_the_placeholders() {
+        let dir = test_dir("placeholder");
+        let template = dir.join("out-{n}.ts");
+        let output = FileOutput::create(template.to_str().unwrap(), false).unwrap();
+        write_segments(output, &[b"first", b"second"]);
+        assert_eq!(read(dir.join("out-0000.ts")), b"first");
+        assert_eq!(read(dir.join("out-0001.ts")), b"second");
+    }
+
+    #[test]
+    fn atomic_split_keeps_the_previous_segment() {
+        let dir = test_dir("atomic");
+        let template = dir.join("out.ts");
+        let events = OutputEvents::default();
+        let output =
+            FileOutput::create_atomic(template.to_str().unwrap(), false, events.clone(), None)
+                .unwrap();
+        write_segments(output, &[b"first", b"second"]);
+        assert_eq!(read(dir.join("out.ts")), b"first");
+        assert_eq!(read(dir.join("out-0001.ts")), b"second");
+        assert!(leftover_parts(template.to_str().unwrap())
+            .unwrap()
+            .is_empty());
+        assert_eq!(events.lock().unwrap().len(), 2);
+    }
+
+    #[test]
+    fn atomic_resume_trims_the_partial_packet() {
+        let dir = test_dir("resume");
+        let template = dir.join("out.ts");
+        let part = dir.join("out.ts.part");
+        let mut generator = crate::emulator::TsGenerator::new(2_000_000);
+        let packets: Vec<u8> = (0..3).flat_map(|_| generator.next_packet()).collect();
+        std::fs::write(&part, [&packets[..], &packets[..100]].concat()).unwrap();
+        let events = OutputEvents::default();
+        let output = FileOutput::create_atomic(
+            template.to_str().unwrap(),
+            false,
+            events.clone(),
+            Some(&part),
+        )
+        .unwrap();
+        match &events.lock().unwrap()[..] {
+            [Event::OutputAppended {
+                file_offset,
+                trimmed,
+                ..
+            }] => assert_eq!((*file_offset, *trimmed), (packets.len() as u64, 100)),
+            other => panic!("unexpected events {:?}", other),
+        }
+        write_segments(output, &[&packets[..]]);
+        assert_eq!(read(template), [&packets[..], &packets[..]].concat());
+        assert!(!part.exists());
+    }
+
+    #[test]
+    fn leftover_parts_match_the_template() {
+        let dir = test_dir("leftover");
+        let template = dir.join("out-{n}.ts");
+        let start = SystemTime::now();
+        for (name, age) in [
+            ("out-0003.ts.part", 10),
+            ("out-0000.ts.part", 30),
+            ("out-0001.ts.part", 20),
+            ("out-0002.ts", 0),
+            ("other.ts.part", 0),
+            ("out-0004.ts.part.bak", 0),
+        ] {
+            let file = File::create(dir.join(name)).unwrap();
+            file.set_modified(start - Duration::from_secs(age)).unwrap();
+        }
+        assert_eq!(
+            leftover_parts(template.to_str().unwrap()).unwrap(),
+            ["out-0000.ts.part", "out-0001.ts.part", "out-0003.ts.part"]
+                .iter()
+                .map(|name| dir.join(name))
+                .collect::<Vec<_>>()
+        );
+        // No placeholder, the name itself.
+        let parts = leftover_parts(dir.join("other.ts").to_str().unwrap()).unwrap();
+        assert_eq!(parts, [dir.join("other.ts.part")]);
+    }
+
+    #[test]
+    fn template_matches_around_the_placeholders() {
+        let pieces: Vec<&str> = "cap-{time}-{n}.ts.part".split(['{', '}']).collect();
+        assert!(template_matches(
+            &pieces,
+            "cap-20240101T000000Z-0001.ts.part"
+        ));
+        assert!(template_matches(&pieces, "cap--.ts.part"));
+        assert!(!template_matches(&pieces, "cap-20240101T000000Z.ts.part"));
+        assert!(!template_matches(&pieces, "xcap-1-2.ts.part"));
+        assert!(!template_matches(&pieces, "cap-1-2.ts"));
+        assert!(template_matches(&["out.ts.part"], "out.ts.part"));
+        assert!(!template_matches(&["out.ts.part"], "out.ts.part2"));
+    }
+
+    /// `count` packets of the emulated stream.
+    fn ts_packets(count: usize) -> Vec<u8> {
+        let mut generator = crate::emulator::TsGenerator::new(2_000_000);
+        (0..count).flat_map(|_| generator.next_packet()).collect()
+    }
+
+    #[test]
+    fn append_point_is_after_the_last_full_packet() {
+        let dir = test_dir("append-point");
+        let path = dir.join("out.ts");
+        assert_eq!(append_point(&path).unwrap(), None);
+        std::fs::write(&path, b"").unwrap();
+        assert_eq!(append_point(&path).unwrap(), Some(0));
+
+        let packets = ts_packets(4);
+        let size = PACKET_SIZE as u64;
+        std::fs::write(&path, &packets).unwrap();
+        assert_eq!(append_point(&path).unwrap(), Some(4 * size));
+        // A trailing partial packet.
+        std::fs::write(&path, &packets[..3 * PACKET_SIZE + 50]).unwrap();
+        assert_eq!(append_point(&path).unwrap(), Some(3 * size));
+        // A last packet without its sync byte.
+        let mut corrupted = packets.clone();
+        corrupted[3 * PACKET_SIZE] = 0x00;
+        std::fs::write(&path, &corrupted).unwrap();
+        assert_eq!(append_point(&path).unwrap(), Some(3 * size));
+        // Leading garbage before the first packet.
+        std::fs::write(&path, [&[0u8; 10][..], &packets].concat()).unwrap();
+        assert_eq!(append_point(&path).unwrap(), Some(10 + 4 * size));
+
+        std::fs::write(&path, vec![0u8; 1000]).unwrap();
+        let e = append_point(&path).unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn seam_marks_each_pid_once_until_the_second_pat() {
+        let data = ts_packets(400);
+        let pats: Vec<usize> = data
+            .chunks(PACKET_SIZE)
+            .enumerate()
+            .filter(|(_, pkt)| Packet::new(pkt).unwrap().pid() == PAT_PID)
+            .map(|(i, _)| i)
+            .collect();
+        assert!(pats.len() >= 2 && pats[0] == 0);
+        let before: HashSet<u16> = data
+            .chunks(PACKET_SIZE)
+            .take(pats[1])
+            .map(|pkt| Packet::new(pkt).unwrap().pid())
+            .filter(|&pid| pid != NULL_PID)
+            .collect();
+
+        // Fed in two writes, the state is kept between them.
+        let mut marker = SeamMarker::default();
+        let cut = 5 * PACKET_SIZE;
+        let out = [marker.mark(&data[..cut]), marker.mark(&data[cut..])].concat();
+        assert!(marker.done);
+        assert_eq!(out.len(), data.len() + before.len() * PACKET_SIZE);
+
+        let mut marked = HashSet::new();
+        let mut packets = out.chunks(PACKET_SIZE).map(|pkt| Packet::new(pkt).unwrap());
+        let mut received = 0;
+        while let Some(pkt) = packets.next() {
+            if pkt.data() == ts::discontinuity_packet(pkt.pid(), pkt.cc()) {
+                assert!(received < pats[1], "marked after the second PAT");
+                assert!(marked.insert(pkt.pid()), "PID {} marked twice", pkt.pid());
+                // The next packet of the PID follows on the counter.
+                let next = packets.next().unwrap();
+                received += 1;
+                assert_eq!(next.pid(), pkt.pid());
+                let expected = if next.has_payload() {
+                    (pkt.cc() + 1) & 0x0f
+                } else {
+                    pkt.cc()
+                };
+                assert_eq!(next.cc(), expected);
+            } else {
+                received += 1;
+            }
+        }
+        assert_eq!(marked, before);
+    }
+
+    #[test]
+    fn split_stops_on_a_taken_segment_name() {
+        let dir = test_dir("taken");
+        let template = dir.join("out.ts");
+        std::fs::write(dir.join("out-0001.ts"), b"kept").unwrap();
+        let mut output = FileOutput::create(template.to_str().unwrap(), false).unwrap();
+        output.write_all(b"first").unwrap();
+        assert!(output.split().is_err());
+        assert_eq!(read(dir.join("out-0001.ts")), b"kept");
+    }
+}
@@ -0,0 +1,147 @@
+//! Extraction of a single H.264 keyframe from the stream.
+
+use crate::h264::{self, NAL_IDR, NAL_PPS, NAL_SPS};
+use crate::pes;
+use crate::ts::Packet;
+
+/// Reassembles the access units of the video PID until one holds a complete
+/// keyframe (SPS, PPS and IDR slices).
+///
+/// Each PES packet of the IT9910 video stream carries one access unit. As
+/// the PES length is not set, an access unit is only known to be complete
+/// when the next one starts.
+#[derive(Default)]
+pub struct KeyframeExtractor {
+    au: Vec<u8>,
+    collecting: bool,
+    last_cc: Option<u8>,
+}
+
+impl KeyframeExtractor {
+    pub fn new() -> KeyframeExtractor {
+        KeyframeExtractor::default()
+    }
+
+    /// Feed a packet of the video PID, returns the Annex B keyframe once
+    /// complete.
+    pub fn packet(&mut self, pkt: &Packet) -> Option<Vec<u8>> {
+        let payload = pkt.payload()?;
+        let cc = pkt.cc();
+        let expected = self.last_cc.map(|last| (last + 1) & 0x0f);
+        self.last_cc = Some(cc);
+        if !pkt.pusi() {
+            if expected.is_some_and(|e| e != cc) || pkt.tei() {
+                // Part of the access unit is missing.
+                self.collecting = false;
+                self.au.clear();
+            } else if self.collecting {
+                self.au.extend_from_slice(payload);
+            }
+            return None;
+        }
+        let done = if self.collecting && is_complete_keyframe(&self.au) {
+            Some(std::mem::take(&mut self.au))
+        } else {
+            None
+        };
+        self.au.clear();
+        let es = pes::parse_header(payload).and_then(|h| payload.get(h.header_len..));
+        self.collecting = match es {
+            Some(es) if !pkt.tei() && h264::starts_keyframe(es) => {
+                self.au.extend_from_slice(es);
+                true
+            }
+            _ => false,
+        };
+        done
+    }
+}
+
+fn is_complete_keyframe(au: &[u8]) -> bool {
+    let (mut sps, mut pps, mut idr) = (false, false, false);
+    for (_, t) in h264::nal_units(au) {
+        match t {
+            NAL_SPS => sps = true,
+            NAL_PPS => pps = true,
+            NAL_IDR => idr = true,
+            _ => (),
+        }
+    }
+    sps && pps && idr
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::emulator::{TsGenerator, VIDEO_PID};
+    use crate::ts::{PACKET_SIZE, SYNC_BYTE};
+    use proptest::collection::vec;
+    use proptest::prelude::*;
+
+    /// The video packets of the first 1000 packets of the emulated stream,
+    /// holding two keyframes.
+    fn video() -> Vec<u8> {
+        let mut generator = TsGenerator::new(1_000_000);
+        (0..1000)
+            .map(|_| generator.next_packet())
+            .filter(|pkt| Packet::new(pkt).unwrap().pid() == VIDEO_PID)
+            .flatten()
+            .collect()
+    }
+
+    /// Feed the packets of `data`, checking that the access unit never
+    /// holds more than was fed since it started. Returns the keyframes.
+    fn extract(data: &[u8]) -> Result<Vec<Vec<u8>>, TestCaseError> {
+        let mut extractor = KeyframeExtractor::new();
+        let mut frames = Vec::new();
+        let mut unit = 0;
+        for pkt in data.chunks(PACKET_SIZE) {
+            let pkt = Packet::new(pkt).unwrap();
+            let before = unit;
+            if pkt.pusi() {
+                unit = 0;
+            }
+            unit += pkt.payload().map_or(0, <[u8]>::len);
+            if let Some(frame) = extractor.packet(&pkt) {
+                prop_assert!(frame.len() <= before);
+                frames.push(frame);
+            }
+            prop_assert!(extractor.au.len() <= unit);
+        }
+        Ok(frames)
+    }
+
+    #[test]
+    fn generated_keyframe_is_extracted() {
+        let frames = extract(&video()).unwrap();
+        assert_eq!(frames.len(), 2);
+        assert!(frames.iter().all(|frame| is_complete_keyframe(frame)));
+    }
+
+    proptest! {
+        #[test]
+        fn corrupt_video_never_panics(
+            flips in vec((0..1000 * PACKET_SIZE, any::<u8>()), 0..64),
+        ) {
+            let mut data = video();
+            for (pos, mask) in flips {
+                let pos = pos % data.len();
+                if !pos.is_multiple_of(PACKET_SIZE) {
+                    data[pos] ^= mask;
+                }
+            }
+            extract(&data)?;
+        }
+
+        #[test]
+        fn arbitrary_packets_never_panic(
+            bodies in vec(vec(any::<u8>(), PACKET_SIZE - 1), 0..64),
+        ) {
+            let data: Vec<u8> = bodies
+                .iter()
+                .flat_map(|body| std::iter::once(SYNC_BYTE).chain(body.iter().copied()))
+                .collect();
+            extract(&data)?;
+        }
+    }
+}
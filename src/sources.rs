@@ -0,0 +1,113 @@
+//! Audio and video inputs of the device.
+//!
+//! Products expose different inputs, which the hardware grabber response
+//! (opcode 0xf002) appears to list: its first word is taken as a bit mask of
+//! the video inputs and its second word as a bit mask of the audio inputs,
+//! bit N standing for the source of ID N. A zero mask is taken as not
+//! decoded rather than as a device without inputs.
+
+use std::fmt;
+
+use crate::device::Device;
+use crate::error::Error;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Source {
+    pub id: u32,
+    pub name: &'static str,
+}
+
+impl fmt::Display for Source {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} ({})", self.id, self.name)
+    }
+}
+
+pub const VIDEO_SOURCES: &[Source] = &[
+    Source {
+        id: 0,
+        name: "hdmi",
+    },
+    Source {
+        id: 1,
+        name: "component",
+    },
+    Source {
+        id: 2,
+        name: "composite",
+    },
+    Source {
+        id: 3,
+        name: "s-video",
+    },
+];
+
+pub const AUDIO_SOURCES: &[Source] = &[
+    Source {
+        id: 0,
+        name: "hdmi",
+    },
+    Source {
+        id: 1,
+        name: "line-in",
+    },
+];
+
+/// Sources available on a device, or all the known ones when `decoded` is
+/// false.
+#[derive(Clone, Debug)]
+pub struct Available {
+    pub sources: Vec<Source>,
+    pub decoded: bool,
+}
+
+impl Available {
+    fn from_mask(known: &[Source], mask: Option<u32>) -> Available {
+        match mask {
+            Some(mask) if mask != 0 => Available {
+                sources: known
+                    .iter()
+                    .filter(|s| s.id < 32 && mask & (1 << s.id) != 0)
+                    .copied()
+                    .collect(),
+                decoded: true,
+            },
+            _ => Available {
+                sources: known.to_vec(),
+                decoded: false,
+            },
+        }
+    }
+
+    /// Find a source by ID or by name.
+    pub fn find(&self, s: &str) -> Result<Source, String> {
+        let found = self
+            .sources
+            .iter()
+            .find(|src| src.name.eq_ignore_ascii_case(s) || s.parse() == Ok(src.id));
+        found.copied().ok_or_else(|| {
+            let names: Vec<_> = self.sources.iter().map(|s| s.to_string()).collect();
+            format!(
+                "source `{}` not available on this device (available: {})",
+                s,
+                names.join(", ")
+            )
+        })
+    }
+}
+
+/// The video and audio sources of a device.
+pub struct Capabilities {
+    pub video: Available,
+    pub audio: Available,
+}
+
+impl Capabilities {
+    pub fn query(device: &Device) -> Result<Capabilities, Error> {
+        let resp = device.transact(&device.factory().make_get_hw_grabber())?;
+        Ok(Capabilities {
+            video: Available::from_mask(VIDEO_SOURCES, resp.word(0)),
+            audio: Available::from_mask(AUDIO_SOURCES, resp.word(4)),
+        })
+    }
+}
@@ -0,0 +1,174 @@
+//! Scheduling of output splits (segments and rotations) on keyframes.
+
+use std::fmt;
+use std::time::{Duration, Instant};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SplitReason {
+    /// The segment duration elapsed.
+    Segment,
+    /// A rotation was requested with SIGHUP.
+    Rotate,
+    /// The format of the input signal changed.
+    InputChange,
+    /// The encoder was restarted.
+    Restart,
+    /// The device was rebooted after a failure.
+    Reboot,
+    /// The device was reopened after its handle stopped working.
+    Reopen,
+}
+
+impl fmt::Display for SplitReason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SplitReason::Segment => write!(f, "segment"),
+            SplitReason::Rotate => write!(f, "rotate"),
+            SplitReason::InputChange => write!(f, "input change"),
+            SplitReason::Restart => write!(f, "restart"),
+            SplitReason::Reboot => write!(f, "reboot"),
+            SplitReason::Reopen => write!(f, "reopen"),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Split {
+    /// Stream offset of the first packet of the new output.
+    pub offset: u64,
+    /// Whether the split happens on a keyframe.
+    pub aligned: bool,
+    pub reason: SplitReason,
+}
+
+/// Delays the requested splits until the next keyframe.
+///
+/// If no keyframe shows up within the window, the split is forced on the
+/// next packet boundary.
+pub struct SplitScheduler {
+    window: Duration,
+    pending: Option<(SplitReason, Instant)>,
+}
+
+impl SplitScheduler {
+    pub fn new(window: Duration) -> SplitScheduler {
+        SplitScheduler {
+            window,
+            pending: None,
+        }
+    }
+
+    /// Request a split. A request made while another one is pending is
+    /// merged with it.
+    pub fn request(&mut self, reason: SplitReason, now: Instant) {
+        if self.pending.is_none() {
+            self.pending = Some((reason, now));
+        }
+    }
+
+    pub fn is_pending(&self) -> bool {
+        self.pending.is_some()
+    }
+
+    /// Called for each packet, returns the split to make before it.
+    pub fn packet(&mut self, offset: u64, keyframe: bool, now: Instant) -> Option<Split> {
+        let (reason, requested) = self.pending?;
+        let aligned = keyframe;
+        if !aligned && now.duration_since(requested) < self.window {
+            return None;
+        }
+        self.pending = None;
+        Some(Split {
+            offset,
+            aligned,
+            reason,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const WINDOW: Duration = Duration::from_secs(2);
+
+    #[test]
+    fn nothing_without_request() {
+        let mut scheduler = SplitScheduler::new(WINDOW);
+        let now = Instant::now();
+        assert_eq!(scheduler.packet(0, true, now), None);
+        assert!(!scheduler.is_pending());
+    }
+
+    #[test]
+    fn waits_for_a_keyframe() {
+        let mut scheduler = SplitScheduler::new(WINDOW);
+        let start = Instant::now();
+        scheduler.request(SplitReason::Segment, start);
+        for i in 0..10 {
+            let now = start + Duration::from_millis(100 * i);
+            assert_eq!(scheduler.packet(i * 188, false, now), None);
+        }
+        let split = scheduler.packet(10 * 188, true, start + Duration::from_secs(1));
+        assert_eq!(
+            split,
+            Some(Split {
+                offset: 10 * 188,
+                aligned: true,
+                reason: SplitReason::Segment,
+            })
+        );
+        assert!(!scheduler.is_pending());
+        // Once made, the next keyframe does not split again.
+        assert_eq!(scheduler.packet(11 * 188, true, start + WINDOW), None);
+    }
+
+    #[test]
+    fn forced_once_the_window_elapses() {
+        let mut scheduler = SplitScheduler::new(WINDOW);
+        let start = Instant::now();
+        scheduler.request(SplitReason::Rotate, start);
+        let just_before = start + WINDOW - Duration::from_millis(1);
+        assert_eq!(scheduler.packet(0, false, just_before), None);
+        assert_eq!(
+            scheduler.packet(188, false, start + WINDOW),
+            Some(Split {
+                offset: 188,
+                aligned: false,
+                reason: SplitReason::Rotate,
+            })
+        );
+    }
+
+    #[test]
+    fn keyframe_at_the_request_splits_at_once() {
+        let mut scheduler = SplitScheduler::new(WINDOW);
+        let start = Instant::now();
+        scheduler.request(SplitReason::Restart, start);
+        let split = scheduler.packet(376, true, start).unwrap();
+        assert!(split.aligned);
+        assert_eq!(split.offset, 376);
+    }
+
+    #[test]
+    fn zero_window_never_waits() {
+        let mut scheduler = SplitScheduler::new(Duration::ZERO);
+        let start = Instant::now();
+        scheduler.request(SplitReason::Segment, start);
+        let split = scheduler.packet(0, false, start).unwrap();
+        assert!(!split.aligned);
+    }
+
+    #[test]
+    fn requests_are_merged() {
+        let mut scheduler = SplitScheduler::new(WINDOW);
+        let start = Instant::now();
+        scheduler.request(SplitReason::Segment, start);
+        // A later request neither replaces the reason nor delays the
+        // forced split.
+        scheduler.request(SplitReason::InputChange, start + Duration::from_secs(1));
+        let split = scheduler.packet(0, false, start + WINDOW).unwrap();
+        assert_eq!(split.reason, SplitReason::Segment);
+        assert_eq!(scheduler.packet(188, true, start + WINDOW), None);
+    }
+}
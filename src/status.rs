@@ -0,0 +1,245 @@
+//! Parsers for the status responses of the device.
+
+use serde::Serialize;
+
+use crate::protocol;
+use crate::response::Response;
+
+/// Firmware status response (opcode 0x0008).
+///
+/// The meaning of the individual fields is not known yet, so the payload is
+/// kept as a list of little-endian words.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct FirmwareStatus {
+    pub words: Vec<u32>,
+}
+
+impl FirmwareStatus {
+    pub const OPCODE: u16 = protocol::FIRMWARE_STATUS;
+
+    pub fn parse(resp: &Response) -> Option<FirmwareStatus> {
+        if resp.opcode != Self::OPCODE {
+            return None;
+        }
+        let words = resp
+            .payload
+            .chunks_exact(4)
+            .map(|w| u32::from_le_bytes([w[0], w[1], w[2], w[3]]))
+            .collect();
+        Some(FirmwareStatus { words })
+    }
+
+    /// The errors of `layout` flagged in the status.
+    pub fn errors(&self, layout: &'static [StatusError]) -> Vec<&'static StatusError> {
+        layout
+            .iter()
+            .filter(|e| self.words.get(e.word).is_some_and(|w| w & e.mask != 0))
+            .collect()
+    }
+
+    /// Words which differ from `previous`, as (index, old, new).
+    pub fn changes(&self, previous: &FirmwareStatus) -> Vec<(usize, u32, u32)> {
+        let len = self.words.len().max(previous.words.len());
+        (0..len)
+            .filter_map(|i| {
+                let old = previous.words.get(i).copied().unwrap_or(0);
+                let new = self.words.get(i).copied().unwrap_or(0);
+                if old != new {
+                    Some((i, old, new))
+                } else {
+                    None
+                }
+            })
+            .collect()
+    }
+}
+
+/// An error state of the firmware, flagged by bits of a word of the
+/// firmware status. Where they are is known per revision.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct StatusError {
+    /// Index of the word in the status.
+    pub word: usize,
+    pub mask: u32,
+    pub name: &'static str,
+}
+
+/// PC grabber state, returned by the small GET of opcode 0xe001.
+///
+/// The layout is selected by the length field of the response header, as
+/// device variants answer with different sizes.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct PcGrabberState {
+    pub ready: bool,
+}
+
+impl PcGrabberState {
+    pub const OPCODE: u16 = protocol::PC_GRABBER;
+
+    /// Response lengths (header included) with a known layout.
+    pub const KNOWN_LENGTHS: &'static [u16] = &[0x1c, 0x20];
+
+    /// Offset of the ready flag in the payload, for all the known layouts.
+    const READY_OFFSET: usize = 0x08;
+
+    /// Returns `None` if the response does not have a known layout.
+    pub fn parse(resp: &Response) -> Option<PcGrabberState> {
+        if resp.opcode != Self::OPCODE || !Self::KNOWN_LENGTHS.contains(&resp.length) {
+            return None;
+        }
+        let ready = *resp.payload.get(Self::READY_OFFSET)? == 0x01;
+        Some(PcGrabberState { ready })
+    }
+}
+
+/// Input signal, as reported by the profile response (opcode 0x000a).
+///
+/// The layout is inferred from captures: a word telling whether a signal is
+/// locked, then the width, height and frame rate of the input.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+pub struct InputSignal {
+    pub locked: bool,
+    pub width: u32,
+    pub height: u32,
+    pub frame_rate: u32,
+}
+
+impl InputSignal {
+    pub const OPCODE: u16 = protocol::PROFILE;
+
+    pub fn parse(resp: &Response) -> Option<InputSignal> {
+        if resp.opcode != Self::OPCODE {
+            return None;
+        }
+        Some(InputSignal {
+            locked: resp.word(0)? != 0,
+            width: resp.word(4)?,
+            height: resp.word(8)?,
+            frame_rate: resp.word(12)?,
+        })
+    }
+}
+
+impl std::fmt::Display for InputSignal {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if !self.locked {
+            return write!(f, "no signal");
+        }
+        write!(
+            f,
+            "{}x{} at {} Hz",
+            self.width, self.height, self.frame_rate
+        )
+    }
+}
+
+/// Encoder state, returned by the GET of opcode 0x0002.
+///
+/// The first word holds the state last set, 2 while the encoder runs.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct EncoderState {
+    pub running: bool,
+}
+
+impl EncoderState {
+    pub const OPCODE: u16 = protocol::STATE;
+
+    pub fn parse(resp: &Response) -> Option<EncoderState> {
+        if resp.opcode != Self::OPCODE {
+            return None;
+        }
+        Some(EncoderState {
+            running: resp.word(0)? == 0x2,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::command::{CommandFactory, Operation};
+
+    /// A response of `opcode` carrying `payload`, its length field counting
+    /// the header.
+    fn response(opcode: u16, payload: &[u8]) -> Response {
+        let data = CommandFactory::new().make_command(opcode, Operation::Get, payload);
+        Response::parse(&data).unwrap()
+    }
+
+    /// Payload of a PC grabber state of `len` bytes.
+    fn grabber_state(len: usize, ready: bool) -> Vec<u8> {
+        let mut payload = vec![0u8; len];
+        payload[..4].copy_from_slice(&[0x01, 0x40, 0x38, 0x38]);
+        payload[8] = ready as u8;
+        payload
+    }
+
+    #[test]
+    fn grabber_state_of_0x1c_bytes() {
+        let resp = response(protocol::PC_GRABBER, &grabber_state(0x0c, true));
+        assert_eq!(resp.length, 0x1c);
+        assert_eq!(
+            PcGrabberState::parse(&resp),
+            Some(PcGrabberState { ready: true })
+        );
+        let resp = response(protocol::PC_GRABBER, &grabber_state(0x0c, false));
+        assert_eq!(
+            PcGrabberState::parse(&resp),
+            Some(PcGrabberState { ready: false })
+        );
+    }
+
+    #[test]
+    fn grabber_state_of_0x20_bytes() {
+        let mut payload = grabber_state(0x10, true);
+        payload[0x0c..].copy_from_slice(&[0xde, 0xad, 0xbe, 0xef]);
+        let resp = response(protocol::PC_GRABBER, &payload);
+        assert_eq!(resp.length, 0x20);
+        assert_eq!(
+            PcGrabberState::parse(&resp),
+            Some(PcGrabberState { ready: true })
+        );
+    }
+
+    #[test]
+    fn grabber_state_of_unknown_length() {
+        for len in [0x09, 0x0d, 0x14] {
+            let resp = response(protocol::PC_GRABBER, &grabber_state(len, true));
+            assert_eq!(PcGrabberState::parse(&resp), None, "{} bytes", len);
+        }
+        // A known length, but another opcode.
+        let resp = response(protocol::STATE, &grabber_state(0x0c, true));
+        assert_eq!(PcGrabberState::parse(&resp), None);
+    }
+
+    const LAYOUT: &[StatusError] = &[
+        StatusError {
+            word: 2,
+            mask: 0x1,
+            name: "overload",
+        },
+        StatusError {
+            word: 2,
+            mask: 0x2,
+            name: "overrun",
+        },
+        StatusError {
+            word: 9,
+            mask: 0x1,
+            name: "beyond the response",
+        },
+    ];
+
+    #[test]
+    fn decodes_error_states() {
+        let status = FirmwareStatus {
+            words: vec![0x0001_0203, 42, 0x2, 0],
+        };
+        let names: Vec<_> = status.errors(LAYOUT).iter().map(|e| e.name).collect();
+        assert_eq!(names, ["overrun"]);
+        let clear = FirmwareStatus {
+            words: vec![0x0001_0203, 43, 0, 0],
+        };
+        assert!(clear.errors(LAYOUT).is_empty());
+    }
+}
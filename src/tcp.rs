@@ -0,0 +1,212 @@
+//! Output pushing the stream to a TCP server.
+//!
+//! The connection is made from the capture side, for relays which only
+//! accept inbound connections. When it drops, the output keeps retrying
+//! with an increasing delay while the capture goes on: the chunks pile up
+//! in the queue of the sink meanwhile, and its overflow policy decides
+//! whether the capture waits or drops them.
+
+use std::fmt;
+use std::io::{self, Write};
+use std::net::{Shutdown, TcpStream, ToSocketAddrs};
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use log::warn;
+
+use crate::sink::{Connection, Output};
+
+/// Delay before the first reconnection attempt, doubled after each failure.
+const MIN_BACKOFF: Duration = Duration::from_secs(1);
+const MAX_BACKOFF: Duration = Duration::from_secs(30);
+/// Granularity of the waits between attempts, to notice a closing sink.
+const WAIT_STEP: Duration = Duration::from_millis(100);
+
+#[derive(Clone, Debug)]
+pub struct TcpOptions {
+    /// Longest wait for a connection or a write.
+    pub timeout: Duration,
+    pub nodelay: bool,
+    /// Number of consecutive failed attempts after which the output fails,
+    /// `None` to retry forever.
+    pub max_attempts: Option<u32>,
+}
+
+/// Schedule of the connection attempts of a network output.
+pub(crate) struct Retry {
+    address: String,
+    max_attempts: Option<u32>,
+    backoff: Duration,
+    next_attempt: Instant,
+    /// Failed attempts since the last connection.
+    failures: u32,
+}
+
+impl Retry {
+    pub(crate) fn new(address: &str, max_attempts: Option<u32>) -> Retry {
+        Retry {
+            address: address.to_string(),
+            max_attempts,
+            backoff: MIN_BACKOFF,
+            next_attempt: Instant::now(),
+            failures: 0,
+        }
+    }
+
+    /// Wait for the next attempt. Returns `false` if the sink closed
+    /// meanwhile.
+    pub(crate) fn wait(&self, connection: &Connection) -> bool {
+        loop {
+            if connection.closing.load(Ordering::Relaxed) {
+                return false;
+            }
+            let now = Instant::now();
+            if now >= self.next_attempt {
+                return true;
+            }
+            thread::sleep((self.next_attempt - now).min(WAIT_STEP));
+        }
+    }
+
+    pub(crate) fn connected(&mut self, connection: &Connection) {
+        eprintln!("Connected to {}", self.address);
+        connection.connects.fetch_add(1, Ordering::Relaxed);
+        self.backoff = MIN_BACKOFF;
+        self.failures = 0;
+    }
+
+    /// Schedule the next attempt after a failed one, failing after too many.
+    pub(crate) fn failed(
+        &mut self,
+        connection: &Connection,
+        err: &dyn fmt::Display,
+    ) -> io::Result<()> {
+        connection.failed_attempts.fetch_add(1, Ordering::Relaxed);
+        self.failures += 1;
+        if let Some(max) = self.max_attempts {
+            if self.failures >= max {
+                return Err(io::Error::other(format!(
+                    "could not connect to {} after {} attempts: {}",
+                    self.address, self.failures, err
+                )));
+            }
+        }
+        warn!(
+            "Could not connect to {}: {}, retrying in {} s",
+            self.address,
+            err,
+            self.backoff.as_secs()
+        );
+        self.next_attempt = Instant::now() + self.backoff;
+        self.backoff = (self.backoff * 2).min(MAX_BACKOFF);
+        Ok(())
+    }
+
+    /// Reconnect right away after a lost connection.
+    pub(crate) fn lost(&mut self, connection: &Connection, err: &dyn fmt::Display) {
+        warn!("Connection to {} lost: {}", self.address, err);
+        connection.disconnects.fetch_add(1, Ordering::Relaxed);
+        self.next_attempt = Instant::now();
+    }
+}
+
+pub struct TcpOutput {
+    address: String,
+    options: TcpOptions,
+    stream: Option<TcpStream>,
+    connection: Arc<Connection>,
+    retry: Retry,
+}
+
+impl TcpOutput {
+    /// Push to `address`, as `host:port`. A first connection is attempted
+    /// right away, without failing when the server is not there.
+    pub fn new(address: &str, options: TcpOptions) -> TcpOutput {
+        let mut output = TcpOutput {
+            address: address.to_string(),
+            retry: Retry::new(address, options.max_attempts),
+            options,
+            stream: None,
+            connection: Arc::new(Connection::default()),
+        };
+        let _ = output.attempt();
+        output
+    }
+
+    fn connect(&self) -> io::Result<TcpStream> {
+        let stream = connect(&self.address, self.options.timeout)?;
+        stream.set_nodelay(self.options.nodelay)?;
+        Ok(stream)
+    }
+
+    /// Try to connect once, failing after too many attempts.
+    fn attempt(&mut self) -> io::Result<()> {
+        match self.connect() {
+            Ok(stream) => {
+                self.retry.connected(&self.connection);
+                self.stream = Some(stream);
+                Ok(())
+            }
+            Err(e) => self.retry.failed(&self.connection, &e),
+        }
+    }
+
+    /// Wait for a connection. Returns `false` if the sink closed meanwhile.
+    fn reconnect(&mut self) -> io::Result<bool> {
+        while self.stream.is_none() {
+            if !self.retry.wait(&self.connection) {
+                return Ok(false);
+            }
+            self.attempt()?;
+        }
+        Ok(true)
+    }
+}
+
+/// Connect to `address`, as `host:port`, with `timeout` for the connection
+/// and the writes.
+pub(crate) fn connect(address: &str, timeout: Duration) -> io::Result<TcpStream> {
+    let mut last = None;
+    for addr in address.to_socket_addrs()? {
+        match TcpStream::connect_timeout(&addr, timeout) {
+            Ok(stream) => {
+                stream.set_write_timeout(Some(timeout))?;
+                return Ok(stream);
+            }
+            Err(e) => last = Some(e),
+        }
+    }
+    Err(last.unwrap_or_else(|| io::Error::other("no address to connect to")))
+}
+
+impl Output for TcpOutput {
+    fn write_all(&mut self, data: &[u8]) -> io::Result<()> {
+        loop {
+            if !self.reconnect()? {
+                // Closing without a server, the rest of the queue is lost.
+                return Ok(());
+            }
+            let stream = self.stream.as_mut().expect("connected");
+            match stream.write_all(data) {
+                Ok(()) => return Ok(()),
+                Err(e) => {
+                    self.retry.lost(&self.connection, &e);
+                    self.stream = None;
+                }
+            }
+        }
+    }
+
+    fn finish(&mut self) -> io::Result<()> {
+        if let Some(stream) = self.stream.take() {
+            let _ = stream.shutdown(Shutdown::Write);
+        }
+        Ok(())
+    }
+
+    fn connection(&self) -> Option<Arc<Connection>> {
+        Some(self.connection.clone())
+    }
+}
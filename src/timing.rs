@@ -0,0 +1,259 @@
+//! Timing of the stream transfers, for latency analysis.
+//!
+//! Each stage of the path of the stream records how long every transfer
+//! spent in it: the stream read, the pipeline up to the output queues, then
+//! for each output the wait in its queue and the write. The samples are
+//! kept in preallocated rings, one per stage, which a reporter thread
+//! drains periodically to log percentiles and optionally dump them as CSV.
+//! Recording a sample is two clock reads and an uncontended lock; nothing
+//! is recorded when the timing is off.
+
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use log::warn;
+
+use crate::worker::PeriodicWorker;
+
+/// Number of samples kept per stage between two reports, 1 MiB of memory.
+const RING_SIZE: usize = 1 << 16;
+
+#[derive(Clone, Copy, Default)]
+struct Sample {
+    /// Start of the sample, in nanoseconds since the timing started.
+    start: u64,
+    duration: u64,
+}
+
+struct Ring {
+    samples: Vec<Sample>,
+    /// Number of samples recorded since the start.
+    recorded: u64,
+    /// Number of samples already reported.
+    reported: u64,
+}
+
+/// Samples of one stage of the stream path.
+pub struct Stage {
+    name: String,
+    epoch: Instant,
+    ring: Mutex<Ring>,
+}
+
+impl Stage {
+    /// Record a transfer spending from `start` to `end` in the stage.
+    pub fn record(&self, start: Instant, end: Instant) {
+        let sample = Sample {
+            start: start.saturating_duration_since(self.epoch).as_nanos() as u64,
+            duration: end.saturating_duration_since(start).as_nanos() as u64,
+        };
+        let mut ring = self.ring.lock().unwrap();
+        let slot = (ring.recorded % RING_SIZE as u64) as usize;
+        ring.samples[slot] = sample;
+        ring.recorded += 1;
+    }
+
+    /// Samples since the last call, and how many were overwritten before
+    /// being taken.
+    fn take(&self) -> (Vec<Sample>, u64) {
+        let mut ring = self.ring.lock().unwrap();
+        let first = ring
+            .reported
+            .max(ring.recorded.saturating_sub(RING_SIZE as u64));
+        let lost = first - ring.reported;
+        let samples = (first..ring.recorded)
+            .map(|i| ring.samples[(i % RING_SIZE as u64) as usize])
+            .collect();
+        ring.reported = ring.recorded;
+        (samples, lost)
+    }
+}
+
+/// The stages of the stream path, in the order they were added.
+pub struct DebugTiming {
+    epoch: Instant,
+    stages: Mutex<Vec<Arc<Stage>>>,
+}
+
+impl DebugTiming {
+    pub fn new() -> Arc<DebugTiming> {
+        Arc::new(DebugTiming {
+            epoch: Instant::now(),
+            stages: Mutex::new(Vec::new()),
+        })
+    }
+
+    /// Add a stage named `name`.
+    pub fn stage(&self, name: &str) -> Arc<Stage> {
+        let stage = Arc::new(Stage {
+            name: name.to_string(),
+            epoch: self.epoch,
+            ring: Mutex::new(Ring {
+                samples: vec![Sample::default(); RING_SIZE],
+                recorded: 0,
+                reported: 0,
+            }),
+        });
+        self.stages.lock().unwrap().push(stage.clone());
+        stage
+    }
+
+    fn stages(&self) -> Vec<Arc<Stage>> {
+        self.stages.lock().unwrap().clone()
+    }
+}
+
+/// Duration at the `percent` percentile of sorted `durations`, in
+/// milliseconds.
+fn percentile(durations: &[u64], percent: usize) -> f64 {
+    let rank = (durations.len() * percent).div_ceil(100).max(1);
+    durations[rank - 1] as f64 / 1e6
+}
+
+struct Report {
+    timing: Arc<DebugTiming>,
+    csv: Option<BufWriter<File>>,
+    since: Instant,
+}
+
+impl Report {
+    fn run(&mut self) {
+        let secs = self.since.elapsed().as_secs_f64();
+        self.since = Instant::now();
+        for stage in self.timing.stages() {
+            let (samples, lost) = stage.take();
+            if samples.is_empty() {
+                continue;
+            }
+            let mut durations: Vec<u64> = samples.iter().map(|s| s.duration).collect();
+            durations.sort_unstable();
+            eprint!(
+                "Timing {} over {:.1} s: {} samples, p50 {:.2} ms, p95 {:.2} ms, p99 {:.2} ms, max {:.2} ms",
+                stage.name,
+                secs,
+                samples.len(),
+                percentile(&durations, 50),
+                percentile(&durations, 95),
+                percentile(&durations, 99),
+                percentile(&durations, 100)
+            );
+            if lost > 0 {
+                eprint!(", {} samples overwritten", lost);
+            }
+            eprintln!();
+            if let Err(e) = self.dump(&stage.name, &samples) {
+                warn!("Failed to write the timing samples: {}", e);
+                self.csv = None;
+            }
+        }
+        if let Some(Err(e)) = self.csv.as_mut().map(|csv| csv.flush()) {
+            warn!("Failed to write the timing samples: {}", e);
+            self.csv = None;
+        }
+    }
+
+    fn dump(&mut self, stage: &str, samples: &[Sample]) -> io::Result<()> {
+        if let Some(csv) = self.csv.as_mut() {
+            for sample in samples {
+                writeln!(
+                    csv,
+                    "\"{}\",{},{}",
+                    stage.replace('"', "\"\""),
+                    sample.start,
+                    sample.duration
+                )?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Logs the percentiles of each stage periodically.
+pub struct TimingReporter {
+    worker: PeriodicWorker,
+    report: Arc<Mutex<Report>>,
+}
+
+impl TimingReporter {
+    /// Report the samples of `timing` every `interval`. With `csv`, every
+    /// sample is also written to that file, as the stage, its start and
+    /// its duration in nanoseconds.
+    pub fn start(
+        timing: Arc<DebugTiming>,
+        interval: Duration,
+        csv: Option<&Path>,
+    ) -> io::Result<TimingReporter> {
+        let csv = match csv {
+            Some(path) => {
+                let mut csv = BufWriter::new(File::create(path)?);
+                writeln!(csv, "stage,start_ns,duration_ns")?;
+                Some(csv)
+            }
+            None => None,
+        };
+        let report = Arc::new(Mutex::new(Report {
+            timing,
+            csv,
+            since: Instant::now(),
+        }));
+        let worker = {
+            let report = report.clone();
+            PeriodicWorker::spawn("timing", interval, move || report.lock().unwrap().run())
+        };
+        Ok(TimingReporter { worker, report })
+    }
+
+    /// Stop the reporter thread, reporting the last samples.
+    pub fn stop(&mut self) {
+        self.worker.stop();
+        self.report.lock().unwrap().run();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn percentile_ranks() {
+        let durations: Vec<u64> = (1..=10).map(|ms| ms * 1_000_000).collect();
+        assert_eq!(percentile(&durations, 50), 5.0);
+        assert_eq!(percentile(&durations, 99), 10.0);
+        assert_eq!(percentile(&durations, 100), 10.0);
+        assert_eq!(percentile(&durations[..1], 50), 1.0);
+    }
+
+    #[test]
+    fn take_counts_the_overwritten_samples() {
+        let timing = DebugTiming::new();
+        let stage = timing.stage("read");
+        let record = |count: u64, from: u64| {
+            for i in from..from + count {
+                stage.record(timing.epoch, timing.epoch + Duration::from_nanos(i));
+            }
+        };
+        record(3, 0);
+        let (samples, lost) = stage.take();
+        assert_eq!((samples.len(), lost), (3, 0));
+
+        record(RING_SIZE as u64 + 10, 3);
+        let (samples, lost) = stage.take();
+        assert_eq!(lost, 10);
+        assert_eq!(samples.len(), RING_SIZE);
+        assert!(samples
+            .iter()
+            .map(|s| s.duration)
+            .eq(13..RING_SIZE as u64 + 13));
+
+        record(2, RING_SIZE as u64 + 13);
+        let (samples, lost) = stage.take();
+        assert_eq!(lost, 0);
+        assert_eq!(
+            samples.iter().map(|s| s.duration).collect::<Vec<_>>(),
+            vec![RING_SIZE as u64 + 13, RING_SIZE as u64 + 14]
+        );
+    }
+}
@@ -0,0 +1,196 @@
+//! Access to the endpoints of a device.
+
+use std::sync::RwLock;
+use std::time::Duration;
+
+use rusb::{DeviceHandle, Direction, GlobalContext, TransferType};
+
+use crate::device::{PRODUCT_ID, VENDOR_ID};
+
+/// Bulk OUT endpoint receiving the commands.
+pub const EP_COMMAND: u8 = 0x02;
+/// Bulk IN endpoint carrying the command responses.
+pub const EP_RESPONSE: u8 = 0x81;
+/// Bulk IN endpoint carrying the MPEG TS stream.
+pub const EP_STREAM: u8 = 0x83;
+
+/// The endpoints of an IT9910 device, either on USB or emulated.
+pub trait Transport: Send + Sync {
+    fn reset(&self) -> rusb::Result<()>;
+
+    /// Claim the interface and bring the endpoints to a known state.
+    fn claim(&self) -> rusb::Result<()>;
+
+    /// Claim the interface, leaving the endpoints as they are.
+    fn claim_interface(&self) -> rusb::Result<()> {
+        Ok(())
+    }
+
+    fn write_command(&self, data: &[u8], timeout: Duration) -> rusb::Result<usize>;
+
+    fn read_response(&self, buf: &mut [u8], timeout: Duration) -> rusb::Result<usize>;
+
+    fn read_stream(&self, buf: &mut [u8], timeout: Duration) -> rusb::Result<usize>;
+
+    /// Whether the device has an endpoint for asynchronous notifications.
+    fn has_events(&self) -> bool {
+        false
+    }
+
+    /// Wait for a notification on the event endpoint.
+    fn read_event(&self, _buf: &mut [u8], _timeout: Duration) -> rusb::Result<usize> {
+        Err(rusb::Error::NotSupported)
+    }
+
+    /// Whether the device is still on the bus, under the address it was
+    /// opened at.
+    fn is_attached(&self) -> bool {
+        true
+    }
+
+    /// Close the device and open it again, once it is back on the bus or to
+    /// get a fresh handle.
+    fn reopen(&self) -> rusb::Result<()> {
+        Ok(())
+    }
+}
+
+/// A device on the USB bus.
+///
+/// The handle can be replaced by `reopen` while the transport is shared, the
+/// device being found again by its position on the bus and its serial
+/// number. Between the close and the open, the transfers fail with
+/// `NoDevice`.
+pub struct UsbTransport {
+    handle: RwLock<Option<DeviceHandle<GlobalContext>>>,
+    /// Bus number and port path of the device, which stay the same when it
+    /// re-enumerates.
+    location: (u8, Vec<u8>),
+    /// Serial number of the device, if it has one.
+    serial: Option<String>,
+    /// Bus number and address of the device while it is open.
+    address: RwLock<(u8, u8)>,
+    /// Interrupt IN endpoint of the interface, which not all the variants
+    /// have.
+    event_endpoint: Option<u8>,
+}
+
+impl UsbTransport {
+    pub fn new(handle: DeviceHandle<GlobalContext>) -> UsbTransport {
+        let event_endpoint = find_event_endpoint(&handle);
+        let device = handle.device();
+        UsbTransport {
+            location: location(&device),
+            serial: serial_number(&handle),
+            address: RwLock::new((device.bus_number(), device.address())),
+            handle: RwLock::new(Some(handle)),
+            event_endpoint,
+        }
+    }
+
+    /// Run `f` on the handle, failing with `NoDevice` while it is closed.
+    fn with_handle<T>(
+        &self,
+        f: impl FnOnce(&DeviceHandle<GlobalContext>) -> rusb::Result<T>,
+    ) -> rusb::Result<T> {
+        match &*self.handle.read().unwrap() {
+            Some(handle) => f(handle),
+            None => Err(rusb::Error::NoDevice),
+        }
+    }
+}
+
+fn serial_number(handle: &DeviceHandle<GlobalContext>) -> Option<String> {
+    let desc = handle.device().device_descriptor().ok()?;
+    desc.serial_number_string_index()?;
+    handle.read_serial_number_string_ascii(&desc).ok()
+}
+
+fn location(device: &rusb::Device<GlobalContext>) -> (u8, Vec<u8>) {
+    (
+        device.bus_number(),
+        device.port_numbers().unwrap_or_default(),
+    )
+}
+
+fn find_event_endpoint(handle: &DeviceHandle<GlobalContext>) -> Option<u8> {
+    let config = handle.device().active_config_descriptor().ok()?;
+    let interface = config.interfaces().next()?;
+    let desc = interface.descriptors().next()?;
+    let endpoint = desc.endpoint_descriptors().find(|ep| {
+        ep.direction() == Direction::In && ep.transfer_type() == TransferType::Interrupt
+    });
+    endpoint.map(|ep| ep.address())
+}
+
+impl Transport for UsbTransport {
+    fn reset(&self) -> rusb::Result<()> {
+        self.with_handle(|handle| handle.reset())
+    }
+
+    fn claim(&self) -> rusb::Result<()> {
+        self.with_handle(|handle| {
+            handle.claim_interface(0)?;
+            handle.set_alternate_setting(0, 0)?;
+            handle.clear_halt(EP_RESPONSE)?;
+            handle.clear_halt(EP_STREAM)
+        })
+    }
+
+    fn claim_interface(&self) -> rusb::Result<()> {
+        self.with_handle(|handle| handle.claim_interface(0))
+    }
+
+    fn write_command(&self, data: &[u8], timeout: Duration) -> rusb::Result<usize> {
+        self.with_handle(|handle| handle.write_bulk(EP_COMMAND, data, timeout))
+    }
+
+    fn read_response(&self, buf: &mut [u8], timeout: Duration) -> rusb::Result<usize> {
+        self.with_handle(|handle| handle.read_bulk(EP_RESPONSE, buf, timeout))
+    }
+
+    fn read_stream(&self, buf: &mut [u8], timeout: Duration) -> rusb::Result<usize> {
+        self.with_handle(|handle| handle.read_bulk(EP_STREAM, buf, timeout))
+    }
+
+    fn has_events(&self) -> bool {
+        self.event_endpoint.is_some()
+    }
+
+    fn read_event(&self, buf: &mut [u8], timeout: Duration) -> rusb::Result<usize> {
+        match self.event_endpoint {
+            Some(ep) => self.with_handle(|handle| handle.read_interrupt(ep, buf, timeout)),
+            None => Err(rusb::Error::NotSupported),
+        }
+    }
+
+    fn is_attached(&self) -> bool {
+        let address = *self.address.read().unwrap();
+        rusb::devices().is_ok_and(|list| {
+            list.iter()
+                .any(|dev| (dev.bus_number(), dev.address()) == address)
+        })
+    }
+
+    fn reopen(&self) -> rusb::Result<()> {
+        // Close the current handle first, in case it is what went wrong.
+        let mut current = self.handle.write().unwrap();
+        current.take();
+        let device = rusb::devices()?
+            .iter()
+            .find(|dev| {
+                location(dev) == self.location
+                    && dev.device_descriptor().is_ok_and(|desc| {
+                        desc.vendor_id() == VENDOR_ID && desc.product_id() == PRODUCT_ID
+                    })
+            })
+            .ok_or(rusb::Error::NoDevice)?;
+        let handle = device.open()?;
+        if self.serial.is_some() && serial_number(&handle) != self.serial {
+            return Err(rusb::Error::NoDevice);
+        }
+        *self.address.write().unwrap() = (device.bus_number(), device.address());
+        *current = Some(handle);
+        Ok(())
+    }
+}
@@ -0,0 +1,527 @@
+//! MPEG transport stream packets.
+
+use std::collections::HashMap;
+
+pub const PACKET_SIZE: usize = 188;
+pub const SYNC_BYTE: u8 = 0x47;
+/// PID of the stuffing packets.
+pub const NULL_PID: u16 = 0x1fff;
+
+/// Frequency of the PCR clock.
+pub const PCR_HZ: u64 = 27_000_000;
+/// The PCR is a 33-bit base in 90 kHz units plus a 9-bit extension.
+pub const PCR_MODULUS: u64 = (1 << 33) * 300;
+
+/// A view of a single 188-byte TS packet.
+#[derive(Clone, Copy)]
+pub struct Packet<'a> {
+    data: &'a [u8],
+}
+
+impl<'a> Packet<'a> {
+    /// Returns `None` unless `data` is a full packet starting with the sync
+    /// byte.
+    pub fn new(data: &'a [u8]) -> Option<Packet<'a>> {
+        if data.len() != PACKET_SIZE || data[0] != SYNC_BYTE {
+            return None;
+        }
+        Some(Packet { data })
+    }
+
+    pub fn data(&self) -> &'a [u8] {
+        self.data
+    }
+
+    pub fn tei(&self) -> bool {
+        self.data[1] & 0x80 != 0
+    }
+
+    /// Payload unit start indicator.
+    pub fn pusi(&self) -> bool {
+        self.data[1] & 0x40 != 0
+    }
+
+    pub fn pid(&self) -> u16 {
+        u16::from(self.data[1] & 0x1f) << 8 | u16::from(self.data[2])
+    }
+
+    pub fn scrambling(&self) -> u8 {
+        self.data[3] >> 6
+    }
+
+    pub fn has_adaptation(&self) -> bool {
+        self.data[3] & 0x20 != 0
+    }
+
+    pub fn has_payload(&self) -> bool {
+        self.data[3] & 0x10 != 0
+    }
+
+    pub fn cc(&self) -> u8 {
+        self.data[3] & 0x0f
+    }
+
+    /// Adaptation field, without its length byte.
+    pub fn adaptation(&self) -> Option<&'a [u8]> {
+        if !self.has_adaptation() {
+            return None;
+        }
+        let len = usize::from(self.data[4]);
+        self.data.get(5..5 + len)
+    }
+
+    pub fn discontinuity(&self) -> bool {
+        match self.adaptation() {
+            Some(af) if !af.is_empty() => af[0] & 0x80 != 0,
+            _ => false,
+        }
+    }
+
+    /// Program clock reference, in 27 MHz ticks.
+    pub fn pcr(&self) -> Option<u64> {
+        let af = self.adaptation()?;
+        if af.len() < 7 || af[0] & 0x10 == 0 {
+            return None;
+        }
+        let base = u64::from(af[1]) << 25
+            | u64::from(af[2]) << 17
+            | u64::from(af[3]) << 9
+            | u64::from(af[4]) << 1
+            | u64::from(af[5]) >> 7;
+        let ext = u64::from(af[5] & 0x01) << 8 | u64::from(af[6]);
+        Some(base * 300 + ext)
+    }
+
+    pub fn payload(&self) -> Option<&'a [u8]> {
+        if !self.has_payload() {
+            return None;
+        }
+        let start = if self.has_adaptation() {
+            5 + usize::from(self.data[4])
+        } else {
+            4
+        };
+        self.data.get(start..)
+    }
+}
+
+/// A packet of `pid` without payload, whose adaptation field only sets the
+/// discontinuity indicator, telling that the continuity counter and the
+/// clock may jump from this packet on.
+pub fn discontinuity_packet(pid: u16, cc: u8) -> [u8; PACKET_SIZE] {
+    let mut pkt = [0xff; PACKET_SIZE];
+    pkt[0] = SYNC_BYTE;
+    pkt[1] = (pid >> 8) as u8 & 0x1f;
+    pkt[2] = pid as u8;
+    pkt[3] = 0x20 | (cc & 0x0f);
+    pkt[4] = (PACKET_SIZE - 5) as u8;
+    pkt[5] = 0x80;
+    pkt
+}
+
+/// Continuity counters of a PID, as received and as rewritten.
+#[derive(Clone, Copy)]
+struct Counters {
+    input: u8,
+    output: u8,
+}
+
+/// Rewrites the continuity counters of a stream so that they follow each
+/// other on every PID, whatever was lost before. Where packets were lost,
+/// the discontinuity indicator is set, in the adaptation field of the
+/// packet if it has room for it, or else in a packet inserted before it.
+#[derive(Default)]
+pub struct ContinuityFixer {
+    pids: HashMap<u16, Counters>,
+    marked: u64,
+}
+
+impl ContinuityFixer {
+    pub fn new() -> ContinuityFixer {
+        ContinuityFixer::default()
+    }
+
+    /// Number of losses marked with the discontinuity indicator.
+    pub fn marked(&self) -> u64 {
+        self.marked
+    }
+
+    /// Fix the packets of `data`, which is expected to be aligned on them.
+    /// Bytes out of packets are passed through.
+    pub fn fix(&mut self, data: &[u8]) -> Vec<u8> {
+        let mut out = Vec::with_capacity(data.len() + PACKET_SIZE);
+        let mut pos = 0;
+        while pos < data.len() {
+            let mut pkt = match data.get(pos..pos + PACKET_SIZE) {
+                Some(pkt) if pkt[0] == SYNC_BYTE => {
+                    let mut copy = [0; PACKET_SIZE];
+                    copy.copy_from_slice(pkt);
+                    copy
+                }
+                _ => {
+                    out.push(data[pos]);
+                    pos += 1;
+                    continue;
+                }
+            };
+            if let Some(marker) = self.packet(&mut pkt) {
+                out.extend_from_slice(&marker);
+            }
+            out.extend_from_slice(&pkt);
+            pos += PACKET_SIZE;
+        }
+        out
+    }
+
+    /// Rewrite the counter of `data`, returning the packet to insert before
+    /// it if the loss it follows could not be marked in it.
+    fn packet(&mut self, data: &mut [u8; PACKET_SIZE]) -> Option<[u8; PACKET_SIZE]> {
+        let pkt = Packet { data: &data[..] };
+        let pid = pkt.pid();
+        if pid == NULL_PID {
+            return None;
+        }
+        let (cc, flagged, has_payload) = (pkt.cc(), pkt.discontinuity(), pkt.has_payload());
+        let previous = self.pids.get(&pid).copied();
+        let (output, lost) = match previous {
+            None => (cc, false),
+            // The counter only increments with a payload.
+            Some(last) if !has_payload => (last.output, false),
+            // A packet may be sent twice.
+            Some(last) if cc == last.input && !flagged => (last.output, false),
+            Some(last) => (
+                (last.output + 1) & 0x0f,
+                cc != (last.input + 1) & 0x0f || flagged,
+            ),
+        };
+        if has_payload {
+            self.pids.insert(pid, Counters { input: cc, output });
+        }
+        data[3] = (data[3] & 0xf0) | output;
+        if !lost || flagged {
+            return None;
+        }
+        self.marked += 1;
+        let af_len = if data[3] & 0x20 != 0 { data[4] } else { 0 };
+        if af_len > 0 {
+            data[5] |= 0x80;
+            None
+        } else {
+            // No room to set it: a packet without payload repeats the
+            // counter of the previous one.
+            Some(discontinuity_packet(pid, previous?.output))
+        }
+    }
+}
+
+/// Splits a byte stream into aligned TS packets.
+///
+/// USB transfers are not aligned on packet boundaries, and the stream may
+/// start or resume in the middle of a packet. The aligner locks on when it
+/// sees three consecutive sync bytes at packet intervals, and loses the lock
+/// when a packet does not start with one.
+#[derive(Default)]
+pub struct Aligner {
+    buf: Vec<u8>,
+    /// Stream offset of the first byte of `buf`.
+    offset: u64,
+    locked: bool,
+    skipped: u64,
+}
+
+impl Aligner {
+    pub fn new() -> Aligner {
+        Aligner::default()
+    }
+
+    pub fn is_locked(&self) -> bool {
+        self.locked
+    }
+
+    /// Stream offset up to which the data was consumed, either as packets
+    /// or skipped.
+    pub fn offset(&self) -> u64 {
+        self.offset
+    }
+
+    /// Number of bytes dropped while looking for packet boundaries.
+    pub fn skipped(&self) -> u64 {
+        self.skipped
+    }
+
+    /// Feed data, calling `f` with the stream offset and contents of every
+    /// complete packet.
+    pub fn push<F: FnMut(u64, Packet)>(&mut self, data: &[u8], mut f: F) {
+        self.buf.extend_from_slice(data);
+        let mut pos = 0;
+        loop {
+            if !self.locked {
+                match self.find_sync(pos) {
+                    Some(p) => {
+                        self.skipped += (p - pos) as u64;
+                        pos = p;
+                        self.locked = true;
+                    }
+                    None => {
+                        // Keep what could still be the start of a packet.
+                        let keep = self.buf.len().saturating_sub(pos).min(2 * PACKET_SIZE);
+                        let p = self.buf.len() - keep;
+                        self.skipped += (p - pos) as u64;
+                        pos = p;
+                        break;
+                    }
+                }
+            }
+            if self.buf.len() - pos < PACKET_SIZE {
+                break;
+            }
+            match Packet::new(&self.buf[pos..pos + PACKET_SIZE]) {
+                Some(pkt) => {
+                    f(self.offset + pos as u64, pkt);
+                    pos += PACKET_SIZE;
+                }
+                None => self.locked = false,
+            }
+        }
+        self.buf.drain(..pos);
+        self.offset += pos as u64;
+    }
+
+    fn find_sync(&self, from: usize) -> Option<usize> {
+        let buf = &self.buf;
+        (from..buf.len().saturating_sub(2 * PACKET_SIZE)).find(|&i| {
+            buf[i] == SYNC_BYTE
+                && buf[i + PACKET_SIZE] == SYNC_BYTE
+                && buf[i + 2 * PACKET_SIZE] == SYNC_BYTE
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use proptest::collection::vec;
+    use proptest::prelude::*;
+
+    /// Packet of `pid`, with an adaptation field holding `flags` if any.
+    fn packet(pid: u16, cc: u8, flags: Option<u8>, payload: bool) -> [u8; PACKET_SIZE] {
+        let mut data = [0xff; PACKET_SIZE];
+        data[0] = SYNC_BYTE;
+        data[1] = (pid >> 8) as u8;
+        data[2] = pid as u8;
+        data[3] = cc;
+        if payload {
+            data[3] |= 0x10;
+        }
+        if let Some(flags) = flags {
+            data[3] |= 0x20;
+            data[4] = if payload { 1 } else { (PACKET_SIZE - 5) as u8 };
+            data[5] = flags;
+        }
+        data
+    }
+
+    fn payload(pid: u16, cc: u8) -> [u8; PACKET_SIZE] {
+        packet(pid, cc, None, true)
+    }
+
+    /// Counter, payload and discontinuity indicator of the packets of `data`.
+    fn counters(data: &[u8]) -> Vec<(u8, bool, bool)> {
+        assert_eq!(data.len() % PACKET_SIZE, 0);
+        data.chunks(PACKET_SIZE)
+            .map(|pkt| {
+                let pkt = Packet::new(pkt).unwrap();
+                (pkt.cc(), pkt.has_payload(), pkt.discontinuity())
+            })
+            .collect()
+    }
+
+    fn fix(fixer: &mut ContinuityFixer, packets: &[[u8; PACKET_SIZE]]) -> Vec<u8> {
+        fixer.fix(&packets.concat())
+    }
+
+    #[test]
+    fn continuous_counters_are_kept() {
+        let packets: Vec<_> = (7..30).map(|cc| payload(0x200, cc & 0x0f)).collect();
+        let mut fixer = ContinuityFixer::new();
+        assert_eq!(fix(&mut fixer, &packets), packets.concat());
+        assert_eq!(fixer.marked(), 0);
+    }
+
+    #[test]
+    fn loss_is_marked_in_an_inserted_packet() {
+        let mut fixer = ContinuityFixer::new();
+        let out = fix(
+            &mut fixer,
+            &[payload(0x200, 0), payload(0x200, 1), payload(0x200, 5)],
+        );
+        assert_eq!(
+            counters(&out),
+            [
+                (0, true, false),
+                (1, true, false),
+                (1, false, true),
+                (2, true, false)
+            ]
+        );
+        assert_eq!(
+            &out[2 * PACKET_SIZE..3 * PACKET_SIZE],
+            &discontinuity_packet(0x200, 1)[..]
+        );
+        assert_eq!(fixer.marked(), 1);
+    }
+
+    #[test]
+    fn loss_is_marked_in_the_adaptation_field() {
+        let mut fixer = ContinuityFixer::new();
+        let out = fix(
+            &mut fixer,
+            &[payload(0x200, 3), packet(0x200, 9, Some(0), true)],
+        );
+        assert_eq!(counters(&out), [(3, true, false), (4, true, true)]);
+        assert_eq!(fixer.marked(), 1);
+    }
+
+    #[test]
+    fn counter_only_increments_with_a_payload() {
+        let mut fixer = ContinuityFixer::new();
+        let out = fix(
+            &mut fixer,
+            &[
+                payload(0x200, 0),
+                payload(0x200, 9),
+                // Adaptation only, with a counter which is not repeated.
+                packet(0x200, 14, Some(0x10), false),
+                payload(0x200, 10),
+                packet(0x200, 10, Some(0x10), false),
+                payload(0x200, 11),
+            ],
+        );
+        assert_eq!(
+            counters(&out),
+            [
+                (0, true, false),
+                (0, false, true),
+                (1, true, false),
+                (1, false, false),
+                (2, true, false),
+                (2, false, false),
+                (3, true, false),
+            ]
+        );
+        assert_eq!(fixer.marked(), 1);
+    }
+
+    #[test]
+    fn duplicates_and_flagged_jumps_are_not_marked() {
+        let mut fixer = ContinuityFixer::new();
+        let out = fix(
+            &mut fixer,
+            &[
+                payload(0x200, 4),
+                payload(0x200, 4),
+                packet(0x200, 12, Some(0x80), true),
+                payload(0x200, 13),
+            ],
+        );
+        assert_eq!(
+            counters(&out),
+            [
+                (4, true, false),
+                (4, true, false),
+                (5, true, true),
+                (6, true, false)
+            ]
+        );
+        assert_eq!(fixer.marked(), 0);
+    }
+
+    #[test]
+    fn pids_are_counted_apart() {
+        let mut fixer = ContinuityFixer::new();
+        let out = fix(
+            &mut fixer,
+            &[
+                payload(0x200, 0),
+                payload(0x201, 8),
+                payload(0x200, 1),
+                payload(NULL_PID, 3),
+                payload(0x201, 9),
+            ],
+        );
+        let ccs: Vec<u8> = counters(&out).into_iter().map(|(cc, ..)| cc).collect();
+        assert_eq!(ccs, [0, 8, 1, 3, 9]);
+        assert_eq!(fixer.marked(), 0);
+    }
+
+    #[test]
+    fn bytes_out_of_packets_are_passed_through() {
+        let mut data = vec![0x00, 0x12];
+        data.extend_from_slice(&payload(0x200, 5));
+        data.push(0x34);
+        let mut fixer = ContinuityFixer::new();
+        assert_eq!(fixer.fix(&data), data);
+    }
+
+    /// Push `data` in pushes of the sizes of `cuts`, in turn, checking that
+    /// the packets do not overlap and that no more than two packets are
+    /// kept between pushes. Returns the number of packets.
+    fn align(data: &[u8], cuts: &[usize]) -> Result<u64, TestCaseError> {
+        let mut aligner = Aligner::new();
+        let (mut next, mut packets, mut pos) = (0, 0, 0);
+        for &cut in cuts.iter().cycle() {
+            if pos == data.len() {
+                break;
+            }
+            let end = (pos + cut).min(data.len());
+            let mut result = Ok(());
+            aligner.push(&data[pos..end], |offset, pkt| {
+                if offset < next || pkt.data()[0] != SYNC_BYTE {
+                    result = Err(TestCaseError::fail("packets overlap"));
+                }
+                next = offset + PACKET_SIZE as u64;
+                packets += 1;
+            });
+            result?;
+            pos = end;
+            prop_assert!(pos as u64 - aligner.offset() <= 2 * PACKET_SIZE as u64);
+        }
+        // Every byte is either in a packet, skipped or kept for the next
+        // push.
+        prop_assert_eq!(
+            packets * PACKET_SIZE as u64 + aligner.skipped(),
+            aligner.offset()
+        );
+        Ok(packets)
+    }
+
+    proptest! {
+        #[test]
+        fn arbitrary_data_is_aligned(
+            data in vec(any::<u8>(), 0..8192),
+            cuts in vec(1usize..2048, 1..16),
+        ) {
+            align(&data, &cuts)?;
+        }
+
+        #[test]
+        fn stream_is_found_again_after_garbage(
+            garbage in vec((0..200usize, vec(any::<u8>(), 1..400)), 0..4),
+            cuts in vec(1usize..2048, 1..16),
+        ) {
+            let mut packets: Vec<Vec<u8>> = (0..200u8)
+                .map(|i| payload(0x200, i & 0x0f).to_vec())
+                .collect();
+            for (at, bytes) in &garbage {
+                // Garbage without sync bytes cannot be taken for packets.
+                let bytes = bytes.iter().map(|&b| b & 0x7f).collect();
+                packets.insert(*at, bytes);
+            }
+            let data = packets.concat();
+            let found = align(&data, &cuts)?;
+            if garbage.is_empty() {
+                prop_assert_eq!(found, 200);
+            }
+        }
+    }
+}
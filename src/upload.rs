@@ -0,0 +1,193 @@
+//! Transfer of firmware or configuration blobs over the command channel.
+//!
+//! The blob is sent as a sequence of SET commands, each carrying the offset
+//! and length of the chunk as two little-endian words followed by the data.
+//! The device acknowledges a chunk by echoing its offset in the first word
+//! of the response; any other answer is taken as a rejection, and the chunk
+//! is sent again.
+//!
+//! The opcode is not known for all the device variants, so it has to be
+//! given by the caller.
+
+use crate::command::{CommandFactory, Operation};
+use crate::device::Device;
+use crate::error::Error;
+
+/// Largest command accepted by the device, header included.
+const MAX_COMMAND: usize = 0x200;
+/// Most chunk data carried by a command.
+pub const MAX_CHUNK_SIZE: usize = MAX_COMMAND - 0x10 - 8;
+
+pub struct Uploader {
+    pub opcode: u16,
+    pub chunk_size: usize,
+    /// Number of times a chunk is sent again after a timeout or a rejection.
+    pub retries: u32,
+}
+
+impl Uploader {
+    pub fn new(opcode: u16) -> Uploader {
+        Uploader {
+            opcode,
+            chunk_size: MAX_CHUNK_SIZE,
+            retries: 3,
+        }
+    }
+
+    /// Send `image`, calling `progress` with the number of bytes
+    /// acknowledged after each chunk.
+    pub fn upload<F: FnMut(usize)>(
+        &self,
+        device: &Device,
+        image: &[u8],
+        mut progress: F,
+    ) -> Result<(), Error> {
+        let mut factory = device.factory();
+        let chunk_size = self.chunk_size.clamp(1, MAX_CHUNK_SIZE);
+        for (i, chunk) in image.chunks(chunk_size).enumerate() {
+            let offset = (i * chunk_size) as u32;
+            self.send_chunk(device, &mut factory, offset, chunk)?;
+            progress(offset as usize + chunk.len());
+        }
+        Ok(())
+    }
+
+    /// Read `len` bytes back, with GET commands carrying the offset and
+    /// length of each chunk. The device answers with the same two words
+    /// followed by the data.
+    pub fn download(&self, device: &Device, len: usize) -> Result<Vec<u8>, Error> {
+        let chunk_size = self.chunk_size.clamp(1, MAX_CHUNK_SIZE);
+        let mut factory = device.factory();
+        let mut image = Vec::with_capacity(len);
+        while image.len() < len {
+            let offset = image.len() as u32;
+            let size = chunk_size.min(len - image.len());
+            let mut data = Vec::with_capacity(8);
+            data.extend_from_slice(&offset.to_le_bytes());
+            data.extend_from_slice(&(size as u32).to_le_bytes());
+            let cmd = factory.make_command(self.opcode, Operation::Get, &data);
+            let resp = device.transact(&cmd)?;
+            match resp.payload.get(8..8 + size) {
+                Some(chunk) if resp.word(0) == Some(offset) => image.extend_from_slice(chunk),
+                _ => {
+                    return Err(Error::ChunkRejected {
+                        offset,
+                        response: resp.payload,
+                    })
+                }
+            }
+        }
+        Ok(image)
+    }
+
+    fn send_chunk(
+        &self,
+        device: &Device,
+        factory: &mut CommandFactory,
+        offset: u32,
+        chunk: &[u8],
+    ) -> Result<(), Error> {
+        let mut data = Vec::with_capacity(8 + chunk.len());
+        data.extend_from_slice(&offset.to_le_bytes());
+        data.extend_from_slice(&(chunk.len() as u32).to_le_bytes());
+        data.extend_from_slice(chunk);
+        let mut attempt = 0;
+        loop {
+            let cmd = factory.make_command(self.opcode, Operation::Set, &data);
+            let err = match device.transact(&cmd) {
+                Ok(resp) if resp.word(0) == Some(offset) => return Ok(()),
+                Ok(resp) => Error::ChunkRejected {
+                    offset,
+                    response: resp.payload,
+                },
+                Err(e @ Error::Usb(rusb::Error::Timeout)) => e,
+                Err(e @ Error::SequenceMismatch { .. }) => e,
+                Err(e) => return Err(e),
+            };
+            attempt += 1;
+            if attempt > self.retries {
+                return Err(err);
+            }
+            eprintln!("Chunk at offset {:#x}: {}, retrying", offset, err);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::emulator::{Emulator, EmulatorConfig};
+    use std::sync::Arc;
+
+    /// An opcode the emulator acknowledges by echoing the first word.
+    const OPCODE: u16 = 0x0501;
+
+    fn emulated() -> (Arc<Emulator>, Device) {
+        let emulator = Arc::new(Emulator::new(EmulatorConfig::default()));
+        let device = Device::with_transport(emulator.clone());
+        (emulator, device)
+    }
+
+    fn uploader() -> Uploader {
+        Uploader {
+            chunk_size: 100,
+            ..Uploader::new(OPCODE)
+        }
+    }
+
+    /// Upload 1000 bytes in chunks of 100, returning the result and the
+    /// progress reported.
+    fn upload(device: &Device) -> (Result<(), Error>, Vec<usize>) {
+        let mut progress = Vec::new();
+        let res = uploader().upload(device, &[0x5a; 1000], |done| progress.push(done));
+        (res, progress)
+    }
+
+    #[test]
+    fn sends_every_chunk() {
+        let (emulator, device) = emulated();
+        let (res, progress) = upload(&device);
+        res.unwrap();
+        assert_eq!(progress, (1..=10).map(|i| i * 100).collect::<Vec<_>>());
+        assert_eq!(emulator.received(OPCODE), 10);
+    }
+
+    #[test]
+    fn sends_a_nak_chunk_again() {
+        let (emulator, device) = emulated();
+        // The fourth chunk is refused twice.
+        emulator.nak(OPCODE, 3..5);
+        let (res, progress) = upload(&device);
+        res.unwrap();
+        assert_eq!(progress.len(), 10);
+        assert_eq!(emulator.received(OPCODE), 12);
+    }
+
+    #[test]
+    fn gives_up_after_the_retries() {
+        let (emulator, device) = emulated();
+        // The third chunk is refused once more than retried.
+        emulator.nak(OPCODE, 2..6);
+        let (res, progress) = upload(&device);
+        match res {
+            Err(Error::ChunkRejected { offset, response }) => {
+                assert_eq!(offset, 200);
+                assert_eq!(response, (!200u32).to_le_bytes());
+            }
+            other => panic!("unexpected result {:?}", other),
+        }
+        assert_eq!(progress, vec![100, 200]);
+        assert_eq!(emulator.received(OPCODE), 2 + 4);
+    }
+
+    #[test]
+    fn nak_of_a_read_is_not_retried() {
+        let (emulator, device) = emulated();
+        emulator.nak(OPCODE, 0..1);
+        match uploader().download(&device, 100) {
+            Err(Error::ChunkRejected { offset: 0, .. }) => (),
+            other => panic!("unexpected result {:?}", other),
+        }
+        assert_eq!(emulator.received(OPCODE), 1);
+    }
+}
@@ -0,0 +1,80 @@
+//! Pass/fail check of a recorded stream.
+
+use serde::{Deserialize, Serialize};
+
+use crate::report::Report;
+
+/// Limits above which a recording is not usable.
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Thresholds {
+    pub max_cc_errors: u64,
+    /// PCR discontinuities, as left by interruptions of the stream.
+    pub max_gaps: u64,
+    /// Packets with the transport scrambling control bits set.
+    pub max_scrambled: u64,
+    /// Packets with the transport error indicator set.
+    pub max_tei: u64,
+}
+
+/// The criteria a report fails, empty if the recording is usable.
+pub fn check(report: &Report, thresholds: &Thresholds) -> Vec<String> {
+    let mut failures = Vec::new();
+    if report.bytes == 0 {
+        failures.push("empty file".to_string());
+        return failures;
+    }
+    if !report.starts_with_sync {
+        failures.push("does not start on a sync byte".to_string());
+    }
+    if report.trailing_bytes > 0 {
+        failures.push(format!(
+            "{} bytes of trailing garbage",
+            report.trailing_bytes
+        ));
+    }
+    if report.skipped_bytes > 0 {
+        failures.push(format!(
+            "{} bytes out of packet boundaries",
+            report.skipped_bytes
+        ));
+    }
+    if !report.has_pat {
+        failures.push("no PAT".to_string());
+    }
+    if !report.has_pmt {
+        failures.push("no PMT".to_string());
+    } else {
+        if !report.has_video() {
+            failures.push("no video PID".to_string());
+        }
+        if !report.has_audio() {
+            failures.push("no audio PID".to_string());
+        }
+    }
+    if report.continuity_errors > thresholds.max_cc_errors {
+        failures.push(format!(
+            "{} continuity errors (max {})",
+            report.continuity_errors, thresholds.max_cc_errors
+        ));
+    }
+    if report.pcr.discontinuities > thresholds.max_gaps {
+        failures.push(format!(
+            "{} gaps (max {})",
+            report.pcr.discontinuities, thresholds.max_gaps
+        ));
+    }
+    if report.scrambled_packets > thresholds.max_scrambled {
+        failures.push(format!(
+            "{} scrambled packets (max {})",
+            report.scrambled_packets, thresholds.max_scrambled
+        ));
+    }
+    if report.transport_errors > thresholds.max_tei {
+        failures.push(format!(
+            "{} packets with the transport error indicator (max {})",
+            report.transport_errors, thresholds.max_tei
+        ));
+    }
+    failures
+}
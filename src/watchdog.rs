@@ -0,0 +1,63 @@
+//! Tracking of the periods without stream data.
+
+use std::time::{Duration, Instant};
+
+use crate::analysis::Event;
+
+/// Turns the stream read timeouts into aggregated "no data" reports and gap
+/// events.
+///
+/// Rather than one message per read timeout, a report is made when the
+/// stream has been silent for `report_after`, and then every `report_after`
+/// again. When data comes back, the silence is recorded as a gap.
+pub struct StallWatchdog {
+    report_after: Duration,
+    /// Silences shorter than this are not reported as gaps.
+    min_gap: Duration,
+    last_data: Instant,
+    next_report: Duration,
+}
+
+impl StallWatchdog {
+    pub fn new(report_after: Duration, min_gap: Duration) -> StallWatchdog {
+        StallWatchdog {
+            report_after,
+            min_gap,
+            last_data: Instant::now(),
+            next_report: report_after,
+        }
+    }
+
+    /// Time since the last data was received.
+    pub fn idle(&self) -> Duration {
+        self.last_data.elapsed()
+    }
+
+    /// Record a read timeout. Returns a message when the silence reaches a
+    /// reporting threshold.
+    pub fn timeout(&mut self) -> Option<String> {
+        let idle = self.idle();
+        if idle < self.next_report {
+            return None;
+        }
+        while self.next_report <= idle {
+            self.next_report += self.report_after;
+        }
+        Some(format!("No data for {} s", idle.as_secs()))
+    }
+
+    /// Record the arrival of data at byte `offset`, returning the gap event
+    /// if the stream was silent until then.
+    pub fn data(&mut self, offset: u64) -> Option<Event> {
+        let idle = self.idle();
+        self.last_data = Instant::now();
+        self.next_report = self.report_after;
+        if idle < self.min_gap {
+            return None;
+        }
+        Some(Event::Gap {
+            offset,
+            duration_ms: idle.as_millis() as u64,
+        })
+    }
+}
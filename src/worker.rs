@@ -0,0 +1,66 @@
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+/// A background thread running a task at a fixed interval until stopped.
+///
+/// The task runs once right after spawning. Dropping the worker stops it and
+/// waits for the thread to exit.
+pub struct PeriodicWorker {
+    stop: Arc<(Mutex<bool>, Condvar)>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl PeriodicWorker {
+    pub fn spawn<F>(name: &str, interval: Duration, mut task: F) -> PeriodicWorker
+    where
+        F: FnMut() + Send + 'static,
+    {
+        let stop = Arc::new((Mutex::new(false), Condvar::new()));
+        let thread = {
+            let stop = stop.clone();
+            thread::Builder::new()
+                .name(name.to_string())
+                .spawn(move || {
+                    let (lock, cvar) = &*stop;
+                    let mut next = Instant::now();
+                    loop {
+                        task();
+                        next += interval;
+                        let mut stopped = lock.lock().unwrap();
+                        loop {
+                            if *stopped {
+                                return;
+                            }
+                            let now = Instant::now();
+                            if now >= next {
+                                break;
+                            }
+                            stopped = cvar.wait_timeout(stopped, next - now).unwrap().0;
+                        }
+                    }
+                })
+                .expect("failed to spawn worker thread")
+        };
+        PeriodicWorker {
+            stop,
+            thread: Some(thread),
+        }
+    }
+
+    /// Signal the thread to stop and wait for it to exit.
+    pub fn stop(&mut self) {
+        let (lock, cvar) = &*self.stop;
+        *lock.lock().unwrap() = true;
+        cvar.notify_all();
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+impl Drop for PeriodicWorker {
+    fn drop(&mut self) {
+        self.stop();
+    }
+}
@@ -0,0 +1,84 @@
+//! Initialization and stream start of a device, driven through the
+//! emulator.
+
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use it9910_stream_example::device::Device;
+use it9910_stream_example::emulator::{Emulator, EmulatorConfig, VIDEO_PID};
+use it9910_stream_example::firmware::{self, FirmwareVersion};
+use it9910_stream_example::grabber::GrabberConfig;
+use it9910_stream_example::protocol;
+use it9910_stream_example::psi::PAT_PID;
+use it9910_stream_example::session::CaptureSession;
+use it9910_stream_example::status::{EncoderState, InputSignal};
+use it9910_stream_example::ts::Aligner;
+
+fn emulated() -> (Arc<Emulator>, Device) {
+    let emulator = Arc::new(Emulator::new(EmulatorConfig::default()));
+    let device = Device::with_transport(emulator.clone());
+    device.reset().unwrap();
+    device.claim().unwrap();
+    (emulator, device)
+}
+
+/// Start the encoder the way a capture does.
+fn start(device: &Device) -> CaptureSession {
+    let version = FirmwareVersion::query(device).unwrap();
+    assert!(version.and_then(|v| v.known()).is_some());
+    let mut session = CaptureSession::new(device.clone(), firmware::quirks_for(version));
+    session.set_grabber_entries(firmware::grabber_entries_for(version).to_vec());
+    session.start(GrabberConfig::default()).unwrap();
+    session
+}
+
+/// Read the stream for `duration`, returning the PIDs of the packets.
+fn read_pids(device: &Device, duration: Duration) -> Vec<u16> {
+    let mut aligner = Aligner::new();
+    let mut pids = Vec::new();
+    let mut buf = vec![0u8; 64 * 1024];
+    let started = Instant::now();
+    while started.elapsed() < duration {
+        if let Ok(len) = device.read_stream(&mut buf, Duration::from_millis(100)) {
+            aligner.push(&buf[..len], |_, pkt| pids.push(pkt.pid()));
+        }
+    }
+    pids
+}
+
+#[test]
+fn reports_an_input_signal() {
+    let (_, device) = emulated();
+    let resp = device
+        .transact(&device.factory().make_get_profile())
+        .unwrap();
+    assert!(InputSignal::parse(&resp).is_some());
+}
+
+#[test]
+fn init_and_stream_start() {
+    let (emulator, device) = emulated();
+    // Nothing streams before the encoder is started.
+    assert!(read_pids(&device, Duration::from_millis(200)).is_empty());
+
+    let mut session = start(&device);
+    let entries = firmware::grabber_entries_for(FirmwareVersion::query(&device).unwrap());
+    // The disable, enable and the configuration entries.
+    assert!(emulator.received(protocol::PC_GRABBER) as usize >= entries.len() + 2);
+    let resp = device.transact(&device.factory().make_get_state()).unwrap();
+    assert_eq!(
+        EncoderState::parse(&resp),
+        Some(EncoderState { running: true })
+    );
+
+    let pids = read_pids(&device, Duration::from_millis(500));
+    assert!(pids.contains(&PAT_PID));
+    assert!(pids.contains(&VIDEO_PID));
+
+    session.stop().unwrap();
+    let resp = device.transact(&device.factory().make_get_state()).unwrap();
+    assert_eq!(
+        EncoderState::parse(&resp),
+        Some(EncoderState { running: false })
+    );
+}